@@ -0,0 +1,690 @@
+//! BLE-MIDI packets
+//!
+//! BLE-MIDI sends midi as GATT characteristic writes and notifications. Every packet starts with a
+//! header byte holding the upper 6 bits of a 13 bit millisecond timestamp, and every message is
+//! preceded by a timestamp byte holding the lower 7 bits. The status byte, and the timestamp byte
+//! when the timestamp did not change, may be left out for running status. SysEx messages that do
+//! not fit in a packet continue in the next one.
+//!
+//! ```
+//! use midi_convert::ble::{BleMidiDecoder, BleMidiEvent, BleMidiWriter};
+//! use midi_types::MidiMessage;
+//!
+//! let message = MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into());
+//! let mut buf = [0; 20];
+//! let mut writer = BleMidiWriter::new(&mut buf, 23);
+//! writer.write(1000, &message).unwrap();
+//! writer.write(1000, &message).unwrap();
+//! assert_eq!(writer.as_bytes(), &[0x87, 0xe8, 0x92, 0x76, 0x34, 0x76, 0x34]);
+//!
+//! let mut decoder: BleMidiDecoder<64> = BleMidiDecoder::new();
+//! let mut events = decoder.decode(writer.as_bytes()).unwrap();
+//! assert_eq!(
+//!     events.next_event(),
+//!     Some(Ok(BleMidiEvent::Message { timestamp: 1000, message }))
+//! );
+//! ```
+
+use {
+    crate::{parse::MidiParser, render::encode},
+    midi_types::{MidiMessage, status::*},
+};
+
+/// ATT header bytes that are not available for the characteristic value
+const ATT_OVERHEAD: usize = 3;
+
+/// Timestamps roll over after 2^13 milliseconds
+const TIMESTAMP_MASK: u64 = 0x1fff;
+
+/// Errors writing or decoding BLE-MIDI packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BleMidiError {
+    /// The packet does not start with a valid header byte
+    InvalidHeader,
+
+    /// The message does not fit in the packet, or its timestamp can not be expressed in it
+    PacketFull,
+
+    /// A SysEx message did not fit in the reassembly buffer and was dropped
+    SysExTooLong,
+}
+
+/// Writes timestamped messages into a single BLE-MIDI packet
+///
+/// Timestamps are in milliseconds, only the lower 13 bits are sent. Timestamps written to a packet
+/// must not decrease. Running status is only used within the packet, the first message of every
+/// packet carries its status byte.
+#[derive(Debug)]
+pub struct BleMidiWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    high: u8,
+    last: Option<u16>,
+    running_status: Option<u8>,
+}
+
+impl<'a> BleMidiWriter<'a> {
+    /// Create a writer for packets of up to `mtu - 3` bytes, limited to the length of `buf`
+    pub fn new(buf: &'a mut [u8], mtu: usize) -> Self {
+        let len = buf.len().min(mtu.saturating_sub(ATT_OVERHEAD));
+        assert!(len >= 5, "Packet too short for a 3 byte message");
+        Self {
+            buf: &mut buf[..len],
+            len: 0,
+            high: 0,
+            last: None,
+            running_status: None,
+        }
+    }
+
+    /// The bytes of the packet written so far
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// The length of the packet written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true when nothing has been written to the packet
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Start a new packet
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.last = None;
+        self.running_status = None;
+    }
+
+    /// Write a message with a timestamp in milliseconds
+    ///
+    /// Fails with [`BleMidiError::PacketFull`] when a new packet should be started, the packet is
+    /// left unchanged in that case.
+    pub fn write(&mut self, timestamp: u64, message: &MidiMessage) -> Result<(), BleMidiError> {
+        let timestamp = (timestamp & TIMESTAMP_MASK) as u16;
+        if !self.fits(timestamp) {
+            return Err(BleMidiError::PacketFull);
+        }
+
+        let mut running_status = self.running_status;
+        let mut buf = [0; 3];
+        let bytes = encode::<true>(message, &mut running_status, &mut buf);
+        // Data bytes only, continuing the previous status and timestamp
+        let continued = bytes.len() < message.len() && self.last == Some(timestamp);
+
+        let needed = usize::from(self.len == 0) + usize::from(!continued) + bytes.len();
+        if self.len + needed > self.buf.len() {
+            return Err(BleMidiError::PacketFull);
+        }
+
+        if !continued {
+            self.timestamp(timestamp);
+        }
+        self.push(bytes);
+        self.running_status = running_status;
+        Ok(())
+    }
+
+    /// Write as many messages as fit into the packet and return how many were written
+    pub fn write_all(&mut self, messages: &[(u64, MidiMessage)]) -> usize {
+        messages
+            .iter()
+            .take_while(|(timestamp, message)| self.write(*timestamp, message).is_ok())
+            .count()
+    }
+
+    /// Write a SysEx message, including the leading 0xF0 and trailing 0xF7, and return how many
+    /// bytes were written
+    ///
+    /// When only part of the message fits, the rest should be written to the next packet. The rest
+    /// continues the SysEx message and can only be written to an empty packet.
+    pub fn write_sysex(&mut self, timestamp: u64, data: &[u8]) -> usize {
+        let timestamp = (timestamp & TIMESTAMP_MASK) as u16;
+        let Some(&first) = data.first() else {
+            return 0;
+        };
+        if first != SYSEX_START && self.len > 0 {
+            return 0;
+        }
+
+        // Every status byte needs a timestamp byte, the header is needed in an empty packet
+        let mut written = 0;
+        for &byte in data {
+            let needed = usize::from(self.len == 0) + 1 + usize::from(byte & 0x80 != 0);
+            if self.len + needed > self.buf.len() || (byte & 0x80 != 0 && !self.fits(timestamp)) {
+                break;
+            }
+            if byte & 0x80 != 0 {
+                self.timestamp(timestamp);
+            } else if self.len == 0 {
+                self.header(timestamp);
+            }
+            self.push(&[byte]);
+            written += 1;
+        }
+
+        if written > 0 {
+            self.running_status = None;
+        }
+        written
+    }
+
+    /// Check a timestamp can follow the previous timestamp in this packet
+    fn fits(&self, timestamp: u16) -> bool {
+        let Some(last) = self.last else {
+            return true;
+        };
+        // A lower 7 bits value smaller than the previous one increments the upper bits
+        let high = if timestamp & 0x7f < last & 0x7f {
+            (self.high + 1) & 0x3f
+        } else {
+            self.high
+        };
+        timestamp >= last && u16::from(high) == timestamp >> 7
+    }
+
+    fn header(&mut self, timestamp: u16) {
+        self.high = (timestamp >> 7) as u8;
+        self.push(&[0x80 | self.high]);
+    }
+
+    fn timestamp(&mut self, timestamp: u16) {
+        if self.len == 0 {
+            self.header(timestamp);
+        }
+        self.high = (timestamp >> 7) as u8;
+        self.last = Some(timestamp);
+        self.push(&[0x80 | (timestamp & 0x7f) as u8]);
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+/// Decoded BLE-MIDI events, timestamps are in milliseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BleMidiEvent<'a> {
+    /// A midi message
+    Message {
+        timestamp: u64,
+        message: MidiMessage,
+    },
+
+    /// A complete SysEx message, including the leading 0xF0 and trailing 0xF7, with the timestamp
+    /// of its start
+    SysEx { timestamp: u64, data: &'a [u8] },
+}
+
+/// Decodes BLE-MIDI packets, reassembling SysEx messages of up to N bytes
+///
+/// The 13 bit timestamps are extended to a continuous millisecond time starting at the first
+/// received timestamp, which stays correct as long as packets are no more than 8 seconds apart.
+/// Midi bytes are parsed with a [`MidiParser`], so running status carries over between timestamps
+/// and packets.
+#[derive(Debug, Clone)]
+pub struct BleMidiDecoder<const N: usize> {
+    parser: MidiParser,
+    time: Option<u64>,
+    sysex: [u8; N],
+    sysex_len: usize,
+    sysex_time: u64,
+    sysex_active: bool,
+    sysex_overflow: bool,
+}
+
+impl<const N: usize> BleMidiDecoder<N> {
+    /// Create a new decoder
+    pub fn new() -> Self {
+        Self {
+            parser: MidiParser::new(),
+            time: None,
+            sysex: [0; N],
+            sysex_len: 0,
+            sysex_time: 0,
+            sysex_active: false,
+            sysex_overflow: false,
+        }
+    }
+
+    /// Start decoding a packet, the events are returned by [`BleMidiEvents::next_event`]
+    pub fn decode<'a>(
+        &'a mut self,
+        packet: &'a [u8],
+    ) -> Result<BleMidiEvents<'a, N>, BleMidiError> {
+        match packet.first() {
+            Some(header) if header & 0xc0 == 0x80 => Ok(BleMidiEvents {
+                decoder: self,
+                packet,
+                pos: 1,
+                high: header & 0x3f,
+                last_low: None,
+                expect_status: false,
+            }),
+            _ => Err(BleMidiError::InvalidHeader),
+        }
+    }
+
+    /// The time of the last received timestamp
+    pub fn time(&self) -> Option<u64> {
+        self.time
+    }
+
+    /// Forget the time, running status and any partially received SysEx message
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn byte(&mut self, byte: u8) -> Option<Result<Completed, BleMidiError>> {
+        let time = self.time.unwrap_or(0);
+        if self.sysex_active {
+            match byte {
+                0x00..=0x7f => {
+                    self.sysex_push(byte);
+                    return None;
+                }
+                SYSEX_END => {
+                    self.sysex_active = false;
+                    self.parser.parse(byte);
+                    self.sysex_push(byte);
+                    if self.sysex_overflow {
+                        return Some(Err(BleMidiError::SysExTooLong));
+                    }
+                    return Some(Ok(Completed::SysEx));
+                }
+                // Real time messages may be sent during SysEx
+                0xf8..=0xff => {}
+                // Any other status aborts the SysEx message
+                _ => self.sysex_active = false,
+            }
+        }
+
+        if byte == SYSEX_START {
+            self.sysex_active = true;
+            self.sysex_overflow = false;
+            self.sysex_len = 0;
+            self.sysex_time = time;
+            self.sysex_push(byte);
+        }
+        self.parser
+            .parse(byte)
+            .map(|message| Ok(Completed::Message(time, message)))
+    }
+
+    fn sysex_push(&mut self, byte: u8) {
+        if self.sysex_len < N {
+            self.sysex[self.sysex_len] = byte;
+            self.sysex_len += 1;
+        } else {
+            self.sysex_overflow = true;
+        }
+    }
+}
+
+impl<const N: usize> Default for BleMidiDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A message completed by [`BleMidiDecoder::byte`]
+#[derive(Debug, Clone, Copy)]
+enum Completed {
+    Message(u64, MidiMessage),
+    SysEx,
+}
+
+/// The events of a single packet, see [`BleMidiDecoder::decode`]
+#[derive(Debug)]
+pub struct BleMidiEvents<'a, const N: usize> {
+    decoder: &'a mut BleMidiDecoder<N>,
+    packet: &'a [u8],
+    pos: usize,
+    high: u8,
+    last_low: Option<u8>,
+    expect_status: bool,
+}
+
+impl<const N: usize> BleMidiEvents<'_, N> {
+    /// The next event in the packet, SysEx data is only valid until the next call
+    pub fn next_event(&mut self) -> Option<Result<BleMidiEvent<'_>, BleMidiError>> {
+        let completed = self.advance()?;
+        Some(completed.map(|completed| match completed {
+            Completed::Message(timestamp, message) => BleMidiEvent::Message { timestamp, message },
+            Completed::SysEx => BleMidiEvent::SysEx {
+                timestamp: self.decoder.sysex_time,
+                data: &self.decoder.sysex[..self.decoder.sysex_len],
+            },
+        }))
+    }
+
+    fn advance(&mut self) -> Option<Result<Completed, BleMidiError>> {
+        while let Some(&byte) = self.packet.get(self.pos) {
+            self.pos += 1;
+            // Outside of a message every byte with the high bit set is a timestamp byte, the byte
+            // following it is either a status byte or data for running status
+            if byte & 0x80 != 0 && !self.expect_status {
+                self.timestamp(byte & 0x7f);
+                self.expect_status = true;
+                continue;
+            }
+            self.expect_status = false;
+            if let Some(completed) = self.decoder.byte(byte) {
+                return Some(completed);
+            }
+        }
+        None
+    }
+
+    fn timestamp(&mut self, low: u8) {
+        if self.last_low.is_some_and(|last_low| low < last_low) {
+            self.high = (self.high + 1) & 0x3f;
+        }
+        self.last_low = Some(low);
+
+        let timestamp = u64::from(self.high) << 7 | u64::from(low);
+        self.decoder.time = Some(match self.decoder.time {
+            Some(time) => time + (timestamp.wrapping_sub(time) & TIMESTAMP_MASK),
+            None => timestamp,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::test::{TEST_1BYTE, TEST_2BYTE, TEST_3BYTE};
+    use midi_types::{Channel, Note, Value7};
+    use std::vec::Vec;
+
+    fn decode_all(decoder: &mut BleMidiDecoder<16>, packet: &[u8]) -> Vec<(u64, MidiMessage)> {
+        let mut events = decoder.decode(packet).unwrap();
+        let mut messages = Vec::new();
+        while let Some(event) = events.next_event() {
+            match event.unwrap() {
+                BleMidiEvent::Message { timestamp, message } => messages.push((timestamp, message)),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        messages
+    }
+
+    fn note_on(note: Note, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn(Channel::new(0), note, Value7::new(velocity))
+    }
+
+    #[test]
+    fn should_write_running_status() {
+        let mut buf = [0; 20];
+        let mut writer = BleMidiWriter::new(&mut buf, 23);
+        writer.write(0x105, &note_on(Note::C3, 0x40)).unwrap();
+        // Same timestamp, data bytes only
+        writer.write(0x105, &note_on(Note::D4, 0x41)).unwrap();
+        // New timestamp without status
+        writer.write(0x107, &note_on(Note::E4, 0x42)).unwrap();
+        writer.write(0x107, &MidiMessage::TimingClock).unwrap();
+        // System common cancels running status
+        writer
+            .write(0x108, &MidiMessage::SongSelect(3.into()))
+            .unwrap();
+        writer.write(0x108, &note_on(Note::C3, 0)).unwrap();
+
+        assert_eq!(
+            writer.as_bytes(),
+            &[
+                0x82, 0x85, 0x90, 0x3c, 0x40, 0x4a, 0x41, 0x87, 0x4c, 0x42, 0x87, 0xf8, 0x88, 0xf3,
+                0x03, 0x88, 0x90, 0x3c, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn should_write_timestamp_overflow_within_packet() {
+        let mut buf = [0; 20];
+        let mut writer = BleMidiWriter::new(&mut buf, 23);
+        writer.write(0x17e, &MidiMessage::Start).unwrap();
+        writer.write(0x181, &MidiMessage::Stop).unwrap();
+        // Earlier timestamps and more than one overflow need a new packet
+        assert_eq!(
+            writer.write(0x180, &MidiMessage::Stop),
+            Err(BleMidiError::PacketFull)
+        );
+        assert_eq!(
+            writer.write(0x202, &MidiMessage::Stop),
+            Err(BleMidiError::PacketFull)
+        );
+        assert_eq!(writer.as_bytes(), &[0x82, 0xfe, 0xfa, 0x81, 0xfc]);
+
+        let mut decoder = BleMidiDecoder::new();
+        assert_eq!(
+            decode_all(&mut decoder, writer.as_bytes()),
+            [(0x17e, MidiMessage::Start), (0x181, MidiMessage::Stop)]
+        );
+    }
+
+    #[test]
+    fn should_fill_packets_up_to_mtu() {
+        let messages: Vec<(u64, MidiMessage)> = (0..10)
+            .map(|i| {
+                (
+                    i,
+                    MidiMessage::ControlChange(Channel::new(i as u8), 7.into(), 0x10.into()),
+                )
+            })
+            .collect();
+        let mut buf = [0; 64];
+        let mut writer = BleMidiWriter::new(&mut buf, 23);
+        // Header plus 4 bytes for each message
+        assert_eq!(writer.write_all(&messages), 4);
+        assert_eq!(writer.len(), 17);
+
+        writer.clear();
+        assert_eq!(writer.write_all(&messages[4..]), 4);
+        assert_eq!(
+            &writer.as_bytes()[..6],
+            &[0x80, 0x84, 0xb4, 0x07, 0x10, 0x85]
+        );
+    }
+
+    #[test]
+    fn should_split_sysex_across_packets() {
+        let mut sysex = [0x11; 24];
+        sysex[0] = SYSEX_START;
+        sysex[23] = SYSEX_END;
+
+        let mut buf = [0; 20];
+        let mut writer = BleMidiWriter::new(&mut buf, 23);
+        writer.write(10, &MidiMessage::TimingClock).unwrap();
+        let written = writer.write_sysex(10, &sysex);
+        assert_eq!(written, 16);
+        assert_eq!(&writer.as_bytes()[..5], &[0x80, 0x8a, 0xf8, 0x8a, 0xf0]);
+
+        let mut decoder: BleMidiDecoder<24> = BleMidiDecoder::new();
+        let mut events = decoder.decode(writer.as_bytes()).unwrap();
+        assert_eq!(
+            events.next_event(),
+            Some(Ok(BleMidiEvent::Message {
+                timestamp: 10,
+                message: MidiMessage::TimingClock
+            }))
+        );
+        assert_eq!(events.next_event(), None);
+
+        // The continuation has no timestamp before the data bytes
+        writer.clear();
+        assert_eq!(writer.write_sysex(12, &sysex[written..]), 8);
+        assert_eq!(
+            writer.as_bytes(),
+            &[0x80, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x8c, 0xf7]
+        );
+        let mut events = decoder.decode(writer.as_bytes()).unwrap();
+        assert_eq!(
+            events.next_event(),
+            Some(Ok(BleMidiEvent::SysEx {
+                timestamp: 10,
+                data: &sysex
+            }))
+        );
+        assert_eq!(events.next_event(), None);
+    }
+
+    #[test]
+    fn should_not_continue_sysex_in_used_packet() {
+        let mut buf = [0; 20];
+        let mut writer = BleMidiWriter::new(&mut buf, 23);
+        writer.write(0, &MidiMessage::Start).unwrap();
+        assert_eq!(writer.write_sysex(0, &[0x01, 0x02, 0xf7]), 0);
+        assert_eq!(writer.write_sysex(0, &[]), 0);
+    }
+
+    #[test]
+    fn should_reconstruct_timestamps_across_rollover() {
+        let mut decoder = BleMidiDecoder::new();
+        assert_eq!(
+            decode_all(&mut decoder, &[0xbf, 0xf0, 0xf8]),
+            [(8176, MidiMessage::TimingClock)]
+        );
+        // Rolls over to 3
+        assert_eq!(
+            decode_all(&mut decoder, &[0xbf, 0xfe, 0xf8, 0x83, 0xf8]),
+            [
+                (8190, MidiMessage::TimingClock),
+                (8195, MidiMessage::TimingClock)
+            ]
+        );
+        assert_eq!(
+            decode_all(&mut decoder, &[0x81, 0x80, 0xf8]),
+            [(8192 + 128, MidiMessage::TimingClock)]
+        );
+        assert_eq!(decoder.time(), Some(8320));
+    }
+
+    #[test]
+    fn should_decode_running_status_after_timestamps() {
+        let mut decoder = BleMidiDecoder::new();
+        assert_eq!(
+            decode_all(
+                &mut decoder,
+                &[
+                    0x80, 0x81, 0x90, 0x3c, 0x40, 0x4a, 0x41, 0x82, 0x4c, 0x42, 0x83, 0xf8, 0x3c,
+                    0x00
+                ]
+            ),
+            [
+                (1, note_on(Note::C3, 0x40)),
+                (1, note_on(Note::D4, 0x41)),
+                (2, note_on(Note::E4, 0x42)),
+                (3, MidiMessage::TimingClock),
+                (3, note_on(Note::C3, 0)),
+            ]
+        );
+        // Running status carries over to the next packet
+        assert_eq!(
+            decode_all(&mut decoder, &[0x80, 0x84, 0x3c, 0x40]),
+            [(4, note_on(Note::C3, 0x40))]
+        );
+    }
+
+    #[test]
+    fn should_decode_real_time_during_sysex() {
+        let mut decoder: BleMidiDecoder<8> = BleMidiDecoder::new();
+        let mut events = decoder
+            .decode(&[0x80, 0x81, 0xf0, 0x01, 0x02, 0x82, 0xf8, 0x03, 0x83, 0xf7])
+            .unwrap();
+        assert_eq!(
+            events.next_event(),
+            Some(Ok(BleMidiEvent::Message {
+                timestamp: 2,
+                message: MidiMessage::TimingClock
+            }))
+        );
+        assert_eq!(
+            events.next_event(),
+            Some(Ok(BleMidiEvent::SysEx {
+                timestamp: 1,
+                data: &[0xf0, 0x01, 0x02, 0x03, 0xf7]
+            }))
+        );
+        assert_eq!(events.next_event(), None);
+    }
+
+    #[test]
+    fn should_report_sysex_overflow() {
+        let mut decoder: BleMidiDecoder<4> = BleMidiDecoder::new();
+        let mut events = decoder
+            .decode(&[
+                0x80, 0x81, 0xf0, 0x01, 0x02, 0x03, 0x04, 0x81, 0xf7, 0x81, 0xfa,
+            ])
+            .unwrap();
+        assert_eq!(events.next_event(), Some(Err(BleMidiError::SysExTooLong)));
+        assert_eq!(
+            events.next_event(),
+            Some(Ok(BleMidiEvent::Message {
+                timestamp: 1,
+                message: MidiMessage::Start
+            }))
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_header() {
+        let mut decoder: BleMidiDecoder<4> = BleMidiDecoder::new();
+        assert!(matches!(
+            decoder.decode(&[]),
+            Err(BleMidiError::InvalidHeader)
+        ));
+        assert!(matches!(
+            decoder.decode(&[0xc0, 0x80, 0xf8]),
+            Err(BleMidiError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn should_round_trip_with_parser() {
+        let messages: Vec<(u64, MidiMessage)> = TEST_1BYTE
+            .iter()
+            .chain(TEST_2BYTE.iter())
+            .chain(TEST_3BYTE.iter())
+            .chain(TEST_3BYTE.iter())
+            .enumerate()
+            .map(|(i, message)| (5000 + 97 * (i as u64 / 2), *message))
+            .collect();
+
+        // Packets as written by the writer, parsed as a byte stream without timestamps
+        let mut buf = [0; 20];
+        let mut decoder = BleMidiDecoder::new();
+        let mut parser = MidiParser::new();
+        let mut decoded = Vec::new();
+        let mut parsed = Vec::new();
+        let mut remaining = &messages[..];
+        while !remaining.is_empty() {
+            let mut writer = BleMidiWriter::new(&mut buf, 23);
+            let written = writer.write_all(remaining);
+            assert!(written > 0);
+            remaining = &remaining[written..];
+
+            decoded.extend(decode_all(&mut decoder, writer.as_bytes()));
+
+            let mut expect_status = false;
+            for &byte in &writer.as_bytes()[1..] {
+                if byte & 0x80 != 0 && !expect_status {
+                    expect_status = true;
+                    continue;
+                }
+                expect_status = false;
+                parsed.extend(parser.parse(byte));
+            }
+        }
+
+        assert_eq!(decoded, messages);
+        let messages: Vec<MidiMessage> = messages.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(parsed, messages);
+    }
+}
@@ -0,0 +1,214 @@
+//! A `tokio-util` codec for midi byte streams
+//!
+//! ```
+//! use midi_convert::codec::MidiCodec;
+//! use tokio_util::codec::{Decoder, Encoder};
+//! use bytes::BytesMut;
+//! use midi_types::MidiMessage;
+//!
+//! let mut codec: MidiCodec = MidiCodec::new();
+//! let mut buf = BytesMut::new();
+//! codec.encode(MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into()), &mut buf).unwrap();
+//! codec.encode(MidiMessage::NoteOn(2.into(), 0x33.into(), 0x65.into()), &mut buf).unwrap();
+//! assert_eq!(&buf[..], &[0x92, 0x76, 0x34, 0x33, 0x65]);
+//!
+//! assert_eq!(codec.decode(&mut buf).unwrap(), Some(MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into())));
+//! assert_eq!(codec.decode(&mut buf).unwrap(), Some(MidiMessage::NoteOn(2.into(), 0x33.into(), 0x65.into())));
+//! assert_eq!(codec.decode(&mut buf).unwrap(), None);
+//! ```
+
+use {
+    crate::{parse::MidiParser, render::encode},
+    bytes::{Buf, BufMut, BytesMut},
+    midi_types::MidiMessage,
+    std::io,
+    tokio_util::codec::{Decoder, Encoder},
+};
+
+/// Codec turning a byte stream into `MidiMessage`s and back, for use with `FramedRead`,
+/// `FramedWrite` and `Framed`.
+///
+/// Decoding uses a [`MidiParser`], so partial messages and running status are carried over between
+/// reads. Encoding renders messages the same way as the
+/// [`MidiRenderer`](crate::render::MidiRenderer), the boolean const generic RUNNING_STATUS enables
+/// or disables rendering running status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiCodec<const RUNNING_STATUS: bool = true> {
+    parser: MidiParser,
+    running_status: Option<u8>,
+}
+
+impl<const RUNNING_STATUS: bool> MidiCodec<RUNNING_STATUS> {
+    /// Create a new codec
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<const RUNNING_STATUS: bool> Default for MidiCodec<RUNNING_STATUS> {
+    fn default() -> Self {
+        Self {
+            parser: MidiParser::new(),
+            running_status: None,
+        }
+    }
+}
+
+impl<const RUNNING_STATUS: bool> Decoder for MidiCodec<RUNNING_STATUS> {
+    type Item = MidiMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<MidiMessage>, io::Error> {
+        for (i, byte) in src.iter().enumerate() {
+            if let Some(message) = self.parser.parse(*byte) {
+                src.advance(i + 1);
+                return Ok(Some(message));
+            }
+        }
+
+        // Everything was consumed, any partial message is kept in the parser state
+        src.clear();
+        Ok(None)
+    }
+}
+
+impl<const RUNNING_STATUS: bool> Encoder<MidiMessage> for MidiCodec<RUNNING_STATUS> {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: MidiMessage, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode(&message, dst)
+    }
+}
+
+impl<const RUNNING_STATUS: bool> Encoder<&MidiMessage> for MidiCodec<RUNNING_STATUS> {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: &MidiMessage, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut buf = [0; 3];
+        dst.put_slice(encode::<RUNNING_STATUS>(
+            message,
+            &mut self.running_status,
+            &mut buf,
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use midi_types::{Channel, Note, Value7};
+    use std::vec::Vec;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    const MESSAGES: [MidiMessage; 6] = [
+        MidiMessage::NoteOn(Channel::new(0), Note::C3, Value7::new(0x40)),
+        MidiMessage::NoteOn(Channel::new(0), Note::D4, Value7::new(0x41)),
+        MidiMessage::TimingClock,
+        MidiMessage::NoteOn(Channel::new(0), Note::E4, Value7::new(0x42)),
+        MidiMessage::SongSelect(Value7::new(3)),
+        MidiMessage::NoteOn(Channel::new(0), Note::C3, Value7::new(0)),
+    ];
+
+    #[tokio::test]
+    async fn should_encode_with_running_status() {
+        let (client, mut server) = duplex(64);
+        let mut sink = FramedWrite::new(client, MidiCodec::<true>::new());
+        for message in MESSAGES {
+            sink.send(message).await.unwrap();
+        }
+        drop(sink);
+
+        let mut bytes = Vec::new();
+        server.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(
+            bytes,
+            &[
+                0x90, 0x3c, 0x40, 0x4a, 0x41, 0xf8, 0x4c, 0x42, 0xf3, 0x03, 0x90, 0x3c, 0x00
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_encode_without_running_status() {
+        let (client, mut server) = duplex(64);
+        let mut sink = FramedWrite::new(client, MidiCodec::<false>::new());
+        sink.send(MESSAGES[0]).await.unwrap();
+        sink.send(MESSAGES[1]).await.unwrap();
+        drop(sink);
+
+        let mut bytes = Vec::new();
+        server.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, &[0x90, 0x3c, 0x40, 0x90, 0x4a, 0x41]);
+    }
+
+    #[test]
+    fn should_keep_partial_message_in_parser() {
+        let mut codec: MidiCodec = MidiCodec::new();
+        let mut buf = BytesMut::from(&[0x92, 0x76][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+
+        buf.put_slice(&[0x34, 0x33]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into()))
+        );
+        assert_eq!(&buf[..], &[0x33]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.put_slice(&[0x65]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(MidiMessage::NoteOn(2.into(), 0x33.into(), 0x65.into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_carry_partial_frames_over() {
+        let (mut client, server) = duplex(64);
+        let mut stream = FramedRead::new(server, MidiCodec::<true>::new());
+
+        client.write_all(&[0xE3, 0x3C]).await.unwrap();
+        client.flush().await.unwrap();
+        client.write_all(&[0x18, 0x43]).await.unwrap();
+        client.flush().await.unwrap();
+        client.write_all(&[0x01]).await.unwrap();
+        drop(client);
+
+        let messages: Vec<MidiMessage> = stream.by_ref().map(Result::unwrap).collect().await;
+        assert_eq!(
+            messages.as_slice(),
+            &[
+                MidiMessage::PitchBendChange(3.into(), (0x18, 0x3c).into()),
+                MidiMessage::PitchBendChange(3.into(), (0x01, 0x43).into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_round_trip_through_duplex() {
+        let (client, server) = duplex(4);
+        let mut sink = FramedWrite::new(client, MidiCodec::<true>::new());
+        let mut stream = FramedRead::new(server, MidiCodec::<true>::new());
+
+        let writer = async {
+            for message in MESSAGES.iter() {
+                sink.send(message).await.unwrap();
+            }
+            SinkExt::<&MidiMessage>::close(&mut sink).await.unwrap();
+        };
+        let reader = async {
+            let mut received = Vec::new();
+            while let Some(message) = stream.next().await {
+                received.push(message.unwrap());
+            }
+            received
+        };
+
+        let (_, received) = tokio::join!(writer, reader);
+        assert_eq!(received.as_slice(), &MESSAGES);
+    }
+}
@@ -0,0 +1,210 @@
+//! Integration with `std::io` readers and writers
+//!
+//! `Vec<u8>` is a [`MidiTransport`] of its own, other writers are wrapped in [`IoTransport`]. A
+//! blanket implementation for every `std::io::Write` would make the `std` feature non-additive:
+//! enabling it anywhere in the dependency graph would break crates that implement
+//! [`MidiTransport`] for their own writers.
+
+use {
+    crate::{parse::MidiParser, render::MidiTransport},
+    core::convert::Infallible,
+    midi_types::MidiMessage,
+    std::{
+        io::{self, ErrorKind, Read, Write},
+        vec::Vec,
+    },
+};
+
+impl MidiTransport for Vec<u8> {
+    type Error = Infallible;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Wraps any `std::io::Write`, like `File` and `TcpStream`, so it can be used as a transport for
+/// the [`MidiRenderer`](crate::render::MidiRenderer)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoTransport<W> {
+    writer: W,
+}
+
+impl<W: Write> IoTransport<W> {
+    /// Create a new transport writing to `writer`
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Release the underlying writer
+    pub fn release(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> MidiTransport for IoTransport<W> {
+    type Error = io::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.writer.write_all(bytes)
+    }
+}
+
+/// An iterator that reads bytes from a `std::io::Read` and yields the midi messages parsed from them.
+///
+/// Iteration ends when the reader reaches end of file, errors other than `ErrorKind::Interrupted`
+/// are yielded and the reader can be polled again afterwards.
+#[derive(Debug)]
+pub struct MidiReader<R> {
+    reader: R,
+    parser: MidiParser,
+    buf: [u8; 64],
+    pos: usize,
+    len: usize,
+}
+
+impl<R: Read> MidiReader<R> {
+    /// Create a new reader wrapping `reader`
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            parser: MidiParser::new(),
+            buf: [0; 64],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Release the underlying reader, any buffered bytes that were not parsed yet are dropped
+    pub fn release(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for MidiReader<R> {
+    type Item = Result<MidiMessage, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.pos < self.len {
+                let byte = self.buf[self.pos];
+                self.pos += 1;
+                if let Some(message) = self.parser.parse(byte) {
+                    return Some(Ok(message));
+                }
+            }
+
+            self.pos = 0;
+            self.len = 0;
+            match self.reader.read(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(len) => self.len = len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::MidiRenderer;
+    use midi_types::Note;
+
+    #[test]
+    fn should_render_into_vec() {
+        let mut renderer: MidiRenderer<_> = MidiRenderer::new(Vec::new());
+        renderer
+            .render(&MidiMessage::NoteOn(2.into(), Note::D4, 0x34.into()))
+            .unwrap();
+        renderer
+            .render(&MidiMessage::NoteOn(2.into(), Note::G6, 0x65.into()))
+            .unwrap();
+        assert_eq!(renderer.release(), &[0x92, 0x4a, 0x34, 0x67, 0x65]);
+    }
+
+    #[test]
+    fn should_render_into_writer() {
+        let mut buf = [0u8; 4];
+        let mut renderer: MidiRenderer<_, false> =
+            MidiRenderer::new(IoTransport::new(&mut buf[..]));
+        renderer
+            .render(&MidiMessage::ProgramChange(1.into(), 2.into()))
+            .unwrap();
+        renderer
+            .render(&MidiMessage::ProgramChange(1.into(), 3.into()))
+            .unwrap();
+        assert_eq!(
+            renderer
+                .render(&MidiMessage::TuneRequest)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::WriteZero
+        );
+        assert_eq!(buf, [0xC1, 0x02, 0xC1, 0x03]);
+    }
+
+    #[test]
+    fn should_read_messages() {
+        let bytes: &[u8] = &[
+            0xE3, 0x3C, 0x18, // First pitchbend
+            0x43, 0x01, // Second pitchbend without status byte
+            0xfa, // Start
+        ];
+        let messages: Vec<MidiMessage> = MidiReader::new(bytes).map(Result::unwrap).collect();
+        assert_eq!(
+            messages.as_slice(),
+            &[
+                MidiMessage::PitchBendChange(3.into(), (0x18, 0x3c).into()),
+                MidiMessage::PitchBendChange(3.into(), (0x01, 0x43).into()),
+                MidiMessage::Start,
+            ]
+        );
+    }
+
+    #[test]
+    fn should_yield_read_errors() {
+        struct FailingReader(bool);
+
+        impl Read for FailingReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 {
+                    return Ok(0);
+                }
+                self.0 = true;
+                buf[0] = 0xf8;
+                Err(io::Error::other("broken"))
+            }
+        }
+
+        let mut reader = MidiReader::new(FailingReader(false));
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), ErrorKind::Other);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn should_read_across_buffer_boundaries() {
+        let mut bytes = Vec::new();
+        let mut renderer: MidiRenderer<_> = MidiRenderer::new(IoTransport::new(&mut bytes));
+        for i in 0..100u8 {
+            renderer
+                .render(&MidiMessage::ControlChange(
+                    (i % 2).into(),
+                    7.into(),
+                    i.into(),
+                ))
+                .unwrap();
+        }
+
+        let messages: Vec<MidiMessage> = MidiReader::new(bytes.as_slice())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(messages.len(), 100);
+        assert_eq!(
+            messages[99],
+            MidiMessage::ControlChange(1.into(), 7.into(), 99.into())
+        );
+    }
+}
@@ -37,10 +37,32 @@
 //!
 
 #![no_std]
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod ble;
+#[cfg(feature = "tokio-util")]
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod io;
+pub mod optimize;
+pub mod osc;
+pub mod packed;
 #[warn(missing_debug_implementations, missing_docs)]
 pub mod parse;
+#[cfg(feature = "async")]
+pub mod parse_async;
+pub mod queue;
 pub mod render;
+#[cfg(feature = "async")]
+pub mod render_async;
 pub mod render_slice;
+pub mod rtp;
+pub mod scale;
+pub mod throttle;
+pub mod uart;
+pub mod ump;
+pub mod usb;
 
 pub use midi_types;
 
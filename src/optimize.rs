@@ -0,0 +1,591 @@
+//! Offline optimization of message sequences for the smallest rendered byte stream
+//!
+//! The optimizer
+//!
+//! * drops messages that do not change the receiver state: repeated controller values, duplicate
+//!   program changes and pitch bends equal to the current value,
+//! * rewrites a NoteOff with the default release velocity (64) into a NoteOn with velocity 0 when
+//!   that allows running status,
+//! * reorders simultaneous messages on different channels to reuse running status, messages on the
+//!   same channel and system messages keep their order.
+//!
+//! Byte counts in the [`OptimizeReport`] use the same encoder as the
+//! [`MidiRenderer`](crate::render::MidiRenderer) with running status enabled.
+//!
+//! ```
+//! use midi_convert::optimize::optimize;
+//! use midi_types::MidiMessage;
+//!
+//! let input = [
+//!     MidiMessage::ControlChange(0.into(), 7.into(), 100.into()),
+//!     MidiMessage::ControlChange(0.into(), 7.into(), 100.into()),
+//!     MidiMessage::NoteOn(0.into(), 60.into(), 100.into()),
+//!     MidiMessage::NoteOff(0.into(), 60.into(), 64.into()),
+//! ];
+//! let mut output = input;
+//! let report = optimize(&input, &mut output);
+//! assert_eq!(
+//!     &output[..report.messages_out],
+//!     &[
+//!         MidiMessage::ControlChange(0.into(), 7.into(), 100.into()),
+//!         MidiMessage::NoteOn(0.into(), 60.into(), 100.into()),
+//!         MidiMessage::NoteOn(0.into(), 60.into(), 0.into()),
+//!     ]
+//! );
+//! assert_eq!(report.bytes_in, 11);
+//! assert_eq!(report.bytes_out, 8);
+//! assert_eq!(report.bytes_saved(), 3);
+//! ```
+
+use {
+    crate::render::encode,
+    midi_types::{MidiMessage, status::*},
+};
+
+/// Summary of an optimization pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OptimizeReport {
+    /// Number of input messages
+    pub messages_in: usize,
+    /// Number of messages written to the output
+    pub messages_out: usize,
+    /// Number of redundant messages that were dropped
+    pub dropped: usize,
+    /// Number of NoteOff messages rewritten to NoteOn with velocity 0
+    pub rewritten: usize,
+    /// Rendered size of the input
+    pub bytes_in: usize,
+    /// Rendered size of the output
+    pub bytes_out: usize,
+}
+
+impl OptimizeReport {
+    /// The number of bytes saved by the optimization
+    pub fn bytes_saved(&self) -> usize {
+        self.bytes_in.saturating_sub(self.bytes_out)
+    }
+}
+
+/// Optimize a sequence of messages into `output`, returning a report. The optimized sequence is
+/// `output[..report.messages_out]`.
+///
+/// Without timing information every message is considered to happen at a different time, so
+/// messages are never reordered, see [`optimize_timed`].
+///
+/// # Panics
+///
+/// Panics if `output` is shorter than `input`.
+pub fn optimize(input: &[MidiMessage], output: &mut [MidiMessage]) -> OptimizeReport {
+    optimize_events(input, output)
+}
+
+/// Optimize a sequence of timestamped messages into `output`, returning a report. The optimized
+/// sequence is `output[..report.messages_out]`.
+///
+/// Consecutive messages with equal timestamps are considered simultaneous and may be reordered.
+///
+/// # Panics
+///
+/// Panics if `output` is shorter than `input`.
+pub fn optimize_timed<T: Copy + PartialEq>(
+    input: &[(T, MidiMessage)],
+    output: &mut [(T, MidiMessage)],
+) -> OptimizeReport {
+    optimize_events(input, output)
+}
+
+trait Event: Copy {
+    fn message(&self) -> &MidiMessage;
+    fn message_mut(&mut self) -> &mut MidiMessage;
+    fn simultaneous(&self, other: &Self) -> bool;
+}
+
+impl Event for MidiMessage {
+    fn message(&self) -> &MidiMessage {
+        self
+    }
+
+    fn message_mut(&mut self) -> &mut MidiMessage {
+        self
+    }
+
+    fn simultaneous(&self, _other: &Self) -> bool {
+        false
+    }
+}
+
+impl<T: Copy + PartialEq> Event for (T, MidiMessage) {
+    fn message(&self) -> &MidiMessage {
+        &self.1
+    }
+
+    fn message_mut(&mut self) -> &mut MidiMessage {
+        &mut self.1
+    }
+
+    fn simultaneous(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+/// Number of bytes `messages` render to using running status
+fn rendered_len<'a>(messages: impl Iterator<Item = &'a MidiMessage>) -> usize {
+    let mut running_status = None;
+    messages
+        .map(|message| encode::<true>(message, &mut running_status, &mut [0; 3]).len())
+        .sum()
+}
+
+fn optimize_events<E: Event>(input: &[E], output: &mut [E]) -> OptimizeReport {
+    assert!(output.len() >= input.len());
+    output[..input.len()].copy_from_slice(input);
+
+    let mut report = OptimizeReport {
+        messages_in: input.len(),
+        bytes_in: rendered_len(input.iter().map(Event::message)),
+        ..Default::default()
+    };
+
+    let mut state = State::new();
+    let mut end = input.len();
+    let mut i = 0;
+    while i < end {
+        // The range of messages simultaneous with output[i] that are not separated by system
+        // messages
+        let mut segment_end = i + 1;
+        if channel(output[i].message()).is_some() {
+            while segment_end < end
+                && output[segment_end].simultaneous(&output[i])
+                && channel(output[segment_end].message()).is_some()
+            {
+                segment_end += 1;
+            }
+        }
+
+        while i < segment_end {
+            match state.pick(&output[i..segment_end]) {
+                Pick::Redundant(j) => {
+                    output[i + j..end].rotate_left(1);
+                    segment_end -= 1;
+                    end -= 1;
+                    report.dropped += 1;
+                }
+                Pick::Next(j) => {
+                    output[i..=i + j].rotate_right(1);
+                    if state.emit(output[i].message_mut()) {
+                        report.rewritten += 1;
+                    }
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    report.messages_out = end;
+    report.bytes_out = rendered_len(output[..end].iter().map(Event::message));
+    report
+}
+
+/// The channel of a channel voice message
+fn channel(message: &MidiMessage) -> Option<u8> {
+    match *message {
+        MidiMessage::NoteOff(c, ..)
+        | MidiMessage::NoteOn(c, ..)
+        | MidiMessage::KeyPressure(c, ..)
+        | MidiMessage::ControlChange(c, ..)
+        | MidiMessage::ProgramChange(c, ..)
+        | MidiMessage::ChannelPressure(c, ..)
+        | MidiMessage::PitchBendChange(c, ..) => Some(c.into()),
+        _ => None,
+    }
+}
+
+/// Data entry, increment/decrement and channel mode controllers act on other state or trigger an
+/// action, these are never dropped
+fn is_stateless_control(control: u8) -> bool {
+    matches!(control, 6 | 38 | 96 | 97 | 120..=127)
+}
+
+enum Pick {
+    /// The message at this index does not change the receiver state
+    Redundant(usize),
+    /// The message at this index should be emitted next
+    Next(usize),
+}
+
+/// Receiver state as far as it is known from the messages emitted so far
+struct State {
+    running_status: Option<u8>,
+    controls: [[Option<u8>; 128]; 16],
+    programs: [Option<u8>; 16],
+    pitch_bends: [Option<(u8, u8)>; 16],
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            running_status: None,
+            controls: [[None; 128]; 16],
+            programs: [None; 16],
+            pitch_bends: [None; 16],
+        }
+    }
+
+    /// Pick the next message to handle from a segment of simultaneous messages. Only the first
+    /// remaining message of every channel is eligible, so the order within a channel is kept.
+    fn pick<E: Event>(&self, segment: &[E]) -> Pick {
+        let mut seen: u16 = 0;
+        for (j, event) in segment.iter().enumerate() {
+            let message = event.message();
+            let Some(channel) = channel(message) else {
+                return Pick::Next(0);
+            };
+            if seen & (1 << channel) != 0 {
+                continue;
+            }
+            seen |= 1 << channel;
+
+            if self.is_redundant(message) {
+                return Pick::Redundant(j);
+            }
+            if self.running_status.is_some()
+                && self.running_status == Some(self.status_for(message).0)
+            {
+                return Pick::Next(j);
+            }
+        }
+        Pick::Next(0)
+    }
+
+    fn is_redundant(&self, message: &MidiMessage) -> bool {
+        match *message {
+            MidiMessage::ControlChange(c, control, value) => {
+                let control: u8 = control.into();
+                !is_stateless_control(control)
+                    && self.controls[usize::from(u8::from(c))][usize::from(control)]
+                        == Some(value.into())
+            }
+            MidiMessage::ProgramChange(c, program) => {
+                self.programs[usize::from(u8::from(c))] == Some(program.into())
+            }
+            MidiMessage::PitchBendChange(c, value) => {
+                self.pitch_bends[usize::from(u8::from(c))] == Some(value.into())
+            }
+            _ => false,
+        }
+    }
+
+    /// The status byte `message` renders with, and whether it should be rewritten to a NoteOn with
+    /// velocity 0
+    fn status_for(&self, message: &MidiMessage) -> (u8, bool) {
+        let mut buf = [0; 3];
+        let mut running_status = None;
+        encode::<true>(message, &mut running_status, &mut buf);
+        if let MidiMessage::NoteOff(c, _, velocity) = *message {
+            let note_on = NOTE_ON + u8::from(c);
+            if u8::from(velocity) == 64 && self.running_status == Some(note_on) {
+                return (note_on, true);
+            }
+        }
+        (buf[0], false)
+    }
+
+    /// Update the state for an emitted message, returns true if the message was rewritten
+    fn emit(&mut self, message: &mut MidiMessage) -> bool {
+        let (_, rewrite) = self.status_for(message);
+        if let (true, MidiMessage::NoteOff(c, note, _)) = (rewrite, *message) {
+            *message = MidiMessage::NoteOn(c, note, 0.into());
+        }
+
+        match *message {
+            MidiMessage::ControlChange(c, control, value) => {
+                let c = usize::from(u8::from(c));
+                let control: u8 = control.into();
+                let changed = self.controls[c][usize::from(control)] != Some(value.into());
+                self.controls[c][usize::from(control)] = Some(value.into());
+                match control {
+                    // Bank select only takes effect with the next program change
+                    0 | 32 if changed => self.programs[c] = None,
+                    // Selecting an NRPN deselects the RPN and the other way around
+                    98 | 99 => {
+                        self.controls[c][100] = None;
+                        self.controls[c][101] = None;
+                    }
+                    100 | 101 => {
+                        self.controls[c][98] = None;
+                        self.controls[c][99] = None;
+                    }
+                    121 => {
+                        self.controls[c] = [None; 128];
+                        self.pitch_bends[c] = None;
+                    }
+                    _ => {}
+                }
+            }
+            MidiMessage::ProgramChange(c, program) => {
+                self.programs[usize::from(u8::from(c))] = Some(program.into());
+            }
+            MidiMessage::PitchBendChange(c, value) => {
+                self.pitch_bends[usize::from(u8::from(c))] = Some(value.into());
+            }
+            MidiMessage::Reset => *self = Self::new(),
+            _ => {}
+        }
+
+        encode::<true>(message, &mut self.running_status, &mut [0; 3]);
+        rewrite
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{MidiRenderer, MidiTransport};
+    use midi_types::Note;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    fn cc(channel: u8, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(channel.into(), control.into(), value.into())
+    }
+
+    fn note_on(channel: u8, note: Note, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn(channel.into(), note, velocity.into())
+    }
+
+    fn note_off(channel: u8, note: Note, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOff(channel.into(), note, velocity.into())
+    }
+
+    fn run(input: &[MidiMessage]) -> (Vec<MidiMessage>, OptimizeReport) {
+        let mut output = input.to_vec();
+        let report = optimize(input, &mut output);
+        output.truncate(report.messages_out);
+        (output, report)
+    }
+
+    fn run_timed(input: &[(u32, MidiMessage)]) -> (Vec<(u32, MidiMessage)>, OptimizeReport) {
+        let mut output = input.to_vec();
+        let report = optimize_timed(input, &mut output);
+        output.truncate(report.messages_out);
+        (output, report)
+    }
+
+    #[test]
+    fn should_drop_redundant_messages() {
+        let (output, report) = run(&[
+            cc(0, 7, 100),
+            cc(1, 7, 100),
+            cc(0, 7, 100),
+            MidiMessage::ProgramChange(0.into(), 3.into()),
+            MidiMessage::ProgramChange(0.into(), 3.into()),
+            MidiMessage::PitchBendChange(0.into(), 8192u16.into()),
+            MidiMessage::PitchBendChange(0.into(), 8192u16.into()),
+            MidiMessage::PitchBendChange(0.into(), 8193u16.into()),
+        ]);
+        assert_eq!(
+            output,
+            &[
+                cc(0, 7, 100),
+                cc(1, 7, 100),
+                MidiMessage::ProgramChange(0.into(), 3.into()),
+                MidiMessage::PitchBendChange(0.into(), 8192u16.into()),
+                MidiMessage::PitchBendChange(0.into(), 8193u16.into()),
+            ]
+        );
+        assert_eq!(report.dropped, 3);
+        assert_eq!(report.bytes_in, 3 + 3 + 3 + 2 + 1 + 3 + 2 + 2);
+        assert_eq!(report.bytes_out, 3 + 3 + 2 + 3 + 2);
+    }
+
+    #[test]
+    fn should_keep_stateless_and_dependent_controllers() {
+        let input = [
+            cc(0, 101, 0),
+            cc(0, 100, 0),
+            cc(0, 6, 2),
+            cc(0, 6, 2),
+            cc(0, 96, 0),
+            cc(0, 96, 0),
+            cc(0, 123, 0),
+            cc(0, 123, 0),
+        ];
+        let (output, report) = run(&input);
+        assert_eq!(output, &input);
+        assert_eq!(report.dropped, 0);
+    }
+
+    #[test]
+    fn should_forget_data_entry_when_parameter_changes() {
+        let (output, _) = run(&[
+            cc(0, 101, 0),
+            cc(0, 100, 0),
+            cc(0, 6, 2),
+            cc(0, 100, 1),
+            cc(0, 6, 2),
+            cc(0, 101, 0),
+        ]);
+        assert_eq!(
+            output,
+            &[
+                cc(0, 101, 0),
+                cc(0, 100, 0),
+                cc(0, 6, 2),
+                cc(0, 100, 1),
+                cc(0, 6, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_select_rpn_again_after_nrpn() {
+        let input = [
+            cc(0, 101, 0),
+            cc(0, 100, 0),
+            cc(0, 6, 2),
+            cc(0, 99, 1),
+            cc(0, 98, 2),
+            cc(0, 6, 3),
+            cc(0, 101, 0),
+            cc(0, 100, 0),
+            cc(0, 6, 2),
+            cc(0, 99, 1),
+            cc(0, 98, 2),
+        ];
+        let (output, report) = run(&input);
+        assert_eq!(output, &input);
+        assert_eq!(report.dropped, 0);
+    }
+
+    #[test]
+    fn should_keep_program_change_after_bank_select() {
+        let input = [
+            cc(0, 0, 0),
+            MidiMessage::ProgramChange(0.into(), 3.into()),
+            cc(0, 0, 1),
+            MidiMessage::ProgramChange(0.into(), 3.into()),
+        ];
+        let (output, _) = run(&input);
+        assert_eq!(output, &input);
+    }
+
+    #[test]
+    fn should_forget_state_on_reset() {
+        let input = [
+            cc(0, 7, 1),
+            cc(0, 121, 0),
+            cc(0, 7, 1),
+            MidiMessage::ProgramChange(2.into(), 3.into()),
+            MidiMessage::Reset,
+            MidiMessage::ProgramChange(2.into(), 3.into()),
+        ];
+        let (output, _) = run(&input);
+        assert_eq!(output, &input);
+    }
+
+    #[test]
+    fn should_rewrite_note_off_for_running_status() {
+        let (output, report) = run(&[
+            note_on(0, Note::C3, 100),
+            note_off(0, Note::C3, 64),
+            note_off(1, Note::C3, 64),
+            note_off(1, Note::D4, 12),
+        ]);
+        assert_eq!(
+            output,
+            &[
+                note_on(0, Note::C3, 100),
+                note_on(0, Note::C3, 0),
+                note_off(1, Note::C3, 64),
+                note_off(1, Note::D4, 12),
+            ]
+        );
+        assert_eq!(report.rewritten, 1);
+        assert_eq!(report.bytes_saved(), 1);
+    }
+
+    #[test]
+    fn should_reorder_simultaneous_messages() {
+        let (output, report) = run_timed(&[
+            (0, note_on(0, Note::C3, 100)),
+            (0, note_on(1, Note::C3, 100)),
+            (0, note_on(0, Note::E4, 100)),
+            (0, note_on(1, Note::E4, 100)),
+            (1, note_off(1, Note::C3, 64)),
+            (1, note_off(0, Note::C3, 64)),
+            (1, cc(0, 7, 3)),
+            (1, note_off(0, Note::E4, 64)),
+        ]);
+        assert_eq!(
+            output,
+            &[
+                (0, note_on(0, Note::C3, 100)),
+                (0, note_on(0, Note::E4, 100)),
+                (0, note_on(1, Note::C3, 100)),
+                (0, note_on(1, Note::E4, 100)),
+                (1, note_on(1, Note::C3, 0)),
+                (1, note_off(0, Note::C3, 64)),
+                (1, cc(0, 7, 3)),
+                (1, note_off(0, Note::E4, 64)),
+            ]
+        );
+        assert_eq!(report.bytes_in, 24);
+        assert_eq!(report.bytes_out, 10 + 2 + 3 + 3 + 3);
+    }
+
+    #[test]
+    fn should_not_reorder_across_system_messages() {
+        let input = [
+            (0, note_on(0, Note::C3, 100)),
+            (0, note_on(1, Note::C3, 100)),
+            (0, MidiMessage::TimingClock),
+            (0, note_on(0, Note::E4, 100)),
+        ];
+        let (output, _) = run_timed(&input);
+        assert_eq!(output, &input);
+    }
+
+    #[test]
+    fn should_report_rendered_size() {
+        let input = [
+            (0, cc(0, 7, 1)),
+            (0, cc(1, 7, 1)),
+            (0, cc(0, 7, 1)),
+            (0, cc(0, 8, 1)),
+            (1, note_on(1, Note::C3, 1)),
+            (2, MidiMessage::TimingClock),
+            (2, note_off(1, Note::C3, 64)),
+        ];
+        let (output, report) = run_timed(&input);
+
+        let mut renderer: MidiRenderer<MockTransport> = MidiRenderer::new(MockTransport::default());
+        for (_, message) in output.iter() {
+            renderer.render(message).unwrap();
+        }
+        let bytes = renderer.release().buffer;
+        assert_eq!(
+            bytes,
+            &[
+                0xB0, 0x07, 0x01, 0x08, 0x01, 0xB1, 0x07, 0x01, 0x91, 0x3c, 0x01, 0xf8, 0x3c, 0x00
+            ]
+        );
+        assert_eq!(report.bytes_out, bytes.len());
+        assert_eq!(report.bytes_in, 18);
+    }
+
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        buffer: Vec<u8>,
+    }
+
+    impl MidiTransport for MockTransport {
+        type Error = ();
+
+        fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.buffer.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+}
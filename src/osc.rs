@@ -0,0 +1,942 @@
+//! Open Sound Control (OSC 1.0) messages carrying MIDI
+//!
+//! OSC has a MIDI argument type, tagged `m`, holding a port id, a status byte and two data bytes.
+//! [`write_midi`] encodes a `MidiMessage` as such an argument and [`OscMessage::midi`] decodes it.
+//!
+//! Controllers like TouchOSC send plain numbers to addresses of their own instead. An
+//! [`OscMapper`] translates between those and MIDI messages using a list of [`OscRoute`]s. Route
+//! addresses may hold a `{channel}` placeholder, the channel numbered from 1, and a `{number}`
+//! placeholder for the note or controller number. Values are sent as integers or as floats scaled
+//! to a range.
+//!
+//! ```
+//! use midi_convert::osc::{OscMapper, OscMessage, OscRoute, OscValue, RouteKind};
+//! use midi_types::MidiMessage;
+//!
+//! let routes = [OscRoute {
+//!     address: "/ch{channel}/cc/{number}",
+//!     kind: RouteKind::ControlChange,
+//!     channel: 0,
+//!     number: 0,
+//!     value: OscValue::UNIT,
+//! }];
+//! let mapper = OscMapper::new(&routes);
+//!
+//! let mut buf = [0; 64];
+//! let cc = MidiMessage::ControlChange(0.into(), 7.into(), 0.into());
+//! let len = mapper.write(&cc, &mut buf).unwrap().unwrap();
+//! assert_eq!(&buf[..len], b"/ch1/cc/7\0\0\0,f\0\0\0\0\0\0");
+//!
+//! let message = OscMessage::parse(&buf[..len]).unwrap();
+//! assert_eq!(message.address(), "/ch1/cc/7");
+//! assert_eq!(mapper.to_midi(&message), Ok(Some(cc)));
+//! ```
+
+use {
+    crate::{parse::MidiParser, render::encode},
+    midi_types::{Channel, MidiMessage, Value14},
+};
+
+#[cfg(feature = "std")]
+pub mod udp;
+
+const CHANNEL: &str = "{channel}";
+const NUMBER: &str = "{number}";
+
+/// Errors reading or writing OSC messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OscError {
+    /// The buffer is shorter than the data it should hold
+    BufferTooShort,
+
+    /// A string is not terminated, not padded or not UTF-8, or the address does not start with '/'
+    InvalidString,
+
+    /// The type tag string does not start with ','
+    InvalidTypeTag,
+
+    /// An argument has a type that can not be read
+    UnsupportedType,
+
+    /// A MIDI argument does not hold a MIDI message
+    InvalidMidi,
+
+    /// An argument does not fit the value of a route
+    InvalidArgument,
+}
+
+/// An OSC argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OscArgument<'a> {
+    /// `i`, a 32 bit integer
+    Int(i32),
+
+    /// `f`, a 32 bit float
+    Float(f32),
+
+    /// `s`, a string
+    String(&'a str),
+
+    /// `b`, a blob of bytes
+    Blob(&'a [u8]),
+
+    /// `h`, a 64 bit integer
+    Long(i64),
+
+    /// `d`, a 64 bit float
+    Double(f64),
+
+    /// `t`, an NTP time tag
+    TimeTag(u64),
+
+    /// `m`, a port id, a status byte and two data bytes
+    Midi([u8; 4]),
+
+    /// `T`
+    True,
+
+    /// `F`
+    False,
+
+    /// `N`
+    Nil,
+
+    /// `I`
+    Impulse,
+}
+
+impl OscArgument<'_> {
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Int(_) => b'i',
+            Self::Float(_) => b'f',
+            Self::String(_) => b's',
+            Self::Blob(_) => b'b',
+            Self::Long(_) => b'h',
+            Self::Double(_) => b'd',
+            Self::TimeTag(_) => b't',
+            Self::Midi(_) => b'm',
+            Self::True => b'T',
+            Self::False => b'F',
+            Self::Nil => b'N',
+            Self::Impulse => b'I',
+        }
+    }
+}
+
+/// A parsed OSC message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OscMessage<'a> {
+    address: &'a str,
+    tags: &'a str,
+    data: &'a [u8],
+}
+
+impl<'a> OscMessage<'a> {
+    /// Parse an OSC message, bundles are not supported
+    pub fn parse(packet: &'a [u8]) -> Result<Self, OscError> {
+        let mut rest = packet;
+        let address = read_string(&mut rest)?;
+        if !address.starts_with('/') {
+            return Err(OscError::InvalidString);
+        }
+        // Messages without a type tag string are allowed by OSC 1.0 and have no arguments
+        let tags = if rest.is_empty() {
+            ""
+        } else {
+            read_string(&mut rest)?
+                .strip_prefix(',')
+                .ok_or(OscError::InvalidTypeTag)?
+        };
+        Ok(Self {
+            address,
+            tags,
+            data: rest,
+        })
+    }
+
+    /// The address pattern
+    pub fn address(&self) -> &'a str {
+        self.address
+    }
+
+    /// The type tags of the arguments, without the leading ','
+    pub fn type_tags(&self) -> &'a str {
+        self.tags
+    }
+
+    /// The arguments
+    pub fn arguments(&self) -> OscArguments<'a> {
+        OscArguments {
+            tags: self.tags.as_bytes(),
+            data: self.data,
+        }
+    }
+
+    /// Decode the first MIDI argument, returning `None` when there is none
+    ///
+    /// The port id is ignored.
+    pub fn midi(&self) -> Result<Option<MidiMessage>, OscError> {
+        for argument in self.arguments() {
+            if let OscArgument::Midi([_, status, data1, data2]) = argument? {
+                if status & 0x80 == 0 {
+                    return Err(OscError::InvalidMidi);
+                }
+                let mut parser = MidiParser::new();
+                return [status, data1, data2]
+                    .into_iter()
+                    .find_map(|byte| parser.parse(byte))
+                    .map(Some)
+                    .ok_or(OscError::InvalidMidi);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Iterator over the arguments of an [`OscMessage`]
+#[derive(Debug, Clone)]
+pub struct OscArguments<'a> {
+    tags: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> OscArguments<'a> {
+    fn argument(&mut self, tag: u8) -> Result<OscArgument<'a>, OscError> {
+        let data = &mut self.data;
+        Ok(match tag {
+            b'i' => OscArgument::Int(i32::from_be_bytes(read_array(data)?)),
+            b'f' => OscArgument::Float(f32::from_be_bytes(read_array(data)?)),
+            b's' => OscArgument::String(read_string(data)?),
+            b'b' => {
+                let len = usize::try_from(i32::from_be_bytes(read_array(data)?))
+                    .map_err(|_| OscError::BufferTooShort)?;
+                let padded = len.next_multiple_of(4);
+                if data.len() < padded {
+                    return Err(OscError::BufferTooShort);
+                }
+                let blob = &data[..len];
+                *data = &data[padded..];
+                OscArgument::Blob(blob)
+            }
+            b'h' => OscArgument::Long(i64::from_be_bytes(read_array(data)?)),
+            b'd' => OscArgument::Double(f64::from_be_bytes(read_array(data)?)),
+            b't' => OscArgument::TimeTag(u64::from_be_bytes(read_array(data)?)),
+            b'm' => OscArgument::Midi(read_array(data)?),
+            b'T' => OscArgument::True,
+            b'F' => OscArgument::False,
+            b'N' => OscArgument::Nil,
+            b'I' => OscArgument::Impulse,
+            _ => return Err(OscError::UnsupportedType),
+        })
+    }
+}
+
+impl<'a> Iterator for OscArguments<'a> {
+    type Item = Result<OscArgument<'a>, OscError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&tag, rest) = self.tags.split_first()?;
+        self.tags = rest;
+        let argument = self.argument(tag);
+        if argument.is_err() {
+            // The arguments after an unreadable one can not be located
+            self.tags = &[];
+        }
+        Some(argument)
+    }
+}
+
+fn read_array<const N: usize>(data: &mut &[u8]) -> Result<[u8; N], OscError> {
+    let (bytes, rest) = data
+        .split_first_chunk::<N>()
+        .ok_or(OscError::BufferTooShort)?;
+    *data = rest;
+    Ok(*bytes)
+}
+
+/// Read a NUL terminated string padded to a multiple of 4 bytes
+fn read_string<'a>(data: &mut &'a [u8]) -> Result<&'a str, OscError> {
+    let end = data
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or(OscError::InvalidString)?;
+    let padded = (end + 1).next_multiple_of(4);
+    if data.len() < padded {
+        return Err(OscError::InvalidString);
+    }
+    let string = core::str::from_utf8(&data[..end]).map_err(|_| OscError::InvalidString)?;
+    *data = &data[padded..];
+    Ok(string)
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), OscError> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(OscError::BufferTooShort)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    /// Pad with zeros to a multiple of 4 bytes
+    fn pad(&mut self) -> Result<(), OscError> {
+        let padded = self.len.next_multiple_of(4);
+        self.push(&[0; 3][..padded - self.len])
+    }
+
+    fn string(&mut self, string: &[u8]) -> Result<(), OscError> {
+        self.push(string)?;
+        self.push(&[0])?;
+        self.pad()
+    }
+
+    fn tags(&mut self, arguments: &[OscArgument]) -> Result<(), OscError> {
+        self.push(b",")?;
+        for argument in arguments {
+            self.push(&[argument.tag()])?;
+        }
+        self.push(&[0])?;
+        self.pad()
+    }
+
+    fn argument(&mut self, argument: &OscArgument) -> Result<(), OscError> {
+        match *argument {
+            OscArgument::Int(value) => self.push(&value.to_be_bytes()),
+            OscArgument::Float(value) => self.push(&value.to_be_bytes()),
+            OscArgument::String(value) => self.string(value.as_bytes()),
+            OscArgument::Blob(value) => {
+                let len = i32::try_from(value.len()).map_err(|_| OscError::BufferTooShort)?;
+                self.push(&len.to_be_bytes())?;
+                self.push(value)?;
+                self.pad()
+            }
+            OscArgument::Long(value) => self.push(&value.to_be_bytes()),
+            OscArgument::Double(value) => self.push(&value.to_be_bytes()),
+            OscArgument::TimeTag(value) => self.push(&value.to_be_bytes()),
+            OscArgument::Midi(value) => self.push(&value),
+            OscArgument::True | OscArgument::False | OscArgument::Nil | OscArgument::Impulse => {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write an OSC message to the start of `buf` and return its length
+pub fn write_message(
+    buf: &mut [u8],
+    address: &str,
+    arguments: &[OscArgument],
+) -> Result<usize, OscError> {
+    let mut writer = Writer { buf, len: 0 };
+    writer.string(address.as_bytes())?;
+    writer.tags(arguments)?;
+    for argument in arguments {
+        writer.argument(argument)?;
+    }
+    Ok(writer.len)
+}
+
+/// Write an OSC message holding `message` as a MIDI argument for port `port`, and return its
+/// length
+///
+/// Unused data bytes are 0.
+pub fn write_midi(
+    buf: &mut [u8],
+    address: &str,
+    port: u8,
+    message: &MidiMessage,
+) -> Result<usize, OscError> {
+    let mut bytes = [0; 3];
+    let mut midi = [port, 0, 0, 0];
+    let encoded = encode::<false>(message, &mut None, &mut bytes);
+    midi[1..1 + encoded.len()].copy_from_slice(encoded);
+    write_message(buf, address, &[OscArgument::Midi(midi)])
+}
+
+/// The kind of MIDI message a route maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RouteKind {
+    /// Note on, the number is the note and the value the velocity
+    NoteOn,
+
+    /// Note off, the number is the note and the value the velocity
+    NoteOff,
+
+    /// Polyphonic key pressure, the number is the note
+    KeyPressure,
+
+    /// Control change, the number is the controller
+    ControlChange,
+
+    /// Program change, the value is the program
+    ProgramChange,
+
+    /// Channel pressure
+    ChannelPressure,
+
+    /// Pitch bend, the value is 14 bit
+    PitchBend,
+}
+
+/// How a route sends MIDI values
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OscValue {
+    /// An `i` argument holding the MIDI value
+    Int,
+
+    /// An `f` argument, the MIDI value scaled from `min` at 0 to `max` at the largest value
+    Float { min: f32, max: f32 },
+}
+
+impl OscValue {
+    /// Floats from 0 to 1, as sent by most OSC controllers
+    pub const UNIT: Self = Self::Float { min: 0.0, max: 1.0 };
+
+    fn argument(&self, value: u16, max: u16) -> OscArgument<'static> {
+        match *self {
+            Self::Int => OscArgument::Int(i32::from(value)),
+            Self::Float { min, max: top } => {
+                OscArgument::Float(min + (top - min) * f32::from(value) / f32::from(max))
+            }
+        }
+    }
+
+    /// The MIDI value of an argument, integers are taken as MIDI values and floats are scaled,
+    /// both limited to the valid range
+    fn value(&self, argument: &OscArgument, max: u16) -> Result<u16, OscError> {
+        let scaled = |value: f32| (value.clamp(0.0, 1.0) * f32::from(max) + 0.5) as u16;
+        match (*self, *argument) {
+            (_, OscArgument::Int(value)) => Ok(value.clamp(0, i32::from(max)) as u16),
+            (Self::Float { min, max: top }, OscArgument::Float(value)) => {
+                Ok(scaled((value - min) / (top - min)))
+            }
+            (Self::Int, OscArgument::Float(value)) => Ok(scaled(value / f32::from(max))),
+            (_, OscArgument::True) => Ok(max),
+            (_, OscArgument::False) => Ok(0),
+            _ => Err(OscError::InvalidArgument),
+        }
+    }
+}
+
+/// Maps an OSC address to a kind of MIDI message
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OscRoute<'a> {
+    /// The address, optionally holding `{channel}` and `{number}` placeholders
+    pub address: &'a str,
+
+    /// The kind of MIDI message
+    pub kind: RouteKind,
+
+    /// The channel, 0 to 15, used when the address has no `{channel}` placeholder
+    pub channel: u8,
+
+    /// The note or controller number used when the address has no `{number}` placeholder
+    pub number: u8,
+
+    /// How values are sent
+    pub value: OscValue,
+}
+
+impl OscRoute<'_> {
+    /// The channel and number held by `address` when it matches the route
+    fn matches(&self, address: &str) -> Option<(u8, u8)> {
+        let (mut channel, mut number) = (self.channel, self.number);
+        let mut pattern = self.address;
+        let mut address = address;
+        while !pattern.is_empty() {
+            if let Some(rest) = pattern.strip_prefix(CHANNEL) {
+                let (value, tail) = split_number(address)?;
+                channel = u8::try_from(value).ok().filter(|c| (1..=16).contains(c))? - 1;
+                (pattern, address) = (rest, tail);
+            } else if let Some(rest) = pattern.strip_prefix(NUMBER) {
+                let (value, tail) = split_number(address)?;
+                number = u8::try_from(value).ok().filter(|n| *n <= 127)?;
+                (pattern, address) = (rest, tail);
+            } else {
+                let len = literal_len(pattern);
+                address = address.strip_prefix(&pattern[..len])?;
+                pattern = &pattern[len..];
+            }
+        }
+        // `midi-types` can't represent controller 127
+        let unsupported = self.kind == RouteKind::ControlChange && number == 127;
+        (address.is_empty() && !unsupported).then_some((channel, number))
+    }
+
+    /// Whether MIDI messages on `channel` with `number` map to this route
+    fn accepts(&self, channel: u8, number: Option<u8>) -> bool {
+        (self.address.contains(CHANNEL) || self.channel == channel)
+            && number.is_none_or(|number| self.address.contains(NUMBER) || self.number == number)
+    }
+
+    fn write_address(&self, writer: &mut Writer, channel: u8, number: u8) -> Result<(), OscError> {
+        let mut pattern = self.address;
+        while !pattern.is_empty() {
+            if let Some(rest) = pattern.strip_prefix(CHANNEL) {
+                write_number(writer, channel + 1)?;
+                pattern = rest;
+            } else if let Some(rest) = pattern.strip_prefix(NUMBER) {
+                write_number(writer, number)?;
+                pattern = rest;
+            } else {
+                let len = literal_len(pattern);
+                writer.push(&pattern.as_bytes()[..len])?;
+                pattern = &pattern[len..];
+            }
+        }
+        writer.push(&[0])?;
+        writer.pad()
+    }
+}
+
+/// The length of the text before the next placeholder, a '{' that does not start one is text
+fn literal_len(pattern: &str) -> usize {
+    match pattern.find('{') {
+        Some(0) => 1,
+        Some(len) => len,
+        None => pattern.len(),
+    }
+}
+
+/// Split the leading decimal number from `address`
+fn split_number(address: &str) -> Option<(u32, &str)> {
+    let len = address.bytes().take_while(u8::is_ascii_digit).count();
+    if len == 0 || len > 3 {
+        return None;
+    }
+    Some((address[..len].parse().ok()?, &address[len..]))
+}
+
+fn write_number(writer: &mut Writer, number: u8) -> Result<(), OscError> {
+    let digits = [number / 100, number / 10 % 10, number % 10].map(|digit| b'0' + digit);
+    let skip = match number {
+        0..=9 => 2,
+        10..=99 => 1,
+        _ => 0,
+    };
+    writer.push(&digits[skip..])
+}
+
+/// The route kind, channel, number, value and largest value of a message
+fn fields(message: &MidiMessage) -> Option<(RouteKind, u8, Option<u8>, u16, u16)> {
+    Some(match *message {
+        MidiMessage::NoteOn(channel, note, velocity) => (
+            RouteKind::NoteOn,
+            channel.into(),
+            Some(note.into()),
+            u8::from(velocity).into(),
+            127,
+        ),
+        MidiMessage::NoteOff(channel, note, velocity) => (
+            RouteKind::NoteOff,
+            channel.into(),
+            Some(note.into()),
+            u8::from(velocity).into(),
+            127,
+        ),
+        MidiMessage::KeyPressure(channel, note, value) => (
+            RouteKind::KeyPressure,
+            channel.into(),
+            Some(note.into()),
+            u8::from(value).into(),
+            127,
+        ),
+        MidiMessage::ControlChange(channel, control, value) => (
+            RouteKind::ControlChange,
+            channel.into(),
+            Some(control.into()),
+            u8::from(value).into(),
+            127,
+        ),
+        MidiMessage::ProgramChange(channel, program) => (
+            RouteKind::ProgramChange,
+            channel.into(),
+            None,
+            u8::from(program).into(),
+            127,
+        ),
+        MidiMessage::ChannelPressure(channel, value) => (
+            RouteKind::ChannelPressure,
+            channel.into(),
+            None,
+            u8::from(value).into(),
+            127,
+        ),
+        MidiMessage::PitchBendChange(channel, value) => (
+            RouteKind::PitchBend,
+            channel.into(),
+            None,
+            value.into(),
+            16383,
+        ),
+        _ => return None,
+    })
+}
+
+/// Translates between OSC messages and MIDI messages using a list of routes
+///
+/// The first matching route is used in both directions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OscMapper<'a> {
+    routes: &'a [OscRoute<'a>],
+}
+
+impl<'a> OscMapper<'a> {
+    /// Create a mapper using `routes`
+    pub fn new(routes: &'a [OscRoute<'a>]) -> Self {
+        Self { routes }
+    }
+
+    /// The routes
+    pub fn routes(&self) -> &'a [OscRoute<'a>] {
+        self.routes
+    }
+
+    /// Translate an OSC message, returning `None` when no route matches its address
+    ///
+    /// The value is taken from the first argument. Controller 127 and program 127 can't be
+    /// represented by `midi-types` and also give `None`.
+    pub fn to_midi(&self, message: &OscMessage) -> Result<Option<MidiMessage>, OscError> {
+        let Some((route, channel, number)) = self.routes.iter().find_map(|route| {
+            let (channel, number) = route.matches(message.address())?;
+            Some((route, channel, number))
+        }) else {
+            return Ok(None);
+        };
+        let argument = message
+            .arguments()
+            .next()
+            .ok_or(OscError::InvalidArgument)??;
+
+        let max = if route.kind == RouteKind::PitchBend {
+            16383
+        } else {
+            127
+        };
+        let value = route.value.value(&argument, max)?;
+        if route.kind == RouteKind::ProgramChange && value == 127 {
+            return Ok(None);
+        }
+        let channel = Channel::new(channel);
+        let value7 = (value as u8).into();
+        Ok(Some(match route.kind {
+            RouteKind::NoteOn => MidiMessage::NoteOn(channel, number.into(), value7),
+            RouteKind::NoteOff => MidiMessage::NoteOff(channel, number.into(), value7),
+            RouteKind::KeyPressure => MidiMessage::KeyPressure(channel, number.into(), value7),
+            RouteKind::ControlChange => MidiMessage::ControlChange(channel, number.into(), value7),
+            RouteKind::ProgramChange => MidiMessage::ProgramChange(channel, (value as u8).into()),
+            RouteKind::ChannelPressure => MidiMessage::ChannelPressure(channel, value7),
+            RouteKind::PitchBend => MidiMessage::PitchBendChange(channel, Value14::from(value)),
+        }))
+    }
+
+    /// Write the OSC message for `message`, returning its length, or `None` when no route matches
+    pub fn write(&self, message: &MidiMessage, buf: &mut [u8]) -> Result<Option<usize>, OscError> {
+        let Some((kind, channel, number, value, max)) = fields(message) else {
+            return Ok(None);
+        };
+        let Some(route) = self
+            .routes
+            .iter()
+            .find(|route| route.kind == kind && route.accepts(channel, number))
+        else {
+            return Ok(None);
+        };
+
+        let argument = route.value.argument(value, max);
+        let mut writer = Writer { buf, len: 0 };
+        route.write_address(&mut writer, channel, number.unwrap_or(route.number))?;
+        writer.tags(&[argument])?;
+        writer.argument(&argument)?;
+        Ok(Some(writer.len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::test::{TEST_1BYTE, TEST_2BYTE, TEST_3BYTE},
+        std::vec::Vec,
+    };
+
+    fn cc(channel: u8, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(channel.into(), control.into(), value.into())
+    }
+
+    fn route(address: &'static str, kind: RouteKind, value: OscValue) -> OscRoute<'static> {
+        OscRoute {
+            address,
+            kind,
+            channel: 0,
+            number: 0,
+            value,
+        }
+    }
+
+    fn written(mapper: &OscMapper, message: &MidiMessage) -> Option<Vec<u8>> {
+        let mut buf = [0; 64];
+        let len = mapper.write(message, &mut buf).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    fn mapped(mapper: &OscMapper, address: &str, argument: OscArgument) -> Option<MidiMessage> {
+        let mut buf = [0; 64];
+        let len = write_message(&mut buf, address, &[argument]).unwrap();
+        mapper
+            .to_midi(&OscMessage::parse(&buf[..len]).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn should_write_midi_argument() {
+        let mut buf = [0; 32];
+        let message = MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into());
+        assert_eq!(write_midi(&mut buf, "/midi", 1, &message), Ok(16));
+        assert_eq!(buf[..16], *b"/midi\0\0\0,m\0\0\x01\x92\x76\x34");
+        assert_eq!(
+            write_midi(&mut buf[..15], "/midi", 1, &message),
+            Err(OscError::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn should_round_trip_midi_arguments() {
+        let mut buf = [0; 32];
+        for message in TEST_1BYTE.iter().chain(&TEST_2BYTE).chain(&TEST_3BYTE) {
+            let len = write_midi(&mut buf, "/midi", 0, message).unwrap();
+            let parsed = OscMessage::parse(&buf[..len]).unwrap();
+            assert_eq!(parsed.midi(), Ok(Some(*message)), "{message:?}");
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_midi_arguments() {
+        let mut buf = [0; 32];
+        for midi in [[0, 0x12, 0, 0], [0, 0xf4, 0, 0]] {
+            let len = write_message(&mut buf, "/midi", &[OscArgument::Midi(midi)]).unwrap();
+            let parsed = OscMessage::parse(&buf[..len]).unwrap();
+            assert_eq!(parsed.midi(), Err(OscError::InvalidMidi));
+        }
+        let len = write_message(&mut buf, "/midi", &[OscArgument::Int(1)]).unwrap();
+        assert_eq!(OscMessage::parse(&buf[..len]).unwrap().midi(), Ok(None));
+    }
+
+    #[test]
+    fn should_round_trip_arguments() {
+        let arguments = [
+            OscArgument::Int(-2),
+            OscArgument::Float(0.5),
+            OscArgument::String("hello"),
+            OscArgument::Blob(&[1, 2, 3, 4, 5]),
+            OscArgument::Long(1 << 40),
+            OscArgument::Double(0.25),
+            OscArgument::TimeTag(1),
+            OscArgument::Midi([0, 0x90, 60, 100]),
+            OscArgument::True,
+            OscArgument::False,
+            OscArgument::Nil,
+            OscArgument::Impulse,
+        ];
+        let mut buf = [0; 128];
+        let len = write_message(&mut buf, "/a", &arguments).unwrap();
+        assert_eq!(len, 4 + 16 + 4 + 4 + 8 + 12 + 8 + 8 + 8 + 4);
+        let message = OscMessage::parse(&buf[..len]).unwrap();
+        assert_eq!(message.address(), "/a");
+        assert_eq!(message.type_tags(), "ifsbhdtmTFNI");
+        let parsed: Result<Vec<_>, _> = message.arguments().collect();
+        assert_eq!(parsed.unwrap(), arguments);
+    }
+
+    #[test]
+    fn should_reject_invalid_messages() {
+        assert_eq!(OscMessage::parse(b"/a"), Err(OscError::InvalidString));
+        assert_eq!(OscMessage::parse(b"/a\0"), Err(OscError::InvalidString));
+        assert_eq!(OscMessage::parse(b"a\0\0\0"), Err(OscError::InvalidString));
+        assert_eq!(
+            OscMessage::parse(b"/a\0\0i\0\0\0"),
+            Err(OscError::InvalidTypeTag)
+        );
+        assert_eq!(OscMessage::parse(b"/a\0\0").unwrap().type_tags(), "");
+
+        let message = OscMessage::parse(b"/a\0\0,ci\0\0\0\0\0").unwrap();
+        let mut arguments = message.arguments();
+        assert_eq!(arguments.next(), Some(Err(OscError::UnsupportedType)));
+        assert_eq!(arguments.next(), None);
+
+        let message = OscMessage::parse(b"/a\0\0,i\0\0\0\0").unwrap();
+        assert_eq!(
+            message.arguments().next(),
+            Some(Err(OscError::BufferTooShort))
+        );
+    }
+
+    #[test]
+    fn should_map_control_change_with_placeholders() {
+        let routes = [route(
+            "/ch{channel}/cc/{number}",
+            RouteKind::ControlChange,
+            OscValue::UNIT,
+        )];
+        let mapper = OscMapper::new(&routes);
+
+        assert_eq!(
+            written(&mapper, &cc(15, 74, 127)).unwrap(),
+            b"/ch16/cc/74\0,f\0\0\x3f\x80\0\0"
+        );
+        assert_eq!(
+            mapped(&mapper, "/ch16/cc/74", OscArgument::Float(1.0)),
+            Some(cc(15, 74, 127))
+        );
+        assert_eq!(
+            mapped(&mapper, "/ch1/cc/100", OscArgument::Float(0.5)),
+            Some(cc(0, 100, 64))
+        );
+        assert_eq!(
+            mapped(&mapper, "/ch1/cc/1", OscArgument::Float(2.0)),
+            Some(cc(0, 1, 127))
+        );
+        assert_eq!(
+            mapped(&mapper, "/ch1/cc/1", OscArgument::Int(20)),
+            Some(cc(0, 1, 20))
+        );
+        assert_eq!(
+            mapped(&mapper, "/ch1/cc/1", OscArgument::True),
+            Some(cc(0, 1, 127))
+        );
+        for address in [
+            "/ch0/cc/1",
+            "/ch17/cc/1",
+            "/ch1/cc/127",
+            "/ch1/cc/128",
+            "/ch1/cc/",
+            "/ch1/cc/1/x",
+        ] {
+            assert_eq!(mapped(&mapper, address, OscArgument::Int(1)), None);
+        }
+    }
+
+    #[test]
+    fn should_map_fixed_routes() {
+        let routes = [
+            OscRoute {
+                address: "/synth/cutoff",
+                kind: RouteKind::ControlChange,
+                channel: 2,
+                number: 74,
+                value: OscValue::Float {
+                    min: 20.0,
+                    max: 20_000.0,
+                },
+            },
+            OscRoute {
+                address: "/synth/bend",
+                kind: RouteKind::PitchBend,
+                channel: 2,
+                number: 0,
+                value: OscValue::Float {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            },
+            OscRoute {
+                address: "/synth/program",
+                kind: RouteKind::ProgramChange,
+                channel: 2,
+                number: 0,
+                value: OscValue::Int,
+            },
+        ];
+        let mapper = OscMapper::new(&routes);
+
+        assert_eq!(
+            mapped(&mapper, "/synth/cutoff", OscArgument::Float(20.0)),
+            Some(cc(2, 74, 0))
+        );
+        assert_eq!(
+            written(&mapper, &cc(2, 74, 127)).unwrap()[20..],
+            20_000f32.to_be_bytes()
+        );
+        assert_eq!(written(&mapper, &cc(1, 74, 127)), None);
+        assert_eq!(written(&mapper, &cc(2, 73, 127)), None);
+
+        let center = MidiMessage::PitchBendChange(2.into(), Value14::from(8192u16));
+        assert_eq!(
+            mapped(&mapper, "/synth/bend", OscArgument::Float(0.0)),
+            Some(center)
+        );
+        let top = MidiMessage::PitchBendChange(2.into(), Value14::from(16383u16));
+        assert_eq!(written(&mapper, &top).unwrap()[16..], 1f32.to_be_bytes());
+
+        let program = MidiMessage::ProgramChange(2.into(), 5.into());
+        assert_eq!(
+            mapped(&mapper, "/synth/program", OscArgument::Int(5)),
+            Some(program)
+        );
+        assert_eq!(
+            mapped(&mapper, "/synth/program", OscArgument::Int(127)),
+            None
+        );
+        assert_eq!(
+            written(&mapper, &program).unwrap(),
+            b"/synth/program\0\0,i\0\0\0\0\0\x05"
+        );
+        assert_eq!(written(&mapper, &MidiMessage::Start), None);
+    }
+
+    #[test]
+    fn should_map_notes() {
+        let routes = [
+            route("/note/{number}", RouteKind::NoteOn, OscValue::Int),
+            route("/off/{number}", RouteKind::NoteOff, OscValue::Int),
+        ];
+        let mapper = OscMapper::new(&routes);
+        let note_on = MidiMessage::NoteOn(0.into(), 60.into(), 100.into());
+        assert_eq!(
+            written(&mapper, &note_on).unwrap(),
+            b"/note/60\0\0\0\0,i\0\0\0\0\0\x64"
+        );
+        assert_eq!(
+            mapped(&mapper, "/note/60", OscArgument::Int(100)),
+            Some(note_on)
+        );
+        assert_eq!(
+            mapped(&mapper, "/off/0", OscArgument::Int(0)),
+            Some(MidiMessage::NoteOff(0.into(), 0.into(), 0.into()))
+        );
+        assert_eq!(
+            written(
+                &mapper,
+                &MidiMessage::NoteOn(1.into(), 60.into(), 100.into())
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn should_reject_missing_arguments() {
+        let routes = [route("/x", RouteKind::ChannelPressure, OscValue::Int)];
+        let mapper = OscMapper::new(&routes);
+        let message = OscMessage::parse(b"/x\0\0,\0\0\0").unwrap();
+        assert_eq!(mapper.to_midi(&message), Err(OscError::InvalidArgument));
+        let message = OscMessage::parse(b"/x\0\0,s\0\0a\0\0\0").unwrap();
+        assert_eq!(mapper.to_midi(&message), Err(OscError::InvalidArgument));
+        let message = OscMessage::parse(b"/y\0\0,i\0\0\0\0\0\0").unwrap();
+        assert_eq!(mapper.to_midi(&message), Ok(None));
+    }
+}
@@ -0,0 +1,140 @@
+//! Sending and receiving OSC MIDI messages over `std::net` UDP sockets
+
+use {
+    super::{OscError, OscMapper, OscMessage, write_midi},
+    midi_types::MidiMessage,
+    std::{
+        io::{self, ErrorKind},
+        net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    },
+};
+
+/// A UDP socket exchanging MIDI messages as OSC packets
+#[derive(Debug)]
+pub struct OscSocket {
+    socket: UdpSocket,
+    buf: [u8; 1536],
+}
+
+impl OscSocket {
+    /// Wrap a bound socket
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            buf: [0; 1536],
+        }
+    }
+
+    /// The socket, for instance to set timeouts
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Release the socket
+    pub fn release(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Send `message` to `addr` as a MIDI argument for port 0 at the OSC address `address`
+    pub fn send_midi(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        address: &str,
+        message: &MidiMessage,
+    ) -> io::Result<()> {
+        let len = write_midi(&mut self.buf, address, 0, message).map_err(invalid_data)?;
+        self.socket.send_to(&self.buf[..len], addr)?;
+        Ok(())
+    }
+
+    /// Send `message` to `addr` using the first matching route of `mapper`, returning false when
+    /// no route matches
+    pub fn send_mapped(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        mapper: &OscMapper,
+        message: &MidiMessage,
+    ) -> io::Result<bool> {
+        match mapper.write(message, &mut self.buf).map_err(invalid_data)? {
+            Some(len) => {
+                self.socket.send_to(&self.buf[..len], addr)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Receive a packet and return the MIDI message it holds, with the address of the sender
+    ///
+    /// The message is taken from a MIDI argument when there is one and translated by `mapper`
+    /// otherwise. Packets that hold no MIDI message give `None`.
+    pub fn recv(&mut self, mapper: &OscMapper) -> io::Result<(Option<MidiMessage>, SocketAddr)> {
+        let (len, addr) = self.socket.recv_from(&mut self.buf)?;
+        let message = OscMessage::parse(&self.buf[..len]).map_err(invalid_data)?;
+        let midi = match message.midi().map_err(invalid_data)? {
+            Some(midi) => Some(midi),
+            None => mapper.to_midi(&message).map_err(invalid_data)?,
+        };
+        Ok((midi, addr))
+    }
+}
+
+fn invalid_data(error: OscError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, std::format!("{error:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::osc::{OscRoute, OscValue, RouteKind},
+        std::time::Duration,
+    };
+
+    fn socket() -> OscSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        OscSocket::new(socket)
+    }
+
+    #[test]
+    fn should_exchange_midi_over_localhost() {
+        let routes = [OscRoute {
+            address: "/ch{channel}/cc/{number}",
+            kind: RouteKind::ControlChange,
+            channel: 0,
+            number: 0,
+            value: OscValue::UNIT,
+        }];
+        let mapper = OscMapper::new(&routes);
+        let mut sender = socket();
+        let mut receiver = socket();
+        let addr = receiver.socket().local_addr().unwrap();
+
+        let note_on = MidiMessage::NoteOn(1.into(), 60.into(), 100.into());
+        sender.send_midi(addr, "/midi", &note_on).unwrap();
+        let cc = MidiMessage::ControlChange(3.into(), 7.into(), 90.into());
+        assert!(sender.send_mapped(addr, &mapper, &cc).unwrap());
+        assert!(!sender.send_mapped(addr, &mapper, &note_on).unwrap());
+
+        let sender_addr = sender.socket().local_addr().unwrap();
+        assert_eq!(
+            receiver.recv(&mapper).unwrap(),
+            (Some(note_on), sender_addr)
+        );
+        assert_eq!(receiver.recv(&mapper).unwrap(), (Some(cc), sender_addr));
+
+        sender
+            .socket()
+            .send_to(b"/other\0\0,i\0\0\0\0\0\x01", addr)
+            .unwrap();
+        assert_eq!(receiver.recv(&mapper).unwrap(), (None, sender_addr));
+        sender.socket().send_to(b"nonsense", addr).unwrap();
+        assert_eq!(
+            receiver.recv(&mapper).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
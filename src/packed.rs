@@ -0,0 +1,241 @@
+//! Packed 32-bit short messages
+//!
+//! Windows MME, PortMidi and many C APIs hold a short message in a `u32` as
+//! `status | data1 << 8 | data2 << 16`, with unused bytes zero.
+//!
+//! ```
+//! use midi_convert::packed::MidiPacked;
+//! use midi_types::MidiMessage;
+//!
+//! let m = MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into());
+//! assert_eq!(m.to_packed(), 0x0034_7692);
+//! assert_eq!(MidiMessage::try_from_packed(0x0034_7692), Ok(m));
+//! ```
+
+use {
+    crate::{parse::MidiTryParseSlice, render::encode},
+    midi_types::{MidiMessage, status::*},
+};
+
+/// Errors converting packed messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PackedError {
+    /// The low byte isn't the status of a short message
+    InvalidStatus,
+
+    /// A data byte has the status bit set, or names controller or program 127 which `midi-types`
+    /// can't represent
+    InvalidData,
+
+    /// A byte past the end of the message isn't zero
+    UnusedBits,
+}
+
+/// An error converting a batch of packed events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatchError {
+    /// The index of the offending event
+    pub index: usize,
+
+    /// What is wrong with it
+    pub error: PackedError,
+}
+
+/// Conversion to and from packed 32-bit short messages
+pub trait MidiPacked: Sized {
+    /// Pack into `status | data1 << 8 | data2 << 16`
+    fn to_packed(&self) -> u32;
+
+    /// Unpack, rejecting values that don't round trip
+    fn try_from_packed(packed: u32) -> Result<Self, PackedError>;
+}
+
+impl MidiPacked for MidiMessage {
+    fn to_packed(&self) -> u32 {
+        let mut buf = [0; 3];
+        encode::<false>(self, &mut None, &mut buf)
+            .iter()
+            .rev()
+            .fold(0, |packed, byte| packed << 8 | u32::from(*byte))
+    }
+
+    fn try_from_packed(packed: u32) -> Result<Self, PackedError> {
+        let bytes = packed.to_le_bytes();
+        let len = message_len(bytes[0]).ok_or(PackedError::InvalidStatus)?;
+        let number_127 = matches!(bytes[0] & 0xF0, 0xB0 | 0xC0) && bytes[1] == 0x7F;
+        if bytes[1..len].iter().any(|b| b & 0x80 != 0) || number_127 {
+            Err(PackedError::InvalidData)
+        } else if bytes[len..].iter().any(|b| *b != 0) {
+            Err(PackedError::UnusedBits)
+        } else {
+            MidiMessage::try_parse_slice(&bytes[..len]).map_err(|_| PackedError::InvalidStatus)
+        }
+    }
+}
+
+/// The length of the short message starting with `status`
+fn message_len(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | SONG_POSITION_POINTER => Some(3),
+        0xC0..=0xDF | QUARTER_FRAME | SONG_SELECT => Some(2),
+        TUNE_REQUEST | TIMING_CLOCK | START | CONTINUE | STOP | ACTIVE_SENSING | RESET => Some(1),
+        _ => None,
+    }
+}
+
+/// A timestamped packed message, laid out like PortMidi's `PmEvent`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PackedEvent {
+    /// The packed message
+    pub message: u32,
+
+    /// The timestamp, in whatever unit the API uses
+    pub timestamp: u32,
+}
+
+/// Pack timestamped `messages` into `out`, returning the number of events written
+///
+/// Stops when either slice runs out.
+pub fn pack_events(messages: &[(u32, MidiMessage)], out: &mut [PackedEvent]) -> usize {
+    for ((timestamp, message), event) in messages.iter().zip(out.iter_mut()) {
+        *event = PackedEvent {
+            message: message.to_packed(),
+            timestamp: *timestamp,
+        };
+    }
+    messages.len().min(out.len())
+}
+
+/// Unpack `events` into timestamped messages in `out`, returning the number written
+///
+/// Stops when either slice runs out, or at the first event that isn't a valid short message.
+pub fn unpack_events(
+    events: &[PackedEvent],
+    out: &mut [(u32, MidiMessage)],
+) -> Result<usize, BatchError> {
+    for (index, (event, slot)) in events.iter().zip(out.iter_mut()).enumerate() {
+        let message = MidiMessage::try_from_packed(event.message)
+            .map_err(|error| BatchError { index, error })?;
+        *slot = (event.timestamp, message);
+    }
+    Ok(events.len().min(out.len()))
+}
+
+/// Iterate over the timestamped messages of `events`, skipping the ones that aren't valid
+/// short messages
+pub fn valid_events(events: &[PackedEvent]) -> impl Iterator<Item = (u32, MidiMessage)> + '_ {
+    events.iter().filter_map(|event| {
+        MidiMessage::try_from_packed(event.message)
+            .ok()
+            .map(|message| (event.timestamp, message))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::{
+            render_slice::MidiRenderSlice,
+            test::{TEST_1BYTE, TEST_2BYTE, TEST_3BYTE},
+        },
+        std::vec::Vec,
+    };
+
+    #[test]
+    fn should_pack_in_wire_order() {
+        let bend = MidiMessage::PitchBendChange(1.into(), 0x2000u16.into());
+        assert_eq!(bend.to_packed(), 0x0040_00E1);
+        assert_eq!(MidiMessage::Start.to_packed(), 0xFA);
+        assert_eq!(
+            MidiMessage::ProgramChange(3.into(), 5.into()).to_packed(),
+            0x05C3
+        );
+        for m in TEST_1BYTE.iter().chain(&TEST_2BYTE).chain(&TEST_3BYTE) {
+            let mut buf = [0; 4];
+            m.render_slice(&mut buf);
+            assert_eq!(m.to_packed(), u32::from_le_bytes(buf), "{m:?}");
+        }
+    }
+
+    #[test]
+    fn should_round_trip_messages() {
+        for m in TEST_1BYTE.iter().chain(&TEST_2BYTE).chain(&TEST_3BYTE) {
+            assert_eq!(MidiMessage::try_from_packed(m.to_packed()).as_ref(), Ok(m));
+        }
+    }
+
+    #[test]
+    fn should_round_trip_packed_values() {
+        let data = [0x00, 0x01, 0x40, 0x7e, 0x7f, 0x80, 0xff];
+        for status in 0..=0xff {
+            for d1 in data {
+                for d2 in data {
+                    for d3 in [0x00, 0x01] {
+                        let packed = u32::from_le_bytes([status, d1, d2, d3]);
+                        if let Ok(m) = MidiMessage::try_from_packed(packed) {
+                            assert_eq!(m.to_packed(), packed, "{packed:#010x}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_packed_values() {
+        use PackedError::*;
+        let unpack = MidiMessage::try_from_packed;
+        assert_eq!(unpack(0x0034_7612), Err(InvalidStatus));
+        for status in [0xf0, 0xf4, 0xf5, 0xf7, 0xf9, 0xfd] {
+            assert_eq!(unpack(status), Err(InvalidStatus), "{status:#x}");
+        }
+        assert_eq!(unpack(0x0034_F692), Err(InvalidData));
+        assert_eq!(unpack(0x0084_7692), Err(InvalidData));
+        assert_eq!(unpack(0x0000_85C2), Err(InvalidData));
+        assert_eq!(unpack(0x0000_7FB0), Err(InvalidData));
+        assert_eq!(unpack(0x0000_7FC5), Err(InvalidData));
+        assert_eq!(unpack(0x0134_7692), Err(UnusedBits));
+        assert_eq!(unpack(0x0001_05C2), Err(UnusedBits));
+        assert_eq!(unpack(0x0000_01F8), Err(UnusedBits));
+    }
+
+    #[test]
+    fn should_convert_event_batches() {
+        let messages = [
+            (0, MidiMessage::NoteOn(0.into(), 60.into(), 100.into())),
+            (10, MidiMessage::PitchBendChange(0.into(), 0x1234u16.into())),
+            (20, MidiMessage::TimingClock),
+        ];
+        let mut events = [PackedEvent::default(); 4];
+        assert_eq!(pack_events(&messages, &mut events), 3);
+        assert_eq!(
+            events[1],
+            PackedEvent {
+                message: 0x0024_34E0,
+                timestamp: 10
+            }
+        );
+
+        let mut out = [(0, MidiMessage::Reset); 3];
+        assert_eq!(unpack_events(&events[..3], &mut out), Ok(3));
+        assert_eq!(out, messages);
+        assert_eq!(unpack_events(&events[..3], &mut out[..2]), Ok(2));
+        assert_eq!(
+            unpack_events(&events, &mut [(0, MidiMessage::Reset); 4]),
+            Err(BatchError {
+                index: 3,
+                error: PackedError::InvalidStatus
+            })
+        );
+
+        events[1].message = 0xF0;
+        let valid: Vec<_> = valid_events(&events).collect();
+        assert_eq!(valid, [messages[0], messages[2]]);
+    }
+}
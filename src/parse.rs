@@ -253,6 +253,45 @@ impl Default for MidiParser {
     }
 }
 
+/// Extension trait adapting any byte iterator into an iterator of midi messages.
+///
+/// ```
+/// use midi_convert::parse::MidiMessagesExt;
+/// use midi_types::MidiMessage;
+///
+/// let mut messages = [0x92, 0x76, 0x34, 0xf8].into_iter().midi_messages();
+/// assert_eq!(messages.next(), Some(MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into())));
+/// assert_eq!(messages.next(), Some(MidiMessage::TimingClock));
+/// assert_eq!(messages.next(), None);
+/// ```
+pub trait MidiMessagesExt: Iterator<Item = u8> + Sized {
+    /// Parse the bytes of this iterator, yielding every completed midi message
+    fn midi_messages(self) -> MidiMessages<Self> {
+        MidiMessages {
+            bytes: self,
+            parser: MidiParser::new(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = u8>> MidiMessagesExt for I {}
+
+/// Iterator over the midi messages parsed from a byte iterator, see [`MidiMessagesExt`]
+#[derive(Debug, Clone)]
+pub struct MidiMessages<I> {
+    bytes: I,
+    parser: MidiParser,
+}
+
+impl<I: Iterator<Item = u8>> Iterator for MidiMessages<I> {
+    type Item = MidiMessage;
+
+    fn next(&mut self) -> Option<MidiMessage> {
+        let parser = &mut self.parser;
+        self.bytes.find_map(|byte| parser.parse(byte))
+    }
+}
+
 const NOTE_OFF_END: u8 = NOTE_OFF + 0x0F;
 const NOTE_ON_END: u8 = NOTE_ON + 0x0F;
 const KEY_PRESSURE_END: u8 = KEY_PRESSURE + 0x0F;
@@ -261,16 +300,18 @@ const PITCH_BEND_CHANGE_END: u8 = PITCH_BEND_CHANGE + 0x0F;
 const PROGRAM_CHANGE_END: u8 = PROGRAM_CHANGE + 0x0F;
 const CHANNEL_PRESSURE_END: u8 = CHANNEL_PRESSURE + 0x0F;
 
-//parse helper guard
+//parse helper guard, data bytes must not have the status bit set
 fn check_len<F: Fn() -> Result<MidiMessage, MidiParseError>>(
     buf: &[u8],
     len: usize,
     func: F,
 ) -> Result<MidiMessage, MidiParseError> {
-    if buf.len() >= len {
-        func()
-    } else {
+    if buf.len() < len {
         Err(MidiParseError::BufferTooShort)
+    } else if buf[1..len].iter().any(|b| b & 0x80 != 0) {
+        Err(MidiParseError::MessageNotFound)
+    } else {
+        func()
     }
 }
 
@@ -337,12 +378,12 @@ impl MidiTryParseSlice for MidiMessage {
                 s @ PITCH_BEND_CHANGE..=PITCH_BEND_CHANGE_END => check_len(buf, 3, || {
                     Ok(MidiMessage::PitchBendChange(
                         chan(s),
-                        Value14::from((buf[1], buf[2])),
+                        Value14::from((buf[2], buf[1])),
                     ))
                 }),
                 SONG_POSITION_POINTER => check_len(buf, 3, || {
                     Ok(MidiMessage::SongPositionPointer(Value14::from((
-                        buf[1], buf[2],
+                        buf[2], buf[1],
                     ))))
                 }),
 
@@ -780,6 +821,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn should_adapt_byte_iterator() {
+        let messages: Vec<MidiMessage> = [0xB3, 0x3C, 0x18, 0x43, 0x01, 0xf6, 0x92]
+            .into_iter()
+            .midi_messages()
+            .collect();
+        assert_eq!(
+            messages.as_slice(),
+            &[
+                MidiMessage::ControlChange(3.into(), 0x3c.into(), 0x18.into()),
+                MidiMessage::ControlChange(3.into(), 0x43.into(), 0x01.into()),
+                MidiMessage::TuneRequest,
+            ]
+        );
+    }
+
+    #[test]
+    fn should_try_parse_slice_in_wire_order() {
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0xE8, 0x14, 0x56]),
+            Ok(MidiMessage::PitchBendChange(8.into(), (0x56, 0x14).into()))
+        );
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0xf2, 0x7f, 0x68]),
+            Ok(MidiMessage::SongPositionPointer((0x68, 0x7f).into()))
+        );
+    }
+
+    #[test]
+    fn should_try_parse_slice_edge_cases() {
+        use MidiParseError::*;
+        assert_eq!(MidiMessage::try_parse_slice(&[]), Err(BufferTooShort));
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0x92, 0x76]),
+            Err(BufferTooShort)
+        );
+        assert_eq!(MidiMessage::try_parse_slice(&[0xC2]), Err(BufferTooShort));
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0x76, 0x34]),
+            Err(MessageNotFound)
+        );
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0x92, 0x76, 0x84]),
+            Err(MessageNotFound)
+        );
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0xC2, 0xf8]),
+            Err(MessageNotFound)
+        );
+        for status in [0xf0, 0xf4, 0xf5, 0xf7, 0xf9, 0xfd] {
+            assert_eq!(
+                MidiMessage::try_parse_slice(&[status, 0, 0]),
+                Err(MessageNotFound)
+            );
+        }
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0xC2, 0x05, 0xff, 0xff]),
+            Ok(MidiMessage::ProgramChange(2.into(), 5.into()))
+        );
+        assert_eq!(
+            MidiMessage::try_parse_slice(&[0xf8, 0x92]),
+            Ok(MidiMessage::TimingClock)
+        );
+    }
+
     impl MidiParser {
         /// Test helper function, asserts if a slice of bytes parses to some set of midi events
         fn assert_result(&mut self, bytes: &[u8], expected_events: &[MidiMessage]) {
@@ -0,0 +1,142 @@
+//! Parse midi messages from an asynchronous byte stream
+
+use {crate::parse::MidiParser, midi_types::MidiMessage};
+
+/// Reads bytes from an [`embedded_io_async::Read`] and parses them into midi messages.
+#[derive(Debug)]
+pub struct MidiReader<R> {
+    reader: R,
+    parser: MidiParser,
+    buf: [u8; 16],
+    pos: usize,
+    len: usize,
+}
+
+impl<R: embedded_io_async::Read> MidiReader<R> {
+    /// Create a new reader wrapping `reader`
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            parser: MidiParser::new(),
+            buf: [0; 16],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Release the underlying reader, any buffered bytes that were not parsed yet are dropped
+    pub fn release(self) -> R {
+        self.reader
+    }
+
+    /// Wait for the next complete midi message.
+    ///
+    /// Returns `Ok(None)` once the underlying reader reaches end of file.
+    pub async fn next(&mut self) -> Result<Option<MidiMessage>, R::Error> {
+        loop {
+            while self.pos < self.len {
+                let byte = self.buf[self.pos];
+                self.pos += 1;
+                if let Some(message) = self.parser.parse(byte) {
+                    return Ok(Some(message));
+                }
+            }
+
+            self.pos = 0;
+            self.len = self.reader.read(&mut self.buf).await?;
+            if self.len == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::render_async::{AsyncIoTransport, AsyncMidiRenderer};
+    use embassy_futures::block_on;
+    use midi_types::Note;
+    use std::vec::Vec;
+
+    /// Reads `bytes` two at a time, so the reader sees partial messages
+    struct Chunked<'a>(&'a [u8]);
+
+    impl embedded_io_async::ErrorType for Chunked<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl embedded_io_async::Read for Chunked<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = buf.len().min(self.0.len()).min(2);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn should_read_messages_until_eof() {
+        let bytes: &[u8] = &[
+            0x92, 0x76, 0x34, // note on
+            0x33, 0x65, // note on with running status
+            0xf8, // timing clock
+        ];
+        let mut reader = MidiReader::new(bytes);
+        block_on(async {
+            assert_eq!(
+                reader.next().await,
+                Ok(Some(MidiMessage::NoteOn(
+                    2.into(),
+                    0x76.into(),
+                    0x34.into()
+                )))
+            );
+            assert_eq!(
+                reader.next().await,
+                Ok(Some(MidiMessage::NoteOn(
+                    2.into(),
+                    0x33.into(),
+                    0x65.into()
+                )))
+            );
+            assert_eq!(reader.next().await, Ok(Some(MidiMessage::TimingClock)));
+            assert_eq!(reader.next().await, Ok(None));
+        });
+    }
+
+    #[test]
+    fn should_read_rendered_messages_in_small_chunks() {
+        let messages = [
+            MidiMessage::NoteOn(0.into(), Note::C3, 0x40.into()),
+            MidiMessage::PitchBendChange(0.into(), (0x56, 0x14).into()),
+            MidiMessage::PitchBendChange(0.into(), (0x12, 0x7f).into()),
+            MidiMessage::SongPositionPointer((0x68, 0x7f).into()),
+            MidiMessage::ProgramChange(15.into(), 0x15.into()),
+            MidiMessage::NoteOff(0.into(), Note::C3, 0x40.into()),
+        ];
+
+        let mut buf = [0; 32];
+        let mut unwritten = &mut buf[..];
+        let mut renderer: AsyncMidiRenderer<_> =
+            AsyncMidiRenderer::new(AsyncIoTransport::new(&mut unwritten));
+        block_on(async {
+            for message in messages.iter() {
+                renderer.render(message).await.unwrap();
+            }
+        });
+        let len = 32 - unwritten.len();
+
+        let mut reader = MidiReader::new(Chunked(&buf[..len]));
+        let received = block_on(async {
+            let mut received = Vec::new();
+            while let Some(message) = reader.next().await.unwrap() {
+                received.push(message);
+            }
+            received
+        });
+
+        assert_eq!(received.as_slice(), &messages);
+    }
+}
@@ -0,0 +1,468 @@
+//! A fixed capacity output queue that coalesces and rate-limits continuous controllers
+//!
+//! Sweeping a knob easily generates more control changes than a slow transport can carry. The
+//! [`OutputQueue`] sits in front of a [`MidiRenderer`] and
+//!
+//! * coalesces pending continuous controller and pitch bend messages per channel and controller,
+//!   only the latest value is sent,
+//! * sends each continuous controller and pitch bend at most once per configured interval,
+//! * keeps all other messages, like notes, switches, bank and program changes and RPN/NRPN
+//!   sequences, in their original order without ever delaying them.
+//!
+//! The LSB of a 14-bit controller (controllers 32 to 63) is never coalesced, it waits for a
+//! pending MSB of the same controller queued before it so the pair arrives in order.
+//!
+//! Delayed continuous controllers can be overtaken by other messages, their values are sent as soon
+//! as the interval allows.
+//!
+//! ```
+//! use core::time::Duration;
+//! use midi_convert::queue::OutputQueue;
+//! use midi_types::MidiMessage;
+//!
+//! let mut queue: OutputQueue<8> = OutputQueue::new(Duration::from_millis(10));
+//! for value in 0..100 {
+//!     queue.push(MidiMessage::ControlChange(0.into(), 7.into(), value.into())).unwrap();
+//! }
+//! assert_eq!(queue.len(), 1);
+//! assert_eq!(queue.pop(Duration::ZERO), Some(MidiMessage::ControlChange(0.into(), 7.into(), 99.into())));
+//! ```
+
+use {
+    crate::render::{MidiRenderer, MidiTransport},
+    core::time::Duration,
+    midi_types::MidiMessage,
+};
+
+/// Identifies the parameter a continuous message updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Key {
+    Control(u8, u8),
+    PitchBend(u8),
+}
+
+/// Returns true for controllers that carry a continuous value which can be coalesced.
+///
+/// Bank select, data entry, increment/decrement, RPN/NRPN selection, switches, portamento control
+/// and channel mode messages only make sense in sequence and are never coalesced, neither are the
+/// LSBs that complete a 14-bit value.
+fn is_continuous_control(control: u8) -> bool {
+    matches!(control, 1..=5 | 7..=31 | 70..=83 | 85..=95)
+}
+
+/// The coalescing key for messages that are continuous
+fn continuous_key(message: &MidiMessage) -> Option<Key> {
+    match *message {
+        MidiMessage::ControlChange(channel, control, _)
+            if is_continuous_control(control.into()) =>
+        {
+            Some(Key::Control(channel.into(), control.into()))
+        }
+        MidiMessage::PitchBendChange(channel, _) => Some(Key::PitchBend(channel.into())),
+        _ => None,
+    }
+}
+
+/// The coalescing key of the MSB an LSB controller message belongs to
+fn msb_key(message: &MidiMessage) -> Option<Key> {
+    match *message {
+        MidiMessage::ControlChange(channel, control, _) => {
+            let control = u8::from(control).checked_sub(32)?;
+            is_continuous_control(control).then_some(Key::Control(channel.into(), control))
+        }
+        _ => None,
+    }
+}
+
+/// An output queue holding at most N pending messages, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct OutputQueue<const N: usize> {
+    pending: [Option<MidiMessage>; N],
+    len: usize,
+    last_sent: [Option<(Key, Duration)>; N],
+    min_interval: Duration,
+}
+
+impl<const N: usize> OutputQueue<N> {
+    /// Create a queue that sends every continuous controller or pitch bend at most once per
+    /// `min_interval`, `Duration::ZERO` only coalesces without rate limiting
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            pending: [None; N],
+            len: 0,
+            last_sent: [None; N],
+            min_interval,
+        }
+    }
+
+    /// Create a queue that sends every continuous controller or pitch bend at most `rate` times per
+    /// second
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is 0
+    pub fn with_rate(rate: u32) -> Self {
+        Self::new(Duration::from_secs(1) / rate)
+    }
+
+    /// The number of pending messages
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no messages are pending
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue a message, coalescing it with a pending message for the same parameter.
+    ///
+    /// A pending MSB followed by the LSB of its controller is kept. Returns the message back if
+    /// the queue is full.
+    pub fn push(&mut self, message: MidiMessage) -> Result<(), MidiMessage> {
+        if let Some(key) = continuous_key(&message) {
+            let pending = &mut self.pending[..self.len];
+            if let Some(index) = pending
+                .iter()
+                .rposition(|pending| pending.as_ref().and_then(continuous_key) == Some(key))
+            {
+                if !pending[index..]
+                    .iter()
+                    .flatten()
+                    .any(|pending| msb_key(pending) == Some(key))
+                {
+                    pending[index] = Some(message);
+                    return Ok(());
+                }
+            }
+        }
+
+        if self.len == N {
+            return Err(message);
+        }
+        self.pending[self.len] = Some(message);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take the next message that may be sent at time `now`
+    pub fn pop(&mut self, now: Duration) -> Option<MidiMessage> {
+        let index = self.next_index(now)?;
+        Some(self.remove(index, now))
+    }
+
+    /// Render every message that may be sent at time `now`, returning the number of rendered
+    /// messages.
+    ///
+    /// A message that fails to render stays queued.
+    pub fn drain<T: MidiTransport, const RUNNING_STATUS: bool>(
+        &mut self,
+        renderer: &mut MidiRenderer<T, RUNNING_STATUS>,
+        now: Duration,
+    ) -> Result<usize, T::Error> {
+        let mut count = 0;
+        while let Some(index) = self.next_index(now) {
+            if let Some(message) = &self.pending[index] {
+                renderer.render(message)?;
+            }
+            self.remove(index, now);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The earliest time at which [`pop`](Self::pop) returns a message, or None if the queue is
+    /// empty. Messages that can be sent right away report `Duration::ZERO`.
+    pub fn next_due(&self) -> Option<Duration> {
+        // A waiting LSB is due after its MSB, which is already taken into account
+        (0..self.len)
+            .filter(|&index| !self.waits_for_msb(index))
+            .filter_map(|index| self.pending[index].as_ref())
+            .map(|message| match continuous_key(message) {
+                Some(key) => self.due(key),
+                None => Duration::ZERO,
+            })
+            .min()
+    }
+
+    /// Drop all pending messages and forget rate limiting history
+    pub fn clear(&mut self) {
+        self.pending = [None; N];
+        self.len = 0;
+        self.last_sent = [None; N];
+    }
+
+    /// The time a message for `key` may be sent again
+    fn due(&self, key: Key) -> Duration {
+        self.last_sent
+            .iter()
+            .flatten()
+            .find(|(k, _)| *k == key)
+            .map_or(Duration::ZERO, |(_, sent)| *sent + self.min_interval)
+    }
+
+    /// Returns true if the message at `index` is an LSB queued after the MSB of its controller
+    fn waits_for_msb(&self, index: usize) -> bool {
+        self.pending[index]
+            .as_ref()
+            .and_then(msb_key)
+            .is_some_and(|key| {
+                self.pending[..index]
+                    .iter()
+                    .flatten()
+                    .any(|pending| continuous_key(pending) == Some(key))
+            })
+    }
+
+    fn next_index(&self, now: Duration) -> Option<usize> {
+        (0..self.len).find(
+            |&index| match self.pending[index].as_ref().and_then(continuous_key) {
+                Some(key) => self.due(key) <= now,
+                None => !self.waits_for_msb(index),
+            },
+        )
+    }
+
+    fn remove(&mut self, index: usize, now: Duration) -> MidiMessage {
+        let message = self.pending[index].take().expect("pending message");
+        self.pending[index..self.len].rotate_left(1);
+        self.len -= 1;
+
+        if let Some(key) = continuous_key(&message) {
+            self.record_sent(key, now);
+        }
+        message
+    }
+
+    fn record_sent(&mut self, key: Key, now: Duration) {
+        if self.min_interval.is_zero() {
+            return;
+        }
+
+        // Reuse the slot for this key, an expired or free slot, or evict the oldest entry
+        let min_interval = self.min_interval;
+        let slot = match self
+            .last_sent
+            .iter()
+            .position(|entry| matches!(entry, Some((k, _)) if *k == key))
+        {
+            Some(slot) => slot,
+            None => self
+                .last_sent
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| match entry {
+                    None => None,
+                    Some((_, sent)) if *sent + min_interval <= now => None,
+                    Some((_, sent)) => Some(*sent),
+                })
+                .map_or(0, |(slot, _)| slot),
+        };
+
+        if let Some(entry) = self.last_sent.get_mut(slot) {
+            *entry = Some((key, now));
+        }
+    }
+}
+
+impl<const N: usize> Default for OutputQueue<N> {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midi_types::Note;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn cc(channel: u8, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(channel.into(), control.into(), value.into())
+    }
+
+    fn note_on(note: Note) -> MidiMessage {
+        MidiMessage::NoteOn(0.into(), note, 0x40.into())
+    }
+
+    fn pop_all<const N: usize>(queue: &mut OutputQueue<N>, now: Duration) -> Vec<MidiMessage> {
+        core::iter::from_fn(|| queue.pop(now)).collect()
+    }
+
+    #[test]
+    fn should_coalesce_per_channel_and_control() {
+        let mut queue: OutputQueue<8> = OutputQueue::default();
+        queue.push(cc(0, 7, 1)).unwrap();
+        queue.push(cc(1, 7, 2)).unwrap();
+        queue.push(cc(0, 10, 3)).unwrap();
+        queue.push(cc(0, 7, 4)).unwrap();
+        queue.push(cc(1, 7, 5)).unwrap();
+        assert_eq!(
+            pop_all(&mut queue, Duration::ZERO),
+            &[cc(0, 7, 4), cc(1, 7, 5), cc(0, 10, 3)]
+        );
+    }
+
+    #[test]
+    fn should_coalesce_pitch_bend_per_channel() {
+        let mut queue: OutputQueue<8> = OutputQueue::default();
+        queue
+            .push(MidiMessage::PitchBendChange(0.into(), 100u16.into()))
+            .unwrap();
+        queue
+            .push(MidiMessage::PitchBendChange(1.into(), 200u16.into()))
+            .unwrap();
+        queue
+            .push(MidiMessage::PitchBendChange(0.into(), 300u16.into()))
+            .unwrap();
+        assert_eq!(
+            pop_all(&mut queue, Duration::ZERO),
+            &[
+                MidiMessage::PitchBendChange(0.into(), 300u16.into()),
+                MidiMessage::PitchBendChange(1.into(), 200u16.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_keep_order_of_other_messages() {
+        let mut queue: OutputQueue<8> = OutputQueue::default();
+        let messages = [
+            note_on(Note::C3),
+            cc(0, 64, 127),
+            cc(0, 101, 0),
+            cc(0, 100, 0),
+            cc(0, 6, 2),
+            cc(0, 6, 12),
+            note_on(Note::C3),
+            MidiMessage::ProgramChange(0.into(), 1.into()),
+        ];
+        for message in messages {
+            queue.push(message).unwrap();
+        }
+        assert_eq!(pop_all(&mut queue, Duration::ZERO), &messages);
+    }
+
+    #[test]
+    fn should_refuse_when_full() {
+        let mut queue: OutputQueue<2> = OutputQueue::default();
+        queue.push(note_on(Note::C3)).unwrap();
+        queue.push(cc(0, 7, 1)).unwrap();
+        assert_eq!(queue.push(note_on(Note::D4)), Err(note_on(Note::D4)));
+        // Coalescing still works when full
+        assert_eq!(queue.push(cc(0, 7, 2)), Ok(()));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn should_rate_limit_continuous_controllers() {
+        let mut queue: OutputQueue<4> = OutputQueue::with_rate(100);
+        queue.push(cc(0, 7, 1)).unwrap();
+        assert_eq!(queue.pop(Duration::ZERO), Some(cc(0, 7, 1)));
+
+        queue.push(cc(0, 7, 2)).unwrap();
+        queue.push(note_on(Note::C3)).unwrap();
+        queue.push(cc(0, 7, 3)).unwrap();
+        assert_eq!(queue.next_due(), Some(Duration::ZERO));
+
+        // The note overtakes the held back controller
+        assert_eq!(pop_all(&mut queue, 5 * MS), &[note_on(Note::C3)]);
+        assert_eq!(queue.next_due(), Some(10 * MS));
+        assert_eq!(pop_all(&mut queue, 10 * MS), &[cc(0, 7, 3)]);
+
+        // Other controllers are limited independently
+        queue.push(cc(0, 7, 4)).unwrap();
+        queue.push(cc(0, 1, 5)).unwrap();
+        assert_eq!(pop_all(&mut queue, 12 * MS), &[cc(0, 1, 5)]);
+        assert_eq!(pop_all(&mut queue, 20 * MS), &[cc(0, 7, 4)]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.next_due(), None);
+    }
+
+    #[test]
+    fn should_keep_14_bit_controller_pairs_together() {
+        let mut queue: OutputQueue<8> = OutputQueue::with_rate(100);
+        queue.push(cc(0, 7, 1)).unwrap();
+        queue.push(cc(0, 39, 2)).unwrap();
+        assert_eq!(
+            pop_all(&mut queue, Duration::ZERO),
+            &[cc(0, 7, 1), cc(0, 39, 2)]
+        );
+
+        // The LSB waits for its held back MSB, later MSBs are only coalesced up to the LSB
+        queue.push(cc(0, 7, 3)).unwrap();
+        queue.push(cc(0, 39, 4)).unwrap();
+        queue.push(cc(0, 7, 5)).unwrap();
+        queue.push(cc(0, 39, 6)).unwrap();
+        queue.push(cc(0, 39, 7)).unwrap();
+        queue.push(note_on(Note::C3)).unwrap();
+        assert_eq!(queue.len(), 6);
+        assert_eq!(pop_all(&mut queue, 5 * MS), &[note_on(Note::C3)]);
+        assert_eq!(queue.next_due(), Some(10 * MS));
+        assert_eq!(pop_all(&mut queue, 10 * MS), &[cc(0, 7, 3), cc(0, 39, 4)]);
+        assert_eq!(
+            pop_all(&mut queue, 20 * MS),
+            &[cc(0, 7, 5), cc(0, 39, 6), cc(0, 39, 7)]
+        );
+
+        queue.push(cc(0, 7, 8)).unwrap();
+        queue.push(cc(0, 7, 9)).unwrap();
+        queue.push(cc(0, 39, 10)).unwrap();
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn should_drain_into_renderer() {
+        let mut queue: OutputQueue<4> = OutputQueue::new(10 * MS);
+        let mut renderer: MidiRenderer<MockTransport> = MidiRenderer::new(MockTransport::default());
+
+        for value in 0..10 {
+            queue.push(cc(2, 7, value)).unwrap();
+        }
+        queue.push(note_on(Note::C3)).unwrap();
+        assert_eq!(queue.drain(&mut renderer, Duration::ZERO), Ok(2));
+
+        queue.push(cc(2, 7, 100)).unwrap();
+        assert_eq!(queue.drain(&mut renderer, 5 * MS), Ok(0));
+        assert_eq!(queue.drain(&mut renderer, 10 * MS), Ok(1));
+        assert_eq!(
+            renderer.release().buffer,
+            &[0xB2, 0x07, 0x09, 0x90, 0x3c, 0x40, 0xB2, 0x07, 0x64]
+        );
+    }
+
+    #[test]
+    fn should_keep_message_when_rendering_fails() {
+        let mut queue: OutputQueue<4> = OutputQueue::default();
+        let mut renderer: MidiRenderer<MockTransport> = MidiRenderer::new(MockTransport {
+            fail: true,
+            ..Default::default()
+        });
+        queue.push(note_on(Note::C3)).unwrap();
+        assert_eq!(queue.drain(&mut renderer, Duration::ZERO), Err(()));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        buffer: Vec<u8>,
+        fail: bool,
+    }
+
+    impl MidiTransport for MockTransport {
+        type Error = ();
+
+        fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err(());
+            }
+            self.buffer.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+}
@@ -30,109 +30,147 @@ impl<T: MidiTransport, const RUNNING_STATUS: bool> MidiRenderer<T, RUNNING_STATU
     }
 
     pub fn render(&mut self, message: &MidiMessage) -> Result<(), T::Error> {
-        match *message {
-            // Channel voice messages
-            MidiMessage::NoteOn(channel, note, velocity) => {
-                self.write_channel_msg(&[
-                    NOTE_ON + Into::<u8>::into(channel),
-                    note.into(),
-                    velocity.into(),
-                ])?;
-            }
-            MidiMessage::NoteOff(channel, note, velocity) => {
-                self.write_channel_msg(&[
-                    NOTE_OFF + Into::<u8>::into(channel),
-                    note.into(),
-                    velocity.into(),
-                ])?;
-            }
-            MidiMessage::KeyPressure(channel, note, value) => {
-                self.write_channel_msg(&[
-                    KEY_PRESSURE + Into::<u8>::into(channel),
-                    note.into(),
-                    value.into(),
-                ])?;
-            }
-            MidiMessage::ControlChange(channel, control, value) => {
-                self.write_channel_msg(&[
-                    CONTROL_CHANGE + Into::<u8>::into(channel),
-                    control.into(),
-                    value.into(),
-                ])?;
-            }
-            MidiMessage::ProgramChange(channel, program) => {
-                self.write_channel_msg(&[
-                    PROGRAM_CHANGE + Into::<u8>::into(channel),
-                    program.into(),
-                ])?;
-            }
-            MidiMessage::ChannelPressure(channel, value) => {
-                self.write_channel_msg(&[
-                    CHANNEL_PRESSURE + Into::<u8>::into(channel),
-                    value.into(),
-                ])?;
-            }
-            MidiMessage::PitchBendChange(channel, value) => {
-                let (msb, lsb) = value.into();
-                self.write_channel_msg(&[PITCH_BEND_CHANGE + Into::<u8>::into(channel), lsb, msb])?;
-            }
-
-            // System common messages
-            MidiMessage::QuarterFrame(value) => {
-                self.write_sys_common_msg(&[QUARTER_FRAME, value.into()])?;
-            }
-            MidiMessage::SongPositionPointer(value) => {
-                let (msb, lsb) = value.into();
-                self.write_sys_common_msg(&[SONG_POSITION_POINTER, lsb, msb])?;
-            }
-            MidiMessage::SongSelect(value) => {
-                self.write_sys_common_msg(&[SONG_SELECT, value.into()])?;
-            }
-            MidiMessage::TuneRequest => {
-                self.write_sys_common_msg(&[TUNE_REQUEST])?;
-            }
-
-            // System real time messages
-            MidiMessage::TimingClock => self.transport.write(&[TIMING_CLOCK])?,
-            MidiMessage::Start => self.transport.write(&[START])?,
-            MidiMessage::Continue => self.transport.write(&[CONTINUE])?,
-            MidiMessage::Stop => self.transport.write(&[STOP])?,
-            MidiMessage::ActiveSensing => self.transport.write(&[ACTIVE_SENSING])?,
-            MidiMessage::Reset => self.transport.write(&[RESET])?,
-        }
-        Ok(())
+        let mut buf = [0; 3];
+        let bytes = encode::<RUNNING_STATUS>(message, &mut self.running_status, &mut buf);
+        self.transport.write(bytes)
+    }
+
+    /// Write SysEx bytes in chunks of up to 3 bytes, this ends running status
+    pub(crate) fn write_exclusive(&mut self, bytes: &[u8]) -> Result<(), T::Error> {
+        self.running_status = None;
+        bytes
+            .chunks(3)
+            .try_for_each(|chunk| self.transport.write(chunk))
     }
+}
 
-    /// Write a channel voice or channel mode messages, these messages optionally use running status to
-    /// skip sending the status byte
-    fn write_channel_msg(&mut self, data: &[u8]) -> Result<(), T::Error> {
-        let status = data[0];
-        if RUNNING_STATUS && self.running_status == Some(status) {
-            // If the last command written had the same status/channel, the MIDI protocol allows us to
-            // omit sending the status byte again.
-            self.transport.write(&data[1..])?;
-        } else {
-            self.transport.write(data)?;
-
-            if RUNNING_STATUS {
-                // Store running state so the next message can use it
-                self.running_status = Some(status);
-            }
+/// Render a message into `buf` and return the bytes that should be written to the transport,
+/// skipping the status byte when running status allows it.
+pub(crate) fn encode<'a, const RUNNING_STATUS: bool>(
+    message: &MidiMessage,
+    running_status: &mut Option<u8>,
+    buf: &'a mut [u8; 3],
+) -> &'a [u8] {
+    match *message {
+        // Channel voice messages
+        MidiMessage::NoteOn(channel, note, velocity) => {
+            *buf = [
+                NOTE_ON + Into::<u8>::into(channel),
+                note.into(),
+                velocity.into(),
+            ];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..])
+        }
+        MidiMessage::NoteOff(channel, note, velocity) => {
+            *buf = [
+                NOTE_OFF + Into::<u8>::into(channel),
+                note.into(),
+                velocity.into(),
+            ];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..])
+        }
+        MidiMessage::KeyPressure(channel, note, value) => {
+            *buf = [
+                KEY_PRESSURE + Into::<u8>::into(channel),
+                note.into(),
+                value.into(),
+            ];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..])
+        }
+        MidiMessage::ControlChange(channel, control, value) => {
+            *buf = [
+                CONTROL_CHANGE + Into::<u8>::into(channel),
+                control.into(),
+                value.into(),
+            ];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..])
+        }
+        MidiMessage::ProgramChange(channel, program) => {
+            *buf = [
+                PROGRAM_CHANGE + Into::<u8>::into(channel),
+                program.into(),
+                0,
+            ];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..2])
+        }
+        MidiMessage::ChannelPressure(channel, value) => {
+            *buf = [
+                CHANNEL_PRESSURE + Into::<u8>::into(channel),
+                value.into(),
+                0,
+            ];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..2])
+        }
+        MidiMessage::PitchBendChange(channel, value) => {
+            let (msb, lsb) = value.into();
+            *buf = [PITCH_BEND_CHANGE + Into::<u8>::into(channel), lsb, msb];
+            channel_msg::<RUNNING_STATUS>(running_status, &buf[..])
         }
 
-        Ok(())
-    }
+        // System common messages
+        MidiMessage::QuarterFrame(value) => {
+            *buf = [QUARTER_FRAME, value.into(), 0];
+            sys_common_msg::<RUNNING_STATUS>(running_status, &buf[..2])
+        }
+        MidiMessage::SongPositionPointer(value) => {
+            let (msb, lsb) = value.into();
+            *buf = [SONG_POSITION_POINTER, lsb, msb];
+            sys_common_msg::<RUNNING_STATUS>(running_status, &buf[..])
+        }
+        MidiMessage::SongSelect(value) => {
+            *buf = [SONG_SELECT, value.into(), 0];
+            sys_common_msg::<RUNNING_STATUS>(running_status, &buf[..2])
+        }
+        MidiMessage::TuneRequest => {
+            *buf = [TUNE_REQUEST, 0, 0];
+            sys_common_msg::<RUNNING_STATUS>(running_status, &buf[..1])
+        }
 
-    /// Write a System common message, these messages do not use running status but do reset it
-    fn write_sys_common_msg(&mut self, data: &[u8]) -> Result<(), T::Error> {
-        self.transport.write(data)?;
+        // System real time messages
+        MidiMessage::TimingClock => real_time_msg(buf, TIMING_CLOCK),
+        MidiMessage::Start => real_time_msg(buf, START),
+        MidiMessage::Continue => real_time_msg(buf, CONTINUE),
+        MidiMessage::Stop => real_time_msg(buf, STOP),
+        MidiMessage::ActiveSensing => real_time_msg(buf, ACTIVE_SENSING),
+        MidiMessage::Reset => real_time_msg(buf, RESET),
+    }
+}
 
+/// Channel voice or channel mode messages optionally use running status to skip sending the status
+/// byte
+fn channel_msg<'a, const RUNNING_STATUS: bool>(
+    running_status: &mut Option<u8>,
+    data: &'a [u8],
+) -> &'a [u8] {
+    let status = data[0];
+    if RUNNING_STATUS && *running_status == Some(status) {
+        // If the last command written had the same status/channel, the MIDI protocol allows us to
+        // omit sending the status byte again.
+        &data[1..]
+    } else {
         if RUNNING_STATUS {
-            self.running_status = None;
+            // Store running state so the next message can use it
+            *running_status = Some(status);
         }
+        data
+    }
+}
 
-        Ok(())
+/// System common messages do not use running status but do reset it
+fn sys_common_msg<'a, const RUNNING_STATUS: bool>(
+    running_status: &mut Option<u8>,
+    data: &'a [u8],
+) -> &'a [u8] {
+    if RUNNING_STATUS {
+        *running_status = None;
     }
+    data
+}
+
+/// System real time messages are a single byte and leave running status untouched
+fn real_time_msg(buf: &mut [u8; 3], status: u8) -> &[u8] {
+    buf[0] = status;
+    &buf[..1]
 }
 
 #[cfg(test)]
@@ -0,0 +1,126 @@
+//! Render messages to an asynchronous transport
+
+use {crate::render::encode, midi_types::MidiMessage};
+
+/// This trait abstracts the transport mechanism for the AsyncMidiRenderer. It is the asynchronous
+/// counterpart of [`MidiTransport`](crate::render::MidiTransport)
+///
+/// Any [`embedded_io_async::Write`], like the UARTs and pipes of async HALs like Embassy, can be
+/// used through [`AsyncIoTransport`].
+#[allow(async_fn_in_trait)]
+pub trait AsyncMidiTransport {
+    type Error;
+
+    /// Write a message as series of bytes to the midi transport layer
+    ///
+    /// For compatibility this should always be used to write one whole midi-message with a maximum of 3 bytes
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Wraps any [`embedded_io_async::Write`] so it can be used as a transport for the
+/// [`AsyncMidiRenderer`]
+///
+/// Like `IoTransport` for `std::io::Write`, this is a wrapper rather than a blanket
+/// implementation so crates can still implement [`AsyncMidiTransport`] for their own writers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AsyncIoTransport<W> {
+    writer: W,
+}
+
+impl<W: embedded_io_async::Write> AsyncIoTransport<W> {
+    /// Create a new transport writing to `writer`
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Release the underlying writer
+    pub fn release(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: embedded_io_async::Write> AsyncMidiTransport for AsyncIoTransport<W> {
+    type Error = W::Error;
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.writer.write_all(bytes).await
+    }
+}
+
+/// The AsyncMidiRenderer takes MIDI messages and writes them to the underlying asynchronous
+/// transport, the boolean const generic RUNNING_STATUS enables or disables rendering running status
+/// for midi messages
+#[derive(Debug)]
+pub struct AsyncMidiRenderer<T, const RUNNING_STATUS: bool = true> {
+    transport: T,
+    running_status: Option<u8>,
+}
+
+impl<T: AsyncMidiTransport, const RUNNING_STATUS: bool> AsyncMidiRenderer<T, RUNNING_STATUS> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            running_status: None,
+        }
+    }
+
+    pub fn release(self) -> T {
+        self.transport
+    }
+
+    pub async fn render(&mut self, message: &MidiMessage) -> Result<(), T::Error> {
+        let mut buf = [0; 3];
+        let bytes = encode::<RUNNING_STATUS>(message, &mut self.running_status, &mut buf);
+        self.transport.write(bytes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use midi_types::Note;
+
+    #[test]
+    fn should_render_to_buffer() {
+        let mut buf = [0; 16];
+        let mut unwritten = &mut buf[..];
+        let mut renderer: AsyncMidiRenderer<_> =
+            AsyncMidiRenderer::new(AsyncIoTransport::new(&mut unwritten));
+        block_on(async {
+            renderer
+                .render(&MidiMessage::NoteOn(2.into(), Note::D4, 0x34.into()))
+                .await
+                .unwrap();
+            renderer
+                .render(&MidiMessage::NoteOn(2.into(), Note::G6, 0x65.into()))
+                .await
+                .unwrap();
+            renderer.render(&MidiMessage::TimingClock).await.unwrap();
+        });
+
+        let len = 16 - unwritten.len();
+        assert_eq!(&buf[..len], &[0x92, 0x4a, 0x34, 0x67, 0x65, 0xF8]);
+    }
+
+    #[test]
+    fn should_not_skip_repeated_status_with_running_status_off() {
+        let mut buf = [0; 16];
+        let mut unwritten = &mut buf[..];
+        let mut renderer: AsyncMidiRenderer<_, false> =
+            AsyncMidiRenderer::new(AsyncIoTransport::new(&mut unwritten));
+        block_on(async {
+            renderer
+                .render(&MidiMessage::ControlChange(1.into(), 7.into(), 0x10.into()))
+                .await
+                .unwrap();
+            renderer
+                .render(&MidiMessage::ControlChange(1.into(), 7.into(), 0x11.into()))
+                .await
+                .unwrap();
+        });
+
+        let len = 16 - unwritten.len();
+        assert_eq!(&buf[..len], &[0xB1, 0x07, 0x10, 0xB1, 0x07, 0x11]);
+    }
+}
@@ -59,12 +59,12 @@ impl MidiRenderSlice for MidiMessage {
             MidiMessage::KeyPressure(c, n, v) => chan3byte(buf, KEY_PRESSURE, c, n, v),
             MidiMessage::ControlChange(c, n, v) => chan3byte(buf, CONTROL_CHANGE, c, n, v),
             MidiMessage::PitchBendChange(c, v) => {
-                let (v0, v1): (u8, u8) = (*v).into();
-                chan3byte(buf, PITCH_BEND_CHANGE, c, &v0, &v1)
+                let (msb, lsb): (u8, u8) = (*v).into();
+                chan3byte(buf, PITCH_BEND_CHANGE, c, &lsb, &msb)
             }
             MidiMessage::SongPositionPointer(v) => {
-                let (v0, v1): (u8, u8) = (*v).into();
-                chan3byte(buf, SONG_POSITION_POINTER, &0, &v0, &v1)
+                let (msb, lsb): (u8, u8) = (*v).into();
+                chan3byte(buf, SONG_POSITION_POINTER, &0, &lsb, &msb)
             }
             MidiMessage::ProgramChange(c, p) => chan2byte(buf, PROGRAM_CHANGE, c, p),
             MidiMessage::ChannelPressure(c, p) => chan2byte(buf, CHANNEL_PRESSURE, c, p),
@@ -142,4 +142,13 @@ mod test {
             assert_eq!(3, v.render_slice(&mut buf100), "{:?}", v);
         }
     }
+
+    #[test]
+    fn render_value14_lsb_first() {
+        let mut buf = [0; 3];
+        MidiMessage::PitchBendChange(8.into(), (0x56, 0x14).into()).render_slice(&mut buf);
+        assert_eq!(buf, [0xE8, 0x14, 0x56]);
+        MidiMessage::SongPositionPointer((0x68, 0x7f).into()).render_slice(&mut buf);
+        assert_eq!(buf, [0xf2, 0x7f, 0x68]);
+    }
 }
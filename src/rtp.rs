@@ -0,0 +1,663 @@
+//! RTP-MIDI (RFC 6295) packets
+//!
+//! An RTP-MIDI payload starts with the MIDI command section: a header holding the B, J, Z and P
+//! flags and the length of the MIDI list, followed by the list itself. Commands in the list are
+//! separated by delta-times in RTP timestamp units and may use running status. When the J flag is
+//! set a recovery journal follows the command section.
+//!
+//! ```
+//! use midi_convert::rtp::{CommandSection, RtpHeader};
+//! use midi_types::MidiMessage;
+//!
+//! let packet = [
+//!     0x80, 0x61, 0x5e, 0x28, 0x00, 0x00, 0x27, 0x10, 0xf5, 0x0e, 0x3f, 0x1b, // RTP header
+//!     0x03, 0x92, 0x76, 0x34, // command section
+//! ];
+//! let (header, payload) = RtpHeader::parse(&packet).unwrap();
+//! let (section, _journal) = CommandSection::parse(payload).unwrap();
+//! let mut messages = section.messages(header.timestamp);
+//! assert_eq!(
+//!     messages.next(),
+//!     Some(Ok((10000, MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into()))))
+//! );
+//! assert_eq!(messages.next(), None);
+//! ```
+
+use {
+    crate::{parse::MidiParser, render::encode},
+    midi_types::{MidiMessage, status::*},
+};
+
+pub mod journal;
+pub mod session;
+
+/// The RTP payload type commonly used for RTP-MIDI sessions
+pub const PAYLOAD_TYPE: u8 = 0x61;
+
+const FLAG_B: u8 = 0x80;
+const FLAG_J: u8 = 0x40;
+const FLAG_Z: u8 = 0x20;
+const FLAG_P: u8 = 0x10;
+
+/// The longest MIDI list a short header can describe
+const SHORT_LEN: usize = 0x0f;
+
+/// The longest MIDI list a long header can describe
+const LONG_LEN: usize = 0x0fff;
+
+/// The largest value a delta-time can hold
+const MAX_DELTA: u32 = 0x0fff_ffff;
+
+/// Errors reading or writing RTP-MIDI packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RtpMidiError {
+    /// The buffer is shorter than the data it should hold
+    BufferTooShort,
+
+    /// The packet is not an RTP version 2 packet
+    InvalidHeader,
+
+    /// A delta-time is longer than 4 bytes
+    InvalidDeltaTime,
+
+    /// A command lacks a status byte or holds a status byte in place of a data byte
+    InvalidCommand,
+
+    /// The MIDI list exceeds the 4095 bytes a command section can hold
+    ListTooLong,
+
+    /// The message timestamps decrease or lie before the packet timestamp
+    InvalidTimestamp,
+}
+
+/// The fixed part of an RTP header
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RtpHeader {
+    /// The marker bit
+    pub marker: bool,
+
+    /// The payload type, usually [`PAYLOAD_TYPE`]
+    pub payload_type: u8,
+
+    /// The sequence number, incremented for every packet
+    pub sequence: u16,
+
+    /// The time of the packet in units of the session clock
+    pub timestamp: u32,
+
+    /// The synchronization source identifier of the sender
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    /// The length of a header without CSRC identifiers or extensions
+    pub const LEN: usize = 12;
+
+    /// Parse a header, returning it and the payload that follows it
+    ///
+    /// CSRC identifiers, header extensions and padding are skipped.
+    pub fn parse(packet: &[u8]) -> Result<(Self, &[u8]), RtpMidiError> {
+        if packet.len() < Self::LEN {
+            return Err(RtpMidiError::BufferTooShort);
+        }
+        if packet[0] >> 6 != 2 {
+            return Err(RtpMidiError::InvalidHeader);
+        }
+
+        let header = Self {
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        };
+
+        let csrc = usize::from(packet[0] & 0x0f);
+        let mut payload = packet
+            .get(Self::LEN + 4 * csrc..)
+            .ok_or(RtpMidiError::BufferTooShort)?;
+        if packet[0] & 0x10 != 0 {
+            let words = payload.get(2..4).ok_or(RtpMidiError::BufferTooShort)?;
+            let len = 4 + 4 * usize::from(u16::from_be_bytes([words[0], words[1]]));
+            payload = payload.get(len..).ok_or(RtpMidiError::BufferTooShort)?;
+        }
+        if packet[0] & 0x20 != 0 {
+            let padding = usize::from(*packet.last().unwrap_or(&0));
+            let len = payload
+                .len()
+                .checked_sub(padding)
+                .ok_or(RtpMidiError::BufferTooShort)?;
+            payload = &payload[..len];
+        }
+        Ok((header, payload))
+    }
+
+    /// Write the header to the start of `buf` and return its length
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, RtpMidiError> {
+        let buf = buf
+            .get_mut(..Self::LEN)
+            .ok_or(RtpMidiError::BufferTooShort)?;
+        buf[0] = 0x80;
+        buf[1] = u8::from(self.marker) << 7 | self.payload_type & 0x7f;
+        buf[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        Ok(Self::LEN)
+    }
+}
+
+/// A parsed MIDI command section
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandSection<'a> {
+    flags: u8,
+    list: &'a [u8],
+}
+
+impl<'a> CommandSection<'a> {
+    /// Parse the command section at the start of an RTP-MIDI payload, returning it and the bytes
+    /// that follow it, which hold the recovery journal when [`journal`](Self::journal) is set
+    pub fn parse(payload: &'a [u8]) -> Result<(Self, &'a [u8]), RtpMidiError> {
+        let flags = *payload.first().ok_or(RtpMidiError::BufferTooShort)?;
+        let (len, start) = if flags & FLAG_B != 0 {
+            let low = *payload.get(1).ok_or(RtpMidiError::BufferTooShort)?;
+            (usize::from(flags & 0x0f) << 8 | usize::from(low), 2)
+        } else {
+            (usize::from(flags & 0x0f), 1)
+        };
+        let list = payload
+            .get(start..start + len)
+            .ok_or(RtpMidiError::BufferTooShort)?;
+        Ok((Self { flags, list }, &payload[start + len..]))
+    }
+
+    /// Returns true when a recovery journal follows the command section
+    pub fn journal(&self) -> bool {
+        self.flags & FLAG_J != 0
+    }
+
+    /// Returns true when the status byte of the first command was not in the original stream
+    pub fn phantom(&self) -> bool {
+        self.flags & FLAG_P != 0
+    }
+
+    /// The raw MIDI list
+    pub fn list(&self) -> &'a [u8] {
+        self.list
+    }
+
+    /// Returns true when the MIDI list holds no commands
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// The messages in the MIDI list, timestamped relative to the packet timestamp `timestamp`
+    ///
+    /// SysEx commands and undefined status bytes are skipped.
+    pub fn messages(&self, timestamp: u32) -> Messages<'a> {
+        Messages {
+            list: self.list,
+            time: timestamp,
+            first_delta: self.flags & FLAG_Z != 0,
+            first: true,
+            running_status: None,
+            parser: MidiParser::new(),
+        }
+    }
+}
+
+/// Iterator over the messages of a MIDI list, see [`CommandSection::messages`]
+#[derive(Debug, Clone)]
+pub struct Messages<'a> {
+    list: &'a [u8],
+    time: u32,
+    first_delta: bool,
+    first: bool,
+    running_status: Option<u8>,
+    parser: MidiParser,
+}
+
+impl Messages<'_> {
+    fn command(&mut self) -> Result<Option<(u32, MidiMessage)>, RtpMidiError> {
+        // The first command only has a delta-time when the Z flag is set
+        if !self.first || self.first_delta {
+            let delta = self.delta_time()?;
+            self.time = self.time.wrapping_add(delta);
+        }
+        self.first = false;
+
+        let (&byte, rest) = self
+            .list
+            .split_first()
+            .ok_or(RtpMidiError::BufferTooShort)?;
+        let status = if byte & 0x80 != 0 {
+            self.list = rest;
+            match byte {
+                0x80..=0xef => self.running_status = Some(byte),
+                0xf0..=0xf7 => self.running_status = None,
+                _ => {}
+            }
+            if let Some(message) = self.parser.parse(byte) {
+                return Ok(Some((self.time, message)));
+            }
+            byte
+        } else {
+            self.running_status.ok_or(RtpMidiError::InvalidCommand)?
+        };
+
+        let data_len = match status {
+            0xc0..=0xdf | QUARTER_FRAME | SONG_SELECT => 1,
+            0x80..=0xef | SONG_POSITION_POINTER => 2,
+            SYSEX_START | SYSEX_END => {
+                // Complete SysEx commands and segments end with 0xF7 or 0xF0
+                let end = self
+                    .list
+                    .iter()
+                    .position(|byte| *byte == SYSEX_END || *byte == SYSEX_START)
+                    .ok_or(RtpMidiError::BufferTooShort)?;
+                self.list = &self.list[end + 1..];
+                return Ok(None);
+            }
+            _ => 0,
+        };
+
+        if self.list.len() < data_len {
+            return Err(RtpMidiError::BufferTooShort);
+        }
+        let (data, rest) = self.list.split_at(data_len);
+        self.list = rest;
+        let mut message = None;
+        for &byte in data {
+            if byte & 0x80 != 0 {
+                return Err(RtpMidiError::InvalidCommand);
+            }
+            message = self.parser.parse(byte);
+        }
+        Ok(message.map(|message| (self.time, message)))
+    }
+
+    fn delta_time(&mut self) -> Result<u32, RtpMidiError> {
+        let mut delta = 0;
+        for (i, &byte) in self.list.iter().enumerate().take(4) {
+            delta = delta << 7 | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                self.list = &self.list[i + 1..];
+                return Ok(delta);
+            }
+        }
+        if self.list.len() < 4 {
+            Err(RtpMidiError::BufferTooShort)
+        } else {
+            Err(RtpMidiError::InvalidDeltaTime)
+        }
+    }
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<(u32, MidiMessage), RtpMidiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.list.is_empty() {
+            match self.command() {
+                Ok(Some(message)) => return Some(Ok(message)),
+                Ok(None) => {}
+                Err(error) => {
+                    // The rest of the list can not be interpreted
+                    self.list = &[];
+                    return Some(Err(error));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Write a MIDI command section holding `messages` for a packet with timestamp `timestamp`, and
+/// return its length
+///
+/// Message timestamps are in the same units as the packet timestamp and must not decrease. The
+/// first command always carries its status byte, later commands use running status. Set `journal`
+/// when a recovery journal will follow the command section.
+pub fn write_command_section(
+    buf: &mut [u8],
+    timestamp: u32,
+    messages: &[(u32, MidiMessage)],
+    journal: bool,
+) -> Result<usize, RtpMidiError> {
+    // The list is written after a short header and moved when it needs a long header
+    let mut header_len = 1;
+    let mut len = 0;
+    let mut offset = 0;
+    let mut first_delta = false;
+    let mut running_status = None;
+    for (i, (time, message)) in messages.iter().enumerate() {
+        let delta = time
+            .wrapping_sub(timestamp)
+            .checked_sub(offset)
+            .filter(|delta| *delta <= MAX_DELTA)
+            .ok_or(RtpMidiError::InvalidTimestamp)?;
+        offset += delta;
+
+        let mut delta_buf = [0; 4];
+        let delta_bytes = if i > 0 || delta > 0 {
+            first_delta |= i == 0;
+            write_delta_time(delta, &mut delta_buf)
+        } else {
+            &[]
+        };
+        let mut message_buf = [0; 3];
+        let bytes = encode::<true>(message, &mut running_status, &mut message_buf);
+
+        let command_len = delta_bytes.len() + bytes.len();
+        if len + command_len > LONG_LEN {
+            return Err(RtpMidiError::ListTooLong);
+        }
+        if len + command_len > SHORT_LEN && header_len == 1 {
+            if buf.len() < 2 + len {
+                return Err(RtpMidiError::BufferTooShort);
+            }
+            buf.copy_within(1..1 + len, 2);
+            header_len = 2;
+        }
+
+        let start = header_len + len;
+        let dst = buf
+            .get_mut(start..start + command_len)
+            .ok_or(RtpMidiError::BufferTooShort)?;
+        dst[..delta_bytes.len()].copy_from_slice(delta_bytes);
+        dst[delta_bytes.len()..].copy_from_slice(bytes);
+        len += command_len;
+    }
+
+    let mut flags = 0;
+    if journal {
+        flags |= FLAG_J;
+    }
+    if first_delta {
+        flags |= FLAG_Z;
+    }
+
+    let header = buf
+        .get_mut(..header_len)
+        .ok_or(RtpMidiError::BufferTooShort)?;
+    if header_len == 1 {
+        header[0] = flags | len as u8;
+    } else {
+        header[0] = FLAG_B | flags | (len >> 8) as u8;
+        header[1] = len as u8;
+    }
+    Ok(header_len + len)
+}
+
+/// Encode a delta-time, most significant 7 bits first
+fn write_delta_time(delta: u32, buf: &mut [u8; 4]) -> &[u8] {
+    let len = match delta {
+        0..=0x7f => 1,
+        0x80..=0x3fff => 2,
+        0x4000..=0x1f_ffff => 3,
+        _ => 4,
+    };
+    for (i, byte) in buf[..len].iter_mut().enumerate() {
+        let continuation = if i + 1 < len { 0x80 } else { 0 };
+        *byte = continuation | (delta >> (7 * (len - 1 - i))) as u8 & 0x7f;
+    }
+    &buf[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::test::{TEST_1BYTE, TEST_2BYTE, TEST_3BYTE};
+    use midi_types::{Channel, Control, Note, Value7};
+    use std::vec::Vec;
+
+    type MessagesResult = Result<Vec<(u32, MidiMessage)>, RtpMidiError>;
+
+    fn messages(payload: &[u8], timestamp: u32) -> MessagesResult {
+        let (section, _) = CommandSection::parse(payload)?;
+        section.messages(timestamp).collect()
+    }
+
+    fn note_on(note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn(Channel::new(0), Note::new(note), Value7::new(velocity))
+    }
+
+    #[test]
+    fn should_parse_captured_packet() {
+        // A chord in the form sent by macOS network sessions, followed by a journal
+        let packet = [
+            0x80, 0x61, 0xc4, 0x1f, 0x1b, 0x4a, 0x7e, 0x06, 0x4f, 0x7b, 0x0e, 0x3a, 0x49, 0x90,
+            0x3c, 0x64, 0x00, 0x40, 0x64, 0x00, 0x43, 0x64, 0x20, 0xc4, 0x1e, 0x00, 0x07, 0x08,
+            0x81, 0x83, 0x88, 0x3c, 0xe4,
+        ];
+        let (header, payload) = RtpHeader::parse(&packet).unwrap();
+        assert_eq!(
+            header,
+            RtpHeader {
+                marker: false,
+                payload_type: PAYLOAD_TYPE,
+                sequence: 0xc41f,
+                timestamp: 0x1b4a7e06,
+                ssrc: 0x4f7b0e3a,
+            }
+        );
+
+        let (section, journal) = CommandSection::parse(payload).unwrap();
+        assert!(section.journal());
+        assert!(!section.phantom());
+        assert_eq!(journal, &packet[22..]);
+        assert_eq!(
+            section
+                .messages(header.timestamp)
+                .collect::<MessagesResult>(),
+            Ok([
+                (0x1b4a7e06, note_on(0x3c, 0x64)),
+                (0x1b4a7e06, note_on(0x40, 0x64)),
+                (0x1b4a7e06, note_on(0x43, 0x64)),
+            ]
+            .to_vec())
+        );
+    }
+
+    #[test]
+    fn should_parse_long_header_and_delta_times() {
+        let payload = [
+            0xa0, 0x0f, // B and Z set, 15 bytes
+            0x81, 0x00, 0xb1, 0x07, 0x64, // delta 128, control change
+            0x0a, 0x0a, 0x40, // delta 10, running status
+            0x83, 0x80, 0x00, 0xf8, // delta 49152, timing clock
+            0x00, 0x0b, 0x7f, // running status after real time
+            0x00, // trailing byte of the next section
+        ];
+        let (section, rest) = CommandSection::parse(&payload).unwrap();
+        assert_eq!(section.list().len(), 15);
+        assert_eq!(rest, &[0x00]);
+        assert_eq!(
+            section.messages(0xffff_fff0).collect::<MessagesResult>(),
+            Ok([
+                (
+                    0x70,
+                    MidiMessage::ControlChange(Channel::new(1), Control::new(7), 0x64.into())
+                ),
+                (
+                    0x7a,
+                    MidiMessage::ControlChange(Channel::new(1), Control::new(10), 0x40.into())
+                ),
+                (0xc07a, MidiMessage::TimingClock),
+                (
+                    0xc07a,
+                    MidiMessage::ControlChange(Channel::new(1), Control::new(11), 0x7f.into())
+                ),
+            ]
+            .to_vec())
+        );
+    }
+
+    #[test]
+    fn should_skip_sysex_commands() {
+        let payload = [
+            0x0c, 0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7, 0x00, 0xf8, 0x00, 0xf0, 0x01, 0xf0,
+        ];
+        assert_eq!(
+            messages(&payload, 0),
+            Ok([(0, MidiMessage::TimingClock)].to_vec())
+        );
+    }
+
+    #[test]
+    fn should_report_invalid_lists() {
+        assert_eq!(
+            messages(&[0x02, 0x90, 0x3c], 0),
+            Err(RtpMidiError::BufferTooShort)
+        );
+        assert_eq!(
+            messages(&[0x03, 0x90], 0),
+            Err(RtpMidiError::BufferTooShort)
+        );
+        // The first command needs a status byte
+        assert_eq!(
+            messages(&[0x02, 0x3c, 0x40], 0),
+            Err(RtpMidiError::InvalidCommand)
+        );
+        assert_eq!(
+            messages(&[0x04, 0x90, 0x3c, 0x90, 0x40], 0),
+            Err(RtpMidiError::InvalidCommand)
+        );
+        assert_eq!(
+            messages(&[0x06, 0xf8, 0x80, 0x80, 0x80, 0x80, 0x00], 0),
+            Err(RtpMidiError::InvalidDeltaTime)
+        );
+        assert_eq!(
+            messages(&[0x06, 0x90, 0x3c, 0x40, 0x81, 0x80, 0x80], 0),
+            Err(RtpMidiError::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn should_write_running_status_and_delta_times() {
+        let mut buf = [0; 32];
+        let messages = [
+            (100, note_on(0x3c, 0x40)),
+            (100, note_on(0x4a, 0x41)),
+            (228, MidiMessage::TimingClock),
+            (228, note_on(0x4c, 0x42)),
+            (16612, MidiMessage::SongSelect(3.into())),
+            (16612, note_on(0x3c, 0x00)),
+        ];
+        let len = write_command_section(&mut buf, 100, &messages, false).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x80, 0x15, 0x90, 0x3c, 0x40, 0x00, 0x4a, 0x41, 0x81, 0x00, 0xf8, 0x00, 0x4c, 0x42,
+                0x81, 0x80, 0x00, 0xf3, 0x03, 0x00, 0x90, 0x3c, 0x00
+            ]
+        );
+
+        // A delta-time for the first command sets Z, a short list a short header
+        let len = write_command_section(&mut buf, 90, &messages[..1], true).unwrap();
+        assert_eq!(&buf[..len], &[0x64, 0x0a, 0x90, 0x3c, 0x40]);
+
+        let len = write_command_section(&mut buf, 90, &[], false).unwrap();
+        assert_eq!(&buf[..len], &[0x00]);
+    }
+
+    #[test]
+    fn should_reject_invalid_timestamps() {
+        let mut buf = [0; 32];
+        let messages = [(100, MidiMessage::Start), (99, MidiMessage::Stop)];
+        assert_eq!(
+            write_command_section(&mut buf, 100, &messages, false),
+            Err(RtpMidiError::InvalidTimestamp)
+        );
+        assert_eq!(
+            write_command_section(&mut buf, 101, &messages[..1], false),
+            Err(RtpMidiError::InvalidTimestamp)
+        );
+        assert_eq!(
+            write_command_section(&mut buf[..1], 100, &messages[..1], false),
+            Err(RtpMidiError::BufferTooShort)
+        );
+        assert_eq!(
+            write_command_section(&mut buf[..2], 100, &messages[..1], false),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn should_reject_long_lists() {
+        let mut buf = [0; 8192];
+        let messages: Vec<(u32, MidiMessage)> =
+            (0..2049).map(|_| (0, MidiMessage::TimingClock)).collect();
+        assert_eq!(
+            write_command_section(&mut buf, 0, &messages, false),
+            Err(RtpMidiError::ListTooLong)
+        );
+        assert_eq!(
+            write_command_section(&mut buf, 0, &messages[..2048], false),
+            Ok(4097)
+        );
+    }
+
+    #[test]
+    fn should_round_trip_packets() {
+        let messages: Vec<(u32, MidiMessage)> = TEST_1BYTE
+            .iter()
+            .chain(TEST_2BYTE.iter())
+            .chain(TEST_3BYTE.iter())
+            .chain(TEST_3BYTE.iter())
+            .enumerate()
+            .map(|(i, message)| (0xffff_ff00u32.wrapping_add(i as u32 * 23), *message))
+            .collect();
+
+        let mut buf = [0; 128];
+        let header = RtpHeader {
+            marker: true,
+            payload_type: PAYLOAD_TYPE,
+            sequence: 7,
+            timestamp: 0xffff_ff00,
+            ssrc: 0x1234_5678,
+        };
+        let len = header.write(&mut buf).unwrap();
+        let len = len
+            + write_command_section(&mut buf[len..], header.timestamp, &messages, false).unwrap();
+
+        let (parsed, payload) = RtpHeader::parse(&buf[..len]).unwrap();
+        assert_eq!(parsed, header);
+        let (section, rest) = CommandSection::parse(payload).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            section
+                .messages(parsed.timestamp)
+                .collect::<MessagesResult>(),
+            Ok(messages)
+        );
+    }
+
+    #[test]
+    fn should_skip_csrc_extension_and_padding() {
+        let packet = [
+            0xb1, 0x61, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, // header
+            0x00, 0x00, 0x00, 0x04, // CSRC
+            0xbe, 0xde, 0x00, 0x01, 0x10, 0x20, 0x30, 0x40, // extension
+            0x01, 0xfa, // payload
+            0x00, 0x00, 0x03, // padding
+        ];
+        let (_, payload) = RtpHeader::parse(&packet).unwrap();
+        assert_eq!(payload, &[0x01, 0xfa]);
+        assert_eq!(
+            RtpHeader::parse(&packet[1..]),
+            Err(RtpMidiError::InvalidHeader)
+        );
+        assert_eq!(
+            RtpHeader::parse(&packet[..11]),
+            Err(RtpMidiError::BufferTooShort)
+        );
+        assert_eq!(
+            RtpHeader::parse(&[0x8f, 0x61, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]),
+            Err(RtpMidiError::BufferTooShort)
+        );
+    }
+}
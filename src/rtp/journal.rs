@@ -0,0 +1,1129 @@
+//! RTP-MIDI recovery journal
+//!
+//! The recovery journal lets a receiver repair its state after packet loss. The sender keeps the
+//! channel and system state changed since the checkpoint, the latest packet the receiver is known
+//! to have, and appends it to every packet. When a receiver sees a gap in the sequence numbers it
+//! compares the journal of the next packet with its own state and plays the messages that bring it
+//! back in line, such as the NoteOff of a lost packet.
+//!
+//! The sender writes chapters P (program change), C (control change), W (pitch wheel) and N
+//! (notes) for each channel and chapter D (reset, tune request and song select) for the system.
+//! Other chapters are skipped when received.
+//!
+//! ```
+//! use midi_convert::rtp::{PAYLOAD_TYPE, RtpHeader, journal::{JournalReceiver, JournalSender}};
+//! use midi_types::MidiMessage;
+//!
+//! let mut sender = JournalSender::new();
+//! let mut receiver = JournalReceiver::new();
+//! let mut packet = [0; 64];
+//! let mut received = [(0, MidiMessage::Reset); 8];
+//! let mut header = RtpHeader { marker: false, payload_type: PAYLOAD_TYPE, sequence: 1, timestamp: 0, ssrc: 1 };
+//!
+//! let note_on = MidiMessage::NoteOn(0.into(), 60.into(), 100.into());
+//! let len = sender.write_packet(&header, &[(0, note_on)], &mut packet).unwrap();
+//! assert_eq!(receiver.receive(&packet[..len], &mut received), Ok(1));
+//!
+//! // The packet holding the NoteOff is lost
+//! header.sequence = 2;
+//! header.timestamp = 100;
+//! let note_off = MidiMessage::NoteOff(0.into(), 60.into(), 0.into());
+//! sender.write_packet(&header, &[(100, note_off)], &mut packet).unwrap();
+//!
+//! // The journal of the next packet ends the note
+//! header.sequence = 3;
+//! header.timestamp = 200;
+//! let len = sender.write_packet(&header, &[], &mut packet).unwrap();
+//! assert_eq!(receiver.receive(&packet[..len], &mut received), Ok(1));
+//! assert_eq!(received[0], (200, MidiMessage::NoteOff(0.into(), 60.into(), 0x40.into())));
+//! ```
+
+use {
+    super::{CommandSection, RtpHeader, RtpMidiError, write_command_section},
+    core::iter::once,
+    midi_types::{Channel, MidiMessage, Value14},
+};
+
+const JOURNAL_Y: u8 = 0x40;
+const JOURNAL_A: u8 = 0x20;
+
+const SYSTEM_D: u8 = 0x40;
+
+const CHAPTER_D_RESET: u8 = 0x40;
+const CHAPTER_D_TUNE_REQUEST: u8 = 0x20;
+const CHAPTER_D_SONG_SELECT: u8 = 0x10;
+
+const CHAPTER_P: u8 = 0x80;
+const CHAPTER_C: u8 = 0x40;
+const CHAPTER_M: u8 = 0x20;
+const CHAPTER_W: u8 = 0x10;
+const CHAPTER_N: u8 = 0x08;
+
+/// Set in a chapter N note log when the receiver should play the note
+const NOTE_Y: u8 = 0x80;
+
+/// Set in a chapter C controller log when it uses one of the alternate forms
+const CONTROLLER_A: u8 = 0x80;
+
+/// Channel mode controllers that end all notes, all but 121 (reset all controllers)
+fn ends_notes(control: u8) -> bool {
+    matches!(control, 120 | 123..=127)
+}
+
+/// Whether a change made in `sequence` is known to the receiver
+fn confirmed(sequence: u16, checkpoint: u16) -> bool {
+    checkpoint.wrapping_sub(sequence) < 0x8000
+}
+
+/// Split `len` bytes from the start of `data`
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], RtpMidiError> {
+    if data.len() < len {
+        return Err(RtpMidiError::BufferTooShort);
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProgramState {
+    program: u8,
+    bank: Option<(u8, u8)>,
+}
+
+#[derive(Debug, Clone)]
+struct ChannelState {
+    /// The velocity of each active note, 0 for notes that are off
+    notes: [u8; 128],
+    note_logs: [Option<u16>; 128],
+    controllers: [Option<u8>; 128],
+    controller_logs: [Option<u16>; 128],
+    program: Option<ProgramState>,
+    program_log: Option<u16>,
+    pitch_wheel: Option<Value14>,
+    pitch_wheel_log: Option<u16>,
+}
+
+impl ChannelState {
+    const NEW: Self = Self {
+        notes: [0; 128],
+        note_logs: [None; 128],
+        controllers: [None; 128],
+        controller_logs: [None; 128],
+        program: None,
+        program_log: None,
+        pitch_wheel: None,
+        pitch_wheel_log: None,
+    };
+
+    fn note(&mut self, note: u8, velocity: u8, sequence: u16) {
+        self.notes[usize::from(note)] = velocity;
+        self.note_logs[usize::from(note)] = Some(sequence);
+    }
+
+    fn logs(&mut self) -> impl Iterator<Item = &mut Option<u16>> {
+        self.note_logs
+            .iter_mut()
+            .chain(self.controller_logs.iter_mut())
+            .chain(once(&mut self.program_log))
+            .chain(once(&mut self.pitch_wheel_log))
+    }
+
+    fn is_logged(&self) -> bool {
+        self.note_logs.iter().any(Option::is_some)
+            || self.controller_logs.iter().any(Option::is_some)
+            || self.program_log.is_some()
+            || self.pitch_wheel_log.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SystemState {
+    /// The number of resets, modulo 128
+    resets: u8,
+    reset_log: Option<u16>,
+    /// The number of tune requests, modulo 128
+    tune_requests: u8,
+    tune_request_log: Option<u16>,
+    song: Option<u8>,
+    song_log: Option<u16>,
+}
+
+impl SystemState {
+    fn is_logged(&self) -> bool {
+        self.reset_log.is_some() || self.tune_request_log.is_some() || self.song_log.is_some()
+    }
+}
+
+/// The state the journal describes, shared by sender and receiver
+#[derive(Debug, Clone)]
+struct State {
+    channels: [ChannelState; 16],
+    system: SystemState,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            channels: [ChannelState::NEW; 16],
+            system: SystemState::default(),
+        }
+    }
+
+    fn channel(&mut self, channel: Channel) -> &mut ChannelState {
+        &mut self.channels[usize::from(u8::from(channel))]
+    }
+
+    fn record(&mut self, message: &MidiMessage, sequence: u16) {
+        match *message {
+            MidiMessage::NoteOn(channel, note, velocity) => {
+                self.channel(channel)
+                    .note(note.into(), velocity.into(), sequence);
+            }
+            MidiMessage::NoteOff(channel, note, _) => {
+                self.channel(channel).note(note.into(), 0, sequence);
+            }
+            MidiMessage::ControlChange(channel, control, value) => {
+                let state = self.channel(channel);
+                let control = u8::from(control);
+                state.controllers[usize::from(control)] = Some(value.into());
+                state.controller_logs[usize::from(control)] = Some(sequence);
+                if ends_notes(control) {
+                    for note in 0..128 {
+                        if state.notes[usize::from(note)] != 0 {
+                            state.note(note, 0, sequence);
+                        }
+                    }
+                }
+            }
+            MidiMessage::ProgramChange(channel, program) => {
+                let state = self.channel(channel);
+                let bank =
+                    state.controllers[0].map(|msb| (msb, state.controllers[32].unwrap_or(0)));
+                state.program = Some(ProgramState {
+                    program: program.into(),
+                    bank,
+                });
+                state.program_log = Some(sequence);
+            }
+            MidiMessage::PitchBendChange(channel, value) => {
+                let state = self.channel(channel);
+                state.pitch_wheel = Some(value);
+                state.pitch_wheel_log = Some(sequence);
+            }
+            MidiMessage::Reset => {
+                self.system.resets = (self.system.resets + 1) & 0x7f;
+                self.system.reset_log = Some(sequence);
+            }
+            MidiMessage::TuneRequest => {
+                self.system.tune_requests = (self.system.tune_requests + 1) & 0x7f;
+                self.system.tune_request_log = Some(sequence);
+            }
+            MidiMessage::SongSelect(song) => {
+                self.system.song = Some(song.into());
+                self.system.song_log = Some(sequence);
+            }
+            _ => {}
+        }
+    }
+
+    fn confirm(&mut self, checkpoint: u16) {
+        let system = &mut self.system;
+        let system_logs = [
+            &mut system.reset_log,
+            &mut system.tune_request_log,
+            &mut system.song_log,
+        ];
+        let channel_logs = self.channels.iter_mut().flat_map(ChannelState::logs);
+        for log in system_logs.into_iter().chain(channel_logs) {
+            if log.is_some_and(|sequence| confirmed(sequence, checkpoint)) {
+                *log = None;
+            }
+        }
+    }
+}
+
+/// Appends bytes to a buffer, failing when it is full
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), RtpMidiError> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(RtpMidiError::BufferTooShort)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+/// Collects the messages a receiver plays, applying them to its state
+struct Output<'a> {
+    messages: &'a mut [(u32, MidiMessage)],
+    len: usize,
+}
+
+impl Output<'_> {
+    fn push(
+        &mut self,
+        state: &mut State,
+        timestamp: u32,
+        message: MidiMessage,
+    ) -> Result<(), RtpMidiError> {
+        let slot = self
+            .messages
+            .get_mut(self.len)
+            .ok_or(RtpMidiError::BufferTooShort)?;
+        *slot = (timestamp, message);
+        self.len += 1;
+        state.record(&message, 0);
+        Ok(())
+    }
+}
+
+/// Tracks outgoing messages and writes the recovery journal for the next packet
+///
+/// The journal holds every change made after the checkpoint. Move the checkpoint with
+/// [`JournalSender::set_checkpoint`] when the receiver reports the packets it has, for instance
+/// through AppleMIDI receiver feedback, to keep the journal short.
+#[derive(Debug, Clone)]
+pub struct JournalSender {
+    state: State,
+    checkpoint: u16,
+    sequence: Option<u16>,
+}
+
+impl Default for JournalSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JournalSender {
+    /// Create a sender with an empty journal
+    pub fn new() -> Self {
+        Self {
+            state: State::new(),
+            checkpoint: 0,
+            sequence: None,
+        }
+    }
+
+    /// The sequence number of the checkpoint packet
+    ///
+    /// Before the first packet is recorded the checkpoint is 0. Afterwards it starts as the
+    /// sequence number preceding the first packet.
+    pub fn checkpoint(&self) -> u16 {
+        self.checkpoint
+    }
+
+    /// Forget the changes made in packets up to and including `sequence`
+    pub fn set_checkpoint(&mut self, sequence: u16) {
+        self.checkpoint = sequence;
+        self.state.confirm(sequence);
+    }
+
+    /// Returns `true` when no change since the checkpoint needs to be journalled
+    pub fn is_empty(&self) -> bool {
+        !self.state.system.is_logged() && !self.state.channels.iter().any(ChannelState::is_logged)
+    }
+
+    /// Record the messages sent in the packet with the sequence number `sequence`
+    pub fn record(&mut self, sequence: u16, messages: &[(u32, MidiMessage)]) {
+        if self.sequence.is_none() {
+            self.checkpoint = sequence.wrapping_sub(1);
+        }
+        self.sequence = Some(sequence);
+        for (_, message) in messages {
+            self.state.record(message, sequence);
+        }
+    }
+
+    /// Write the journal for the next packet, returning the number of bytes written
+    ///
+    /// Nothing is written, and 0 returned, when the journal is empty.
+    pub fn write_journal(&self, buf: &mut [u8]) -> Result<usize, RtpMidiError> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+
+        let mut writer = Writer { buf, len: 0 };
+        writer.push(&[0; 3])?;
+        let mut flags = 0;
+        if self.state.system.is_logged() {
+            flags |= JOURNAL_Y;
+            write_system_journal(&mut writer, &self.state.system)?;
+        }
+        let mut channels = 0;
+        for (channel, state) in self.state.channels.iter().enumerate() {
+            if state.is_logged() {
+                write_channel_journal(&mut writer, channel as u8, state)?;
+                channels += 1;
+            }
+        }
+        if channels > 0 {
+            flags |= JOURNAL_A | (channels - 1);
+        }
+
+        let [high, low] = self.checkpoint.to_be_bytes();
+        writer.buf[..3].copy_from_slice(&[flags, high, low]);
+        Ok(writer.len)
+    }
+
+    /// Write a packet holding `messages` and the journal, then record the messages
+    ///
+    /// Returns the length of the packet.
+    pub fn write_packet(
+        &mut self,
+        header: &RtpHeader,
+        messages: &[(u32, MidiMessage)],
+        buf: &mut [u8],
+    ) -> Result<usize, RtpMidiError> {
+        let mut len = header.write(buf)?;
+        len += write_command_section(
+            &mut buf[len..],
+            header.timestamp,
+            messages,
+            !self.is_empty(),
+        )?;
+        len += self.write_journal(&mut buf[len..])?;
+        self.record(header.sequence, messages);
+        Ok(len)
+    }
+}
+
+fn write_system_journal(writer: &mut Writer, state: &SystemState) -> Result<(), RtpMidiError> {
+    let start = writer.len;
+    writer.push(&[0; 3])?;
+    let mut chapter = 0;
+    if state.reset_log.is_some() {
+        chapter |= CHAPTER_D_RESET;
+        writer.push(&[state.resets])?;
+    }
+    if state.tune_request_log.is_some() {
+        chapter |= CHAPTER_D_TUNE_REQUEST;
+        writer.push(&[state.tune_requests])?;
+    }
+    if state.song_log.is_some() {
+        chapter |= CHAPTER_D_SONG_SELECT;
+        writer.push(&[state.song.unwrap_or(0)])?;
+    }
+
+    let len = writer.len - start;
+    writer.buf[start..start + 3].copy_from_slice(&[
+        SYSTEM_D | (len >> 8) as u8,
+        len as u8,
+        chapter,
+    ]);
+    Ok(())
+}
+
+fn write_channel_journal(
+    writer: &mut Writer,
+    channel: u8,
+    state: &ChannelState,
+) -> Result<(), RtpMidiError> {
+    let start = writer.len;
+    writer.push(&[0; 3])?;
+    let mut chapters = 0;
+
+    if let (Some(_), Some(program)) = (state.program_log, state.program) {
+        chapters |= CHAPTER_P;
+        let (msb, lsb) = program.bank.map_or((0, 0), |(msb, lsb)| (0x80 | msb, lsb));
+        writer.push(&[program.program, msb, lsb])?;
+    }
+
+    let controllers = state.controller_logs.iter().flatten().count();
+    if controllers > 0 {
+        chapters |= CHAPTER_C;
+        writer.push(&[(controllers - 1) as u8])?;
+        for (control, (log, value)) in state
+            .controller_logs
+            .iter()
+            .zip(state.controllers)
+            .enumerate()
+        {
+            if let (Some(_), Some(value)) = (log, value) {
+                writer.push(&[control as u8, value])?;
+            }
+        }
+    }
+
+    if let (Some(_), Some(value)) = (state.pitch_wheel_log, state.pitch_wheel) {
+        chapters |= CHAPTER_W;
+        let (msb, lsb) = value.into();
+        writer.push(&[lsb, msb])?;
+    }
+
+    let logged = |note: &usize| state.note_logs[*note].is_some();
+    let ons = (0..128)
+        .filter(logged)
+        .filter(|note| state.notes[*note] != 0);
+    let mut offs = (0..128)
+        .filter(logged)
+        .filter(|note| state.notes[*note] == 0);
+    let on_count = ons.clone().count();
+    let off_range = offs
+        .next()
+        .map(|low| (low, offs.next_back().unwrap_or(low)));
+    if on_count > 0 || off_range.is_some() {
+        chapters |= CHAPTER_N;
+        let (low, high) = match off_range {
+            Some((low, high)) => (low / 8, high / 8),
+            // LOW = 15 and HIGH = 0 would mean 128 logs when LEN is 127
+            None => (15, usize::from(on_count == 127)),
+        };
+        writer.push(&[(on_count.min(127)) as u8, (low << 4 | high) as u8])?;
+        for note in ons {
+            writer.push(&[note as u8, NOTE_Y | state.notes[note]])?;
+        }
+        for octet in low..=high {
+            let bits = (0..8)
+                .filter(|bit| {
+                    let note = octet * 8 + bit;
+                    logged(&note) && state.notes[note] == 0
+                })
+                .fold(0, |bits, bit| bits | 0x80 >> bit);
+            writer.push(&[bits])?;
+        }
+    }
+
+    let len = writer.len - start;
+    writer.buf[start..start + 3].copy_from_slice(&[
+        channel << 3 | (len >> 8) as u8,
+        len as u8,
+        chapters,
+    ]);
+    Ok(())
+}
+
+/// Receives packets, repairing the state lost with missing packets from the recovery journal
+#[derive(Debug, Clone)]
+pub struct JournalReceiver {
+    state: State,
+    sequence: Option<u16>,
+}
+
+impl Default for JournalReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JournalReceiver {
+    /// Create a receiver that has not seen a packet yet
+    pub fn new() -> Self {
+        Self {
+            state: State::new(),
+            sequence: None,
+        }
+    }
+
+    /// The sequence number of the latest packet received
+    pub fn sequence(&self) -> Option<u16> {
+        self.sequence
+    }
+
+    /// Receive a packet, writing the messages to play into `out`
+    ///
+    /// Returns the number of messages written. When packets were lost the corrections taken from
+    /// the journal come first, timestamped with the packet timestamp, followed by the messages of
+    /// the command section. Late and duplicate packets are ignored.
+    pub fn receive(
+        &mut self,
+        packet: &[u8],
+        out: &mut [(u32, MidiMessage)],
+    ) -> Result<usize, RtpMidiError> {
+        let (header, payload) = RtpHeader::parse(packet)?;
+        let (section, journal) = CommandSection::parse(payload)?;
+        let lost = match self.sequence {
+            Some(sequence) => match header.sequence.wrapping_sub(sequence) {
+                0 | 0x8000.. => return Ok(0),
+                step => step > 1,
+            },
+            None => false,
+        };
+        self.sequence = Some(header.sequence);
+
+        let mut output = Output {
+            messages: out,
+            len: 0,
+        };
+        if section.journal() {
+            self.replay(journal, lost, header.timestamp, &mut output)?;
+        }
+        for message in section.messages(header.timestamp) {
+            let (timestamp, message) = message?;
+            output.push(&mut self.state, timestamp, message)?;
+        }
+        Ok(output.len)
+    }
+
+    fn replay(
+        &mut self,
+        mut journal: &[u8],
+        lost: bool,
+        timestamp: u32,
+        output: &mut Output,
+    ) -> Result<(), RtpMidiError> {
+        let flags = take(&mut journal, 3)?[0];
+
+        if flags & JOURNAL_Y != 0 {
+            let header = journal.get(..2).ok_or(RtpMidiError::BufferTooShort)?;
+            let len = usize::from(header[0] & 0x03) << 8 | usize::from(header[1]);
+            let system = journal.get(2..len).ok_or(RtpMidiError::BufferTooShort)?;
+            if header[0] & SYSTEM_D != 0 {
+                self.replay_system(system, lost, timestamp, output)?;
+            }
+            journal = &journal[len..];
+        }
+
+        if flags & JOURNAL_A != 0 && lost {
+            for _ in 0..=flags & 0x0f {
+                let header = journal.get(..3).ok_or(RtpMidiError::BufferTooShort)?;
+                let len = usize::from(header[0] & 0x03) << 8 | usize::from(header[1]);
+                let chapters = journal.get(3..len).ok_or(RtpMidiError::BufferTooShort)?;
+                self.replay_channel(
+                    header[0] >> 3 & 0x0f,
+                    header[2],
+                    chapters,
+                    timestamp,
+                    output,
+                )?;
+                journal = &journal[len..];
+            }
+        }
+        Ok(())
+    }
+
+    fn replay_system(
+        &mut self,
+        mut data: &[u8],
+        lost: bool,
+        timestamp: u32,
+        output: &mut Output,
+    ) -> Result<(), RtpMidiError> {
+        let chapter = take(&mut data, 1)?[0];
+
+        if chapter & CHAPTER_D_RESET != 0 {
+            let resets = take(&mut data, 1)?[0] & 0x7f;
+            if lost && resets != self.state.system.resets {
+                output.push(&mut self.state, timestamp, MidiMessage::Reset)?;
+            }
+            self.state.system.resets = resets;
+        }
+        if chapter & CHAPTER_D_TUNE_REQUEST != 0 {
+            let tune_requests = take(&mut data, 1)?[0] & 0x7f;
+            if lost && tune_requests != self.state.system.tune_requests {
+                output.push(&mut self.state, timestamp, MidiMessage::TuneRequest)?;
+            }
+            self.state.system.tune_requests = tune_requests;
+        }
+        if chapter & CHAPTER_D_SONG_SELECT != 0 {
+            let song = take(&mut data, 1)?[0] & 0x7f;
+            if lost && self.state.system.song != Some(song) {
+                output.push(
+                    &mut self.state,
+                    timestamp,
+                    MidiMessage::SongSelect(song.into()),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replay_channel(
+        &mut self,
+        channel: u8,
+        chapters: u8,
+        mut data: &[u8],
+        timestamp: u32,
+        output: &mut Output,
+    ) -> Result<(), RtpMidiError> {
+        let index = usize::from(channel);
+        let channel = Channel::new(channel);
+
+        if chapters & CHAPTER_P != 0 {
+            let bytes = take(&mut data, 3)?;
+            let program = ProgramState {
+                program: bytes[0] & 0x7f,
+                bank: (bytes[1] & 0x80 != 0).then_some((bytes[1] & 0x7f, bytes[2] & 0x7f)),
+            };
+            if self.state.channels[index].program != Some(program) {
+                if let Some((msb, lsb)) = program.bank {
+                    let msb = MidiMessage::ControlChange(channel, 0.into(), msb.into());
+                    let lsb = MidiMessage::ControlChange(channel, 32.into(), lsb.into());
+                    output.push(&mut self.state, timestamp, msb)?;
+                    output.push(&mut self.state, timestamp, lsb)?;
+                }
+                let message = MidiMessage::ProgramChange(channel, program.program.into());
+                output.push(&mut self.state, timestamp, message)?;
+                // The bank is taken from the journal, the controllers may still be repaired below
+                self.state.channels[index].program = Some(program);
+            }
+        }
+
+        if chapters & CHAPTER_C != 0 {
+            let len = usize::from(take(&mut data, 1)?[0] & 0x7f) + 1;
+            for log in take(&mut data, 2 * len)?.chunks(2) {
+                if log[1] & CONTROLLER_A != 0 {
+                    continue;
+                }
+                let (control, value) = (log[0] & 0x7f, log[1]);
+                if self.state.channels[index].controllers[usize::from(control)] != Some(value) {
+                    let message = MidiMessage::ControlChange(channel, control.into(), value.into());
+                    output.push(&mut self.state, timestamp, message)?;
+                }
+            }
+        }
+
+        // Parameter changes are not replayed, chapter M is skipped by its length
+        if chapters & CHAPTER_M != 0 {
+            let header = data.get(..2).ok_or(RtpMidiError::BufferTooShort)?;
+            let len = usize::from(header[0] & 0x03) << 8 | usize::from(header[1]);
+            data.get(2..len).ok_or(RtpMidiError::BufferTooShort)?;
+            data = &data[len..];
+        }
+
+        if chapters & CHAPTER_W != 0 {
+            let bytes = take(&mut data, 2)?;
+            let value = Value14::new(bytes[1] & 0x7f, bytes[0] & 0x7f);
+            if self.state.channels[index].pitch_wheel != Some(value) {
+                let message = MidiMessage::PitchBendChange(channel, value);
+                output.push(&mut self.state, timestamp, message)?;
+            }
+        }
+
+        if chapters & CHAPTER_N != 0 {
+            let bytes = take(&mut data, 2)?;
+            let (low, high) = (usize::from(bytes[1] >> 4), usize::from(bytes[1] & 0x0f));
+            let len = match usize::from(bytes[0] & 0x7f) {
+                127 if low == 15 && high == 0 => 128,
+                len => len,
+            };
+            let logs = take(&mut data, 2 * len)?;
+            let offbits = take(&mut data, (high + 1).saturating_sub(low))?;
+
+            for (octet, bits) in (low..).zip(offbits) {
+                for bit in 0..8 {
+                    let note = octet * 8 + bit;
+                    if bits & 0x80 >> bit != 0 && self.state.channels[index].notes[note] != 0 {
+                        let message =
+                            MidiMessage::NoteOff(channel, (note as u8).into(), 0x40.into());
+                        output.push(&mut self.state, timestamp, message)?;
+                    }
+                }
+            }
+            for log in logs.chunks(2) {
+                let (note, velocity) = (log[0] & 0x7f, log[1] & 0x7f);
+                if log[1] & NOTE_Y != 0
+                    && velocity != 0
+                    && self.state.channels[index].notes[usize::from(note)] == 0
+                {
+                    let message = MidiMessage::NoteOn(channel, note.into(), velocity.into());
+                    output.push(&mut self.state, timestamp, message)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, crate::rtp::PAYLOAD_TYPE, std::vec::Vec};
+
+    struct Session {
+        sender: JournalSender,
+        receiver: JournalReceiver,
+        sequence: u16,
+        timestamp: u32,
+        received: Vec<(u32, MidiMessage)>,
+    }
+
+    impl Session {
+        fn new(sequence: u16) -> Self {
+            Self {
+                sender: JournalSender::new(),
+                receiver: JournalReceiver::new(),
+                sequence,
+                timestamp: 0,
+                received: Vec::new(),
+            }
+        }
+
+        fn send(&mut self, messages: &[MidiMessage], lost: bool) -> Vec<(u32, MidiMessage)> {
+            let header = RtpHeader {
+                marker: false,
+                payload_type: PAYLOAD_TYPE,
+                sequence: self.sequence,
+                timestamp: self.timestamp,
+                ssrc: 1,
+            };
+            let messages: Vec<_> = messages.iter().map(|m| (self.timestamp, *m)).collect();
+            let mut packet = [0; 1500];
+            let len = self
+                .sender
+                .write_packet(&header, &messages, &mut packet)
+                .unwrap();
+            self.sequence = self.sequence.wrapping_add(1);
+            self.timestamp += 100;
+            if lost {
+                return Vec::new();
+            }
+
+            let mut out = [(0, MidiMessage::Reset); 512];
+            let n = self.receiver.receive(&packet[..len], &mut out).unwrap();
+            self.received.extend_from_slice(&out[..n]);
+            out[..n].to_vec()
+        }
+    }
+
+    fn journal(sender: &JournalSender) -> Vec<u8> {
+        let mut buf = [0; 1024];
+        let len = sender.write_journal(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn timestamped(timestamp: u32, messages: &[MidiMessage]) -> Vec<(u32, MidiMessage)> {
+        messages.iter().map(|m| (timestamp, *m)).collect()
+    }
+
+    #[test]
+    fn should_write_channel_journal() {
+        let mut sender = JournalSender::new();
+        let channel = Channel::new(2);
+        sender.record(
+            1,
+            &timestamped(
+                0,
+                &[
+                    MidiMessage::ControlChange(channel, 0.into(), 1.into()),
+                    MidiMessage::ControlChange(channel, 32.into(), 2.into()),
+                    MidiMessage::ProgramChange(channel, 5.into()),
+                    MidiMessage::ControlChange(channel, 7.into(), 100.into()),
+                    MidiMessage::PitchBendChange(channel, Value14::new(0x40, 0x01)),
+                    MidiMessage::NoteOn(channel, 60.into(), 100.into()),
+                    MidiMessage::NoteOn(channel, 62.into(), 90.into()),
+                    MidiMessage::NoteOn(channel, 62.into(), 0.into()),
+                ],
+            ),
+        );
+        assert_eq!(sender.checkpoint(), 0);
+        assert_eq!(
+            journal(&sender),
+            [
+                0x20, 0x00, 0x00, // journal header, one channel
+                0x10, 0x14, 0xd8, // channel 2, chapters P, C, W and N
+                0x05, 0x81, 0x02, // program 5, bank 1:2
+                0x02, 0x00, 0x01, 0x07, 0x64, 0x20, 0x02, // controllers 0, 7 and 32
+                0x01, 0x40, // pitch wheel
+                0x01, 0x77, 0x3c, 0xe4, 0x02, // note 60 on, note 62 off
+            ]
+        );
+    }
+
+    #[test]
+    fn should_write_system_journal() {
+        let mut sender = JournalSender::new();
+        sender.record(
+            5,
+            &timestamped(
+                0,
+                &[
+                    MidiMessage::Reset,
+                    MidiMessage::TuneRequest,
+                    MidiMessage::SongSelect(3.into()),
+                ],
+            ),
+        );
+        assert_eq!(
+            journal(&sender),
+            [0x40, 0x00, 0x04, 0x40, 0x06, 0x70, 0x01, 0x01, 0x03]
+        );
+    }
+
+    #[test]
+    fn should_forget_changes_up_to_the_checkpoint() {
+        let mut sender = JournalSender::new();
+        let channel = Channel::new(0);
+        sender.record(
+            0xffff,
+            &timestamped(
+                0,
+                &[MidiMessage::ControlChange(channel, 7.into(), 100.into())],
+            ),
+        );
+        sender.record(
+            0,
+            &timestamped(
+                0,
+                &[MidiMessage::ControlChange(channel, 10.into(), 64.into())],
+            ),
+        );
+        assert_eq!(sender.checkpoint(), 0xfffe);
+        assert_eq!(journal(&sender)[6..], [0x01, 0x07, 0x64, 0x0a, 0x40]);
+
+        sender.set_checkpoint(0xffff);
+        assert_eq!(journal(&sender)[6..], [0x00, 0x0a, 0x40]);
+
+        sender.set_checkpoint(0);
+        assert!(sender.is_empty());
+        assert_eq!(sender.write_journal(&mut []), Ok(0));
+    }
+
+    #[test]
+    fn should_write_all_active_notes() {
+        let mut sender = JournalSender::new();
+        let notes: Vec<_> = (0..128)
+            .map(|note| MidiMessage::NoteOn(Channel::new(0), note.into(), 1.into()))
+            .collect();
+        sender.record(0, &timestamped(0, &notes[..127]));
+        assert_eq!(journal(&sender)[6..8], [0x7f, 0xf1]);
+
+        sender.record(1, &timestamped(0, &notes[127..]));
+        let journal = journal(&sender);
+        assert_eq!(journal[6..8], [0x7f, 0xf0]);
+        assert_eq!(journal.len(), 8 + 256);
+    }
+
+    #[test]
+    fn should_end_notes_of_lost_packets() {
+        let mut session = Session::new(1);
+        let channel = Channel::new(0);
+        session.send(
+            &[MidiMessage::NoteOn(channel, 60.into(), 100.into())],
+            false,
+        );
+        session.send(&[MidiMessage::NoteOff(channel, 60.into(), 0.into())], true);
+        assert_eq!(
+            session.send(
+                &[MidiMessage::ControlChange(channel, 7.into(), 90.into())],
+                false
+            ),
+            [
+                (200, MidiMessage::NoteOff(channel, 60.into(), 0x40.into())),
+                (
+                    200,
+                    MidiMessage::ControlChange(channel, 7.into(), 90.into())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_repair_channel_state() {
+        let mut session = Session::new(1);
+        let channel = Channel::new(9);
+        session.send(
+            &[MidiMessage::ControlChange(channel, 7.into(), 90.into())],
+            false,
+        );
+        session.send(
+            &[
+                MidiMessage::ControlChange(channel, 0.into(), 3.into()),
+                MidiMessage::ProgramChange(channel, 12.into()),
+                MidiMessage::ControlChange(channel, 7.into(), 90.into()),
+                MidiMessage::ControlChange(channel, 10.into(), 20.into()),
+                MidiMessage::PitchBendChange(channel, Value14::new(0x10, 0x20)),
+                MidiMessage::NoteOn(channel, 36.into(), 80.into()),
+            ],
+            true,
+        );
+        assert_eq!(
+            session.send(&[], false),
+            [
+                (200, MidiMessage::ControlChange(channel, 0.into(), 3.into())),
+                (
+                    200,
+                    MidiMessage::ControlChange(channel, 32.into(), 0.into())
+                ),
+                (200, MidiMessage::ProgramChange(channel, 12.into())),
+                (
+                    200,
+                    MidiMessage::ControlChange(channel, 10.into(), 20.into())
+                ),
+                (
+                    200,
+                    MidiMessage::PitchBendChange(channel, Value14::new(0x10, 0x20))
+                ),
+                (200, MidiMessage::NoteOn(channel, 36.into(), 80.into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_skip_parameter_chapter() {
+        let mut receiver = JournalReceiver::new();
+        let mut out = [(0, MidiMessage::Reset); 4];
+        let first = [0x80, 0x61, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0x00];
+        assert_eq!(receiver.receive(&first, &mut out), Ok(0));
+
+        let packet = [
+            0x80, 0x61, 0, 3, 0, 0, 0, 100, 0, 0, 0, 1,    // header
+            0x40, // command section holding only the journal
+            0x20, 0, 1, // journal with one channel
+            0x00, 9, 0x30, // channel 0 with chapters M and W
+            0x00, 4, 0x01, 0x02, // chapter M
+            0x20, 0x10, // chapter W
+        ];
+        assert_eq!(receiver.receive(&packet, &mut out), Ok(1));
+        assert_eq!(
+            out[0],
+            (
+                100,
+                MidiMessage::PitchBendChange(Channel::new(0), Value14::new(0x10, 0x20))
+            )
+        );
+    }
+
+    #[test]
+    fn should_repair_system_state() {
+        let mut session = Session::new(1);
+        session.send(&[MidiMessage::SongSelect(1.into())], false);
+        session.send(
+            &[MidiMessage::Reset, MidiMessage::SongSelect(2.into())],
+            true,
+        );
+        assert_eq!(
+            session.send(&[MidiMessage::TuneRequest], false),
+            [
+                (200, MidiMessage::Reset),
+                (200, MidiMessage::SongSelect(2.into())),
+                (200, MidiMessage::TuneRequest),
+            ]
+        );
+        assert_eq!(session.send(&[], false), []);
+    }
+
+    #[test]
+    fn should_not_repair_without_loss() {
+        let mut session = Session::new(1);
+        let channel = Channel::new(0);
+        session.send(
+            &[MidiMessage::NoteOn(channel, 60.into(), 100.into())],
+            false,
+        );
+        assert_eq!(session.send(&[], false), []);
+        assert_eq!(session.receiver.sequence(), Some(2));
+    }
+
+    #[test]
+    fn should_ignore_late_packets() {
+        let mut sender = JournalSender::new();
+        let mut receiver = JournalReceiver::new();
+        let mut packets = [[0; 64]; 2];
+        let note_on = MidiMessage::NoteOn(Channel::new(0), 60.into(), 100.into());
+        let mut lens = [0; 2];
+        for (sequence, packet) in packets.iter_mut().enumerate() {
+            let header = RtpHeader {
+                marker: false,
+                payload_type: PAYLOAD_TYPE,
+                sequence: sequence as u16,
+                timestamp: 0,
+                ssrc: 1,
+            };
+            lens[sequence] = sender
+                .write_packet(&header, &[(0, note_on)], packet)
+                .unwrap();
+        }
+
+        let mut out = [(0, MidiMessage::Reset); 4];
+        assert_eq!(receiver.receive(&packets[1][..lens[1]], &mut out), Ok(1));
+        assert_eq!(receiver.receive(&packets[0][..lens[0]], &mut out), Ok(0));
+        assert_eq!(receiver.receive(&packets[1][..lens[1]], &mut out), Ok(0));
+    }
+
+    #[test]
+    fn should_fail_when_output_is_full() {
+        let mut session = Session::new(1);
+        let channel = Channel::new(0);
+        session.send(
+            &[MidiMessage::NoteOn(channel, 60.into(), 100.into())],
+            false,
+        );
+        session.send(&[MidiMessage::NoteOff(channel, 60.into(), 0.into())], true);
+        let header = RtpHeader {
+            marker: false,
+            payload_type: PAYLOAD_TYPE,
+            sequence: session.sequence,
+            timestamp: 0,
+            ssrc: 1,
+        };
+        let mut packet = [0; 64];
+        let len = session
+            .sender
+            .write_packet(&header, &[], &mut packet)
+            .unwrap();
+        assert_eq!(
+            session.receiver.receive(&packet[..len], &mut []),
+            Err(RtpMidiError::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn should_converge_when_packets_are_dropped() {
+        let mut session = Session::new(0xfff0);
+        let mut seed = 0x2545_f491_u32;
+        let mut random = move |n: u32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            (seed % n) as u8
+        };
+
+        for packet in 0..300 {
+            let messages: Vec<_> = (0..random(4))
+                .map(|_| {
+                    let channel = Channel::new(random(3));
+                    match random(8) {
+                        0 | 1 => MidiMessage::NoteOn(
+                            channel,
+                            (48 + random(24)).into(),
+                            (1 + random(126)).into(),
+                        ),
+                        2 | 3 => MidiMessage::NoteOff(channel, (48 + random(24)).into(), 0.into()),
+                        4 => MidiMessage::ControlChange(
+                            channel,
+                            [0, 1, 7, 32, 64, 123][usize::from(random(6))].into(),
+                            random(127).into(),
+                        ),
+                        5 => MidiMessage::ProgramChange(channel, random(127).into()),
+                        6 => MidiMessage::PitchBendChange(
+                            channel,
+                            Value14::new(random(128), random(128)),
+                        ),
+                        _ => [
+                            MidiMessage::Reset,
+                            MidiMessage::TuneRequest,
+                            MidiMessage::SongSelect(random(127).into()),
+                        ][usize::from(random(3))],
+                    }
+                })
+                .collect();
+            session.send(&messages, packet % 3 == 1);
+            if packet % 50 == 49 {
+                let sequence = session.receiver.sequence().unwrap();
+                session.sender.set_checkpoint(sequence);
+            }
+        }
+        session.send(&[], false);
+
+        let (sent, received) = (&session.sender.state, &session.receiver.state);
+        for (sent, received) in sent.channels.iter().zip(received.channels.iter()) {
+            let active = |state: &ChannelState| state.notes.map(|velocity| velocity != 0);
+            assert_eq!(active(sent), active(received));
+            assert_eq!(sent.controllers, received.controllers);
+            assert_eq!(sent.program, received.program);
+            assert_eq!(sent.pitch_wheel, received.pitch_wheel);
+        }
+        assert_eq!(sent.system.resets, received.system.resets);
+        assert_eq!(sent.system.tune_requests, received.system.tune_requests);
+        assert_eq!(sent.system.song, received.system.song);
+        assert!(!session.received.is_empty());
+    }
+}
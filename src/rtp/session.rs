@@ -0,0 +1,1052 @@
+//! AppleMIDI network sessions
+//!
+//! RTP-MIDI peers set up a session with the AppleMIDI control protocol before exchanging MIDI. Each
+//! peer listens on a control port and a data port, the port following it. The initiator invites the
+//! responder on both ports (IN), the responder accepts (OK) or rejects (NO), and either side ends
+//! the session (BY). Once connected the initiator regularly synchronizes the clocks with a three way
+//! exchange (CK) on the data port, and the receiver reports the latest packet it has received (RS),
+//! which becomes the checkpoint of the sender's recovery journal.
+//!
+//! [`AppleMidiSession`] is a state machine that does no I/O itself. Received packets are passed to
+//! [`AppleMidiSession::handle`] and the packets to send are taken from
+//! [`AppleMidiSession::poll_transmit`]. MIDI is sent with [`AppleMidiSession::send`]. Times are
+//! durations since an arbitrary start, the session clock counts in units of 100 µs. A std UDP
+//! driver is available with the `std` feature.
+//!
+//! ```
+//! use core::time::Duration;
+//! use midi_convert::rtp::session::{AppleMidiSession, Port, SessionEvent, SessionState};
+//! use midi_types::MidiMessage;
+//!
+//! let mut initiator = AppleMidiSession::new("initiator", 0x1111);
+//! let mut responder = AppleMidiSession::new("responder", 0x2222);
+//! let mut buf = [0; 64];
+//! let mut midi = [(0, MidiMessage::Reset); 8];
+//! let now = Duration::ZERO;
+//!
+//! initiator.connect(1234, now);
+//! while let Some((port, len)) = initiator.poll_transmit(now, &mut buf).unwrap() {
+//!     responder.handle(port, &buf[..len], now, &mut midi).unwrap();
+//!     while let Some((port, len)) = responder.poll_transmit(now, &mut buf).unwrap() {
+//!         initiator.handle(port, &buf[..len], now, &mut midi).unwrap();
+//!     }
+//! }
+//! assert_eq!(initiator.state(), SessionState::Connected);
+//! assert_eq!(responder.state(), SessionState::Connected);
+//!
+//! let note_on = MidiMessage::NoteOn(0.into(), 60.into(), 100.into());
+//! let len = initiator.send(now, &[(0, note_on)], &mut buf).unwrap();
+//! let event = responder.handle(Port::Data, &buf[..len], now, &mut midi).unwrap();
+//! assert_eq!(event, Some(SessionEvent::Midi(1)));
+//! assert_eq!(midi[0], (0, note_on));
+//! ```
+
+use {
+    super::{
+        PAYLOAD_TYPE, RtpHeader, RtpMidiError,
+        journal::{JournalReceiver, JournalSender},
+    },
+    core::time::Duration,
+    midi_types::MidiMessage,
+};
+
+#[cfg(feature = "std")]
+pub mod udp;
+
+/// The control port AppleMIDI sessions usually listen on, the data port is the next port
+pub const DEFAULT_PORT: u16 = 5004;
+
+const SIGNATURE: [u8; 2] = [0xff, 0xff];
+const PROTOCOL_VERSION: u32 = 2;
+
+const INVITATION: [u8; 2] = *b"IN";
+const ACCEPT: [u8; 2] = *b"OK";
+const REJECT: [u8; 2] = *b"NO";
+const END: [u8; 2] = *b"BY";
+const SYNC: [u8; 2] = *b"CK";
+const FEEDBACK: [u8; 2] = *b"RS";
+
+const INVITATION_INTERVAL: Duration = Duration::from_secs(1);
+const INVITATION_ATTEMPTS: u8 = 12;
+
+/// Clocks are synchronized quickly after connecting and less often afterwards
+const FAST_SYNC_INTERVAL: Duration = Duration::from_millis(1500);
+const FAST_SYNCS: u8 = 6;
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Errors reading or writing AppleMIDI packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AppleMidiError {
+    /// The buffer is shorter than the data it should hold
+    BufferTooShort,
+
+    /// The packet is not a valid AppleMIDI command
+    InvalidPacket,
+
+    /// The command is not one of IN, OK, NO, BY, CK or RS
+    UnknownCommand,
+
+    /// MIDI can only be sent in a connected session
+    NotConnected,
+
+    /// An RTP-MIDI packet could not be read or written
+    Rtp(RtpMidiError),
+}
+
+impl From<RtpMidiError> for AppleMidiError {
+    fn from(error: RtpMidiError) -> Self {
+        Self::Rtp(error)
+    }
+}
+
+/// The two ports of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Port {
+    /// The control port, carrying invitations and receiver feedback
+    Control,
+
+    /// The data port, carrying RTP-MIDI and clock synchronization
+    Data,
+}
+
+/// The fields of an invitation and the answers to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Exchange<'a> {
+    /// The protocol version, 2
+    pub version: u32,
+
+    /// The token chosen by the initiator, repeated in the answers
+    pub token: u32,
+
+    /// The synchronization source of the sender
+    pub ssrc: u32,
+
+    /// The name of the sender, absent in BY commands
+    pub name: Option<&'a str>,
+}
+
+/// AppleMIDI control protocol packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AppleMidiPacket<'a> {
+    /// IN, an invitation to join a session
+    Invitation(Exchange<'a>),
+
+    /// OK, an accepted invitation
+    Accept(Exchange<'a>),
+
+    /// NO, a rejected invitation
+    Reject(Exchange<'a>),
+
+    /// BY, the end of a session
+    End(Exchange<'a>),
+
+    /// CK, one of the three clock synchronization packets
+    ///
+    /// `count` is 0 to 2 and `timestamps` holds the session clocks at which the packets of the
+    /// exchange were sent, alternating between initiator and responder.
+    Sync {
+        ssrc: u32,
+        count: u8,
+        timestamps: [u64; 3],
+    },
+
+    /// RS, the sequence number of the latest RTP-MIDI packet received
+    Feedback { ssrc: u32, sequence: u16 },
+}
+
+impl<'a> AppleMidiPacket<'a> {
+    /// Returns true when `packet` starts with the AppleMIDI signature rather than an RTP header
+    pub fn is_apple_midi(packet: &[u8]) -> bool {
+        packet.starts_with(&SIGNATURE)
+    }
+
+    /// Parse an AppleMIDI packet
+    pub fn parse(packet: &'a [u8]) -> Result<Self, AppleMidiError> {
+        if packet.len() < 4 {
+            return Err(AppleMidiError::BufferTooShort);
+        }
+        if !Self::is_apple_midi(packet) {
+            return Err(AppleMidiError::InvalidPacket);
+        }
+        let word = |offset: usize| -> Result<u32, AppleMidiError> {
+            let bytes = packet
+                .get(offset..offset + 4)
+                .ok_or(AppleMidiError::BufferTooShort)?;
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+
+        let command = [packet[2], packet[3]];
+        match command {
+            INVITATION | ACCEPT | REJECT | END => {
+                let name = match packet.get(16..) {
+                    None | Some([]) => None,
+                    Some(name) => {
+                        let end = name
+                            .iter()
+                            .position(|byte| *byte == 0)
+                            .ok_or(AppleMidiError::InvalidPacket)?;
+                        let name = core::str::from_utf8(&name[..end])
+                            .map_err(|_| AppleMidiError::InvalidPacket)?;
+                        Some(name)
+                    }
+                };
+                let exchange = Exchange {
+                    version: word(4)?,
+                    token: word(8)?,
+                    ssrc: word(12)?,
+                    name,
+                };
+                Ok(match command {
+                    INVITATION => Self::Invitation(exchange),
+                    ACCEPT => Self::Accept(exchange),
+                    REJECT => Self::Reject(exchange),
+                    _ => Self::End(exchange),
+                })
+            }
+            SYNC => {
+                if packet.len() < 36 {
+                    return Err(AppleMidiError::BufferTooShort);
+                }
+                let count = packet[8];
+                if count > 2 {
+                    return Err(AppleMidiError::InvalidPacket);
+                }
+                let mut timestamps = [0; 3];
+                for (i, timestamp) in timestamps.iter_mut().enumerate() {
+                    *timestamp = u64::from(word(12 + 8 * i)?) << 32 | u64::from(word(16 + 8 * i)?);
+                }
+                Ok(Self::Sync {
+                    ssrc: word(4)?,
+                    count,
+                    timestamps,
+                })
+            }
+            FEEDBACK => Ok(Self::Feedback {
+                ssrc: word(4)?,
+                sequence: (word(8)? >> 16) as u16,
+            }),
+            _ => Err(AppleMidiError::UnknownCommand),
+        }
+    }
+
+    /// Write the packet to the start of `buf` and return its length
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, AppleMidiError> {
+        let mut writer = Writer { buf, len: 0 };
+        writer.push(&SIGNATURE)?;
+        match self {
+            Self::Invitation(exchange)
+            | Self::Accept(exchange)
+            | Self::Reject(exchange)
+            | Self::End(exchange) => {
+                writer.push(match self {
+                    Self::Invitation(_) => &INVITATION,
+                    Self::Accept(_) => &ACCEPT,
+                    Self::Reject(_) => &REJECT,
+                    _ => &END,
+                })?;
+                writer.push(&exchange.version.to_be_bytes())?;
+                writer.push(&exchange.token.to_be_bytes())?;
+                writer.push(&exchange.ssrc.to_be_bytes())?;
+                if let Some(name) = exchange.name {
+                    writer.push(name.as_bytes())?;
+                    writer.push(&[0])?;
+                }
+            }
+            Self::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                writer.push(&SYNC)?;
+                writer.push(&ssrc.to_be_bytes())?;
+                writer.push(&[*count, 0, 0, 0])?;
+                for timestamp in timestamps {
+                    writer.push(&timestamp.to_be_bytes())?;
+                }
+            }
+            Self::Feedback { ssrc, sequence } => {
+                writer.push(&FEEDBACK)?;
+                writer.push(&ssrc.to_be_bytes())?;
+                writer.push(&sequence.to_be_bytes())?;
+                writer.push(&[0, 0])?;
+            }
+        }
+        Ok(writer.len)
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), AppleMidiError> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(AppleMidiError::BufferTooShort)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+/// The state of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionState {
+    /// Not connected, waiting for an invitation
+    Idle,
+
+    /// Inviting the peer on the given port
+    Inviting(Port),
+
+    /// Accepted an invitation on the control port, waiting for the invitation on the data port
+    Accepting,
+
+    /// Connected, MIDI can be exchanged
+    Connected,
+}
+
+/// What happened when handling a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionEvent {
+    /// The session is connected
+    Connected,
+
+    /// The peer rejected the invitation
+    Rejected,
+
+    /// The peer ended the session
+    Ended,
+
+    /// The given number of MIDI messages were received
+    Midi(usize),
+}
+
+/// A sans-IO AppleMIDI session, see the [module documentation](self)
+///
+/// A session either initiates the connection with [`connect`](Self::connect) or accepts the first
+/// invitation it receives. Invitations from other peers are rejected while connected.
+#[derive(Debug, Clone)]
+pub struct AppleMidiSession<'a> {
+    name: &'a str,
+    ssrc: u32,
+    state: SessionState,
+    initiator: bool,
+    token: u32,
+    peer: Option<u32>,
+    pending: [Option<(Port, AppleMidiPacket<'a>)>; 4],
+    attempts: u8,
+    next_invitation: Duration,
+    syncs: u8,
+    next_sync: Duration,
+    offset: Option<i64>,
+    feedback: Option<u16>,
+    next_feedback: Duration,
+    sequence: u16,
+    sender: JournalSender,
+    receiver: JournalReceiver,
+}
+
+impl<'a> AppleMidiSession<'a> {
+    /// Create a session announcing itself as `name` with the synchronization source `ssrc`
+    pub fn new(name: &'a str, ssrc: u32) -> Self {
+        Self {
+            name,
+            ssrc,
+            state: SessionState::Idle,
+            initiator: false,
+            token: 0,
+            peer: None,
+            pending: [const { None }; 4],
+            attempts: 0,
+            next_invitation: Duration::ZERO,
+            syncs: 0,
+            next_sync: Duration::ZERO,
+            offset: None,
+            feedback: None,
+            next_feedback: Duration::ZERO,
+            sequence: 0,
+            sender: JournalSender::new(),
+            receiver: JournalReceiver::new(),
+        }
+    }
+
+    /// The session clock at time `now`, in units of 100 µs
+    pub fn timestamp(now: Duration) -> u32 {
+        Self::clock(now) as u32
+    }
+
+    fn clock(now: Duration) -> u64 {
+        (now.as_micros() / 100) as u64
+    }
+
+    /// The state of the session
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// The synchronization source of the peer
+    pub fn peer_ssrc(&self) -> Option<u32> {
+        self.peer
+    }
+
+    /// The session clock of the peer minus the local session clock, in units of 100 µs, known
+    /// after the first clock synchronization
+    pub fn clock_offset(&self) -> Option<i64> {
+        self.offset
+    }
+
+    /// Invite a peer using the token `token`
+    ///
+    /// The invitation is repeated every second until the peer answers, after 12 attempts the
+    /// session returns to [`SessionState::Idle`].
+    pub fn connect(&mut self, token: u32, now: Duration) {
+        self.reset();
+        self.initiator = true;
+        self.token = token;
+        self.state = SessionState::Inviting(Port::Control);
+        self.attempts = 0;
+        self.next_invitation = now;
+    }
+
+    /// End the session, the BY command is sent by the next [`poll_transmit`](Self::poll_transmit)
+    pub fn disconnect(&mut self) {
+        if self.state != SessionState::Idle {
+            let end = AppleMidiPacket::End(Exchange {
+                version: PROTOCOL_VERSION,
+                token: self.token,
+                ssrc: self.ssrc,
+                name: None,
+            });
+            self.queue(Port::Control, end);
+        }
+        self.reset();
+    }
+
+    /// Write an RTP-MIDI packet holding `messages` and the recovery journal, returning its length
+    ///
+    /// The packet is sent to the data port of the peer. Message timestamps use the session clock,
+    /// see [`timestamp`](Self::timestamp), and may not lie before `now`.
+    pub fn send(
+        &mut self,
+        now: Duration,
+        messages: &[(u32, MidiMessage)],
+        buf: &mut [u8],
+    ) -> Result<usize, AppleMidiError> {
+        if self.state != SessionState::Connected {
+            return Err(AppleMidiError::NotConnected);
+        }
+        let header = RtpHeader {
+            marker: false,
+            payload_type: PAYLOAD_TYPE,
+            sequence: self.sequence,
+            timestamp: Self::timestamp(now),
+            ssrc: self.ssrc,
+        };
+        let len = self.sender.write_packet(&header, messages, buf)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(len)
+    }
+
+    /// Handle a packet received on `port` at time `now`
+    ///
+    /// The MIDI messages of RTP-MIDI packets from the peer are written to `midi`, including the
+    /// corrections taken from the recovery journal after packet loss, and reported with
+    /// [`SessionEvent::Midi`]. Packets from other sources are ignored.
+    pub fn handle(
+        &mut self,
+        port: Port,
+        packet: &[u8],
+        now: Duration,
+        midi: &mut [(u32, MidiMessage)],
+    ) -> Result<Option<SessionEvent>, AppleMidiError> {
+        if !AppleMidiPacket::is_apple_midi(packet) {
+            if self.state != SessionState::Connected || port != Port::Data {
+                return Ok(None);
+            }
+            let (header, _) = RtpHeader::parse(packet)?;
+            if self.peer != Some(header.ssrc) {
+                return Ok(None);
+            }
+            let len = self.receiver.receive(packet, midi)?;
+            return Ok((len > 0).then_some(SessionEvent::Midi(len)));
+        }
+
+        match AppleMidiPacket::parse(packet)? {
+            AppleMidiPacket::Invitation(invitation) => Ok(self.invited(port, &invitation, now)),
+            AppleMidiPacket::Accept(accept) => Ok(self.accepted(port, &accept, now)),
+            AppleMidiPacket::Reject(reject) => {
+                if matches!(self.state, SessionState::Inviting(_)) && reject.token == self.token {
+                    self.reset();
+                    return Ok(Some(SessionEvent::Rejected));
+                }
+                Ok(None)
+            }
+            AppleMidiPacket::End(end) => {
+                if self.state != SessionState::Idle && self.peer == Some(end.ssrc) {
+                    self.reset();
+                    return Ok(Some(SessionEvent::Ended));
+                }
+                Ok(None)
+            }
+            AppleMidiPacket::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                if self.state == SessionState::Connected && self.peer == Some(ssrc) {
+                    self.synchronize(port, count, timestamps, now);
+                }
+                Ok(None)
+            }
+            AppleMidiPacket::Feedback { ssrc, sequence } => {
+                if self.state == SessionState::Connected && self.peer == Some(ssrc) {
+                    self.sender.set_checkpoint(sequence);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Write the next packet to send at time `now`, returning the port it is sent to and its
+    /// length, or `None` when there is nothing to send
+    ///
+    /// Call this until it returns `None` after handling a packet and whenever
+    /// [`next_due`](Self::next_due) has passed.
+    pub fn poll_transmit(
+        &mut self,
+        now: Duration,
+        buf: &mut [u8],
+    ) -> Result<Option<(Port, usize)>, AppleMidiError> {
+        if let Some((port, packet)) = self.pending[0].take() {
+            self.pending.rotate_left(1);
+            return Ok(Some((port, packet.write(buf)?)));
+        }
+
+        match self.state {
+            SessionState::Inviting(port) if now >= self.next_invitation => {
+                if self.attempts == INVITATION_ATTEMPTS {
+                    self.reset();
+                    return Ok(None);
+                }
+                self.attempts += 1;
+                self.next_invitation = now + INVITATION_INTERVAL;
+                let invitation = AppleMidiPacket::Invitation(self.exchange(self.token));
+                Ok(Some((port, invitation.write(buf)?)))
+            }
+            SessionState::Connected if self.initiator && now >= self.next_sync => {
+                self.syncs = self.syncs.saturating_add(1);
+                let interval = if self.syncs < FAST_SYNCS {
+                    FAST_SYNC_INTERVAL
+                } else {
+                    SYNC_INTERVAL
+                };
+                self.next_sync = now + interval;
+                let sync = AppleMidiPacket::Sync {
+                    ssrc: self.ssrc,
+                    count: 0,
+                    timestamps: [Self::clock(now), 0, 0],
+                };
+                Ok(Some((Port::Data, sync.write(buf)?)))
+            }
+            SessionState::Connected
+                if now >= self.next_feedback && self.receiver.sequence() != self.feedback =>
+            {
+                self.feedback = self.receiver.sequence();
+                self.next_feedback = now + FEEDBACK_INTERVAL;
+                let feedback = AppleMidiPacket::Feedback {
+                    ssrc: self.ssrc,
+                    sequence: self.feedback.unwrap_or(0),
+                };
+                Ok(Some((Port::Control, feedback.write(buf)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The time at which [`poll_transmit`](Self::poll_transmit) has something to send next, or
+    /// `None` when it only sends in response to received packets
+    pub fn next_due(&self) -> Option<Duration> {
+        if self.pending[0].is_some() {
+            return Some(Duration::ZERO);
+        }
+        match self.state {
+            SessionState::Inviting(_) => Some(self.next_invitation),
+            SessionState::Connected => {
+                let sync = self.initiator.then_some(self.next_sync);
+                let feedback =
+                    (self.receiver.sequence() != self.feedback).then_some(self.next_feedback);
+                match (sync, feedback) {
+                    (Some(sync), Some(feedback)) => Some(sync.min(feedback)),
+                    (sync, feedback) => sync.or(feedback),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn invited(
+        &mut self,
+        port: Port,
+        invitation: &Exchange,
+        now: Duration,
+    ) -> Option<SessionEvent> {
+        let known = self.peer == Some(invitation.ssrc);
+        match (self.state, port) {
+            (SessionState::Idle, Port::Control) => {
+                self.initiator = false;
+                self.peer = Some(invitation.ssrc);
+                self.token = invitation.token;
+                self.state = SessionState::Accepting;
+                self.queue(port, AppleMidiPacket::Accept(self.exchange(self.token)));
+                None
+            }
+            (SessionState::Accepting, Port::Data) if known => {
+                self.queue(port, AppleMidiPacket::Accept(self.exchange(self.token)));
+                self.connected(now);
+                Some(SessionEvent::Connected)
+            }
+            // Repeated invitations whose answer was lost
+            (SessionState::Accepting | SessionState::Connected, _) if known && !self.initiator => {
+                self.queue(port, AppleMidiPacket::Accept(self.exchange(self.token)));
+                None
+            }
+            _ => {
+                let reject = AppleMidiPacket::Reject(self.exchange(invitation.token));
+                self.queue(port, reject);
+                None
+            }
+        }
+    }
+
+    fn accepted(&mut self, port: Port, accept: &Exchange, now: Duration) -> Option<SessionEvent> {
+        if self.state != SessionState::Inviting(port) || accept.token != self.token {
+            return None;
+        }
+        match port {
+            Port::Control => {
+                self.peer = Some(accept.ssrc);
+                self.state = SessionState::Inviting(Port::Data);
+                self.attempts = 0;
+                self.next_invitation = now;
+                None
+            }
+            Port::Data if self.peer == Some(accept.ssrc) => {
+                self.connected(now);
+                Some(SessionEvent::Connected)
+            }
+            Port::Data => None,
+        }
+    }
+
+    fn synchronize(&mut self, port: Port, count: u8, timestamps: [u64; 3], now: Duration) {
+        let now = Self::clock(now);
+        match count {
+            0 => {
+                let sync = AppleMidiPacket::Sync {
+                    ssrc: self.ssrc,
+                    count: 1,
+                    timestamps: [timestamps[0], now, 0],
+                };
+                self.queue(port, sync);
+            }
+            1 => {
+                let sync = AppleMidiPacket::Sync {
+                    ssrc: self.ssrc,
+                    count: 2,
+                    timestamps: [timestamps[0], timestamps[1], now],
+                };
+                self.queue(port, sync);
+                // The peer's clock was read halfway between sending and receiving
+                let local = (i128::from(timestamps[0]) + i128::from(now)) / 2;
+                self.offset = Some((i128::from(timestamps[1]) - local) as i64);
+            }
+            _ => {
+                let peer = (i128::from(timestamps[0]) + i128::from(timestamps[2])) / 2;
+                self.offset = Some((peer - i128::from(timestamps[1])) as i64);
+            }
+        }
+    }
+
+    fn connected(&mut self, now: Duration) {
+        self.state = SessionState::Connected;
+        self.syncs = 0;
+        self.next_sync = now;
+        self.offset = None;
+        self.feedback = None;
+        self.next_feedback = now + FEEDBACK_INTERVAL;
+        self.sender = JournalSender::new();
+        self.receiver = JournalReceiver::new();
+    }
+
+    fn reset(&mut self) {
+        self.state = SessionState::Idle;
+        self.peer = None;
+        self.offset = None;
+    }
+
+    fn exchange(&self, token: u32) -> Exchange<'a> {
+        Exchange {
+            version: PROTOCOL_VERSION,
+            token,
+            ssrc: self.ssrc,
+            name: Some(self.name),
+        }
+    }
+
+    fn queue(&mut self, port: Port, packet: AppleMidiPacket<'a>) {
+        // Answers are repeated by the peer, so dropping one when the queue is full is harmless
+        if let Some(slot) = self.pending.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((port, packet));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, midi_types::Channel, std::vec::Vec};
+
+    /// Deliver packets between two sessions until neither has anything left to send
+    fn exchange<'a>(
+        a: &mut AppleMidiSession<'a>,
+        b: &mut AppleMidiSession<'a>,
+        now: Duration,
+    ) -> Vec<(bool, AppleMidiPacket<'static>)> {
+        let mut log = Vec::new();
+        let mut buf = [0; 128];
+        let mut midi = [(0, MidiMessage::Reset); 8];
+        loop {
+            let mut idle = true;
+            for from_a in [true, false] {
+                let (from, to) = if from_a {
+                    (&mut *a, &mut *b)
+                } else {
+                    (&mut *b, &mut *a)
+                };
+                while let Some((port, len)) = from.poll_transmit(now, &mut buf).unwrap() {
+                    idle = false;
+                    log.push((from_a, owned(AppleMidiPacket::parse(&buf[..len]).unwrap())));
+                    to.handle(port, &buf[..len], now, &mut midi).unwrap();
+                }
+            }
+            if idle {
+                return log;
+            }
+        }
+    }
+
+    fn owned(packet: AppleMidiPacket) -> AppleMidiPacket<'static> {
+        let exchange = |e: Exchange| Exchange {
+            name: e.name.map(|_| ""),
+            ..e
+        };
+        match packet {
+            AppleMidiPacket::Invitation(e) => AppleMidiPacket::Invitation(exchange(e)),
+            AppleMidiPacket::Accept(e) => AppleMidiPacket::Accept(exchange(e)),
+            AppleMidiPacket::Reject(e) => AppleMidiPacket::Reject(exchange(e)),
+            AppleMidiPacket::End(e) => AppleMidiPacket::End(exchange(e)),
+            AppleMidiPacket::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => AppleMidiPacket::Sync {
+                ssrc,
+                count,
+                timestamps,
+            },
+            AppleMidiPacket::Feedback { ssrc, sequence } => {
+                AppleMidiPacket::Feedback { ssrc, sequence }
+            }
+        }
+    }
+
+    fn commands(log: &[(bool, AppleMidiPacket)]) -> Vec<(bool, &'static str)> {
+        log.iter()
+            .map(|(from_a, packet)| {
+                let command = match packet {
+                    AppleMidiPacket::Invitation(_) => "IN",
+                    AppleMidiPacket::Accept(_) => "OK",
+                    AppleMidiPacket::Reject(_) => "NO",
+                    AppleMidiPacket::End(_) => "BY",
+                    AppleMidiPacket::Sync { .. } => "CK",
+                    AppleMidiPacket::Feedback { .. } => "RS",
+                };
+                (*from_a, command)
+            })
+            .collect()
+    }
+
+    fn connected_pair() -> (AppleMidiSession<'static>, AppleMidiSession<'static>) {
+        let mut initiator = AppleMidiSession::new("initiator", 1);
+        let mut responder = AppleMidiSession::new("responder", 2);
+        initiator.connect(0x1234, Duration::ZERO);
+        exchange(&mut initiator, &mut responder, Duration::ZERO);
+        (initiator, responder)
+    }
+
+    #[test]
+    fn should_parse_invitation() {
+        let packet = [
+            0xff, 0xff, b'I', b'N', 0x00, 0x00, 0x00, 0x02, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00,
+            0x00, 0x01, b'm', b'i', b'd', b'i', 0x00,
+        ];
+        let invitation = AppleMidiPacket::Invitation(Exchange {
+            version: 2,
+            token: 0x1234_5678,
+            ssrc: 1,
+            name: Some("midi"),
+        });
+        assert_eq!(AppleMidiPacket::parse(&packet), Ok(invitation.clone()));
+
+        let mut buf = [0; 32];
+        assert_eq!(invitation.write(&mut buf), Ok(packet.len()));
+        assert_eq!(buf[..packet.len()], packet);
+        assert_eq!(
+            invitation.write(&mut buf[..20]),
+            Err(AppleMidiError::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn should_parse_end_without_name() {
+        let packet = [
+            0xff, 0xff, b'B', b'Y', 0x00, 0x00, 0x00, 0x02, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00,
+            0x00, 0x01,
+        ];
+        assert_eq!(
+            AppleMidiPacket::parse(&packet),
+            Ok(AppleMidiPacket::End(Exchange {
+                version: 2,
+                token: 0x1234_5678,
+                ssrc: 1,
+                name: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn should_round_trip_sync_and_feedback() {
+        let packets = [
+            AppleMidiPacket::Sync {
+                ssrc: 7,
+                count: 1,
+                timestamps: [1, 0x1_0000_0002, 0],
+            },
+            AppleMidiPacket::Feedback {
+                ssrc: 7,
+                sequence: 0xabcd,
+            },
+        ];
+        let mut buf = [0; 64];
+        for (packet, expected_len) in packets.iter().zip([36, 12]) {
+            let len = packet.write(&mut buf).unwrap();
+            assert_eq!(len, expected_len);
+            assert_eq!(AppleMidiPacket::parse(&buf[..len]).as_ref(), Ok(packet));
+        }
+        assert_eq!(buf[8..12], [0xab, 0xcd, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn should_reject_invalid_packets() {
+        assert_eq!(
+            AppleMidiPacket::parse(&[0xff, 0xff, b'I']),
+            Err(AppleMidiError::BufferTooShort)
+        );
+        assert_eq!(
+            AppleMidiPacket::parse(&[0x80, 0x61, 0x00, 0x00]),
+            Err(AppleMidiError::InvalidPacket)
+        );
+        assert_eq!(
+            AppleMidiPacket::parse(&[0xff, 0xff, b'X', b'X']),
+            Err(AppleMidiError::UnknownCommand)
+        );
+        assert_eq!(
+            AppleMidiPacket::parse(&[0xff, 0xff, b'C', b'K', 0, 0, 0, 1]),
+            Err(AppleMidiError::BufferTooShort)
+        );
+        let mut unterminated = [0; 20];
+        unterminated[..4].copy_from_slice(&[0xff, 0xff, b'O', b'K']);
+        unterminated[16..].copy_from_slice(b"name");
+        assert_eq!(
+            AppleMidiPacket::parse(&unterminated),
+            Err(AppleMidiError::InvalidPacket)
+        );
+    }
+
+    #[test]
+    fn should_connect_and_synchronize() {
+        let mut initiator = AppleMidiSession::new("initiator", 1);
+        let mut responder = AppleMidiSession::new("responder", 2);
+        initiator.connect(0x1234, Duration::ZERO);
+        let log = exchange(&mut initiator, &mut responder, Duration::ZERO);
+        assert_eq!(
+            commands(&log),
+            [
+                (true, "IN"),
+                (false, "OK"),
+                (true, "IN"),
+                (false, "OK"),
+                (true, "CK"),
+                (false, "CK"),
+                (true, "CK"),
+            ]
+        );
+        assert_eq!(initiator.state(), SessionState::Connected);
+        assert_eq!(responder.state(), SessionState::Connected);
+        assert_eq!(initiator.peer_ssrc(), Some(2));
+        assert_eq!(responder.peer_ssrc(), Some(1));
+        assert_eq!(initiator.clock_offset(), Some(0));
+        assert_eq!(responder.clock_offset(), Some(0));
+        assert_eq!(initiator.next_due(), Some(FAST_SYNC_INTERVAL));
+        assert_eq!(responder.next_due(), None);
+    }
+
+    #[test]
+    fn should_estimate_clock_offset() {
+        let (mut initiator, mut responder) = connected_pair();
+        let mut buf = [0; 64];
+        let mut midi = [];
+
+        // The responder's clock runs 5 s ahead, each packet takes 10 ms
+        let now = Duration::from_secs(2);
+        let skew = Duration::from_secs(5);
+        let delay = Duration::from_millis(10);
+        let (port, len) = initiator.poll_transmit(now, &mut buf).unwrap().unwrap();
+        responder
+            .handle(port, &buf[..len], now + skew + delay, &mut midi)
+            .unwrap();
+        let (port, len) = responder
+            .poll_transmit(now + skew + delay, &mut buf)
+            .unwrap()
+            .unwrap();
+        initiator
+            .handle(port, &buf[..len], now + 2 * delay, &mut midi)
+            .unwrap();
+        let (port, len) = initiator
+            .poll_transmit(now + 2 * delay, &mut buf)
+            .unwrap()
+            .unwrap();
+        responder
+            .handle(port, &buf[..len], now + skew + 3 * delay, &mut midi)
+            .unwrap();
+
+        assert_eq!(initiator.clock_offset(), Some(50_000));
+        assert_eq!(responder.clock_offset(), Some(-50_000));
+    }
+
+    #[test]
+    fn should_reject_second_initiator() {
+        let (_, mut responder) = connected_pair();
+        let mut other = AppleMidiSession::new("other", 3);
+        other.connect(0x5678, Duration::ZERO);
+        let log = exchange(&mut other, &mut responder, Duration::ZERO);
+        assert_eq!(commands(&log), [(true, "IN"), (false, "NO")]);
+        assert_eq!(other.state(), SessionState::Idle);
+        assert_eq!(responder.peer_ssrc(), Some(1));
+    }
+
+    #[test]
+    fn should_give_up_inviting() {
+        let mut session = AppleMidiSession::new("initiator", 1);
+        let mut buf = [0; 64];
+        session.connect(1, Duration::ZERO);
+        for attempt in 0..12 {
+            let now = Duration::from_secs(attempt);
+            assert_eq!(session.next_due(), Some(now));
+            assert!(session.poll_transmit(now, &mut buf).unwrap().is_some());
+            assert_eq!(session.poll_transmit(now, &mut buf), Ok(None));
+        }
+        assert_eq!(session.state(), SessionState::Inviting(Port::Control));
+        assert_eq!(
+            session.poll_transmit(Duration::from_secs(12), &mut buf),
+            Ok(None)
+        );
+        assert_eq!(session.state(), SessionState::Idle);
+    }
+
+    #[test]
+    fn should_end_session() {
+        let (mut initiator, mut responder) = connected_pair();
+        let mut buf = [0; 64];
+        let mut midi = [];
+        responder.disconnect();
+        assert_eq!(responder.state(), SessionState::Idle);
+        let (port, len) = responder
+            .poll_transmit(Duration::ZERO, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(port, Port::Control);
+        assert_eq!(
+            initiator.handle(port, &buf[..len], Duration::ZERO, &mut midi),
+            Ok(Some(SessionEvent::Ended))
+        );
+        assert_eq!(initiator.state(), SessionState::Idle);
+        assert_eq!(
+            initiator.send(Duration::ZERO, &[], &mut buf),
+            Err(AppleMidiError::NotConnected)
+        );
+    }
+
+    #[test]
+    fn should_confirm_received_packets() {
+        let (mut initiator, mut responder) = connected_pair();
+        let mut buf = [0; 64];
+        let mut midi = [(0, MidiMessage::Reset); 8];
+        let now = Duration::from_millis(100);
+        let note_on = MidiMessage::NoteOn(Channel::new(0), 60.into(), 100.into());
+        let timestamp = AppleMidiSession::timestamp(now);
+
+        let len = initiator
+            .send(now, &[(timestamp, note_on)], &mut buf)
+            .unwrap();
+        assert_eq!(
+            responder.handle(Port::Data, &buf[..len], now, &mut midi),
+            Ok(Some(SessionEvent::Midi(1)))
+        );
+        assert_eq!(midi[0], (timestamp, note_on));
+        assert_eq!(responder.next_due(), Some(FEEDBACK_INTERVAL));
+
+        // The next packet carries a journal until the feedback arrives
+        let journal_len = initiator.send(now, &[], &mut buf).unwrap();
+        assert!(journal_len > RtpHeader::LEN + 1);
+        let log = exchange(&mut responder, &mut initiator, FEEDBACK_INTERVAL);
+        assert_eq!(commands(&log), [(true, "RS")]);
+        assert_eq!(initiator.send(now, &[], &mut buf), Ok(RtpHeader::LEN + 1));
+    }
+
+    #[test]
+    fn should_ignore_midi_from_other_sources() {
+        let (_, mut responder) = connected_pair();
+        let mut other = AppleMidiSession::new("other", 3);
+        other.state = SessionState::Connected;
+        let mut buf = [0; 64];
+        let mut midi = [(0, MidiMessage::Reset); 8];
+        let note_on = MidiMessage::NoteOn(Channel::new(0), 60.into(), 100.into());
+        let len = other
+            .send(Duration::ZERO, &[(0, note_on)], &mut buf)
+            .unwrap();
+        assert_eq!(
+            responder.handle(Port::Data, &buf[..len], Duration::ZERO, &mut midi),
+            Ok(None)
+        );
+        assert_eq!(
+            responder.handle(Port::Control, &buf[..len], Duration::ZERO, &mut midi),
+            Ok(None)
+        );
+        let feedback = [0xff, 0xff, b'R', b'S', 0, 0, 0, 3, 0, 0, 0, 0];
+        assert_eq!(
+            responder.handle(Port::Control, &feedback, Duration::ZERO, &mut midi),
+            Ok(None)
+        );
+    }
+}
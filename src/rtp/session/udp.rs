@@ -0,0 +1,365 @@
+//! AppleMIDI sessions over `std::net` UDP sockets
+
+use {
+    super::{AppleMidiPacket, AppleMidiSession, Port, SessionEvent},
+    midi_types::MidiMessage,
+    std::{
+        io::{self, ErrorKind},
+        net::{SocketAddr, UdpSocket},
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// An [`AppleMidiSession`] driven over a pair of non-blocking UDP sockets
+///
+/// Received packets are handled and due packets sent while [`poll`](Self::poll) runs, so poll
+/// regularly even when no MIDI is expected.
+#[derive(Debug)]
+pub struct UdpSession<'a> {
+    session: AppleMidiSession<'a>,
+    control: UdpSocket,
+    data: UdpSocket,
+    peer: [Option<SocketAddr>; 2],
+    start: Instant,
+    buf: [u8; 1500],
+}
+
+impl<'a> UdpSession<'a> {
+    /// Bind the control port to `addr` and the data port to the following port
+    ///
+    /// When the port of `addr` is 0 a free pair of ports is picked.
+    pub fn bind(addr: SocketAddr, name: &'a str, ssrc: u32) -> io::Result<Self> {
+        let mut attempts = 0;
+        loop {
+            let control = UdpSocket::bind(addr)?;
+            let mut data_addr = control.local_addr()?;
+            let result = match data_addr.port().checked_add(1) {
+                Some(port) => {
+                    data_addr.set_port(port);
+                    UdpSocket::bind(data_addr)
+                }
+                None => Err(io::Error::from(ErrorKind::AddrNotAvailable)),
+            };
+            match result {
+                Ok(data) => return Self::from_sockets(control, data, name, ssrc),
+                Err(_) if addr.port() == 0 && attempts < 16 => attempts += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Use already bound control and data sockets
+    pub fn from_sockets(
+        control: UdpSocket,
+        data: UdpSocket,
+        name: &'a str,
+        ssrc: u32,
+    ) -> io::Result<Self> {
+        control.set_nonblocking(true)?;
+        data.set_nonblocking(true)?;
+        Ok(Self {
+            session: AppleMidiSession::new(name, ssrc),
+            control,
+            data,
+            peer: [None; 2],
+            start: Instant::now(),
+            buf: [0; 1500],
+        })
+    }
+
+    /// The address of the control socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.control.local_addr()
+    }
+
+    /// The session state machine
+    pub fn session(&self) -> &AppleMidiSession<'a> {
+        &self.session
+    }
+
+    /// Invite the peer whose control port is at `addr`, its data port is the following port
+    pub fn connect(&mut self, addr: SocketAddr, token: u32) -> io::Result<()> {
+        let mut data_addr = addr;
+        data_addr.set_port(addr.port().wrapping_add(1));
+        self.peer = [Some(addr), Some(data_addr)];
+        self.session.connect(token, self.now());
+        self.transmit(None)
+    }
+
+    /// End the session
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.session.disconnect();
+        self.transmit(None)
+    }
+
+    /// Send `messages` to the peer, timestamped with the current time
+    pub fn send(&mut self, messages: &[MidiMessage]) -> io::Result<()> {
+        let now = self.now();
+        let timestamp = AppleMidiSession::timestamp(now);
+        let mut timestamped = [(0, MidiMessage::Reset); 64];
+        for chunk in messages.chunks(timestamped.len()) {
+            for (slot, message) in timestamped.iter_mut().zip(chunk) {
+                *slot = (timestamp, *message);
+            }
+            let len = self
+                .session
+                .send(now, &timestamped[..chunk.len()], &mut self.buf)
+                .map_err(invalid_data)?;
+            if let Some(addr) = self.peer[1] {
+                self.data.send_to(&self.buf[..len], addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle received packets and send due packets for up to `timeout`, returning at the first
+    /// event
+    ///
+    /// Received MIDI messages are written to `midi`, see [`AppleMidiSession::handle`]. Packets that
+    /// are not valid AppleMIDI or RTP-MIDI are ignored.
+    pub fn poll(
+        &mut self,
+        timeout: Duration,
+        midi: &mut [(u32, MidiMessage)],
+    ) -> io::Result<Option<SessionEvent>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut received = false;
+            for port in [Port::Control, Port::Data] {
+                let socket = match port {
+                    Port::Control => &self.control,
+                    Port::Data => &self.data,
+                };
+                let (len, src) = match socket.recv_from(&mut self.buf) {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                };
+                received = true;
+                let packet = &self.buf[..len];
+                // Anyone can send to the ports, packets that can't be decoded are ignored
+                let Ok(event) = self
+                    .session
+                    .handle(port, packet, self.start.elapsed(), midi)
+                else {
+                    continue;
+                };
+                if let Ok(
+                    AppleMidiPacket::Invitation(exchange) | AppleMidiPacket::Accept(exchange),
+                ) = AppleMidiPacket::parse(packet)
+                {
+                    if self.session.peer_ssrc() == Some(exchange.ssrc) {
+                        self.peer[port as usize] = Some(src);
+                    }
+                }
+                self.transmit(Some(src))?;
+                if event.is_some() {
+                    return Ok(event);
+                }
+            }
+            self.transmit(None)?;
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            if !received {
+                thread::sleep((deadline - now).min(Duration::from_millis(1)));
+            }
+        }
+    }
+
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Send the packets the session has queued, the answers to the packet just handled go to
+    /// `reply` and everything else to the peer
+    fn transmit(&mut self, reply: Option<SocketAddr>) -> io::Result<()> {
+        loop {
+            // Answers are queued by `handle` and sent before the packets that fall due
+            let answer = self.session.pending[0].is_some();
+            // A packet that can't be encoded is left to the session to retry or drop
+            let Ok(Some((port, len))) = self.session.poll_transmit(self.now(), &mut self.buf)
+            else {
+                return Ok(());
+            };
+            let addr = match reply {
+                Some(addr) if answer => Some(addr),
+                _ => self.peer[port as usize],
+            };
+            let socket = match port {
+                Port::Control => &self.control,
+                Port::Data => &self.data,
+            };
+            if let Some(addr) = addr {
+                socket.send_to(&self.buf[..len], addr)?;
+            }
+        }
+    }
+}
+
+fn invalid_data<E: core::fmt::Debug>(error: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, std::format!("{error:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::rtp::session::{Exchange, SessionState},
+        midi_types::{Channel, Note, Value7},
+        std::vec::Vec,
+    };
+
+    fn bind(name: &str, ssrc: u32) -> UdpSession<'_> {
+        UdpSession::bind("127.0.0.1:0".parse().unwrap(), name, ssrc).unwrap()
+    }
+
+    /// Poll both sessions until `done` holds, collecting the MIDI `b` receives
+    fn run(
+        a: &mut UdpSession,
+        b: &mut UdpSession,
+        mut done: impl FnMut(&UdpSession, &UdpSession, &[MidiMessage]) -> bool,
+    ) -> Vec<MidiMessage> {
+        let mut received = Vec::new();
+        let mut midi = [(0, MidiMessage::Reset); 64];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(a, b, &received) {
+            assert!(Instant::now() < deadline, "timed out");
+            a.poll(Duration::from_millis(1), &mut midi).unwrap();
+            if let Some(SessionEvent::Midi(len)) =
+                b.poll(Duration::from_millis(1), &mut midi).unwrap()
+            {
+                received.extend(midi[..len].iter().map(|(_, message)| *message));
+            }
+        }
+        received
+    }
+
+    fn connected(a: &UdpSession, b: &UdpSession) -> bool {
+        a.session().state() == SessionState::Connected
+            && b.session().state() == SessionState::Connected
+    }
+
+    #[test]
+    fn should_bind_following_ports() {
+        let session = bind("session", 1);
+        let control = session.local_addr().unwrap();
+        assert_eq!(
+            session.data.local_addr().unwrap().port(),
+            control.port() + 1
+        );
+    }
+
+    #[test]
+    fn should_exchange_midi_over_localhost() {
+        let mut initiator = bind("initiator", 1);
+        let mut responder = bind("responder", 2);
+        initiator
+            .connect(responder.local_addr().unwrap(), 0x1234)
+            .unwrap();
+        run(&mut initiator, &mut responder, |a, b, _| connected(a, b));
+        assert_eq!(initiator.session().peer_ssrc(), Some(2));
+        assert_eq!(responder.session().peer_ssrc(), Some(1));
+
+        let messages: Vec<_> = (0..100)
+            .map(|i| MidiMessage::NoteOn(Channel::new(i % 16), Note::new(i), Value7::new(100)))
+            .collect();
+        initiator.send(&messages).unwrap();
+        let received = run(&mut initiator, &mut responder, |_, _, received| {
+            received.len() == messages.len()
+        });
+        assert_eq!(received, messages);
+        run(&mut initiator, &mut responder, |a, _, _| {
+            a.session().clock_offset().is_some()
+        });
+    }
+
+    #[test]
+    fn should_ignore_invalid_packets_over_localhost() {
+        let mut initiator = bind("initiator", 1);
+        let mut responder = bind("responder", 2);
+        initiator
+            .connect(responder.local_addr().unwrap(), 0x1234)
+            .unwrap();
+        run(&mut initiator, &mut responder, |a, b, _| connected(a, b));
+
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let control = responder.local_addr().unwrap();
+        for addr in [control, responder.data.local_addr().unwrap()] {
+            other.send_to(&[0xff; 5], addr).unwrap();
+            other.send_to(&[0x8f; 12], addr).unwrap();
+        }
+        let mut midi = [(0, MidiMessage::Reset); 8];
+        assert!(responder.poll(Duration::from_millis(10), &mut midi).is_ok());
+        assert_eq!(responder.session().state(), SessionState::Connected);
+
+        initiator.send(&[MidiMessage::Start]).unwrap();
+        let received = run(&mut initiator, &mut responder, |_, _, received| {
+            !received.is_empty()
+        });
+        assert_eq!(received, [MidiMessage::Start]);
+    }
+
+    #[test]
+    fn should_answer_strangers_without_sending_them_session_traffic() {
+        let mut initiator = bind("initiator", 1);
+        let mut responder = bind("responder", 2);
+        initiator
+            .connect(responder.local_addr().unwrap(), 0x1234)
+            .unwrap();
+        run(&mut initiator, &mut responder, |a, b, _| connected(a, b));
+        let mut midi = [(0, MidiMessage::Reset); 8];
+        initiator
+            .poll(Duration::from_millis(20), &mut midi)
+            .unwrap();
+
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 64];
+        let invitation = AppleMidiPacket::Invitation(Exchange {
+            version: 2,
+            token: 0x5678,
+            ssrc: 3,
+            name: Some("other"),
+        });
+        let len = invitation.write(&mut buf).unwrap();
+        other
+            .send_to(&buf[..len], initiator.data.local_addr().unwrap())
+            .unwrap();
+        thread::sleep(Duration::from_millis(10));
+        // Make the next clock synchronization due while the invitation is handled
+        initiator.start -= Duration::from_secs(2);
+        initiator.poll(Duration::ZERO, &mut midi).unwrap();
+
+        let len = other.recv(&mut buf).unwrap();
+        assert!(matches!(
+            AppleMidiPacket::parse(&buf[..len]),
+            Ok(AppleMidiPacket::Reject(Exchange { token: 0x5678, .. }))
+        ));
+        assert!(other.recv(&mut buf).is_err());
+        run(&mut initiator, &mut responder, |a, _, _| {
+            a.session().clock_offset().is_some()
+        });
+    }
+
+    #[test]
+    fn should_end_session_over_localhost() {
+        let mut initiator = bind("initiator", 1);
+        let mut responder = bind("responder", 2);
+        initiator
+            .connect(responder.local_addr().unwrap(), 0x1234)
+            .unwrap();
+        run(&mut initiator, &mut responder, |a, b, _| connected(a, b));
+
+        responder.disconnect().unwrap();
+        run(&mut initiator, &mut responder, |a, _, _| {
+            a.session().state() == SessionState::Idle
+        });
+        assert!(initiator.send(&[MidiMessage::Start]).is_err());
+    }
+}
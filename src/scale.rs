@@ -0,0 +1,265 @@
+//! Scale values between MIDI 1.0 and MIDI 2.0 resolutions and normalized floats
+//!
+//! Upscaling uses the min-center-max scheme of the MIDI 2.0 specification: the minimum, center and
+//! maximum of the source map to the minimum, center and maximum of the destination. Downscaling
+//! drops the low bits, so downscaling an upscaled value gives the original back.
+//!
+//! ```
+//! use midi_convert::scale::{scale, to_bipolar};
+//!
+//! assert_eq!(scale(0x40, 7, 32), 0x8000_0000);
+//! assert_eq!(scale(0x7f, 7, 32), 0xffff_ffff);
+//! assert_eq!(scale(0xffff, 16, 7), 0x7f);
+//! assert_eq!(to_bipolar(0x2000, 14), 0.0);
+//! ```
+
+use midi_types::{Value7, Value14};
+
+/// Scale `value` of `src_bits` up to `dst_bits`, with `src_bits <= dst_bits <= 32`
+pub const fn scale_up(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    let scale_bits = dst_bits - src_bits;
+    let mut scaled = value << scale_bits;
+    if value <= 1 << (src_bits - 1) {
+        return scaled;
+    }
+    // Repeat the bits below the top bit to fill the low bits
+    let repeat_bits = src_bits - 1;
+    let mut repeat = value & ((1 << repeat_bits) - 1);
+    if scale_bits > repeat_bits {
+        repeat <<= scale_bits - repeat_bits;
+    } else {
+        repeat >>= repeat_bits - scale_bits;
+    }
+    while repeat != 0 {
+        scaled |= repeat;
+        repeat >>= repeat_bits;
+    }
+    scaled
+}
+
+/// Scale `value` of `src_bits` down to `dst_bits`, with `dst_bits <= src_bits <= 32`
+pub const fn scale_down(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    value >> (src_bits - dst_bits)
+}
+
+/// Scale `value` of `src_bits` up or down to `dst_bits`
+pub const fn scale(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    if src_bits <= dst_bits {
+        scale_up(value, src_bits, dst_bits)
+    } else {
+        scale_down(value, src_bits, dst_bits)
+    }
+}
+
+const fn max(bits: u32) -> f64 {
+    ((1u64 << bits) - 1) as f64
+}
+
+const fn center(bits: u32) -> f64 {
+    (1u64 << (bits - 1)) as f64
+}
+
+/// Map `value` of `bits` to [0, 1]
+pub const fn to_unipolar(value: u32, bits: u32) -> f32 {
+    (value as f64 / max(bits)) as f32
+}
+
+/// Map `x` in [0, 1] to a value of `bits`, rounding to the nearest value
+///
+/// Values outside the range are clamped, NaN maps to 0.
+pub const fn from_unipolar(x: f32, bits: u32) -> u32 {
+    if x >= 1.0 {
+        max(bits) as u32
+    } else if x > 0.0 {
+        (x as f64 * max(bits) + 0.5) as u32
+    } else {
+        0
+    }
+}
+
+/// Map `value` of `bits` to [-1, 1], with the center at 0
+///
+/// The halves below and above the center are scaled separately so the minimum, center and maximum
+/// map to -1, 0 and 1, as for pitch bend.
+pub const fn to_bipolar(value: u32, bits: u32) -> f32 {
+    let center = center(bits);
+    let offset = value as f64 - center;
+    if offset <= 0.0 {
+        (offset / center) as f32
+    } else {
+        (offset / (max(bits) - center)) as f32
+    }
+}
+
+/// Map `x` in [-1, 1] to a value of `bits`, rounding to the nearest value
+///
+/// Values outside the range are clamped, NaN maps to the center.
+pub const fn from_bipolar(x: f32, bits: u32) -> u32 {
+    let center = center(bits);
+    if x >= 1.0 {
+        max(bits) as u32
+    } else if x <= -1.0 {
+        0
+    } else if x > 0.0 {
+        center as u32 + (x as f64 * (max(bits) - center) + 0.5) as u32
+    } else if x < 0.0 {
+        ((x as f64 + 1.0) * center + 0.5) as u32
+    } else {
+        center as u32
+    }
+}
+
+/// Scale a 7-bit value to 32 bits
+pub fn value7_to_u32(value: Value7) -> u32 {
+    scale_up(u8::from(value).into(), 7, 32)
+}
+
+/// Scale a 32-bit value to 7 bits
+pub fn u32_to_value7(value: u32) -> Value7 {
+    Value7::new(scale_down(value, 32, 7) as u8)
+}
+
+/// Scale a 7-bit value to 16 bits, as for velocity
+pub fn value7_to_u16(value: Value7) -> u16 {
+    scale_up(u8::from(value).into(), 7, 16) as u16
+}
+
+/// Scale a 16-bit value to 7 bits
+pub fn u16_to_value7(value: u16) -> Value7 {
+    Value7::new(scale_down(value.into(), 16, 7) as u8)
+}
+
+/// Scale a 14-bit value to 32 bits
+pub fn value14_to_u32(value: Value14) -> u32 {
+    scale_up(u16::from(value).into(), 14, 32)
+}
+
+/// Scale a 32-bit value to 14 bits
+pub fn u32_to_value14(value: u32) -> Value14 {
+    Value14::from(scale_down(value, 32, 14) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLUTIONS: [u32; 4] = [7, 14, 16, 32];
+
+    #[test]
+    fn should_scale_in_const_context() {
+        const VELOCITY: u32 = scale(0x7f, 7, 16);
+        const HALF: u32 = from_unipolar(0.5, 7);
+        assert_eq!(VELOCITY, 0xffff);
+        assert_eq!(HALF, 0x40);
+    }
+
+    #[test]
+    fn should_match_specification_vectors() {
+        let cases_7_16 = [
+            (0x00, 0x0000),
+            (0x01, 0x0200),
+            (0x20, 0x4000),
+            (0x3f, 0x7e00),
+            (0x40, 0x8000),
+            (0x41, 0x8208),
+            (0x60, 0xc104),
+            (0x7e, 0xfdf7),
+            (0x7f, 0xffff),
+        ];
+        for (value, scaled) in cases_7_16 {
+            assert_eq!(scale_up(value, 7, 16), scaled, "{value:#x}");
+            assert_eq!(scale_down(scaled, 16, 7), value, "{value:#x}");
+        }
+        let cases_7_32 = [
+            (0x00, 0x0000_0000),
+            (0x01, 0x0200_0000),
+            (0x40, 0x8000_0000),
+            (0x41, 0x8208_2082),
+            (0x7f, 0xffff_ffff),
+        ];
+        for (value, scaled) in cases_7_32 {
+            assert_eq!(scale_up(value, 7, 32), scaled, "{value:#x}");
+            assert_eq!(scale_down(scaled, 32, 7), value, "{value:#x}");
+        }
+        let cases_14_32 = [
+            (0x0000, 0x0000_0000),
+            (0x0001, 0x0004_0000),
+            (0x2000, 0x8000_0000),
+            (0x2001, 0x8004_0020),
+            (0x3fff, 0xffff_ffff),
+        ];
+        for (value, scaled) in cases_14_32 {
+            assert_eq!(scale_up(value, 14, 32), scaled, "{value:#x}");
+            assert_eq!(scale_down(scaled, 32, 14), value, "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn should_keep_min_center_max() {
+        for src in RESOLUTIONS {
+            for dst in RESOLUTIONS.into_iter().filter(|dst| *dst >= src) {
+                let max = |bits| ((1u64 << bits) - 1) as u32;
+                assert_eq!(scale(0, src, dst), 0);
+                assert_eq!(scale(1 << (src - 1), src, dst), 1 << (dst - 1));
+                assert_eq!(scale(max(src), src, dst), max(dst), "{src} {dst}");
+            }
+        }
+    }
+
+    #[test]
+    fn should_round_trip_all_values() {
+        for src in [7, 14, 16] {
+            for dst in RESOLUTIONS.into_iter().filter(|dst| *dst >= src) {
+                let mut last = None;
+                for value in 0..1u32 << src {
+                    let scaled = scale(value, src, dst);
+                    assert_eq!(scale(scaled, dst, src), value, "{value:#x} {src} {dst}");
+                    assert!(last < Some(scaled));
+                    last = Some(scaled);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_round_trip_normalized_floats() {
+        for bits in [7, 14, 16] {
+            for value in 0..1u32 << bits {
+                let unipolar = to_unipolar(value, bits);
+                assert!((0.0..=1.0).contains(&unipolar));
+                assert_eq!(from_unipolar(unipolar, bits), value);
+                let bipolar = to_bipolar(value, bits);
+                assert!((-1.0..=1.0).contains(&bipolar));
+                assert_eq!(from_bipolar(bipolar, bits), value, "{value:#x} {bits}");
+            }
+            assert_eq!(to_bipolar(0, bits), -1.0);
+            assert_eq!(to_bipolar(1 << (bits - 1), bits), 0.0);
+            assert_eq!(to_bipolar((1 << bits) - 1, bits), 1.0);
+        }
+        assert_eq!(to_unipolar(u32::MAX, 32), 1.0);
+        assert_eq!(to_bipolar(0x8000_0000, 32), 0.0);
+        assert_eq!(from_bipolar(0.0, 32), 0x8000_0000);
+        assert_eq!(from_bipolar(1.0, 32), u32::MAX);
+        assert_eq!(from_unipolar(1.0, 32), u32::MAX);
+    }
+
+    #[test]
+    fn should_clamp_out_of_range_floats() {
+        assert_eq!(from_unipolar(-0.5, 7), 0);
+        assert_eq!(from_unipolar(1.5, 7), 0x7f);
+        assert_eq!(from_unipolar(f32::NAN, 7), 0);
+        assert_eq!(from_bipolar(-1.5, 14), 0);
+        assert_eq!(from_bipolar(1.5, 14), 0x3fff);
+        assert_eq!(from_bipolar(f32::NAN, 14), 0x2000);
+    }
+
+    #[test]
+    fn should_scale_midi_types() {
+        assert_eq!(value7_to_u32(Value7::new(0x40)), 0x8000_0000);
+        assert_eq!(u32_to_value7(0xffff_ffff), Value7::new(0x7f));
+        assert_eq!(value7_to_u16(Value7::new(0x41)), 0x8208);
+        assert_eq!(u16_to_value7(0x8208), Value7::new(0x41));
+        assert_eq!(value14_to_u32(Value14::from(0x2001u16)), 0x8004_0020);
+        assert_eq!(u32_to_value14(0x8004_0020), Value14::from(0x2001u16));
+    }
+}
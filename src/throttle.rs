@@ -0,0 +1,395 @@
+//! Calculate the on-wire duration of midi messages and throttle rendering to the link bandwidth
+//!
+//! A DIN midi link runs at 31250 baud and every byte takes 10 bits on the wire, so it only carries
+//! 3125 bytes per second.
+//!
+//! ```
+//! use core::time::Duration;
+//! use midi_convert::throttle::{Link, WireTime};
+//! use midi_types::MidiMessage;
+//!
+//! let mut wire: WireTime = WireTime::new(Link::DIN);
+//! let cc = MidiMessage::ControlChange(0.into(), 7.into(), 100.into());
+//! assert_eq!(wire.advance(&cc), Duration::from_micros(960));
+//! // Running status drops the status byte for the next message
+//! assert_eq!(wire.advance(&cc), Duration::from_micros(640));
+//! ```
+
+use {
+    crate::render::{MidiTransport, encode},
+    core::time::Duration,
+    midi_types::MidiMessage,
+};
+
+/// The baud rate of a DIN midi link
+pub const DIN_BAUD_RATE: u32 = 31250;
+
+/// Bits used on the wire for every byte: a start bit, 8 data bits and a stop bit
+pub const BITS_PER_BYTE: u32 = 10;
+
+/// The bandwidth of a serial midi link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Link {
+    baud_rate: u32,
+}
+
+impl Link {
+    /// A standard DIN midi link
+    pub const DIN: Self = Self::new(DIN_BAUD_RATE);
+
+    /// Create a link with a custom baud rate
+    ///
+    /// # Panics
+    ///
+    /// Panics if `baud_rate` is 0
+    pub const fn new(baud_rate: u32) -> Self {
+        assert!(baud_rate > 0, "baud rate must be positive");
+        Self { baud_rate }
+    }
+
+    /// The baud rate of the link
+    pub const fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    /// The number of bytes the link carries per second
+    pub const fn bytes_per_second(&self) -> u32 {
+        self.baud_rate / BITS_PER_BYTE
+    }
+
+    /// The time it takes to transmit `len` bytes
+    pub const fn byte_time(&self, len: usize) -> Duration {
+        let bits = len as u64 * BITS_PER_BYTE as u64;
+        Duration::from_nanos(bits * 1_000_000_000 / self.baud_rate as u64)
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::DIN
+    }
+}
+
+/// Calculates the on-wire length and duration of messages, tracking running status exactly like the
+/// [`MidiRenderer`](crate::render::MidiRenderer) with the same RUNNING_STATUS setting would.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WireTime<const RUNNING_STATUS: bool = true> {
+    link: Link,
+    running_status: Option<u8>,
+}
+
+impl<const RUNNING_STATUS: bool> WireTime<RUNNING_STATUS> {
+    /// Create a new calculator for `link`
+    pub fn new(link: Link) -> Self {
+        Self {
+            link,
+            running_status: None,
+        }
+    }
+
+    /// The link used for the calculations
+    pub fn link(&self) -> Link {
+        self.link
+    }
+
+    /// The number of bytes `message` would take if it was rendered next, without updating the
+    /// running status
+    pub fn len(&self, message: &MidiMessage) -> usize {
+        let mut running_status = self.running_status;
+        encode::<RUNNING_STATUS>(message, &mut running_status, &mut [0; 3]).len()
+    }
+
+    /// The duration `message` would take on the wire if it was rendered next, without updating the
+    /// running status
+    pub fn duration(&self, message: &MidiMessage) -> Duration {
+        self.link.byte_time(self.len(message))
+    }
+
+    /// Account for `message` being rendered, returning its duration on the wire
+    pub fn advance(&mut self, message: &MidiMessage) -> Duration {
+        let len = encode::<RUNNING_STATUS>(message, &mut self.running_status, &mut [0; 3]).len();
+        self.link.byte_time(len)
+    }
+
+    /// The total duration of a sequence of messages rendered after the current state
+    pub fn total(&mut self, messages: &[MidiMessage]) -> Duration {
+        messages.iter().map(|message| self.advance(message)).sum()
+    }
+
+    /// Forget the running status, for example after the transport was reset
+    pub fn reset(&mut self) {
+        self.running_status = None;
+    }
+}
+
+/// A monotonic time source used for throttling
+pub trait Clock {
+    /// The time elapsed since some fixed point in the past
+    fn now(&self) -> Duration;
+}
+
+/// Errors rendering through a [`ThrottledRenderer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ThrottleError<E> {
+    /// The message would push the backlog past the configured budget, it was not rendered
+    Busy,
+
+    /// The transport failed to write the message
+    Transport(E),
+}
+
+/// A renderer that keeps track of how long the bytes it wrote take on the wire and refuses messages
+/// that would make the backlog exceed a configured budget.
+///
+/// The transport is assumed to send bytes back to back at the link rate, the backlog is the time
+/// still needed to transmit everything that was accepted.
+#[derive(Debug)]
+pub struct ThrottledRenderer<T, C, const RUNNING_STATUS: bool = true> {
+    transport: T,
+    clock: C,
+    link: Link,
+    running_status: Option<u8>,
+    max_backlog: Duration,
+    busy_until: Duration,
+    window_start: Duration,
+    busy: Duration,
+}
+
+impl<T: MidiTransport, C: Clock, const RUNNING_STATUS: bool>
+    ThrottledRenderer<T, C, RUNNING_STATUS>
+{
+    /// Create a new renderer that accepts messages as long as the backlog stays within
+    /// `max_backlog`
+    pub fn new(transport: T, clock: C, link: Link, max_backlog: Duration) -> Self {
+        let now = clock.now();
+        Self {
+            transport,
+            clock,
+            link,
+            running_status: None,
+            max_backlog,
+            busy_until: now,
+            window_start: now,
+            busy: Duration::ZERO,
+        }
+    }
+
+    pub fn release(self) -> (T, C) {
+        (self.transport, self.clock)
+    }
+
+    /// Render a message if the link has room for it
+    pub fn render(&mut self, message: &MidiMessage) -> Result<(), ThrottleError<T::Error>> {
+        let now = self.clock.now();
+        let mut running_status = self.running_status;
+        let mut buf = [0; 3];
+        let bytes = encode::<RUNNING_STATUS>(message, &mut running_status, &mut buf);
+        let duration = self.link.byte_time(bytes.len());
+
+        let backlog = self.busy_until.saturating_sub(now);
+        if backlog + duration > self.max_backlog {
+            return Err(ThrottleError::Busy);
+        }
+
+        self.transport
+            .write(bytes)
+            .map_err(ThrottleError::Transport)?;
+        self.running_status = running_status;
+        self.busy_until = self.busy_until.max(now) + duration;
+        self.busy += duration;
+        Ok(())
+    }
+
+    /// Check if `message` would be accepted right now
+    pub fn would_accept(&self, message: &MidiMessage) -> bool {
+        self.backlog() + self.wire_time(message) <= self.max_backlog
+    }
+
+    /// The on-wire duration of `message` if it was rendered next
+    pub fn wire_time(&self, message: &MidiMessage) -> Duration {
+        let mut running_status = self.running_status;
+        let len = encode::<RUNNING_STATUS>(message, &mut running_status, &mut [0; 3]).len();
+        self.link.byte_time(len)
+    }
+
+    /// The time still needed to transmit everything that was rendered
+    pub fn backlog(&self) -> Duration {
+        self.busy_until.saturating_sub(self.clock.now())
+    }
+
+    /// The number of bytes that are still waiting to be transmitted
+    pub fn backlog_bytes(&self) -> usize {
+        let backlog = self.backlog().as_nanos() * self.link.baud_rate() as u128;
+        backlog.div_ceil(BITS_PER_BYTE as u128 * 1_000_000_000) as usize
+    }
+
+    /// The fraction of link capacity used since the renderer was created or
+    /// [`reset_utilization`](Self::reset_utilization) was called, this is larger than 1.0 while
+    /// the backlog is being transmitted faster than real time allows.
+    pub fn utilization(&self) -> f32 {
+        let elapsed = self.clock.now().saturating_sub(self.window_start);
+        if elapsed.is_zero() {
+            if self.busy.is_zero() { 0.0 } else { 1.0 }
+        } else {
+            self.busy.as_secs_f32() / elapsed.as_secs_f32()
+        }
+    }
+
+    /// Start a new utilization measurement window
+    pub fn reset_utilization(&mut self) {
+        self.window_start = self.clock.now();
+        self.busy = Duration::ZERO;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    #[derive(Debug, Default)]
+    struct MockClock(Cell<Duration>);
+
+    impl MockClock {
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    impl Clock for &MockClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        buffer: Vec<u8>,
+    }
+
+    impl MidiTransport for MockTransport {
+        type Error = ();
+
+        fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.buffer.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn cc(value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(0.into(), 1.into(), value.into())
+    }
+
+    #[test]
+    fn should_calculate_byte_time() {
+        assert_eq!(Link::DIN.bytes_per_second(), 3125);
+        assert_eq!(Link::DIN.byte_time(1), Duration::from_micros(320));
+        assert_eq!(Link::DIN.byte_time(3125), Duration::from_secs(1));
+        assert_eq!(Link::new(38400).byte_time(3), Duration::from_nanos(781_250));
+    }
+
+    #[test]
+    fn should_account_for_running_status() {
+        let mut wire: WireTime = WireTime::new(Link::DIN);
+        assert_eq!(wire.len(&cc(1)), 3);
+        assert_eq!(wire.advance(&cc(1)), Duration::from_micros(960));
+        assert_eq!(wire.len(&cc(2)), 2);
+        assert_eq!(
+            wire.advance(&MidiMessage::TimingClock),
+            Duration::from_micros(320)
+        );
+        assert_eq!(wire.len(&cc(2)), 2);
+        assert_eq!(
+            wire.advance(&MidiMessage::SongSelect(1.into())),
+            Duration::from_micros(640)
+        );
+        assert_eq!(wire.len(&cc(2)), 3);
+    }
+
+    #[test]
+    fn should_ignore_running_status_when_disabled() {
+        let mut wire: WireTime<false> = WireTime::new(Link::DIN);
+        assert_eq!(
+            wire.total(&[cc(1), cc(2), cc(3)]),
+            Duration::from_micros(3 * 960)
+        );
+    }
+
+    #[test]
+    fn should_refuse_messages_over_budget() {
+        let clock = MockClock::default();
+        let mut renderer: ThrottledRenderer<_, _> = ThrottledRenderer::new(
+            MockTransport::default(),
+            &clock,
+            Link::DIN,
+            Duration::from_micros(2000),
+        );
+
+        // 960 + 640 + 640 > 2000
+        assert_eq!(renderer.render(&cc(1)), Ok(()));
+        assert_eq!(renderer.render(&cc(2)), Ok(()));
+        assert!(!renderer.would_accept(&cc(3)));
+        assert_eq!(renderer.render(&cc(3)), Err(ThrottleError::Busy));
+        assert_eq!(renderer.backlog(), Duration::from_micros(1600));
+        assert_eq!(renderer.backlog_bytes(), 5);
+
+        // The refused message did not touch running status
+        clock.advance(Duration::from_micros(400));
+        assert_eq!(renderer.backlog(), Duration::from_micros(1200));
+        assert_eq!(renderer.backlog_bytes(), 4);
+        assert_eq!(renderer.render(&cc(3)), Ok(()));
+
+        let (transport, _) = renderer.release();
+        assert_eq!(
+            transport.buffer,
+            &[0xB0, 0x01, 0x01, 0x01, 0x02, 0x01, 0x03]
+        );
+    }
+
+    #[test]
+    fn should_drain_backlog_over_time() {
+        let clock = MockClock::default();
+        let mut renderer: ThrottledRenderer<_, _, false> = ThrottledRenderer::new(
+            MockTransport::default(),
+            &clock,
+            Link::DIN,
+            Duration::from_millis(10),
+        );
+
+        renderer.render(&cc(1)).unwrap();
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(renderer.backlog(), Duration::ZERO);
+        assert_eq!(renderer.backlog_bytes(), 0);
+
+        // An idle link does not build up credit
+        renderer.render(&cc(2)).unwrap();
+        assert_eq!(renderer.backlog(), Duration::from_micros(960));
+    }
+
+    #[test]
+    fn should_report_utilization() {
+        let clock = MockClock::default();
+        let mut renderer: ThrottledRenderer<_, _> = ThrottledRenderer::new(
+            MockTransport::default(),
+            &clock,
+            Link::DIN,
+            Duration::from_millis(10),
+        );
+        assert_eq!(renderer.utilization(), 0.0);
+
+        renderer.render(&MidiMessage::TimingClock).unwrap();
+        renderer.render(&MidiMessage::TimingClock).unwrap();
+        clock.advance(Duration::from_micros(1280));
+        assert_eq!(renderer.utilization(), 0.5);
+
+        renderer.reset_utilization();
+        clock.advance(Duration::from_micros(1280));
+        assert_eq!(renderer.utilization(), 0.0);
+    }
+}
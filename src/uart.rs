@@ -0,0 +1,412 @@
+//! Bit level MIDI framing for software UARTs
+//!
+//! MIDI is an asynchronous serial stream at 31250 baud. Every byte is sent as a frame of 10 bits:
+//! a low start bit, the 8 data bits least significant bit first and a high stop bit. The line is
+//! high while idle.
+//!
+//! For outputs driven by a GPIO or a PIO state machine, [`frame`] builds the frame of a byte as a
+//! word to shift out, [`bits`] yields the line levels bit by bit and [`pulses`] yields how long the
+//! line stays at each level. [`FrameWriter`] is a [`MidiTransport`] handing frames to a closure,
+//! so a [`MidiRenderer`](crate::render::MidiRenderer) can drive it directly.
+//!
+//! For inputs, [`UartDecoder`] reconstructs bytes from pin samples taken at a multiple of the baud
+//! rate and [`MidiUartDecoder`] feeds them to a [`MidiParser`].
+//!
+//! ```
+//! use midi_convert::uart::{MidiUartDecoder, bits};
+//! use midi_types::MidiMessage;
+//!
+//! let mut decoder = MidiUartDecoder::new(4);
+//! let samples = bits(&[0x92, 0x76, 0x34]).flat_map(|level| [level; 4]);
+//! let messages: Vec<_> = samples.filter_map(|sample| decoder.push(sample)).collect();
+//! assert_eq!(messages, [Ok(MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into()))]);
+//! ```
+
+use {
+    crate::{parse::MidiParser, render::MidiTransport},
+    core::time::Duration,
+    midi_types::MidiMessage,
+};
+
+/// The MIDI baud rate
+pub const BAUD_RATE: u32 = 31250;
+
+/// The duration of a bit, 32 µs
+pub const BIT_TIME: Duration = Duration::from_micros(1_000_000 / BAUD_RATE as u64);
+
+/// The number of bits in a frame
+pub const FRAME_BITS: u8 = 10;
+
+/// Errors decoding frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UartError {
+    /// The stop bit of a frame was low, the byte read from the data bits is included
+    Framing(u8),
+}
+
+/// The frame of `byte`, to be shifted out least significant bit first
+///
+/// Bit 0 is the start bit, bits 1 to 8 the data bits and bit 9 the stop bit.
+pub const fn frame(byte: u8) -> u16 {
+    (byte as u16) << 1 | 1 << 9
+}
+
+/// The line levels of the frames of `bytes`, `true` is high
+pub fn bits(bytes: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..FRAME_BITS).map(move |bit| frame(*byte) >> bit & 1 != 0))
+}
+
+/// A period during which the line stays at one level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pulse {
+    /// The level of the line, `true` is high
+    pub level: bool,
+
+    /// The length in bits
+    pub bits: u8,
+}
+
+impl Pulse {
+    /// The duration of the pulse
+    pub fn duration(&self) -> Duration {
+        BIT_TIME * u32::from(self.bits)
+    }
+
+    /// The length of the pulse in ticks of a timer running at `clock_hz`, rounded to the nearest
+    /// tick
+    pub fn ticks(&self, clock_hz: u32) -> u32 {
+        let ticks = u64::from(clock_hz) * u64::from(self.bits);
+        ((ticks + u64::from(BAUD_RATE) / 2) / u64::from(BAUD_RATE)) as u32
+    }
+}
+
+/// The pulses of the frame of `byte`, starting with the start bit and ending with the stop bit
+///
+/// Consecutive bits at the same level form one pulse, so a frame has 2 to 10 pulses.
+pub fn pulses(byte: u8) -> impl Iterator<Item = Pulse> {
+    let frame = frame(byte);
+    let mut bit = 0;
+    core::iter::from_fn(move || {
+        if bit >= FRAME_BITS {
+            return None;
+        }
+        let level = frame >> bit & 1 != 0;
+        let start = bit;
+        while bit < FRAME_BITS && (frame >> bit & 1 != 0) == level {
+            bit += 1;
+        }
+        Some(Pulse {
+            level,
+            bits: bit - start,
+        })
+    })
+}
+
+/// A transport handing the frame of every rendered byte to a closure, for instance to push it
+/// into a PIO FIFO
+#[derive(Debug)]
+pub struct FrameWriter<F> {
+    write: F,
+}
+
+impl<F, E> FrameWriter<F>
+where
+    F: FnMut(u16) -> Result<(), E>,
+{
+    /// Create a transport calling `write` with the frame of every byte
+    pub fn new(write: F) -> Self {
+        Self { write }
+    }
+
+    /// Release the closure
+    pub fn release(self) -> F {
+        self.write
+    }
+}
+
+impl<F, E> MidiTransport for FrameWriter<F>
+where
+    F: FnMut(u16) -> Result<(), E>,
+{
+    type Error = E;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        for byte in bytes {
+            (self.write)(frame(*byte))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reconstructs bytes from pin samples taken at `oversampling` times the baud rate
+///
+/// A frame starts at a falling edge after the line was high. Every bit is read from the sample
+/// nearest to its center. A start bit that is high again at its center is taken as a glitch and
+/// ignored.
+#[derive(Debug, Clone)]
+pub struct UartDecoder {
+    oversampling: u8,
+    last: bool,
+    /// Samples since the falling edge of the current frame
+    position: Option<u16>,
+    byte: u8,
+}
+
+impl UartDecoder {
+    /// Create a decoder for samples taken at `oversampling` times the baud rate
+    ///
+    /// # Panics
+    ///
+    /// When `oversampling` is less than 3
+    pub fn new(oversampling: u8) -> Self {
+        assert!(oversampling >= 3, "oversampling must be at least 3");
+        Self {
+            oversampling,
+            last: true,
+            position: None,
+            byte: 0,
+        }
+    }
+
+    /// The number of samples per bit
+    pub fn oversampling(&self) -> u8 {
+        self.oversampling
+    }
+
+    /// Push the next sample, `true` is high, returning a byte when its stop bit was read
+    pub fn push(&mut self, sample: bool) -> Option<Result<u8, UartError>> {
+        let last = core::mem::replace(&mut self.last, sample);
+        let Some(position) = self.position else {
+            if last && !sample {
+                self.position = Some(0);
+                self.byte = 0;
+            }
+            return None;
+        };
+
+        let position = position + 1;
+        self.position = Some(position);
+        let oversampling = u16::from(self.oversampling);
+        if position % oversampling != oversampling / 2 {
+            return None;
+        }
+        match position / oversampling {
+            0 if sample => self.position = None,
+            0 => {}
+            bit @ 1..=8 => self.byte |= u8::from(sample) << (bit - 1),
+            _ => {
+                // The frame ends at the center of the stop bit, leaving time to find the next
+                // falling edge
+                self.position = None;
+                // After a low stop bit the next start bit is only found once the line was high
+                return Some(if sample {
+                    Ok(self.byte)
+                } else {
+                    Err(UartError::Framing(self.byte))
+                });
+            }
+        }
+        None
+    }
+
+    /// Forget a partially received frame
+    pub fn reset(&mut self) {
+        self.last = true;
+        self.position = None;
+    }
+}
+
+/// Decodes MIDI messages from pin samples, see [`UartDecoder`]
+///
+/// Bytes with framing errors are dropped and reported, the parser state is kept.
+#[derive(Debug, Clone)]
+pub struct MidiUartDecoder {
+    decoder: UartDecoder,
+    parser: MidiParser,
+}
+
+impl MidiUartDecoder {
+    /// Create a decoder for samples taken at `oversampling` times the baud rate
+    ///
+    /// # Panics
+    ///
+    /// When `oversampling` is less than 3
+    pub fn new(oversampling: u8) -> Self {
+        Self {
+            decoder: UartDecoder::new(oversampling),
+            parser: MidiParser::new(),
+        }
+    }
+
+    /// Push the next sample, `true` is high, returning a message when one is complete
+    pub fn push(&mut self, sample: bool) -> Option<Result<MidiMessage, UartError>> {
+        match self.decoder.push(sample)? {
+            Ok(byte) => self.parser.parse(byte).map(Ok),
+            Err(error) => Some(Err(error)),
+        }
+    }
+
+    /// Forget a partially received frame and message
+    pub fn reset(&mut self) {
+        self.decoder.reset();
+        self.parser = MidiParser::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::render::MidiRenderer,
+        std::{vec, vec::Vec},
+    };
+
+    /// Sample a line carrying `bits`, preceded and followed by idle time, `rate` times per bit
+    /// with the first sample taken `offset` samples after the start
+    fn samples(bits: impl IntoIterator<Item = bool>, rate: f64, offset: f64) -> Vec<bool> {
+        let mut levels = vec![true; 2];
+        levels.extend(bits);
+        levels.extend([true; 2]);
+        (0..)
+            .map(|sample| (f64::from(sample) + offset) / rate)
+            .take_while(|time| *time < levels.len() as f64)
+            .map(|time| levels[time as usize])
+            .collect()
+    }
+
+    fn decode(decoder: &mut UartDecoder, samples: &[bool]) -> Vec<Result<u8, UartError>> {
+        samples
+            .iter()
+            .filter_map(|sample| decoder.push(*sample))
+            .collect()
+    }
+
+    #[test]
+    fn should_frame_bytes() {
+        assert_eq!(frame(0x00), 0b10_0000_0000);
+        assert_eq!(frame(0xff), 0b11_1111_1110);
+        assert_eq!(frame(0x90), 0b11_0010_0000);
+        let levels: Vec<_> = bits(&[0x90]).map(u8::from).collect();
+        assert_eq!(levels, [0, 0, 0, 0, 0, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn should_time_pulses() {
+        let runs: Vec<_> = pulses(0x90).map(|p| (p.level, p.bits)).collect();
+        assert_eq!(runs, [(false, 5), (true, 1), (false, 2), (true, 2)]);
+
+        let all: Vec<_> = pulses(0x55).map(|p| p.bits).collect();
+        assert_eq!(all, [1; 10]);
+        let pulse = pulses(0xff).next().unwrap();
+        assert_eq!(pulse.duration(), Duration::from_micros(32));
+        assert_eq!(pulse.ticks(1_000_000), 32);
+        assert_eq!(pulse.ticks(125_000_000), 4000);
+        assert_eq!(pulses(0x00).last().unwrap().ticks(48_000), 2);
+    }
+
+    #[test]
+    fn should_render_frames() {
+        let mut frames = Vec::new();
+        let mut renderer: MidiRenderer<_, true> = MidiRenderer::new(FrameWriter::new(|frame| {
+            frames.push(frame);
+            Ok::<_, ()>(())
+        }));
+        renderer
+            .render(&MidiMessage::NoteOn(0.into(), 0x3c.into(), 0x40.into()))
+            .unwrap();
+        renderer
+            .render(&MidiMessage::NoteOn(0.into(), 0x3e.into(), 0x40.into()))
+            .unwrap();
+        assert_eq!(frames, [0x90, 0x3c, 0x40, 0x3e, 0x40].map(frame));
+    }
+
+    #[test]
+    fn should_decode_at_any_phase_and_oversampling() {
+        let bytes = [0x00, 0xff, 0x55, 0xaa, 0x90, 0x3c, 0x7f, 0x01, 0x80];
+        for oversampling in [3, 4, 8, 16] {
+            for offset in [0.0, 0.25, 0.5, 0.75] {
+                let mut decoder = UartDecoder::new(oversampling);
+                let samples = samples(bits(&bytes), f64::from(oversampling), offset);
+                assert_eq!(
+                    decode(&mut decoder, &samples),
+                    bytes.map(Ok),
+                    "oversampling {oversampling}, offset {offset}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn should_tolerate_clock_mismatch() {
+        let bytes = [0x00, 0xf0, 0x0f, 0x33];
+        for rate in [16.0 * 0.97, 16.0 * 1.03] {
+            let mut decoder = UartDecoder::new(16);
+            let samples = samples(bits(&bytes), rate, 0.0);
+            assert_eq!(decode(&mut decoder, &samples), bytes.map(Ok), "rate {rate}");
+        }
+    }
+
+    #[test]
+    fn should_report_framing_errors() {
+        let mut decoder = UartDecoder::new(8);
+        // A break: the line stays low through the stop bit
+        let mut levels = vec![false; 12];
+        levels.push(true);
+        levels.extend(bits(&[0x42]));
+        let decoded = decode(&mut decoder, &samples(levels, 8.0, 0.0));
+        assert_eq!(decoded, [Err(UartError::Framing(0)), Ok(0x42)]);
+
+        // A low stop bit
+        let mut levels: Vec<_> = bits(&[0x81]).collect();
+        levels[9] = false;
+        levels.push(true);
+        levels.extend(bits(&[0x90]));
+        let decoded = decode(&mut decoder, &samples(levels, 8.0, 0.0));
+        assert_eq!(decoded, [Err(UartError::Framing(0x81)), Ok(0x90)]);
+    }
+
+    #[test]
+    fn should_ignore_glitches() {
+        let mut decoder = UartDecoder::new(8);
+        let mut samples = samples(bits(&[0x3c]), 8.0, 0.0);
+        samples[3] = false;
+        samples[4] = false;
+        assert_eq!(decode(&mut decoder, &samples), [Ok(0x3c)]);
+    }
+
+    #[test]
+    fn should_decode_messages() {
+        let mut decoder = MidiUartDecoder::new(4);
+        let mut levels: Vec<_> = bits(&[0x92, 0x76, 0x34, 0x77]).collect();
+        // Corrupt the stop bit of the last byte, its message is lost but running status is kept
+        levels[39] = false;
+        levels.push(true);
+        levels.extend(bits(&[0x78, 0x00, 0xf8]));
+        let messages: Vec<_> = samples(levels, 4.0, 0.5)
+            .into_iter()
+            .filter_map(|sample| decoder.push(sample))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                Ok(MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into())),
+                Err(UartError::Framing(0x77)),
+                Ok(MidiMessage::NoteOn(2.into(), 0x78.into(), 0.into())),
+                Ok(MidiMessage::TimingClock),
+            ]
+        );
+
+        decoder.push(false);
+        decoder.reset();
+        let messages: Vec<_> = samples(bits(&[0x00, 0xfa]), 4.0, 0.0)
+            .into_iter()
+            .filter_map(|sample| decoder.push(sample))
+            .collect();
+        assert_eq!(messages, [Ok(MidiMessage::Start)]);
+    }
+}
@@ -0,0 +1,1353 @@
+//! MIDI 2.0 Universal MIDI Packets
+//!
+//! A packet is one to four 32-bit words, the message type in the top nibble of the first word
+//! sets its size. [`UmpMessage::parse`] reads the packet at the start of a word slice and
+//! [`UmpMessage::render`] writes one.
+//!
+//! ```
+//! use midi_convert::ump::{Group, Midi2ChannelVoice, UmpMessage};
+//!
+//! let words = [0x4093_3C00, 0xC000_0000];
+//! let message = UmpMessage::Midi2ChannelVoice {
+//!     group: Group::new(0),
+//!     channel: 3.into(),
+//!     message: Midi2ChannelVoice::NoteOn {
+//!         note: 60.into(),
+//!         velocity: 0xC000,
+//!         attribute_type: 0,
+//!         attribute: 0,
+//!     },
+//! };
+//! assert_eq!(UmpMessage::parse(&words), Ok(message));
+//!
+//! let mut buf = [0; 4];
+//! assert_eq!(message.render(&mut buf), Ok(2));
+//! assert_eq!(buf[..2], words);
+//! ```
+
+use {
+    crate::{parse::MidiTryParseSlice, render::encode},
+    midi_types::{Channel, MidiMessage, Note, Value14},
+};
+
+pub mod bytes;
+#[cfg(feature = "std")]
+pub mod clip;
+pub mod flex;
+pub mod net;
+mod segment;
+pub mod stream;
+pub mod sysex;
+pub mod timestamp;
+pub mod translate;
+
+/// Errors parsing or rendering packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UmpError {
+    /// Buffer wasn't long enough for the packet
+    BufferTooShort,
+
+    /// The message type is reserved, [`message_words`] gives its size
+    UnknownMessageType,
+
+    /// The status or opcode isn't defined for the message type
+    InvalidStatus,
+
+    /// A field holds a value it can't take
+    InvalidData,
+}
+
+/// A UMP group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Group(u8);
+
+impl Group {
+    /// Create a new `Group` from the 0 based group, clamped to 0..15
+    #[must_use]
+    pub const fn new(group: u8) -> Self {
+        debug_assert!(group <= 15, "Group exceeds valid range");
+        Self(if group > 15 { 15 } else { group })
+    }
+}
+
+impl From<u8> for Group {
+    fn from(group: u8) -> Self {
+        Self::new(group)
+    }
+}
+
+impl From<Group> for u8 {
+    fn from(group: Group) -> Self {
+        group.0
+    }
+}
+
+/// Where a packet sits in a message spread over several packets
+///
+/// This is the status of SysEx packets and the format of flex data and stream messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Form {
+    /// The whole message is in this packet
+    Complete,
+    /// The first packet
+    Start,
+    /// A packet between the first and the last
+    Continue,
+    /// The last packet
+    End,
+}
+
+impl Form {
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0x3 {
+            0 => Self::Complete,
+            1 => Self::Start,
+            2 => Self::Continue,
+            _ => Self::End,
+        }
+    }
+
+    const fn bits(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The number of words in the packet starting with `word`, reserved message types included
+pub const fn message_words(word: u32) -> usize {
+    match word >> 28 {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// Utility messages, these have no group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Utility {
+    /// No operation
+    NoOp,
+    /// Jitter reduction clock, the sender time in 1/31250 s
+    JrClock(u16),
+    /// Jitter reduction timestamp of the following message, in 1/31250 s
+    JrTimestamp(u16),
+    /// Delta clockstamp ticks per quarter note
+    TicksPerQuarterNote(u16),
+    /// Ticks since the last event, 20 bits
+    DeltaClockstamp(u32),
+}
+
+/// A 7-bit system exclusive packet, without the `0xF0` and `0xF7` framing bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SysEx7 {
+    group: Group,
+    form: Form,
+    len: u8,
+    data: [u8; 6],
+}
+
+impl SysEx7 {
+    /// The most bytes a packet holds
+    pub const MAX_LEN: usize = 6;
+
+    /// A packet holding up to 6 data bytes
+    pub fn new(group: Group, form: Form, data: &[u8]) -> Result<Self, UmpError> {
+        if data.len() > Self::MAX_LEN || data.iter().any(|b| b & 0x80 != 0) {
+            return Err(UmpError::InvalidData);
+        }
+        let mut packet = Self {
+            group,
+            form,
+            len: data.len() as u8,
+            data: [0; 6],
+        };
+        packet.data[..data.len()].copy_from_slice(data);
+        Ok(packet)
+    }
+
+    /// The group
+    pub fn group(&self) -> Group {
+        self.group
+    }
+
+    /// Where the packet sits in the message
+    pub fn form(&self) -> Form {
+        self.form
+    }
+
+    /// The data bytes
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// An 8-bit system exclusive packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SysEx8 {
+    group: Group,
+    form: Form,
+    stream: u8,
+    len: u8,
+    data: [u8; 13],
+}
+
+impl SysEx8 {
+    /// The most bytes a packet holds
+    pub const MAX_LEN: usize = 13;
+
+    /// A packet of `stream` holding up to 13 data bytes
+    pub fn new(group: Group, form: Form, stream: u8, data: &[u8]) -> Result<Self, UmpError> {
+        if data.len() > Self::MAX_LEN {
+            return Err(UmpError::InvalidData);
+        }
+        let mut packet = Self {
+            group,
+            form,
+            stream,
+            len: data.len() as u8,
+            data: [0; 13],
+        };
+        packet.data[..data.len()].copy_from_slice(data);
+        Ok(packet)
+    }
+
+    /// The group
+    pub fn group(&self) -> Group {
+        self.group
+    }
+
+    /// Where the packet sits in the message
+    pub fn form(&self) -> Form {
+        self.form
+    }
+
+    /// The stream id
+    pub fn stream(&self) -> u8 {
+        self.stream
+    }
+
+    /// The data bytes
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Mixed data set packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MixedDataSet {
+    /// The header of a chunk
+    Header {
+        /// The group
+        group: Group,
+        /// The mixed data set id, 4 bits
+        id: u8,
+        /// The number of valid bytes in the chunk
+        valid_bytes: u16,
+        /// The number of chunks in the set
+        chunks: u16,
+        /// The number of this chunk, from 1
+        chunk: u16,
+        /// The manufacturer id
+        manufacturer: u16,
+        /// The device id
+        device: u16,
+        /// Sub id #1 and #2
+        sub_id: [u16; 2],
+    },
+    /// 14 bytes of a chunk
+    Payload {
+        /// The group
+        group: Group,
+        /// The mixed data set id, 4 bits
+        id: u8,
+        /// The payload
+        data: [u8; 14],
+    },
+}
+
+/// MIDI 2.0 channel voice messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Midi2ChannelVoice {
+    /// Registered per-note controller
+    RegisteredPerNoteController {
+        /// The note
+        note: Note,
+        /// The controller
+        index: u8,
+        /// The value
+        data: u32,
+    },
+    /// Assignable per-note controller
+    AssignablePerNoteController {
+        /// The note
+        note: Note,
+        /// The controller
+        index: u8,
+        /// The value
+        data: u32,
+    },
+    /// Registered controller (RPN)
+    RegisteredController {
+        /// The bank, 7 bits
+        bank: u8,
+        /// The controller, 7 bits
+        index: u8,
+        /// The value
+        data: u32,
+    },
+    /// Assignable controller (NRPN)
+    AssignableController {
+        /// The bank, 7 bits
+        bank: u8,
+        /// The controller, 7 bits
+        index: u8,
+        /// The value
+        data: u32,
+    },
+    /// Relative registered controller
+    RelativeRegisteredController {
+        /// The bank, 7 bits
+        bank: u8,
+        /// The controller, 7 bits
+        index: u8,
+        /// The change
+        data: i32,
+    },
+    /// Relative assignable controller
+    RelativeAssignableController {
+        /// The bank, 7 bits
+        bank: u8,
+        /// The controller, 7 bits
+        index: u8,
+        /// The change
+        data: i32,
+    },
+    /// Per-note pitch bend, centered at `0x8000_0000`
+    PerNotePitchBend {
+        /// The note
+        note: Note,
+        /// The bend
+        data: u32,
+    },
+    /// Note off
+    NoteOff {
+        /// The note
+        note: Note,
+        /// The velocity
+        velocity: u16,
+        /// The attribute type, 0 for none
+        attribute_type: u8,
+        /// The attribute value
+        attribute: u16,
+    },
+    /// Note on, a velocity of 0 is not a note off
+    NoteOn {
+        /// The note
+        note: Note,
+        /// The velocity
+        velocity: u16,
+        /// The attribute type, 0 for none
+        attribute_type: u8,
+        /// The attribute value
+        attribute: u16,
+    },
+    /// Polyphonic key pressure
+    KeyPressure {
+        /// The note
+        note: Note,
+        /// The pressure
+        data: u32,
+    },
+    /// Control change
+    ControlChange {
+        /// The controller, 7 bits
+        index: u8,
+        /// The value
+        data: u32,
+    },
+    /// Program change, with an optional bank select
+    ProgramChange {
+        /// The program, 7 bits
+        program: u8,
+        /// The bank
+        bank: Option<Value14>,
+    },
+    /// Channel pressure
+    ChannelPressure(u32),
+    /// Pitch bend, centered at `0x8000_0000`
+    PitchBendChange(u32),
+    /// Per-note management
+    PerNoteManagement {
+        /// The note
+        note: Note,
+        /// Detach per-note controllers from previously received notes
+        detach: bool,
+        /// Reset per-note controllers to their defaults
+        reset: bool,
+    },
+}
+
+impl Midi2ChannelVoice {
+    fn decode(opcode: u8, b2: u8, b3: u8, data: u32) -> Result<Self, UmpError> {
+        let note = Note::new(b2 & 0x7f);
+        let (bank, index) = (b2 & 0x7f, b3 & 0x7f);
+        Ok(match opcode {
+            0x0 => Self::RegisteredPerNoteController {
+                note,
+                index: b3,
+                data,
+            },
+            0x1 => Self::AssignablePerNoteController {
+                note,
+                index: b3,
+                data,
+            },
+            0x2 => Self::RegisteredController { bank, index, data },
+            0x3 => Self::AssignableController { bank, index, data },
+            0x4 => Self::RelativeRegisteredController {
+                bank,
+                index,
+                data: data as i32,
+            },
+            0x5 => Self::RelativeAssignableController {
+                bank,
+                index,
+                data: data as i32,
+            },
+            0x6 => Self::PerNotePitchBend { note, data },
+            0x8 | 0x9 => {
+                let (velocity, attribute) = ((data >> 16) as u16, data as u16);
+                if opcode == 0x8 {
+                    Self::NoteOff {
+                        note,
+                        velocity,
+                        attribute_type: b3,
+                        attribute,
+                    }
+                } else {
+                    Self::NoteOn {
+                        note,
+                        velocity,
+                        attribute_type: b3,
+                        attribute,
+                    }
+                }
+            }
+            0xA => Self::KeyPressure { note, data },
+            0xB => Self::ControlChange { index: bank, data },
+            0xC => Self::ProgramChange {
+                program: (data >> 24) as u8 & 0x7f,
+                bank: (b3 & 0x1 != 0)
+                    .then(|| Value14::new((data >> 8) as u8 & 0x7f, data as u8 & 0x7f)),
+            },
+            0xD => Self::ChannelPressure(data),
+            0xE => Self::PitchBendChange(data),
+            0xF => Self::PerNoteManagement {
+                note,
+                detach: b3 & 0x2 != 0,
+                reset: b3 & 0x1 != 0,
+            },
+            _ => return Err(UmpError::InvalidStatus),
+        })
+    }
+
+    /// The opcode, the two bytes following the channel and the data word
+    fn encode(&self) -> Result<(u8, u8, u8, u32), UmpError> {
+        Ok(match *self {
+            Self::RegisteredPerNoteController { note, index, data } => {
+                (0x0, note.into(), index, data)
+            }
+            Self::AssignablePerNoteController { note, index, data } => {
+                (0x1, note.into(), index, data)
+            }
+            Self::RegisteredController { bank, index, data } => (0x2, u7(bank)?, u7(index)?, data),
+            Self::AssignableController { bank, index, data } => (0x3, u7(bank)?, u7(index)?, data),
+            Self::RelativeRegisteredController { bank, index, data } => {
+                (0x4, u7(bank)?, u7(index)?, data as u32)
+            }
+            Self::RelativeAssignableController { bank, index, data } => {
+                (0x5, u7(bank)?, u7(index)?, data as u32)
+            }
+            Self::PerNotePitchBend { note, data } => (0x6, note.into(), 0, data),
+            Self::NoteOff {
+                note,
+                velocity,
+                attribute_type,
+                attribute,
+            } => (
+                0x8,
+                note.into(),
+                attribute_type,
+                u32::from(velocity) << 16 | u32::from(attribute),
+            ),
+            Self::NoteOn {
+                note,
+                velocity,
+                attribute_type,
+                attribute,
+            } => (
+                0x9,
+                note.into(),
+                attribute_type,
+                u32::from(velocity) << 16 | u32::from(attribute),
+            ),
+            Self::KeyPressure { note, data } => (0xA, note.into(), 0, data),
+            Self::ControlChange { index, data } => (0xB, u7(index)?, 0, data),
+            Self::ProgramChange { program, bank } => {
+                let (msb, lsb): (u8, u8) = bank.map_or((0, 0), Into::into);
+                (
+                    0xC,
+                    0,
+                    u8::from(bank.is_some()),
+                    u32::from(u7(program)?) << 24 | u32::from(msb) << 8 | u32::from(lsb),
+                )
+            }
+            Self::ChannelPressure(data) => (0xD, 0, 0, data),
+            Self::PitchBendChange(data) => (0xE, 0, 0, data),
+            Self::PerNoteManagement {
+                note,
+                detach,
+                reset,
+            } => (0xF, note.into(), u8::from(detach) << 1 | u8::from(reset), 0),
+        })
+    }
+}
+
+/// How a flex data message is addressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlexAddress {
+    /// A channel of the group
+    Channel(Channel),
+    /// The whole group
+    Group,
+}
+
+/// A flex data packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FlexData {
+    /// The group
+    pub group: Group,
+    /// Where the packet sits in the message
+    pub form: Form,
+    /// The destination
+    pub address: FlexAddress,
+    /// The status bank
+    pub status_bank: u8,
+    /// The status
+    pub status: u8,
+    /// The last three words, as big endian bytes
+    pub data: [u8; 12],
+}
+
+/// A UMP stream packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamMessage {
+    /// Where the packet sits in the message
+    pub form: Form,
+    /// The status, 10 bits
+    pub status: u16,
+    /// The packet after the status, as big endian bytes
+    pub data: [u8; 14],
+}
+
+/// A Universal MIDI Packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UmpMessage {
+    /// Utility message, message type 0x0
+    Utility(Utility),
+    /// System common or real time message, message type 0x1
+    System {
+        /// The group
+        group: Group,
+        /// The message, a system common or real time one
+        message: MidiMessage,
+    },
+    /// MIDI 1.0 channel voice message, message type 0x2
+    Midi1ChannelVoice {
+        /// The group
+        group: Group,
+        /// The message, a channel voice one
+        message: MidiMessage,
+    },
+    /// 7-bit system exclusive, message type 0x3
+    SysEx7(SysEx7),
+    /// MIDI 2.0 channel voice message, message type 0x4
+    Midi2ChannelVoice {
+        /// The group
+        group: Group,
+        /// The channel
+        channel: Channel,
+        /// The message
+        message: Midi2ChannelVoice,
+    },
+    /// 8-bit system exclusive, message type 0x5
+    SysEx8(SysEx8),
+    /// Mixed data set, message type 0x5
+    MixedDataSet(MixedDataSet),
+    /// Flex data, message type 0xD
+    FlexData(FlexData),
+    /// UMP stream message, message type 0xF
+    Stream(StreamMessage),
+}
+
+impl UmpMessage {
+    /// Parse the packet at the start of `words`
+    ///
+    /// MIDI 1.0 control changes of controller 127 and program changes to program 127 can't be
+    /// represented by `midi-types` and give [`UmpError::InvalidData`].
+    pub fn parse(words: &[u32]) -> Result<Self, UmpError> {
+        let first = *words.first().ok_or(UmpError::BufferTooShort)?;
+        let words = words
+            .get(..message_words(first))
+            .ok_or(UmpError::BufferTooShort)?;
+        let mut bytes = [0; 16];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+
+        let group = Group::new(bytes[0] & 0xf);
+        let status = bytes[1] >> 4;
+        let len = usize::from(bytes[1] & 0xf);
+        match bytes[0] >> 4 {
+            0x0 => {
+                let data = first & 0xf_ffff;
+                Ok(Self::Utility(match status {
+                    0x0 => Utility::NoOp,
+                    0x1 => Utility::JrClock(data as u16),
+                    0x2 => Utility::JrTimestamp(data as u16),
+                    0x3 => Utility::TicksPerQuarterNote(data as u16),
+                    0x4 => Utility::DeltaClockstamp(data),
+                    _ => return Err(UmpError::InvalidStatus),
+                }))
+            }
+            0x1 => {
+                if bytes[1] < 0xf0 {
+                    return Err(UmpError::InvalidStatus);
+                }
+                let message = midi1(&bytes[1..4])?;
+                Ok(Self::System { group, message })
+            }
+            0x2 => {
+                if bytes[1] >= 0xf0 {
+                    return Err(UmpError::InvalidStatus);
+                }
+                let message = midi1(&bytes[1..4])?;
+                Ok(Self::Midi1ChannelVoice { group, message })
+            }
+            0x3 => {
+                if status > 0x3 {
+                    return Err(UmpError::InvalidStatus);
+                }
+                let mut data = [0; 6];
+                for (d, b) in data.iter_mut().zip(&bytes[2..8]) {
+                    *d = b & 0x7f;
+                }
+                let form = Form::from_bits(status);
+                Ok(Self::SysEx7(SysEx7::new(
+                    group,
+                    form,
+                    data.get(..len).ok_or(UmpError::InvalidData)?,
+                )?))
+            }
+            0x4 => {
+                let data = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+                Ok(Self::Midi2ChannelVoice {
+                    group,
+                    channel: Channel::new(bytes[1] & 0xf),
+                    message: Midi2ChannelVoice::decode(status, bytes[2], bytes[3], data)?,
+                })
+            }
+            0x5 => match status {
+                0x0..=0x3 => {
+                    let data = len
+                        .checked_sub(1)
+                        .and_then(|len| bytes[3..].get(..len))
+                        .ok_or(UmpError::InvalidData)?;
+                    let form = Form::from_bits(status);
+                    Ok(Self::SysEx8(SysEx8::new(group, form, bytes[2], data)?))
+                }
+                0x8 => {
+                    let half = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+                    Ok(Self::MixedDataSet(MixedDataSet::Header {
+                        group,
+                        id: bytes[1] & 0xf,
+                        valid_bytes: half(2),
+                        chunks: half(4),
+                        chunk: half(6),
+                        manufacturer: half(8),
+                        device: half(10),
+                        sub_id: [half(12), half(14)],
+                    }))
+                }
+                0x9 => {
+                    let mut data = [0; 14];
+                    data.copy_from_slice(&bytes[2..]);
+                    Ok(Self::MixedDataSet(MixedDataSet::Payload {
+                        group,
+                        id: bytes[1] & 0xf,
+                        data,
+                    }))
+                }
+                _ => Err(UmpError::InvalidStatus),
+            },
+            0xD => {
+                let address = match (bytes[1] >> 4) & 0x3 {
+                    0 => FlexAddress::Channel(Channel::new(bytes[1] & 0xf)),
+                    1 => FlexAddress::Group,
+                    _ => return Err(UmpError::InvalidData),
+                };
+                let mut data = [0; 12];
+                data.copy_from_slice(&bytes[4..]);
+                Ok(Self::FlexData(FlexData {
+                    group,
+                    form: Form::from_bits(bytes[1] >> 6),
+                    address,
+                    status_bank: bytes[2],
+                    status: bytes[3],
+                    data,
+                }))
+            }
+            0xF => {
+                let mut data = [0; 14];
+                data.copy_from_slice(&bytes[2..]);
+                Ok(Self::Stream(StreamMessage {
+                    form: Form::from_bits(bytes[0] >> 2),
+                    status: u16::from_be_bytes([bytes[0], bytes[1]]) & 0x3ff,
+                    data,
+                }))
+            }
+            _ => Err(UmpError::UnknownMessageType),
+        }
+    }
+
+    /// Render into `buf`, returning the number of words written
+    pub fn render(&self, buf: &mut [u32]) -> Result<usize, UmpError> {
+        let buf = buf
+            .get_mut(..self.words())
+            .ok_or(UmpError::BufferTooShort)?;
+        let mut bytes = [0; 16];
+        match self {
+            Self::Utility(utility) => {
+                let (status, data) = match *utility {
+                    Utility::NoOp => (0x0, 0),
+                    Utility::JrClock(time) => (0x1, u32::from(time)),
+                    Utility::JrTimestamp(time) => (0x2, u32::from(time)),
+                    Utility::TicksPerQuarterNote(ticks) => (0x3, u32::from(ticks)),
+                    Utility::DeltaClockstamp(ticks) if ticks <= 0xf_ffff => (0x4, ticks),
+                    Utility::DeltaClockstamp(_) => return Err(UmpError::InvalidData),
+                };
+                bytes[..4].copy_from_slice(&(status << 20 | data).to_be_bytes());
+            }
+            Self::System { group, message } | Self::Midi1ChannelVoice { group, message } => {
+                let (mt, valid) = match self {
+                    Self::System { .. } => (0x1, 0xf0..=0xff),
+                    _ => (0x2, 0x80..=0xef),
+                };
+                let mut buf = [0; 3];
+                let encoded = encode::<false>(message, &mut None, &mut buf);
+                if !valid.contains(&encoded[0]) {
+                    return Err(UmpError::InvalidStatus);
+                }
+                bytes[0] = mt << 4 | u8::from(*group);
+                bytes[1..1 + encoded.len()].copy_from_slice(encoded);
+            }
+            Self::SysEx7(packet) => {
+                let data = packet.data();
+                bytes[0] = 0x30 | u8::from(packet.group);
+                bytes[1] = packet.form.bits() << 4 | data.len() as u8;
+                bytes[2..2 + data.len()].copy_from_slice(data);
+            }
+            Self::Midi2ChannelVoice {
+                group,
+                channel,
+                message,
+            } => {
+                let (opcode, b2, b3, data) = message.encode()?;
+                bytes[0] = 0x40 | u8::from(*group);
+                bytes[1] = opcode << 4 | u8::from(*channel);
+                bytes[2] = b2;
+                bytes[3] = b3;
+                bytes[4..8].copy_from_slice(&data.to_be_bytes());
+            }
+            Self::SysEx8(packet) => {
+                let data = packet.data();
+                bytes[0] = 0x50 | u8::from(packet.group);
+                bytes[1] = packet.form.bits() << 4 | (data.len() as u8 + 1);
+                bytes[2] = packet.stream;
+                bytes[3..3 + data.len()].copy_from_slice(data);
+            }
+            Self::MixedDataSet(MixedDataSet::Header {
+                group,
+                id,
+                valid_bytes,
+                chunks,
+                chunk,
+                manufacturer,
+                device,
+                sub_id,
+            }) => {
+                bytes[0] = 0x50 | u8::from(*group);
+                bytes[1] = 0x80 | u4(*id)?;
+                for (i, half) in [*valid_bytes, *chunks, *chunk, *manufacturer, *device]
+                    .into_iter()
+                    .chain(*sub_id)
+                    .enumerate()
+                {
+                    bytes[2 + 2 * i..4 + 2 * i].copy_from_slice(&half.to_be_bytes());
+                }
+            }
+            Self::MixedDataSet(MixedDataSet::Payload { group, id, data }) => {
+                bytes[0] = 0x50 | u8::from(*group);
+                bytes[1] = 0x90 | u4(*id)?;
+                bytes[2..].copy_from_slice(data);
+            }
+            Self::FlexData(flex) => {
+                let (address, channel) = match flex.address {
+                    FlexAddress::Channel(channel) => (0, channel.into()),
+                    FlexAddress::Group => (1, 0),
+                };
+                bytes[0] = 0xd0 | u8::from(flex.group);
+                bytes[1] = flex.form.bits() << 6 | address << 4 | channel;
+                bytes[2] = flex.status_bank;
+                bytes[3] = flex.status;
+                bytes[4..].copy_from_slice(&flex.data);
+            }
+            Self::Stream(stream) => {
+                if stream.status > 0x3ff {
+                    return Err(UmpError::InvalidData);
+                }
+                let head = 0xf000 | u16::from(stream.form.bits()) << 10 | stream.status;
+                bytes[..2].copy_from_slice(&head.to_be_bytes());
+                bytes[2..].copy_from_slice(&stream.data);
+            }
+        }
+        for (word, chunk) in buf.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok(buf.len())
+    }
+
+    /// The number of words in the packet
+    pub fn words(&self) -> usize {
+        match self {
+            Self::Utility(_) | Self::System { .. } | Self::Midi1ChannelVoice { .. } => 1,
+            Self::SysEx7(_) | Self::Midi2ChannelVoice { .. } => 2,
+            Self::SysEx8(_) | Self::MixedDataSet(_) | Self::FlexData(_) | Self::Stream(_) => 4,
+        }
+    }
+
+    /// The group, utility and stream messages have none
+    pub fn group(&self) -> Option<Group> {
+        match self {
+            Self::Utility(_) | Self::Stream(_) => None,
+            Self::System { group, .. }
+            | Self::Midi1ChannelVoice { group, .. }
+            | Self::Midi2ChannelVoice { group, .. }
+            | Self::MixedDataSet(
+                MixedDataSet::Header { group, .. } | MixedDataSet::Payload { group, .. },
+            )
+            | Self::FlexData(FlexData { group, .. }) => Some(*group),
+            Self::SysEx7(packet) => Some(packet.group),
+            Self::SysEx8(packet) => Some(packet.group),
+        }
+    }
+}
+
+/// Iterate over the packets in `words`
+///
+/// Errors don't end the iteration, except for a truncated last packet.
+pub fn messages(words: &[u32]) -> UmpMessages<'_> {
+    UmpMessages { words }
+}
+
+/// An iterator over the packets in a word slice, see [`messages`]
+#[derive(Debug, Clone)]
+pub struct UmpMessages<'a> {
+    words: &'a [u32],
+}
+
+impl Iterator for UmpMessages<'_> {
+    type Item = Result<UmpMessage, UmpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = *self.words.first()?;
+        let result = UmpMessage::parse(self.words);
+        let len = message_words(first).min(self.words.len());
+        self.words = &self.words[len..];
+        Some(result)
+    }
+}
+
+fn midi1(bytes: &[u8]) -> Result<MidiMessage, UmpError> {
+    // `midi-types` can't represent controller 127 and program 127
+    if matches!(bytes[0] & 0xf0, 0xb0 | 0xc0) && bytes[1] & 0x7f == 0x7f {
+        return Err(UmpError::InvalidData);
+    }
+    MidiMessage::try_parse_slice(&[bytes[0], bytes[1] & 0x7f, bytes[2] & 0x7f])
+        .map_err(|_| UmpError::InvalidStatus)
+}
+
+fn u4(value: u8) -> Result<u8, UmpError> {
+    if value < 0x10 {
+        Ok(value)
+    } else {
+        Err(UmpError::InvalidData)
+    }
+}
+
+fn u7(value: u8) -> Result<u8, UmpError> {
+    if value < 0x80 {
+        Ok(value)
+    } else {
+        Err(UmpError::InvalidData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, std::vec::Vec};
+
+    fn round_trip(message: UmpMessage, words: &[u32]) {
+        assert_eq!(UmpMessage::parse(words), Ok(message));
+        let mut buf = [0; 4];
+        assert_eq!(message.render(&mut buf), Ok(words.len()));
+        assert_eq!(&buf[..words.len()], words);
+    }
+
+    #[test]
+    fn should_round_trip_utility_messages() {
+        round_trip(UmpMessage::Utility(Utility::NoOp), &[0x0000_0000]);
+        round_trip(
+            UmpMessage::Utility(Utility::JrClock(0x1234)),
+            &[0x0010_1234],
+        );
+        round_trip(
+            UmpMessage::Utility(Utility::JrTimestamp(0xfedc)),
+            &[0x0020_fedc],
+        );
+        round_trip(
+            UmpMessage::Utility(Utility::TicksPerQuarterNote(480)),
+            &[0x0030_01e0],
+        );
+        round_trip(
+            UmpMessage::Utility(Utility::DeltaClockstamp(0xf_ffff)),
+            &[0x004f_ffff],
+        );
+    }
+
+    #[test]
+    fn should_round_trip_midi1_messages() {
+        let group = Group::new(5);
+        round_trip(
+            UmpMessage::Midi1ChannelVoice {
+                group,
+                message: MidiMessage::NoteOn(2.into(), 60.into(), 100.into()),
+            },
+            &[0x2592_3c64],
+        );
+        round_trip(
+            UmpMessage::Midi1ChannelVoice {
+                group,
+                message: MidiMessage::PitchBendChange(0.into(), 0x2001u16.into()),
+            },
+            &[0x25e0_0140],
+        );
+        round_trip(
+            UmpMessage::Midi1ChannelVoice {
+                group,
+                message: MidiMessage::ProgramChange(1.into(), 9.into()),
+            },
+            &[0x25c1_0900],
+        );
+        round_trip(
+            UmpMessage::System {
+                group,
+                message: MidiMessage::SongPositionPointer(0x0081u16.into()),
+            },
+            &[0x15f2_0101],
+        );
+        round_trip(
+            UmpMessage::System {
+                group,
+                message: MidiMessage::TimingClock,
+            },
+            &[0x15f8_0000],
+        );
+    }
+
+    #[test]
+    fn should_round_trip_sysex() {
+        let group = Group::new(1);
+        let packet = SysEx7::new(group, Form::Complete, &[0x7e, 0x7f, 0x06, 0x01]).unwrap();
+        round_trip(UmpMessage::SysEx7(packet), &[0x3104_7e7f, 0x0601_0000]);
+        let packet = SysEx7::new(group, Form::End, &[]).unwrap();
+        round_trip(UmpMessage::SysEx7(packet), &[0x3130_0000, 0x0000_0000]);
+
+        let data: Vec<u8> = (0xf1..=0xfd).collect();
+        let packet = SysEx8::new(group, Form::Start, 0x42, &data).unwrap();
+        assert_eq!(packet.data(), data.as_slice());
+        round_trip(
+            UmpMessage::SysEx8(packet),
+            &[0x511e_42f1, 0xf2f3_f4f5, 0xf6f7_f8f9, 0xfafb_fcfd],
+        );
+        let packet = SysEx8::new(group, Form::Continue, 7, &[1]).unwrap();
+        round_trip(
+            UmpMessage::SysEx8(packet),
+            &[0x5122_0701, 0x0000_0000, 0x0000_0000, 0x0000_0000],
+        );
+    }
+
+    #[test]
+    fn should_round_trip_mixed_data_sets() {
+        let group = Group::new(2);
+        round_trip(
+            UmpMessage::MixedDataSet(MixedDataSet::Header {
+                group,
+                id: 3,
+                valid_bytes: 0x0102,
+                chunks: 2,
+                chunk: 1,
+                manufacturer: 0x0041,
+                device: 0x0203,
+                sub_id: [0x0405, 0x0607],
+            }),
+            &[0x5283_0102, 0x0002_0001, 0x0041_0203, 0x0405_0607],
+        );
+        round_trip(
+            UmpMessage::MixedDataSet(MixedDataSet::Payload {
+                group,
+                id: 3,
+                data: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13],
+            }),
+            &[0x5293_0001, 0x0203_0405, 0x0607_0809, 0x0a0b_0c0d],
+        );
+    }
+
+    #[test]
+    fn should_round_trip_midi2_messages() {
+        let note = Note::new(60);
+        let cases = [
+            (
+                Midi2ChannelVoice::RegisteredPerNoteController {
+                    note,
+                    index: 0xff,
+                    data: 1,
+                },
+                [0x4003_3cff, 1],
+            ),
+            (
+                Midi2ChannelVoice::AssignablePerNoteController {
+                    note,
+                    index: 3,
+                    data: 2,
+                },
+                [0x4013_3c03, 2],
+            ),
+            (
+                Midi2ChannelVoice::RegisteredController {
+                    bank: 0,
+                    index: 6,
+                    data: 3,
+                },
+                [0x4023_0006, 3],
+            ),
+            (
+                Midi2ChannelVoice::AssignableController {
+                    bank: 0x7f,
+                    index: 0x7e,
+                    data: 4,
+                },
+                [0x4033_7f7e, 4],
+            ),
+            (
+                Midi2ChannelVoice::RelativeRegisteredController {
+                    bank: 1,
+                    index: 2,
+                    data: -1,
+                },
+                [0x4043_0102, 0xffff_ffff],
+            ),
+            (
+                Midi2ChannelVoice::RelativeAssignableController {
+                    bank: 1,
+                    index: 2,
+                    data: 5,
+                },
+                [0x4053_0102, 5],
+            ),
+            (
+                Midi2ChannelVoice::PerNotePitchBend {
+                    note,
+                    data: 0x8000_0000,
+                },
+                [0x4063_3c00, 0x8000_0000],
+            ),
+            (
+                Midi2ChannelVoice::NoteOff {
+                    note,
+                    velocity: 0x1234,
+                    attribute_type: 3,
+                    attribute: 0x5678,
+                },
+                [0x4083_3c03, 0x1234_5678],
+            ),
+            (
+                Midi2ChannelVoice::NoteOn {
+                    note,
+                    velocity: 0,
+                    attribute_type: 0,
+                    attribute: 0,
+                },
+                [0x4093_3c00, 0],
+            ),
+            (
+                Midi2ChannelVoice::KeyPressure { note, data: 6 },
+                [0x40a3_3c00, 6],
+            ),
+            (
+                Midi2ChannelVoice::ControlChange {
+                    index: 7,
+                    data: 0xffff_ffff,
+                },
+                [0x40b3_0700, 0xffff_ffff],
+            ),
+            (
+                Midi2ChannelVoice::ProgramChange {
+                    program: 0x7f,
+                    bank: None,
+                },
+                [0x40c3_0000, 0x7f00_0000],
+            ),
+            (
+                Midi2ChannelVoice::ProgramChange {
+                    program: 5,
+                    bank: Some(Value14::new(0x12, 0x34)),
+                },
+                [0x40c3_0001, 0x0500_1234],
+            ),
+            (Midi2ChannelVoice::ChannelPressure(8), [0x40d3_0000, 8]),
+            (
+                Midi2ChannelVoice::PitchBendChange(0x8000_0000),
+                [0x40e3_0000, 0x8000_0000],
+            ),
+            (
+                Midi2ChannelVoice::PerNoteManagement {
+                    note,
+                    detach: true,
+                    reset: false,
+                },
+                [0x40f3_3c02, 0],
+            ),
+        ];
+        for (message, words) in cases {
+            round_trip(
+                UmpMessage::Midi2ChannelVoice {
+                    group: Group::new(0),
+                    channel: Channel::new(3),
+                    message,
+                },
+                &words,
+            );
+        }
+    }
+
+    #[test]
+    fn should_round_trip_flex_data_and_stream_messages() {
+        let mut data = [0; 12];
+        data[..4].copy_from_slice(&10_000_000u32.to_be_bytes());
+        round_trip(
+            UmpMessage::FlexData(FlexData {
+                group: Group::new(1),
+                form: Form::Complete,
+                address: FlexAddress::Group,
+                status_bank: 0,
+                status: 0,
+                data,
+            }),
+            &[0xd110_0000, 10_000_000, 0, 0],
+        );
+        round_trip(
+            UmpMessage::FlexData(FlexData {
+                group: Group::new(1),
+                form: Form::End,
+                address: FlexAddress::Channel(Channel::new(9)),
+                status_bank: 1,
+                status: 3,
+                data: *b"Hello world!",
+            }),
+            &[0xd1c9_0103, 0x4865_6c6c, 0x6f20_776f, 0x726c_6421],
+        );
+        let mut data = [0; 14];
+        data[1] = 0x01;
+        data[2] = 0x01;
+        round_trip(
+            UmpMessage::Stream(StreamMessage {
+                form: Form::Complete,
+                status: 0x000,
+                data,
+            }),
+            &[0xf000_0001, 0x0100_0000, 0, 0],
+        );
+        round_trip(
+            UmpMessage::Stream(StreamMessage {
+                form: Form::Start,
+                status: 0x003,
+                data: *b"Endpoint name!",
+            }),
+            &[0xf403_456e, 0x6470_6f69, 0x6e74_206e, 0x616d_6521],
+        );
+    }
+
+    #[test]
+    fn should_ignore_reserved_bits() {
+        assert_eq!(
+            UmpMessage::parse(&[0x2090_bce4]),
+            UmpMessage::parse(&[0x2090_3c64])
+        );
+        assert_eq!(
+            UmpMessage::parse(&[0x0f0a_bcde]),
+            Ok(UmpMessage::Utility(Utility::NoOp))
+        );
+        assert_eq!(
+            UmpMessage::parse(&[0x3002_ffff, 0xffff_ffff]),
+            Ok(UmpMessage::SysEx7(
+                SysEx7::new(Group::new(0), Form::Complete, &[0x7f, 0x7f]).unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_packets() {
+        use UmpError::*;
+        assert_eq!(UmpMessage::parse(&[]), Err(BufferTooShort));
+        assert_eq!(UmpMessage::parse(&[0x4090_3c00]), Err(BufferTooShort));
+        assert_eq!(UmpMessage::parse(&[0xd000_0000, 0, 0]), Err(BufferTooShort));
+        for mt in [0x6, 0x7, 0x8, 0x9, 0xa, 0xb, 0xc, 0xe] {
+            assert_eq!(
+                UmpMessage::parse(&[mt << 28, 0, 0, 0]),
+                Err(UnknownMessageType)
+            );
+        }
+        assert_eq!(UmpMessage::parse(&[0x0050_0000]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x1090_3c64]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x10f0_0000]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x10f4_0000]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x20f8_0000]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x20b0_7f00]), Err(InvalidData));
+        assert_eq!(UmpMessage::parse(&[0x20c3_7f00]), Err(InvalidData));
+        assert_eq!(UmpMessage::parse(&[0x3040_0000, 0]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x3007_0000, 0]), Err(InvalidData));
+        assert_eq!(UmpMessage::parse(&[0x4070_0000, 0]), Err(InvalidStatus));
+        assert_eq!(UmpMessage::parse(&[0x5000_0000, 0, 0, 0]), Err(InvalidData));
+        assert_eq!(UmpMessage::parse(&[0x500f_0000, 0, 0, 0]), Err(InvalidData));
+        assert_eq!(
+            UmpMessage::parse(&[0x5040_0000, 0, 0, 0]),
+            Err(InvalidStatus)
+        );
+        assert_eq!(UmpMessage::parse(&[0xd020_0000, 0, 0, 0]), Err(InvalidData));
+    }
+
+    #[test]
+    fn should_reject_invalid_messages_when_rendering() {
+        use UmpError::*;
+        let mut buf = [0; 4];
+        let note_on = MidiMessage::NoteOn(0.into(), 60.into(), 100.into());
+        assert_eq!(
+            UmpMessage::System {
+                group: Group::new(0),
+                message: note_on,
+            }
+            .render(&mut buf),
+            Err(InvalidStatus)
+        );
+        assert_eq!(
+            UmpMessage::Midi1ChannelVoice {
+                group: Group::new(0),
+                message: MidiMessage::Reset,
+            }
+            .render(&mut buf),
+            Err(InvalidStatus)
+        );
+        assert_eq!(
+            UmpMessage::Midi1ChannelVoice {
+                group: Group::new(0),
+                message: note_on,
+            }
+            .render(&mut []),
+            Err(BufferTooShort)
+        );
+        assert_eq!(
+            UmpMessage::Midi2ChannelVoice {
+                group: Group::new(0),
+                channel: Channel::new(0),
+                message: Midi2ChannelVoice::ControlChange {
+                    index: 0x80,
+                    data: 0,
+                },
+            }
+            .render(&mut buf),
+            Err(InvalidData)
+        );
+        assert_eq!(
+            UmpMessage::Utility(Utility::DeltaClockstamp(0x10_0000)).render(&mut buf),
+            Err(InvalidData)
+        );
+        assert_eq!(
+            UmpMessage::Stream(StreamMessage {
+                form: Form::Complete,
+                status: 0x400,
+                data: [0; 14],
+            })
+            .render(&mut buf),
+            Err(InvalidData)
+        );
+        assert_eq!(
+            SysEx7::new(Group::new(0), Form::Complete, &[0; 7]),
+            Err(InvalidData)
+        );
+        assert_eq!(
+            SysEx7::new(Group::new(0), Form::Complete, &[0x80]),
+            Err(InvalidData)
+        );
+        assert_eq!(
+            SysEx8::new(Group::new(0), Form::Complete, 0, &[0; 14]),
+            Err(InvalidData)
+        );
+    }
+
+    #[test]
+    fn should_iterate_over_packets() {
+        let words = [
+            0x2090_3c64,
+            0x6000_0000,
+            0x4090_3c00,
+            0xc000_0000,
+            0x10f8_0000,
+            0x4080_3c00,
+        ];
+        let messages: Vec<_> = messages(&words).collect();
+        assert_eq!(
+            messages,
+            [
+                UmpMessage::parse(&words[..1]),
+                Err(UmpError::UnknownMessageType),
+                UmpMessage::parse(&words[2..4]),
+                UmpMessage::parse(&words[4..5]),
+                Err(UmpError::BufferTooShort),
+            ]
+        );
+    }
+}
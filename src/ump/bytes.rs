@@ -0,0 +1,438 @@
+//! Translation between MIDI 1.0 byte streams and UMPs of one group
+//!
+//! ```
+//! use midi_convert::ump::{Group, UmpMessage, bytes::UmpTranslator};
+//!
+//! let mut translator = UmpTranslator::new(Group::new(2));
+//! let packets: Vec<UmpMessage> = [0x92, 0x3c, 0x64]
+//!     .into_iter()
+//!     .flat_map(|byte| translator.push(byte))
+//!     .collect();
+//! let mut words = [0; 1];
+//! packets[0].render(&mut words).unwrap();
+//! assert_eq!(words, [0x2292_3c64]);
+//! ```
+
+use {
+    super::{Form, Group, SysEx7, UmpMessage},
+    crate::{
+        parse::MidiParser,
+        render::{MidiRenderer, MidiTransport},
+    },
+    midi_types::{
+        MidiMessage,
+        status::{SYSEX_END, SYSEX_START},
+    },
+};
+
+/// Translates a MIDI 1.0 byte stream into MIDI 1.0 channel voice, system and SysEx7 packets
+#[derive(Debug, Clone)]
+pub struct UmpTranslator {
+    group: Group,
+    parser: MidiParser,
+    sysex: Option<SysExState>,
+}
+
+/// The bytes of a SysEx message not yet sent in a packet
+#[derive(Debug, Clone, Default)]
+struct SysExState {
+    data: [u8; SysEx7::MAX_LEN],
+    len: usize,
+    started: bool,
+}
+
+impl SysExState {
+    /// Add a data byte, returning the previous packet when it was full
+    fn push(&mut self, group: Group, byte: u8) -> Option<UmpMessage> {
+        let packet = (self.len == self.data.len()).then(|| {
+            let form = if self.started {
+                Form::Continue
+            } else {
+                Form::Start
+            };
+            let packet = self.packet(group, form);
+            self.started = true;
+            self.len = 0;
+            packet
+        });
+        self.data[self.len] = byte;
+        self.len += 1;
+        packet
+    }
+
+    /// The last packet
+    fn end(&self, group: Group) -> UmpMessage {
+        let form = if self.started {
+            Form::End
+        } else {
+            Form::Complete
+        };
+        self.packet(group, form)
+    }
+
+    fn packet(&self, group: Group, form: Form) -> UmpMessage {
+        let mut data = [0; SysEx7::MAX_LEN];
+        data[..self.len].copy_from_slice(&self.data[..self.len]);
+        UmpMessage::SysEx7(SysEx7 {
+            group,
+            form,
+            len: self.len as u8,
+            data,
+        })
+    }
+}
+
+impl UmpTranslator {
+    /// Create a translator producing packets for `group`
+    pub fn new(group: Group) -> Self {
+        Self {
+            group,
+            parser: MidiParser::new(),
+            sysex: None,
+        }
+    }
+
+    /// The group of the packets
+    pub fn group(&self) -> Group {
+        self.group
+    }
+
+    /// Translate a byte, returning the packets it completes
+    ///
+    /// SysEx is sent in packets of 6 bytes. Real time messages within SysEx are returned ahead of
+    /// the buffered SysEx bytes, any other status byte ends the SysEx message.
+    pub fn push(&mut self, byte: u8) -> impl Iterator<Item = UmpMessage> + use<> {
+        let mut packets = [None, None];
+        if let Some(sysex) = &mut self.sysex {
+            if byte < 0x80 {
+                packets[0] = sysex.push(self.group, byte);
+                return packets.into_iter().flatten();
+            }
+            if byte < 0xf8 {
+                packets[0] = Some(sysex.end(self.group));
+                self.sysex = None;
+            }
+        }
+        if byte == SYSEX_START {
+            self.sysex = Some(SysExState::default());
+        }
+        packets[1] = self.parser.parse(byte).map(|message| {
+            if is_system(&message) {
+                UmpMessage::System {
+                    group: self.group,
+                    message,
+                }
+            } else {
+                UmpMessage::Midi1ChannelVoice {
+                    group: self.group,
+                    message,
+                }
+            }
+        });
+        packets.into_iter().flatten()
+    }
+
+    /// Drop any partially received message
+    pub fn reset(&mut self) {
+        self.parser = MidiParser::new();
+        self.sysex = None;
+    }
+}
+
+/// Renders the MIDI 1.0 packets of one group to a MIDI 1.0 byte stream through a [`MidiRenderer`]
+///
+/// SysEx7 packets are joined into one SysEx message, messages other than real time ones end an
+/// unfinished SysEx message with an `0xF7`.
+#[derive(Debug)]
+pub struct UmpRenderer<T, const RUNNING_STATUS: bool = true> {
+    renderer: MidiRenderer<T, RUNNING_STATUS>,
+    group: Group,
+    sysex: bool,
+}
+
+impl<T: MidiTransport, const RUNNING_STATUS: bool> UmpRenderer<T, RUNNING_STATUS> {
+    /// Create a renderer for the packets of `group`
+    pub fn new(transport: T, group: Group) -> Self {
+        Self {
+            renderer: MidiRenderer::new(transport),
+            group,
+            sysex: false,
+        }
+    }
+
+    /// Release the transport
+    pub fn release(self) -> T {
+        self.renderer.release()
+    }
+
+    /// The group rendered
+    pub fn group(&self) -> Group {
+        self.group
+    }
+
+    /// Render a packet, packets of other groups and other message types are skipped
+    pub fn render(&mut self, packet: &UmpMessage) -> Result<(), T::Error> {
+        if packet.group() != Some(self.group) {
+            return Ok(());
+        }
+        match packet {
+            UmpMessage::System { message, .. } | UmpMessage::Midi1ChannelVoice { message, .. } => {
+                if !is_real_time(message) {
+                    self.end_sysex()?;
+                }
+                self.renderer.render(message)
+            }
+            UmpMessage::SysEx7(packet) => {
+                let data = packet.data();
+                let mut buf = [0; SysEx7::MAX_LEN + 2];
+                let mut len = 0;
+                let mut push = |bytes: &[u8]| {
+                    buf[len..len + bytes.len()].copy_from_slice(bytes);
+                    len += bytes.len();
+                };
+                match packet.form() {
+                    Form::Complete | Form::Start => {
+                        self.end_sysex()?;
+                        push(&[SYSEX_START]);
+                    }
+                    Form::Continue | Form::End if !self.sysex => return Ok(()),
+                    Form::Continue | Form::End => {}
+                }
+                push(data);
+                self.sysex = matches!(packet.form(), Form::Start | Form::Continue);
+                if !self.sysex {
+                    push(&[SYSEX_END]);
+                }
+                self.renderer.write_exclusive(&buf[..len])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// End an unfinished SysEx message
+    fn end_sysex(&mut self) -> Result<(), T::Error> {
+        if self.sysex {
+            self.sysex = false;
+            self.renderer.write_exclusive(&[SYSEX_END])?;
+        }
+        Ok(())
+    }
+}
+
+fn is_real_time(message: &MidiMessage) -> bool {
+    matches!(
+        message,
+        MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::ActiveSensing
+            | MidiMessage::Reset
+    )
+}
+
+fn is_system(message: &MidiMessage) -> bool {
+    is_real_time(message)
+        || matches!(
+            message,
+            MidiMessage::QuarterFrame(_)
+                | MidiMessage::SongPositionPointer(_)
+                | MidiMessage::SongSelect(_)
+                | MidiMessage::TuneRequest
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, std::vec::Vec};
+
+    fn translate(bytes: &[u8]) -> Vec<UmpMessage> {
+        let mut translator = UmpTranslator::new(Group::new(3));
+        bytes
+            .iter()
+            .flat_map(|byte| translator.push(*byte))
+            .collect()
+    }
+
+    fn sysex(form: Form, data: &[u8]) -> UmpMessage {
+        UmpMessage::SysEx7(SysEx7::new(Group::new(3), form, data).unwrap())
+    }
+
+    fn midi1(message: MidiMessage) -> UmpMessage {
+        let group = Group::new(3);
+        if is_system(&message) {
+            UmpMessage::System { group, message }
+        } else {
+            UmpMessage::Midi1ChannelVoice { group, message }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        buffer: Vec<u8>,
+    }
+
+    impl MidiTransport for MockTransport {
+        type Error = ();
+
+        fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+            assert!(bytes.len() <= 3, "Too many bytes in one message");
+            self.buffer.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn render(packets: &[UmpMessage]) -> Vec<u8> {
+        let mut renderer: UmpRenderer<_> =
+            UmpRenderer::new(MockTransport::default(), Group::new(3));
+        for packet in packets {
+            renderer.render(packet).unwrap();
+        }
+        renderer.release().buffer
+    }
+
+    #[test]
+    fn should_translate_messages_with_running_status() {
+        assert_eq!(
+            translate(&[
+                0x92, 0x3c, 0x64, 0x3d, 0x64, 0xf8, 0xc1, 0x05, 0xf2, 0x01, 0x02
+            ]),
+            [
+                midi1(MidiMessage::NoteOn(2.into(), 0x3c.into(), 0x64.into())),
+                midi1(MidiMessage::NoteOn(2.into(), 0x3d.into(), 0x64.into())),
+                midi1(MidiMessage::TimingClock),
+                midi1(MidiMessage::ProgramChange(1.into(), 5.into())),
+                midi1(MidiMessage::SongPositionPointer(0x101u16.into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_split_sysex() {
+        let data: Vec<u8> = (1..=14).collect();
+        let mut bytes = [0xf0].to_vec();
+        bytes.extend_from_slice(&data);
+        bytes.push(0xf7);
+        assert_eq!(
+            translate(&bytes),
+            [
+                sysex(Form::Start, &data[..6]),
+                sysex(Form::Continue, &data[6..12]),
+                sysex(Form::End, &data[12..]),
+            ]
+        );
+        assert_eq!(
+            translate(&[0xf0, 1, 2, 3, 4, 5, 6, 0xf7]),
+            [sysex(Form::Complete, &[1, 2, 3, 4, 5, 6])]
+        );
+        assert_eq!(
+            translate(&[0xf0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 0xf7]),
+            [
+                sysex(Form::Start, &[1, 2, 3, 4, 5, 6]),
+                sysex(Form::End, &[7, 8, 9, 10, 11, 12]),
+            ]
+        );
+        assert_eq!(translate(&[0xf0, 0xf7]), [sysex(Form::Complete, &[])]);
+    }
+
+    #[test]
+    fn should_pass_real_time_messages_through_sysex() {
+        assert_eq!(
+            translate(&[0xf0, 1, 2, 0xf8, 3, 0xf7]),
+            [
+                midi1(MidiMessage::TimingClock),
+                sysex(Form::Complete, &[1, 2, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_end_sysex_on_status() {
+        assert_eq!(
+            translate(&[0xf0, 1, 2, 0xf6, 0x90, 0x3c, 0x64]),
+            [
+                sysex(Form::Complete, &[1, 2]),
+                midi1(MidiMessage::TuneRequest),
+                midi1(MidiMessage::NoteOn(0.into(), 0x3c.into(), 0x64.into())),
+            ]
+        );
+        // A SysEx cancels running status
+        assert_eq!(
+            translate(&[0x90, 0x3c, 0x64, 0xf0, 1, 0xf7, 0x3d, 0x64]),
+            [
+                midi1(MidiMessage::NoteOn(0.into(), 0x3c.into(), 0x64.into())),
+                sysex(Form::Complete, &[1]),
+            ]
+        );
+        assert_eq!(translate(&[0xf7, 0x01]), []);
+    }
+
+    #[test]
+    fn should_render_with_running_status() {
+        assert_eq!(
+            render(&[
+                midi1(MidiMessage::NoteOn(2.into(), 0x3c.into(), 0x64.into())),
+                midi1(MidiMessage::TimingClock),
+                midi1(MidiMessage::NoteOn(2.into(), 0x3d.into(), 0x64.into())),
+                sysex(Form::Complete, &[1]),
+                midi1(MidiMessage::NoteOn(2.into(), 0x3e.into(), 0x64.into())),
+            ]),
+            [
+                0x92, 0x3c, 0x64, 0xf8, 0x3d, 0x64, 0xf0, 0x01, 0xf7, 0x92, 0x3e, 0x64
+            ]
+        );
+    }
+
+    #[test]
+    fn should_join_sysex() {
+        assert_eq!(
+            render(&[
+                sysex(Form::Start, &[1, 2, 3, 4, 5, 6]),
+                midi1(MidiMessage::ActiveSensing),
+                sysex(Form::Continue, &[7, 8, 9, 10, 11, 12]),
+                sysex(Form::End, &[13]),
+            ]),
+            [0xf0, 1, 2, 3, 4, 5, 6, 0xfe, 7, 8, 9, 10, 11, 12, 13, 0xf7]
+        );
+    }
+
+    #[test]
+    fn should_end_unfinished_sysex() {
+        assert_eq!(
+            render(&[
+                sysex(Form::Start, &[1, 2]),
+                midi1(MidiMessage::TuneRequest),
+                sysex(Form::End, &[3]),
+                sysex(Form::Start, &[4]),
+                sysex(Form::Start, &[5]),
+                sysex(Form::End, &[]),
+            ]),
+            [0xf0, 1, 2, 0xf7, 0xf6, 0xf0, 4, 0xf7, 0xf0, 5, 0xf7]
+        );
+    }
+
+    #[test]
+    fn should_skip_other_groups_and_message_types() {
+        let note_on = MidiMessage::NoteOn(2.into(), 0x3c.into(), 0x64.into());
+        assert_eq!(
+            render(&[
+                UmpMessage::Midi1ChannelVoice {
+                    group: Group::new(4),
+                    message: note_on,
+                },
+                UmpMessage::Utility(crate::ump::Utility::NoOp),
+            ]),
+            []
+        );
+    }
+
+    #[test]
+    fn should_round_trip_byte_streams() {
+        let bytes = [
+            0x92, 0x3c, 0x64, 0x3d, 0x64, 0xf0, 1, 2, 3, 4, 5, 6, 7, 8, 0xf7, 0xf8, 0xb1, 0x07,
+            0x10, 0x08, 0x20, 0xe1, 0x00, 0x40, 0xf1, 0x12, 0xff,
+        ];
+        assert_eq!(render(&translate(&bytes)), bytes);
+    }
+}
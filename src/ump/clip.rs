@@ -0,0 +1,835 @@
+//! MIDI Clip Files and conversion to and from Standard MIDI Files
+//!
+//! A clip file is `SMF2CLIP` followed by big endian UMPs, each after a delta clockstamp. The
+//! configuration header sets the ticks per quarter note and ends with a start of clip message,
+//! the sequence ends with an end of clip message.
+//!
+//! Standard MIDI Files are imported through [`UmpTranslator`] and, for the MIDI 2.0 protocol,
+//! [`Midi1ToMidi2`]. Exporting translates MIDI 2.0 channel voice messages with [`Midi2ToMidi1`].
+//!
+//! ```
+//! use midi_convert::ump::{Group, clip::Clip, stream::Protocol};
+//!
+//! let smf = [
+//!     b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xe0, // format 0, 480 ticks
+//!     b'M', b'T', b'r', b'k', 0, 0, 0, 13, //
+//!     0x00, 0x90, 0x3c, 0x64, // note on
+//!     0x83, 0x60, 0x80, 0x3c, 0x00, // note off 480 ticks later
+//!     0x00, 0xff, 0x2f, 0x00, // end of track
+//! ];
+//! let clip = Clip::from_smf(&smf, Group::new(0), Protocol::Midi1).unwrap();
+//! assert_eq!(clip.events.len(), 2);
+//! let file = clip.write().unwrap();
+//! assert_eq!(Clip::read(&file), Ok(clip.clone()));
+//! assert_eq!(clip.to_smf(Group::new(0)).unwrap(), smf);
+//! ```
+
+use {
+    super::{
+        FlexAddress, Form, Group, UmpError, UmpMessage, Utility,
+        bytes::UmpTranslator,
+        flex::{Flex, MetaEvent, TextKind, TextReassembler, text_packets},
+        message_words,
+        stream::{Protocol, Stream},
+        timestamp::{DeltaClockReader, DeltaClockWriter},
+        translate::{Midi1ToMidi2, Midi2ToMidi1},
+    },
+    crate::render::encode,
+    midi_types::{
+        MidiMessage,
+        status::{SYSEX_END, SYSEX_START},
+    },
+    std::{string::String, vec::Vec},
+};
+
+const CLIP_HEADER: &[u8; 8] = b"SMF2CLIP";
+
+const META: u8 = 0xff;
+const META_END_OF_TRACK: u8 = 0x2f;
+
+/// The longest delta time a Standard MIDI File holds
+const MAX_DELTA_TIME: u64 = 0x0fff_ffff;
+
+/// Errors reading or writing clip files and Standard MIDI Files
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClipError {
+    /// The data does not start with a clip file or Standard MIDI File header
+    InvalidHeader,
+
+    /// The data ends in the middle of a packet, chunk or event
+    Truncated,
+
+    /// A packet could not be read or written
+    Ump(UmpError),
+
+    /// The clip has no ticks per quarter note or start of clip message
+    MissingConfiguration,
+
+    /// The Standard MIDI File uses SMPTE time, or the ticks per quarter note don't fit in one
+    UnsupportedDivision,
+
+    /// A Standard MIDI File event is malformed
+    InvalidEvent,
+}
+
+impl From<UmpError> for ClipError {
+    fn from(error: UmpError) -> Self {
+        Self::Ump(error)
+    }
+}
+
+/// A MIDI clip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clip {
+    /// Delta clockstamp ticks per quarter note
+    pub ticks_per_quarter_note: u16,
+    /// The configuration header messages between the ticks per quarter note and the start of clip
+    pub header: Vec<UmpMessage>,
+    /// The sequence, with ticks since the start of the clip
+    pub events: Vec<(u64, UmpMessage)>,
+    /// The tick of the end of clip
+    pub end: u64,
+}
+
+impl Clip {
+    /// Create an empty clip
+    pub fn new(ticks_per_quarter_note: u16) -> Self {
+        Self {
+            ticks_per_quarter_note,
+            header: Vec::new(),
+            events: Vec::new(),
+            end: 0,
+        }
+    }
+
+    /// Read a clip file
+    ///
+    /// A clip without an end of clip message ends at its last event. Packets that can't be
+    /// decoded, such as reserved message types, are skipped.
+    pub fn read(data: &[u8]) -> Result<Self, ClipError> {
+        let data = data
+            .strip_prefix(CLIP_HEADER)
+            .ok_or(ClipError::InvalidHeader)?;
+        if data.len() % 4 != 0 {
+            return Err(ClipError::Truncated);
+        }
+        let words: Vec<u32> = data
+            .chunks_exact(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+
+        let mut reader = DeltaClockReader::new();
+        let mut header = Vec::new();
+        let mut events = Vec::new();
+        let mut start = None;
+        let mut end = None;
+        let mut words = &words[..];
+        while let Some(&first) = words.first() {
+            let len = message_words(first);
+            if words.len() < len {
+                return Err(ClipError::Truncated);
+            }
+            let message = UmpMessage::parse(words);
+            words = &words[len..];
+            let Ok(message) = message else {
+                continue;
+            };
+            let Some((ticks, message)) = reader.push(&message) else {
+                continue;
+            };
+            let stream = match message {
+                UmpMessage::Stream(stream) => Stream::try_from(stream).ok(),
+                _ => None,
+            };
+            match (start, stream) {
+                (None, Some(Stream::StartOfClip)) => start = Some(ticks),
+                (None, _) => header.push(message),
+                (Some(start), Some(Stream::EndOfClip)) => {
+                    end = Some(ticks - start);
+                    break;
+                }
+                (Some(start), _) => events.push((ticks - start, message)),
+            }
+        }
+
+        let ticks_per_quarter_note = reader.ticks_per_quarter_note();
+        let (Some(ticks_per_quarter_note), Some(_)) = (ticks_per_quarter_note, start) else {
+            return Err(ClipError::MissingConfiguration);
+        };
+        let last = events.last().map_or(0, |(ticks, _)| *ticks);
+        Ok(Self {
+            ticks_per_quarter_note,
+            header,
+            events,
+            end: end.unwrap_or(last),
+        })
+    }
+
+    /// Write the clip file, with a delta clockstamp before every message
+    pub fn write(&self) -> Result<Vec<u8>, ClipError> {
+        let mut data = CLIP_HEADER.to_vec();
+        let mut write = |message: &UmpMessage| -> Result<(), ClipError> {
+            let mut words = [0; 4];
+            let len = message.render(&mut words)?;
+            for word in &words[..len] {
+                data.extend_from_slice(&word.to_be_bytes());
+            }
+            Ok(())
+        };
+        let clockstamp = UmpMessage::Utility(Utility::DeltaClockstamp(0));
+
+        let configuration = [UmpMessage::Utility(Utility::TicksPerQuarterNote(
+            self.ticks_per_quarter_note,
+        ))];
+        let start = UmpMessage::from(Stream::StartOfClip);
+        for message in configuration.iter().chain(&self.header).chain([&start]) {
+            write(&clockstamp)?;
+            write(message)?;
+        }
+
+        let mut writer = DeltaClockWriter::new();
+        let last = self.events.last().map_or(0, |(ticks, _)| *ticks);
+        let end = (self.end.max(last), UmpMessage::from(Stream::EndOfClip));
+        for (ticks, message) in self.events.iter().chain([&end]) {
+            let packets = writer.stamp(*ticks, *message);
+            if packets.len() == 1 {
+                write(&clockstamp)?;
+            }
+            for packet in packets {
+                write(&packet)?;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Import a Standard MIDI File of format 0 or 1, putting its messages in `group`
+    ///
+    /// The tracks are merged. Tempo, time signature, key signature and text meta events become
+    /// flex data messages, other meta events are dropped. The clip ends at the last end of track.
+    pub fn from_smf(data: &[u8], group: Group, protocol: Protocol) -> Result<Self, ClipError> {
+        let smf = read_smf(data)?;
+        let mut clip = Self::new(smf.ticks_per_quarter_note);
+        let mut translator = UmpTranslator::new(group);
+        let mut midi2 = Midi1ToMidi2::new();
+        let mut events = Vec::new();
+        for event in &smf.events {
+            match event.kind {
+                SmfEventKind::Midi(bytes, len) => {
+                    events.extend(
+                        bytes[..len]
+                            .iter()
+                            .flat_map(|byte| translator.push(*byte))
+                            .map(|message| (event.ticks, message)),
+                    );
+                }
+                SmfEventKind::SysEx(bytes) => {
+                    events.extend(
+                        bytes
+                            .iter()
+                            .flat_map(|byte| translator.push(*byte))
+                            .map(|message| (event.ticks, message)),
+                    );
+                }
+                SmfEventKind::SysExStart(bytes) => {
+                    events.extend(
+                        [SYSEX_START]
+                            .iter()
+                            .chain(bytes)
+                            .flat_map(|byte| translator.push(*byte))
+                            .map(|message| (event.ticks, message)),
+                    );
+                }
+                SmfEventKind::Meta(meta_type, bytes) => {
+                    if let Some(kind) = TextKind::from_meta_type(meta_type) {
+                        let text = String::from_utf8_lossy(bytes);
+                        events.extend(
+                            text_packets(group, FlexAddress::Group, kind, &text)
+                                .map(|message| (event.ticks, message)),
+                        );
+                    } else if let Some(flex) =
+                        MetaEvent::parse(meta_type, bytes).and_then(|meta| Flex::from_meta(&meta))
+                    {
+                        let packet = flex.to_packet(group, FlexAddress::Group);
+                        events.push((event.ticks, UmpMessage::FlexData(packet)));
+                    }
+                }
+            }
+        }
+
+        for (ticks, message) in events {
+            let message = match message {
+                UmpMessage::Midi1ChannelVoice { message, .. } if protocol == Protocol::Midi2 => {
+                    let Some((channel, message)) = midi2.translate(&message) else {
+                        continue;
+                    };
+                    UmpMessage::Midi2ChannelVoice {
+                        group,
+                        channel,
+                        message,
+                    }
+                }
+                message => message,
+            };
+            clip.events.push((ticks, message));
+        }
+        clip.end = smf.end;
+        Ok(clip)
+    }
+
+    /// Export the messages of `group` as a Standard MIDI File of format 0
+    ///
+    /// Flex data messages with a meta event equivalent become meta events, texts longer than
+    /// 1 KiB are dropped. System messages are written as escaped events. Gaps longer than the
+    /// longest delta time are shortened.
+    pub fn to_smf(&self, group: Group) -> Result<Vec<u8>, ClipError> {
+        if self.ticks_per_quarter_note & 0x8000 != 0 {
+            return Err(ClipError::UnsupportedDivision);
+        }
+        let mut track = SmfTrack::default();
+        let mut midi1 = Midi2ToMidi1::new();
+        let mut texts = TextReassembler::<1024, 4>::new();
+        let mut sysex: Option<Vec<u8>> = None;
+
+        let header = self.header.iter().map(|message| (0, message));
+        let events = self.events.iter().map(|(ticks, message)| (*ticks, message));
+        for (ticks, message) in header.chain(events) {
+            if message.group() != Some(group) {
+                continue;
+            }
+            match message {
+                UmpMessage::Midi1ChannelVoice { message, .. } => track.midi(ticks, message),
+                UmpMessage::System { message, .. } => track.system(ticks, message),
+                UmpMessage::Midi2ChannelVoice {
+                    channel, message, ..
+                } => {
+                    for message in midi1.translate(*channel, message) {
+                        track.midi(ticks, &message);
+                    }
+                }
+                UmpMessage::SysEx7(packet) => {
+                    if matches!(packet.form(), Form::Complete | Form::Start) {
+                        sysex = Some(Vec::new());
+                    }
+                    if let Some(data) = sysex.as_mut() {
+                        data.extend_from_slice(packet.data());
+                        if matches!(packet.form(), Form::Complete | Form::End) {
+                            track.sysex(ticks, data);
+                            sysex = None;
+                        }
+                    }
+                }
+                UmpMessage::FlexData(packet) => {
+                    if let Ok(Some(text)) = texts.push(message) {
+                        if let Some(meta_type) = text.kind.meta_type() {
+                            track.meta(ticks, meta_type, text.data);
+                        }
+                    } else if let Some(meta) =
+                        Flex::try_from(*packet).ok().and_then(|flex| flex.to_meta())
+                    {
+                        let mut buf = [0; 4];
+                        track.meta(ticks, meta.meta_type(), meta.data(&mut buf));
+                    }
+                }
+                _ => {}
+            }
+        }
+        track.meta(self.end.max(track.ticks), META_END_OF_TRACK, &[]);
+
+        let mut data = Vec::with_capacity(22 + track.data.len());
+        data.extend_from_slice(b"MThd");
+        data.extend_from_slice(&6u32.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(&self.ticks_per_quarter_note.to_be_bytes());
+        data.extend_from_slice(b"MTrk");
+        data.extend_from_slice(&(track.data.len() as u32).to_be_bytes());
+        data.extend_from_slice(&track.data);
+        Ok(data)
+    }
+}
+
+/// A Standard MIDI File with its tracks merged
+struct Smf<'a> {
+    ticks_per_quarter_note: u16,
+    events: Vec<SmfEvent<'a>>,
+    end: u64,
+}
+
+struct SmfEvent<'a> {
+    ticks: u64,
+    kind: SmfEventKind<'a>,
+}
+
+enum SmfEventKind<'a> {
+    /// A channel message and its length, with the status byte
+    Midi([u8; 3], usize),
+    /// The bytes after an `0xF0`
+    SysExStart(&'a [u8]),
+    /// The bytes of an `0xF7` escape
+    SysEx(&'a [u8]),
+    /// A meta event type and its data
+    Meta(u8, &'a [u8]),
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ClipError> {
+        if self.data.len() < len {
+            return Err(ClipError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, ClipError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ClipError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ClipError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A variable length quantity, at most 4 bytes
+    fn vlq(&mut self) -> Result<u32, ClipError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = value << 7 | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ClipError::InvalidEvent)
+    }
+
+    fn chunk(&mut self) -> Result<(&'a [u8], &'a [u8]), ClipError> {
+        let chunk_type = self.take(4)?;
+        let len = self.u32()?;
+        Ok((chunk_type, self.take(len as usize)?))
+    }
+}
+
+fn read_smf(data: &[u8]) -> Result<Smf<'_>, ClipError> {
+    let mut cursor = Cursor { data };
+    let (chunk_type, header) = cursor.chunk()?;
+    if chunk_type != b"MThd" || header.len() < 6 {
+        return Err(ClipError::InvalidHeader);
+    }
+    let mut header = Cursor { data: header };
+    let _format = header.u16()?;
+    let tracks = header.u16()?;
+    let division = header.u16()?;
+    if division & 0x8000 != 0 {
+        return Err(ClipError::UnsupportedDivision);
+    }
+
+    let mut events = Vec::new();
+    let mut end = 0;
+    let mut found = 0;
+    while found < tracks && !cursor.data.is_empty() {
+        let (chunk_type, track) = cursor.chunk()?;
+        if chunk_type == b"MTrk" {
+            found += 1;
+            end = end.max(read_track(track, &mut events)?);
+        }
+    }
+    // Events of the same tick stay in track order
+    events.sort_by_key(|event| event.ticks);
+    Ok(Smf {
+        ticks_per_quarter_note: division,
+        events,
+        end,
+    })
+}
+
+/// Read the events of a track, returning the tick it ends at
+fn read_track<'a>(track: &'a [u8], events: &mut Vec<SmfEvent<'a>>) -> Result<u64, ClipError> {
+    let mut cursor = Cursor { data: track };
+    let mut ticks = 0;
+    let mut running_status = None;
+    while !cursor.data.is_empty() {
+        ticks += u64::from(cursor.vlq()?);
+        let status = match cursor.data.first() {
+            Some(&status) if status & 0x80 != 0 => {
+                cursor.byte()?;
+                status
+            }
+            _ => running_status.ok_or(ClipError::InvalidEvent)?,
+        };
+        let kind = match status {
+            0x80..=0xef => {
+                running_status = Some(status);
+                let len = if matches!(status & 0xf0, 0xc0 | 0xd0) {
+                    1
+                } else {
+                    2
+                };
+                let data = cursor.take(len)?;
+                if data.iter().any(|b| b & 0x80 != 0) {
+                    return Err(ClipError::InvalidEvent);
+                }
+                let mut message = [status, 0, 0];
+                message[1..=len].copy_from_slice(data);
+                SmfEventKind::Midi(message, len + 1)
+            }
+            SYSEX_START | SYSEX_END => {
+                running_status = None;
+                let len = cursor.vlq()?;
+                let data = cursor.take(len as usize)?;
+                if status == SYSEX_START {
+                    SmfEventKind::SysExStart(data)
+                } else {
+                    SmfEventKind::SysEx(data)
+                }
+            }
+            META => {
+                running_status = None;
+                let meta_type = cursor.byte()?;
+                let len = cursor.vlq()?;
+                let data = cursor.take(len as usize)?;
+                if meta_type == META_END_OF_TRACK {
+                    return Ok(ticks);
+                }
+                SmfEventKind::Meta(meta_type, data)
+            }
+            _ => return Err(ClipError::InvalidEvent),
+        };
+        events.push(SmfEvent { ticks, kind });
+    }
+    Ok(ticks)
+}
+
+/// A Standard MIDI File track being written
+#[derive(Default)]
+struct SmfTrack {
+    data: Vec<u8>,
+    ticks: u64,
+}
+
+impl SmfTrack {
+    fn delta(&mut self, ticks: u64) {
+        let delta = ticks.saturating_sub(self.ticks).min(MAX_DELTA_TIME);
+        self.ticks = self.ticks.max(ticks);
+        self.vlq(delta as u32);
+    }
+
+    fn vlq(&mut self, value: u32) {
+        let mut started = false;
+        for shift in [21, 14, 7] {
+            let group = (value >> shift & 0x7f) as u8;
+            started |= group != 0;
+            if started {
+                self.data.push(0x80 | group);
+            }
+        }
+        self.data.push(value as u8 & 0x7f);
+    }
+
+    fn midi(&mut self, ticks: u64, message: &MidiMessage) {
+        let mut buf = [0; 3];
+        let bytes = encode::<false>(message, &mut None, &mut buf);
+        self.delta(ticks);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// System messages go in escaped events
+    fn system(&mut self, ticks: u64, message: &MidiMessage) {
+        let mut buf = [0; 3];
+        let bytes = encode::<false>(message, &mut None, &mut buf);
+        self.delta(ticks);
+        self.data.push(SYSEX_END);
+        self.vlq(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn sysex(&mut self, ticks: u64, data: &[u8]) {
+        self.delta(ticks);
+        self.data.push(SYSEX_START);
+        self.vlq(data.len() as u32 + 1);
+        self.data.extend_from_slice(data);
+        self.data.push(SYSEX_END);
+    }
+
+    fn meta(&mut self, ticks: u64, meta_type: u8, data: &[u8]) {
+        self.delta(ticks);
+        self.data.extend_from_slice(&[META, meta_type]);
+        self.vlq(data.len() as u32);
+        self.data.extend_from_slice(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::ump::{Midi2ChannelVoice, sysex::sysex7_packets},
+        midi_types::{Channel, Note, Value7},
+    };
+
+    fn note_on(note: u8, velocity: u8) -> UmpMessage {
+        UmpMessage::Midi1ChannelVoice {
+            group: Group::new(0),
+            message: MidiMessage::NoteOn(Channel::new(0), Note::new(note), Value7::new(velocity)),
+        }
+    }
+
+    fn note_off(note: u8) -> UmpMessage {
+        UmpMessage::Midi1ChannelVoice {
+            group: Group::new(0),
+            message: MidiMessage::NoteOff(Channel::new(0), Note::new(note), Value7::new(0)),
+        }
+    }
+
+    fn words(data: &[u8]) -> Vec<u32> {
+        data.chunks(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    fn smf(format: u8, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut data = b"MThd".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 6, 0, format, 0, tracks.len() as u8, 0x01, 0xe0]);
+        for track in tracks {
+            data.extend_from_slice(b"MTrk");
+            data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            data.extend_from_slice(track);
+        }
+        data
+    }
+
+    #[test]
+    fn should_write_clip_files() {
+        let mut clip = Clip::new(480);
+        clip.events = [(0, note_on(60, 100)), (480, note_off(60))].to_vec();
+        clip.end = 960;
+        let data = clip.write().unwrap();
+        assert_eq!(&data[..8], b"SMF2CLIP");
+        assert_eq!(
+            words(&data[8..]),
+            [
+                0x0040_0000,
+                0x0030_01e0,
+                0x0040_0000,
+                0xf020_0000,
+                0,
+                0,
+                0,
+                0x0040_0000,
+                0x2090_3c64,
+                0x0040_01e0,
+                0x2080_3c00,
+                0x0040_01e0,
+                0xf021_0000,
+                0,
+                0,
+                0
+            ]
+        );
+        assert_eq!(Clip::read(&data), Ok(clip));
+    }
+
+    #[test]
+    fn should_read_clip_files() {
+        let group = Group::new(0);
+        let tempo = Flex::SetTempo(50_000_000).to_packet(group, FlexAddress::Group);
+        let mut clip = Clip::new(96);
+        clip.header.push(UmpMessage::FlexData(tempo));
+        clip.events.push((0, note_on(60, 100)));
+        clip.events.extend(
+            sysex7_packets(group, &[0x7e, 0x7f, 0x06, 0x01, 1, 2, 3, 4])
+                .unwrap()
+                .map(|packet| (24, packet)),
+        );
+        clip.events.push((
+            0x30_0000,
+            UmpMessage::Midi2ChannelVoice {
+                group,
+                channel: Channel::new(1),
+                message: Midi2ChannelVoice::ChannelPressure(0x8000_0000),
+            },
+        ));
+        clip.end = 0x30_0000;
+        let data = clip.write().unwrap();
+        assert_eq!(Clip::read(&data), Ok(clip.clone()));
+
+        // Messages need no delta clockstamp before them, the end of clip is optional and packets
+        // that can't be decoded are skipped
+        let mut packed = CLIP_HEADER.to_vec();
+        for word in [
+            0x0030_0060,
+            0xf020_0000,
+            0,
+            0,
+            0,
+            0x2090_3c64,
+            0x6012_3456,
+            0x0050_0000,
+            0x0040_0010,
+            0x2080_3c00,
+        ] {
+            packed.extend_from_slice(&u32::to_be_bytes(word));
+        }
+        let clip = Clip::read(&packed).unwrap();
+        assert_eq!(clip.events, [(0, note_on(60, 100)), (16, note_off(60))]);
+        assert_eq!(clip.end, 16);
+    }
+
+    #[test]
+    fn should_reject_invalid_clip_files() {
+        assert_eq!(Clip::read(b"SMF2CLI"), Err(ClipError::InvalidHeader));
+        assert_eq!(
+            Clip::read(b"SMF2CLIP\x20\x90\x3c"),
+            Err(ClipError::Truncated)
+        );
+        assert_eq!(
+            Clip::read(b"SMF2CLIP\x40\x90\x3c\x64"),
+            Err(ClipError::Truncated)
+        );
+        assert_eq!(
+            Clip::read(b"SMF2CLIP\x00\x30\x01\xe0"),
+            Err(ClipError::MissingConfiguration)
+        );
+        assert_eq!(
+            Clip::read(b"SMF2CLIP\x00\x50\x00\x00"),
+            Err(ClipError::MissingConfiguration)
+        );
+    }
+
+    #[test]
+    fn should_import_smf() {
+        let conductor: &[u8] = &[
+            0x00, 0xff, 0x03, 0x04, b'S', b'o', b'n', b'g', // track name
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // tempo
+            0x00, 0xff, 0x21, 0x01, 0x00, // port, dropped
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let notes: &[u8] = &[
+            0x00, 0x90, 0x3c, 0x64, // note on
+            0x00, 0x40, 0x64, // running status
+            0x60, 0x3c, 0x00, // note on without velocity
+            0x00, 0xf0, 0x04, 0x7e, 0x7f, 0x09, 0xf7, // sysex
+            0x00, 0xff, 0x05, 0x02, b'l', b'a', // lyric
+            0x83, 0x00, 0xff, 0x2f, 0x00, // end of track at 480
+        ];
+        let data = smf(1, &[conductor, notes]);
+
+        let clip = Clip::from_smf(&data, Group::new(0), Protocol::Midi1).unwrap();
+        assert_eq!(clip.ticks_per_quarter_note, 480);
+        assert_eq!(clip.end, 480);
+        let group = Group::new(0);
+        let text = |kind, text: &str| {
+            text_packets(group, FlexAddress::Group, kind, text)
+                .next()
+                .unwrap()
+        };
+        let tempo =
+            UmpMessage::FlexData(Flex::SetTempo(50_000_000).to_packet(group, FlexAddress::Group));
+        let sysex = sysex7_packets(group, &[0x7e, 0x7f, 0x09])
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(
+            clip.events,
+            [
+                (0, text(TextKind::from_meta_type(0x03).unwrap(), "Song")),
+                (0, tempo),
+                (0, note_on(60, 100)),
+                (0, note_on(64, 100)),
+                (96, note_on(60, 0)),
+                (96, sysex),
+                (96, text(TextKind::from_meta_type(0x05).unwrap(), "la")),
+            ]
+        );
+
+        let clip = Clip::from_smf(&data, Group::new(0), Protocol::Midi2).unwrap();
+        let midi2: Vec<_> = clip
+            .events
+            .iter()
+            .filter_map(|(ticks, message)| match message {
+                UmpMessage::Midi2ChannelVoice { message, .. } => Some((*ticks, *message)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(midi2.len(), 3);
+        assert!(matches!(
+            midi2[2],
+            (
+                96,
+                Midi2ChannelVoice::NoteOff {
+                    velocity: 0x8000,
+                    ..
+                }
+            )
+        ));
+    }
+
+    #[test]
+    fn should_export_smf() {
+        let track: &[u8] = &[
+            0x00, 0xff, 0x03, 0x04, b'S', b'o', b'n', b'g', // track name
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // tempo
+            0x00, 0xff, 0x58, 0x04, 0x06, 0x03, 0x18, 0x08, // time signature
+            0x00, 0xff, 0x59, 0x02, 0xfd, 0x01, // C minor
+            0x00, 0x90, 0x3c, 0x64, // note on
+            0x00, 0xf0, 0x04, 0x7e, 0x7f, 0x09, 0xf7, // sysex
+            0x60, 0xff, 0x05, 0x02, b'l', b'a', // lyric
+            0x00, 0x80, 0x3c, 0x00, // note off
+            0x83, 0x00, 0xff, 0x2f, 0x00, // end of track
+        ];
+        let data = smf(0, &[track]);
+        let clip = Clip::from_smf(&data, Group::new(0), Protocol::Midi1).unwrap();
+        assert_eq!(clip.to_smf(Group::new(0)), Ok(data.clone()));
+        let file = clip.write().unwrap();
+        assert_eq!(
+            Clip::read(&file).unwrap().to_smf(Group::new(0)),
+            Ok(data.clone())
+        );
+
+        let clip = Clip::from_smf(&data, Group::new(0), Protocol::Midi2).unwrap();
+        assert_eq!(clip.to_smf(Group::new(0)), Ok(data));
+        assert_eq!(
+            clip.to_smf(Group::new(1)),
+            Ok(smf(0, &[&[0x83, 0x60, 0xff, 0x2f, 0x00]]))
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_smf() {
+        let group = Group::new(0);
+        let import = |data: &[u8]| Clip::from_smf(data, group, Protocol::Midi1);
+        assert_eq!(import(b"RIFF"), Err(ClipError::Truncated));
+        assert_eq!(
+            import(b"MTrk\0\0\0\x06\0\0\0\x01\x01\xe0"),
+            Err(ClipError::InvalidHeader)
+        );
+        let mut smpte = smf(0, &[]);
+        smpte[12] = 0xe7;
+        assert_eq!(import(&smpte), Err(ClipError::UnsupportedDivision));
+        assert_eq!(
+            import(&smf(0, &[&[0x00, 0x3c, 0x64]])),
+            Err(ClipError::InvalidEvent)
+        );
+        assert_eq!(
+            import(&smf(0, &[&[0x00, 0x90, 0x3c]])),
+            Err(ClipError::Truncated)
+        );
+        assert_eq!(
+            import(&smf(0, &[&[0x00, 0xf1, 0x00]])),
+            Err(ClipError::InvalidEvent)
+        );
+        assert_eq!(
+            Clip::new(0x8000).to_smf(group),
+            Err(ClipError::UnsupportedDivision)
+        );
+    }
+}
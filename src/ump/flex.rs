@@ -0,0 +1,1018 @@
+//! UMP flex data messages for tempo, time signature, key, chords and text
+//!
+//! [`Flex`] is the typed form of a [`FlexData`] packet. Text longer than a packet is split with
+//! [`text_packets`] and put back together with [`TextReassembler`]. [`MetaEvent`] holds the
+//! Standard MIDI File meta events with a flex data equivalent.
+//!
+//! ```
+//! use midi_convert::ump::{
+//!     FlexAddress, Group,
+//!     flex::{Flex, MetaEvent},
+//! };
+//!
+//! let tempo = Flex::from_meta(&MetaEvent::Tempo(500_000)).unwrap();
+//! assert_eq!(tempo, Flex::SetTempo(50_000_000));
+//! let packet = tempo.to_packet(Group::new(0), FlexAddress::Group);
+//! assert_eq!(Flex::try_from(packet), Ok(tempo));
+//! assert_eq!(tempo.to_meta(), Some(MetaEvent::Tempo(500_000)));
+//! ```
+
+use super::{
+    FlexAddress, FlexData, Form, Group, UmpError, UmpMessage,
+    segment::{Reassembler, ReassemblyError, Segments},
+    stream::Text,
+};
+
+const SETUP_BANK: u8 = 0x00;
+const METADATA_BANK: u8 = 0x01;
+const PERFORMANCE_BANK: u8 = 0x02;
+
+const SET_TEMPO: u8 = 0x00;
+const SET_TIME_SIGNATURE: u8 = 0x01;
+const SET_METRONOME: u8 = 0x02;
+const SET_KEY_SIGNATURE: u8 = 0x05;
+const SET_CHORD_NAME: u8 = 0x06;
+
+const META_TEXT: u8 = 0x01;
+const META_COPYRIGHT: u8 = 0x02;
+const META_TRACK_NAME: u8 = 0x03;
+const META_LYRIC: u8 = 0x05;
+const META_TEMPO: u8 = 0x51;
+const META_TIME_SIGNATURE: u8 = 0x58;
+const META_KEY_SIGNATURE: u8 = 0x59;
+
+/// The bytes of text a packet holds
+pub const TEXT_LEN: usize = 12;
+
+/// A note name, as used by key signatures and chords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Tonic {
+    /// Unknown or not applicable
+    Unknown,
+    /// A
+    A,
+    /// B
+    B,
+    /// C
+    C,
+    /// D
+    D,
+    /// E
+    E,
+    /// F
+    F,
+    /// G
+    G,
+}
+
+impl Tonic {
+    const NAMES: [Self; 8] = [
+        Self::Unknown,
+        Self::A,
+        Self::B,
+        Self::C,
+        Self::D,
+        Self::E,
+        Self::F,
+        Self::G,
+    ];
+
+    fn from_bits(bits: u8) -> Result<Self, UmpError> {
+        Self::NAMES
+            .get(usize::from(bits & 0xf))
+            .copied()
+            .ok_or(UmpError::InvalidData)
+    }
+
+    fn bits(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A key signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeySignature {
+    /// Sharps if positive, flats if negative, -8 for a non-standard key
+    pub sharps_flats: i8,
+    /// The tonic note
+    pub tonic: Tonic,
+}
+
+/// A time signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimeSignature {
+    /// Beats per bar
+    pub numerator: u8,
+    /// The beat as a negative power of two, 2 for a quarter note
+    pub denominator: u8,
+    /// The number of 1/32 notes per beat
+    pub thirty_seconds: u8,
+}
+
+/// A metronome setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Metronome {
+    /// MIDI clocks per primary click
+    pub clocks_per_click: u8,
+    /// The number of clicks in each of the three parts of a bar accent, 0 for none
+    pub accents: [u8; 3],
+    /// The number of subdivision clicks between primary clicks, for two subdivision sounds
+    pub subdivision_clicks: [u8; 2],
+}
+
+/// A chord name
+///
+/// Alterations are a type in the high nibble and a degree in the low one, 0 for none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChordName {
+    /// The tonic accidental, -2 to 2 for double flat to double sharp
+    pub sharps_flats: i8,
+    /// The tonic
+    pub tonic: Tonic,
+    /// The chord type, 0 for no chord
+    pub chord_type: u8,
+    /// The chord alterations
+    pub alterations: [u8; 4],
+    /// The bass note accidental
+    pub bass_sharps_flats: i8,
+    /// The bass note, unknown if it is the tonic
+    pub bass_note: Tonic,
+    /// The bass chord type, 0 for none
+    pub bass_chord_type: u8,
+    /// The bass chord alterations
+    pub bass_alterations: [u8; 2],
+}
+
+/// The kind of a text message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TextKind {
+    /// Metadata text, status bank 0x01
+    Metadata(MetadataText),
+    /// Performance text, status bank 0x02
+    Performance(PerformanceText),
+}
+
+/// Metadata text statuses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MetadataText {
+    /// Unknown text
+    Unknown,
+    /// Project name
+    ProjectName,
+    /// Composition name
+    CompositionName,
+    /// MIDI clip name
+    ClipName,
+    /// Copyright notice
+    Copyright,
+    /// Composer name
+    ComposerName,
+    /// Lyricist name
+    LyricistName,
+    /// Arranger name
+    ArrangerName,
+    /// Publisher name
+    PublisherName,
+    /// Primary performer name
+    PrimaryPerformerName,
+    /// Accompanying performer name
+    AccompanyingPerformerName,
+    /// Recording or concert date
+    RecordingDate,
+    /// Recording or concert location
+    RecordingLocation,
+}
+
+/// Performance text statuses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PerformanceText {
+    /// Unknown text
+    Unknown,
+    /// Lyrics
+    Lyrics,
+    /// Lyrics language
+    LyricsLanguage,
+    /// Ruby
+    Ruby,
+    /// Ruby language
+    RubyLanguage,
+}
+
+impl TextKind {
+    fn from_status(bank: u8, status: u8) -> Result<Self, UmpError> {
+        use {MetadataText as M, PerformanceText as P};
+        const METADATA: [MetadataText; 13] = [
+            M::Unknown,
+            M::ProjectName,
+            M::CompositionName,
+            M::ClipName,
+            M::Copyright,
+            M::ComposerName,
+            M::LyricistName,
+            M::ArrangerName,
+            M::PublisherName,
+            M::PrimaryPerformerName,
+            M::AccompanyingPerformerName,
+            M::RecordingDate,
+            M::RecordingLocation,
+        ];
+        const PERFORMANCE: [PerformanceText; 5] = [
+            P::Unknown,
+            P::Lyrics,
+            P::LyricsLanguage,
+            P::Ruby,
+            P::RubyLanguage,
+        ];
+        let kind = match bank {
+            METADATA_BANK => METADATA
+                .get(usize::from(status))
+                .copied()
+                .map(Self::Metadata),
+            PERFORMANCE_BANK => PERFORMANCE
+                .get(usize::from(status))
+                .copied()
+                .map(Self::Performance),
+            _ => None,
+        };
+        kind.ok_or(UmpError::InvalidStatus)
+    }
+
+    fn status(self) -> (u8, u8) {
+        match self {
+            Self::Metadata(status) => (METADATA_BANK, status as u8),
+            Self::Performance(status) => (PERFORMANCE_BANK, status as u8),
+        }
+    }
+
+    /// The kind of text in a Standard MIDI File text meta event of `meta_type`, if there is one
+    pub fn from_meta_type(meta_type: u8) -> Option<Self> {
+        match meta_type {
+            META_TEXT => Some(Self::Metadata(MetadataText::Unknown)),
+            META_COPYRIGHT => Some(Self::Metadata(MetadataText::Copyright)),
+            META_TRACK_NAME => Some(Self::Metadata(MetadataText::ClipName)),
+            META_LYRIC => Some(Self::Performance(PerformanceText::Lyrics)),
+            _ => None,
+        }
+    }
+
+    /// The Standard MIDI File text meta event type for this kind of text, if there is one
+    pub fn meta_type(self) -> Option<u8> {
+        match self {
+            Self::Metadata(MetadataText::Unknown) => Some(META_TEXT),
+            Self::Metadata(MetadataText::Copyright) => Some(META_COPYRIGHT),
+            Self::Metadata(MetadataText::ClipName) => Some(META_TRACK_NAME),
+            Self::Performance(PerformanceText::Lyrics) => Some(META_LYRIC),
+            _ => None,
+        }
+    }
+}
+
+/// A typed flex data message, without the group and address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Flex {
+    /// Set the tempo, in 10 ns units per quarter note
+    SetTempo(u32),
+    /// Set the time signature
+    SetTimeSignature(TimeSignature),
+    /// Set the metronome
+    SetMetronome(Metronome),
+    /// Set the key signature
+    SetKeySignature(KeySignature),
+    /// Set the chord name
+    SetChordName(ChordName),
+    /// A packet of text
+    Text {
+        /// The kind of text
+        kind: TextKind,
+        /// The text
+        text: Text<TEXT_LEN>,
+    },
+}
+
+fn nibbles(sharps_flats: i8, tonic: Tonic) -> u8 {
+    (sharps_flats as u8) << 4 | tonic.bits()
+}
+
+fn sharps_flats(byte: u8) -> i8 {
+    // Arithmetic shift to sign extend the high nibble
+    (byte as i8) >> 4
+}
+
+impl TryFrom<FlexData> for Flex {
+    type Error = UmpError;
+
+    fn try_from(packet: FlexData) -> Result<Self, UmpError> {
+        let d = &packet.data;
+        if packet.status_bank != SETUP_BANK {
+            return Ok(Self::Text {
+                kind: TextKind::from_status(packet.status_bank, packet.status)?,
+                text: Text::decode(packet.form, d),
+            });
+        }
+        match packet.status {
+            SET_TEMPO => Ok(Self::SetTempo(u32::from_be_bytes([d[0], d[1], d[2], d[3]]))),
+            SET_TIME_SIGNATURE => Ok(Self::SetTimeSignature(TimeSignature {
+                numerator: d[0],
+                denominator: d[1],
+                thirty_seconds: d[2],
+            })),
+            SET_METRONOME => Ok(Self::SetMetronome(Metronome {
+                clocks_per_click: d[0],
+                accents: [d[1], d[2], d[3]],
+                subdivision_clicks: [d[4], d[5]],
+            })),
+            SET_KEY_SIGNATURE => Ok(Self::SetKeySignature(KeySignature {
+                sharps_flats: sharps_flats(d[0]),
+                tonic: Tonic::from_bits(d[0])?,
+            })),
+            SET_CHORD_NAME => Ok(Self::SetChordName(ChordName {
+                sharps_flats: sharps_flats(d[0]),
+                tonic: Tonic::from_bits(d[0])?,
+                chord_type: d[1],
+                alterations: [d[2], d[3], d[4], d[5]],
+                bass_sharps_flats: sharps_flats(d[8]),
+                bass_note: Tonic::from_bits(d[8])?,
+                bass_chord_type: d[9],
+                bass_alterations: [d[10], d[11]],
+            })),
+            _ => Err(UmpError::InvalidStatus),
+        }
+    }
+}
+
+impl Flex {
+    /// The packet for this message, sent to `address` of `group`
+    pub fn to_packet(&self, group: Group, address: FlexAddress) -> FlexData {
+        let mut packet = FlexData {
+            group,
+            form: Form::Complete,
+            address,
+            status_bank: SETUP_BANK,
+            status: 0,
+            data: [0; 12],
+        };
+        let d = &mut packet.data;
+        packet.status = match self {
+            Self::SetTempo(tempo) => {
+                d[..4].copy_from_slice(&tempo.to_be_bytes());
+                SET_TEMPO
+            }
+            Self::SetTimeSignature(signature) => {
+                d[0] = signature.numerator;
+                d[1] = signature.denominator;
+                d[2] = signature.thirty_seconds;
+                SET_TIME_SIGNATURE
+            }
+            Self::SetMetronome(metronome) => {
+                d[0] = metronome.clocks_per_click;
+                d[1..4].copy_from_slice(&metronome.accents);
+                d[4..6].copy_from_slice(&metronome.subdivision_clicks);
+                SET_METRONOME
+            }
+            Self::SetKeySignature(key) => {
+                d[0] = nibbles(key.sharps_flats, key.tonic);
+                SET_KEY_SIGNATURE
+            }
+            Self::SetChordName(chord) => {
+                d[0] = nibbles(chord.sharps_flats, chord.tonic);
+                d[1] = chord.chord_type;
+                d[2..6].copy_from_slice(&chord.alterations);
+                d[8] = nibbles(chord.bass_sharps_flats, chord.bass_note);
+                d[9] = chord.bass_chord_type;
+                d[10..].copy_from_slice(&chord.bass_alterations);
+                SET_CHORD_NAME
+            }
+            Self::Text { kind, text } => {
+                packet.form = text.form();
+                d[..text.data().len()].copy_from_slice(text.data());
+                let (bank, status) = kind.status();
+                packet.status_bank = bank;
+                status
+            }
+        };
+        packet
+    }
+
+    /// The flex data equivalent of a Standard MIDI File meta event
+    ///
+    /// A time signature keeps the numerator, denominator and 1/32 notes, the MIDI clocks per
+    /// metronome click map to [`Flex::SetMetronome`], see [`Metronome::from_meta`]. A key
+    /// signature outside -7 to 7 sharps has no equivalent.
+    pub fn from_meta(meta: &MetaEvent) -> Option<Self> {
+        match *meta {
+            MetaEvent::Tempo(tempo) => Some(Self::SetTempo(tempo.saturating_mul(100))),
+            MetaEvent::TimeSignature {
+                numerator,
+                denominator,
+                thirty_seconds,
+                ..
+            } => Some(Self::SetTimeSignature(TimeSignature {
+                numerator,
+                denominator,
+                thirty_seconds,
+            })),
+            MetaEvent::KeySignature {
+                sharps_flats,
+                minor,
+            } => {
+                let index = usize::try_from(i16::from(sharps_flats) + 7).ok()?;
+                let tonics = if minor { MINOR_TONICS } else { MAJOR_TONICS };
+                Some(Self::SetKeySignature(KeySignature {
+                    sharps_flats,
+                    tonic: *tonics.get(index)?,
+                }))
+            }
+        }
+    }
+
+    /// The Standard MIDI File meta event equivalent of this message
+    ///
+    /// The tempo is rounded to the nearest microsecond. A time signature gets 24 MIDI clocks per
+    /// metronome click. A key signature needs a tonic that makes it a major or minor key.
+    pub fn to_meta(&self) -> Option<MetaEvent> {
+        match *self {
+            Self::SetTempo(tempo) => Some(MetaEvent::Tempo(
+                (tempo.saturating_add(50) / 100).min(MetaEvent::MAX_TEMPO),
+            )),
+            Self::SetTimeSignature(signature) => Some(MetaEvent::TimeSignature {
+                numerator: signature.numerator,
+                denominator: signature.denominator,
+                clocks_per_click: 24,
+                thirty_seconds: signature.thirty_seconds,
+            }),
+            Self::SetKeySignature(key) => {
+                let index = usize::try_from(i16::from(key.sharps_flats) + 7).ok()?;
+                let minor = match key.tonic {
+                    tonic if MAJOR_TONICS.get(index) == Some(&tonic) => false,
+                    tonic if MINOR_TONICS.get(index) == Some(&tonic) => true,
+                    _ => return None,
+                };
+                Some(MetaEvent::KeySignature {
+                    sharps_flats: key.sharps_flats,
+                    minor,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Metronome {
+    /// A metronome clicking every `clocks_per_click` MIDI clocks, from a Standard MIDI File time
+    /// signature, without accents or subdivisions
+    pub fn from_meta(meta: &MetaEvent) -> Option<Self> {
+        match *meta {
+            MetaEvent::TimeSignature {
+                clocks_per_click, ..
+            } => Some(Self {
+                clocks_per_click,
+                accents: [0; 3],
+                subdivision_clicks: [0; 2],
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Major key tonics from 7 flats to 7 sharps
+const MAJOR_TONICS: [Tonic; 15] = {
+    use Tonic::*;
+    [C, G, D, A, E, B, F, C, G, D, A, E, B, F, C]
+};
+
+/// Minor key tonics from 7 flats to 7 sharps
+const MINOR_TONICS: [Tonic; 15] = {
+    use Tonic::*;
+    [A, E, B, F, C, G, D, A, E, B, F, C, G, D, A]
+};
+
+/// The Standard MIDI File meta events with a flex data equivalent, other than text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MetaEvent {
+    /// Tempo in microseconds per quarter note, 24 bits, type 0x51
+    Tempo(u32),
+    /// Time signature, type 0x58
+    TimeSignature {
+        /// Beats per bar
+        numerator: u8,
+        /// The beat as a negative power of two
+        denominator: u8,
+        /// MIDI clocks per metronome click
+        clocks_per_click: u8,
+        /// The number of 1/32 notes per quarter note
+        thirty_seconds: u8,
+    },
+    /// Key signature, type 0x59
+    KeySignature {
+        /// Sharps if positive, flats if negative
+        sharps_flats: i8,
+        /// Whether the key is minor
+        minor: bool,
+    },
+}
+
+impl MetaEvent {
+    const MAX_TEMPO: u32 = 0xff_ffff;
+
+    /// Parse the data of a meta event of `meta_type`
+    pub fn parse(meta_type: u8, data: &[u8]) -> Option<Self> {
+        match (meta_type, data) {
+            (META_TEMPO, &[a, b, c]) => Some(Self::Tempo(u32::from_be_bytes([0, a, b, c]))),
+            (META_TIME_SIGNATURE, &[numerator, denominator, clocks_per_click, thirty_seconds]) => {
+                Some(Self::TimeSignature {
+                    numerator,
+                    denominator,
+                    clocks_per_click,
+                    thirty_seconds,
+                })
+            }
+            (META_KEY_SIGNATURE, &[sharps_flats, minor]) => Some(Self::KeySignature {
+                sharps_flats: sharps_flats as i8,
+                minor: minor != 0,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The meta event type
+    pub fn meta_type(&self) -> u8 {
+        match self {
+            Self::Tempo(_) => META_TEMPO,
+            Self::TimeSignature { .. } => META_TIME_SIGNATURE,
+            Self::KeySignature { .. } => META_KEY_SIGNATURE,
+        }
+    }
+
+    /// Write the meta event data into `buf`
+    pub fn data<'a>(&self, buf: &'a mut [u8; 4]) -> &'a [u8] {
+        match *self {
+            Self::Tempo(tempo) => {
+                buf.copy_from_slice(&tempo.min(Self::MAX_TEMPO).to_be_bytes());
+                &buf[1..]
+            }
+            Self::TimeSignature {
+                numerator,
+                denominator,
+                clocks_per_click,
+                thirty_seconds,
+            } => {
+                *buf = [numerator, denominator, clocks_per_click, thirty_seconds];
+                &buf[..]
+            }
+            Self::KeySignature {
+                sharps_flats,
+                minor,
+            } => {
+                buf[0] = sharps_flats as u8;
+                buf[1] = minor.into();
+                &buf[..2]
+            }
+        }
+    }
+}
+
+/// Iterator over the packets of a text, see [`text_packets`]
+#[derive(Debug, Clone)]
+pub struct TextPackets<'a> {
+    group: Group,
+    address: FlexAddress,
+    kind: TextKind,
+    segments: Segments<'a>,
+}
+
+impl Iterator for TextPackets<'_> {
+    type Item = UmpMessage;
+
+    fn next(&mut self) -> Option<UmpMessage> {
+        let (form, chunk) = self.segments.next()?;
+        let (status_bank, status) = self.kind.status();
+        let mut data = [0; 12];
+        data[..chunk.len()].copy_from_slice(chunk);
+        Some(UmpMessage::FlexData(FlexData {
+            group: self.group,
+            form,
+            address: self.address,
+            status_bank,
+            status,
+            data,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.segments.size_hint()
+    }
+}
+
+impl ExactSizeIterator for TextPackets<'_> {}
+
+/// Split `text` into packets sent to `address` of `group`, an empty text has none
+///
+/// The text ends at the first zero byte.
+pub fn text_packets(
+    group: Group,
+    address: FlexAddress,
+    kind: TextKind,
+    text: &str,
+) -> TextPackets<'_> {
+    let data = text.as_bytes();
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    TextPackets {
+        group,
+        address,
+        kind,
+        segments: Segments::new(&data[..len], TEXT_LEN, false),
+    }
+}
+
+/// A reassembled text message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FlexText<'a> {
+    /// The group
+    pub group: Group,
+    /// The destination
+    pub address: FlexAddress,
+    /// The kind of text
+    pub kind: TextKind,
+    /// The UTF-8 text
+    pub data: &'a [u8],
+}
+
+/// Errors reassembling text messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TextError {
+    /// The text did not fit in the buffer and was dropped
+    TooLong,
+    /// A start packet arrived before the previous text ended, the unfinished text was dropped
+    Interrupted,
+    /// A continue or end packet arrived without a start packet
+    Unexpected,
+    /// Every buffer holds an unfinished text, the text was dropped
+    NoFreeBuffer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    group: Group,
+    address: FlexAddress,
+    kind: TextKind,
+}
+
+impl TextError {
+    fn new(error: ReassemblyError) -> Self {
+        match error {
+            ReassemblyError::TooLong => Self::TooLong,
+            ReassemblyError::Interrupted => Self::Interrupted,
+            ReassemblyError::Unexpected => Self::Unexpected,
+            ReassemblyError::NoFreeBuffer => Self::NoFreeBuffer,
+        }
+    }
+}
+
+/// Reassembles text messages of up to `N` bytes, for up to `S` destinations and kinds of text at
+/// the same time
+#[derive(Debug, Clone)]
+pub struct TextReassembler<const N: usize, const S: usize> {
+    reassembler: Reassembler<Key, N, S>,
+}
+
+impl<const N: usize, const S: usize> TextReassembler<N, S> {
+    /// Create a new reassembler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a packet, returning the text it completes
+    ///
+    /// Packets other than flex data text are ignored. Complete packets are returned straight away
+    /// and leave unfinished texts of their destination and kind alone.
+    pub fn push(&mut self, packet: &UmpMessage) -> Result<Option<FlexText<'_>>, TextError> {
+        let UmpMessage::FlexData(packet) = packet else {
+            return Ok(None);
+        };
+        let Ok(Flex::Text { kind, text }) = Flex::try_from(*packet) else {
+            return Ok(None);
+        };
+        let key = Key {
+            group: packet.group,
+            address: packet.address,
+            kind,
+        };
+        let data = self
+            .reassembler
+            .feed(key, text.form(), text.data())
+            .map_err(TextError::new)?;
+        Ok(data.map(|data| FlexText {
+            group: key.group,
+            address: key.address,
+            kind: key.kind,
+            data,
+        }))
+    }
+
+    /// Drop all unfinished texts
+    pub fn reset(&mut self) {
+        self.reassembler.reset();
+    }
+}
+
+impl<const N: usize, const S: usize> Default for TextReassembler<N, S> {
+    fn default() -> Self {
+        Self {
+            reassembler: Reassembler::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, midi_types::Channel, std::vec::Vec};
+
+    fn round_trip(flex: Flex, address: FlexAddress, words: [u32; 4]) {
+        let mut buf = [0; 4];
+        let message = UmpMessage::FlexData(flex.to_packet(Group::new(0), address));
+        assert_eq!(message.render(&mut buf), Ok(4));
+        assert_eq!(buf, words, "{flex:?}");
+        let Ok(UmpMessage::FlexData(packet)) = UmpMessage::parse(&words) else {
+            panic!("{words:x?}");
+        };
+        assert_eq!(Flex::try_from(packet), Ok(flex));
+    }
+
+    #[test]
+    fn should_encode_flex_messages() {
+        round_trip(
+            Flex::SetTempo(50_000_000),
+            FlexAddress::Group,
+            [0xd010_0000, 0x02fa_f080, 0, 0],
+        );
+        round_trip(
+            Flex::SetTimeSignature(TimeSignature {
+                numerator: 6,
+                denominator: 3,
+                thirty_seconds: 8,
+            }),
+            FlexAddress::Group,
+            [0xd010_0001, 0x0603_0800, 0, 0],
+        );
+        round_trip(
+            Flex::SetMetronome(Metronome {
+                clocks_per_click: 24,
+                accents: [3, 3, 0],
+                subdivision_clicks: [2, 0],
+            }),
+            FlexAddress::Group,
+            [0xd010_0002, 0x1803_0300, 0x0200_0000, 0],
+        );
+        round_trip(
+            Flex::SetKeySignature(KeySignature {
+                sharps_flats: -3,
+                tonic: Tonic::E,
+            }),
+            FlexAddress::Channel(Channel::new(2)),
+            [0xd002_0005, 0xd500_0000, 0, 0],
+        );
+        round_trip(
+            Flex::SetChordName(ChordName {
+                sharps_flats: -1,
+                tonic: Tonic::B,
+                chord_type: 0x0d,
+                alterations: [0x19, 0, 0, 0],
+                bass_sharps_flats: 0,
+                bass_note: Tonic::D,
+                bass_chord_type: 0,
+                bass_alterations: [0, 0],
+            }),
+            FlexAddress::Group,
+            [0xd010_0006, 0xf20d_1900, 0x0000_0000, 0x0400_0000],
+        );
+        round_trip(
+            Flex::Text {
+                kind: TextKind::Performance(PerformanceText::Lyrics),
+                text: Text::new(Form::Complete, b"la la").unwrap(),
+            },
+            FlexAddress::Channel(Channel::new(0)),
+            [0xd000_0201, 0x6c61_206c, 0x6100_0000, 0],
+        );
+        round_trip(
+            Flex::Text {
+                kind: TextKind::Metadata(MetadataText::RecordingLocation),
+                text: Text::new(Form::End, b"Amsterdam").unwrap(),
+            },
+            FlexAddress::Group,
+            [0xd0d0_010c, 0x416d_7374, 0x6572_6461, 0x6d00_0000],
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_flex_messages() {
+        let packet = |status_bank, status, data| FlexData {
+            group: Group::new(0),
+            form: Form::Complete,
+            address: FlexAddress::Group,
+            status_bank,
+            status,
+            data,
+        };
+        assert_eq!(
+            Flex::try_from(packet(0, 3, [0; 12])),
+            Err(UmpError::InvalidStatus)
+        );
+        assert_eq!(
+            Flex::try_from(packet(1, 13, [0; 12])),
+            Err(UmpError::InvalidStatus)
+        );
+        assert_eq!(
+            Flex::try_from(packet(2, 5, [0; 12])),
+            Err(UmpError::InvalidStatus)
+        );
+        assert_eq!(
+            Flex::try_from(packet(3, 0, [0; 12])),
+            Err(UmpError::InvalidStatus)
+        );
+        assert_eq!(
+            Flex::try_from(packet(0, 5, [0x08; 12])),
+            Err(UmpError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn should_reassemble_text() {
+        let lyrics = TextKind::Performance(PerformanceText::Lyrics);
+        let title = TextKind::Metadata(MetadataText::CompositionName);
+        let verse = "Twinkle, twinkle, little star, how I wonder what you are";
+        let name = "Twinkle twinkle little star";
+        let mut lyric_packets = text_packets(Group::new(0), FlexAddress::Group, lyrics, verse);
+        let mut title_packets = text_packets(Group::new(0), FlexAddress::Group, title, name);
+        assert_eq!(lyric_packets.len(), 5);
+        assert_eq!(title_packets.len(), 3);
+
+        let mut reassembler = TextReassembler::<64, 2>::new();
+        let mut texts = Vec::new();
+        while lyric_packets.len() + title_packets.len() > 0 {
+            for packet in lyric_packets.next().into_iter().chain(title_packets.next()) {
+                if let Some(text) = reassembler.push(&packet).unwrap() {
+                    texts.push((text.kind, std::str::from_utf8(text.data).unwrap().into()));
+                }
+            }
+        }
+        assert_eq!(
+            texts,
+            [
+                (title, std::string::String::from(name)),
+                (lyrics, verse.into())
+            ]
+        );
+
+        let short = text_packets(Group::new(0), FlexAddress::Group, lyrics, "la");
+        let mut texts = Vec::new();
+        for packet in short {
+            texts.push(
+                reassembler
+                    .push(&packet)
+                    .unwrap()
+                    .map(|text| text.data.to_vec()),
+            );
+        }
+        assert_eq!(texts, [Some(b"la".to_vec())]);
+    }
+
+    #[test]
+    fn should_report_text_errors() {
+        let lyrics = TextKind::Performance(PerformanceText::Lyrics);
+        let packets: Vec<_> = text_packets(
+            Group::new(0),
+            FlexAddress::Group,
+            lyrics,
+            "a line of lyrics",
+        )
+        .collect();
+        let mut reassembler = TextReassembler::<8, 1>::new();
+        assert_eq!(reassembler.push(&packets[1]), Err(TextError::Unexpected));
+        assert_eq!(reassembler.push(&packets[0]), Ok(None));
+        assert_eq!(reassembler.push(&packets[0]), Err(TextError::Interrupted));
+        let other = text_packets(
+            Group::new(1),
+            FlexAddress::Group,
+            lyrics,
+            "another line of lyrics",
+        )
+        .next()
+        .unwrap();
+        assert_eq!(reassembler.push(&other), Err(TextError::NoFreeBuffer));
+        assert_eq!(reassembler.push(&packets[1]), Err(TextError::TooLong));
+        assert_eq!(reassembler.push(&packets[1]), Err(TextError::Unexpected));
+        assert_eq!(
+            reassembler.push(&UmpMessage::FlexData(
+                Flex::SetTempo(1).to_packet(Group::new(0), FlexAddress::Group)
+            )),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn should_convert_meta_events() {
+        let mut buf = [0; 4];
+        let tempo = MetaEvent::parse(0x51, &[0x07, 0xa1, 0x20]).unwrap();
+        assert_eq!(tempo, MetaEvent::Tempo(500_000));
+        assert_eq!(tempo.data(&mut buf), [0x07, 0xa1, 0x20]);
+        assert_eq!(Flex::from_meta(&tempo), Some(Flex::SetTempo(50_000_000)));
+        assert_eq!(Flex::SetTempo(49_999_999).to_meta(), Some(tempo));
+        assert_eq!(
+            Flex::SetTempo(u32::MAX).to_meta(),
+            Some(MetaEvent::Tempo(0xff_ffff))
+        );
+
+        let time = MetaEvent::parse(0x58, &[6, 3, 36, 8]).unwrap();
+        assert_eq!(time.meta_type(), 0x58);
+        assert_eq!(time.data(&mut buf), [6, 3, 36, 8]);
+        let flex = Flex::from_meta(&time).unwrap();
+        assert_eq!(
+            flex,
+            Flex::SetTimeSignature(TimeSignature {
+                numerator: 6,
+                denominator: 3,
+                thirty_seconds: 8
+            })
+        );
+        assert_eq!(
+            Metronome::from_meta(&time).map(|m| m.clocks_per_click),
+            Some(36)
+        );
+        assert_eq!(flex.to_meta(), MetaEvent::parse(0x58, &[6, 3, 24, 8]));
+
+        for sharps_flats in -7..=7 {
+            for minor in [false, true] {
+                let key = MetaEvent::KeySignature {
+                    sharps_flats,
+                    minor,
+                };
+                let flex = Flex::from_meta(&key).unwrap();
+                assert_eq!(flex.to_meta(), Some(key));
+                let mut buf = [0; 4];
+                assert_eq!(
+                    MetaEvent::parse(key.meta_type(), key.data(&mut buf)),
+                    Some(key)
+                );
+            }
+        }
+        let e_flat = MetaEvent::parse(0x59, &[0xfd, 0]).unwrap();
+        assert_eq!(
+            Flex::from_meta(&e_flat),
+            Some(Flex::SetKeySignature(KeySignature {
+                sharps_flats: -3,
+                tonic: Tonic::E
+            }))
+        );
+        let f_sharp_minor = MetaEvent::parse(0x59, &[3, 1]).unwrap();
+        assert_eq!(
+            Flex::from_meta(&f_sharp_minor),
+            Some(Flex::SetKeySignature(KeySignature {
+                sharps_flats: 3,
+                tonic: Tonic::F
+            }))
+        );
+        assert_eq!(
+            Flex::from_meta(&MetaEvent::KeySignature {
+                sharps_flats: 8,
+                minor: false
+            }),
+            None
+        );
+        let corrupt = MetaEvent::parse(0x59, &[0x7f, 0]).unwrap();
+        assert_eq!(Flex::from_meta(&corrupt), None);
+        let unknown = Flex::SetKeySignature(KeySignature {
+            sharps_flats: 0,
+            tonic: Tonic::D,
+        });
+        assert_eq!(unknown.to_meta(), None);
+        let out_of_range = Flex::SetKeySignature(KeySignature {
+            sharps_flats: i8::MAX,
+            tonic: Tonic::C,
+        });
+        assert_eq!(out_of_range.to_meta(), None);
+        assert_eq!(MetaEvent::parse(0x51, &[0, 0]), None);
+
+        assert_eq!(
+            TextKind::from_meta_type(0x05),
+            Some(TextKind::Performance(PerformanceText::Lyrics))
+        );
+        assert_eq!(
+            TextKind::from_meta_type(0x03).and_then(TextKind::meta_type),
+            Some(0x03)
+        );
+        assert_eq!(TextKind::from_meta_type(0x06), None);
+    }
+}
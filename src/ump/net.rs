@@ -0,0 +1,2662 @@
+//! Network MIDI 2.0 over UDP
+//!
+//! Every UDP packet starts with the signature `MIDI` followed by one or more commands, each a four
+//! byte header holding the command code, the payload length in words and two command specific
+//! bytes, followed by the payload. UMPs travel in UMP Data commands numbered with a sequence
+//! number, so the receiver can detect loss and ask for a retransmission. A sender may also repeat
+//! its latest UMP Data commands in every packet, forward error correction that lets the receiver
+//! recover from the loss of single packets without a round trip.
+//!
+//! A client invites a host, which accepts, asks for authentication or answers with a bye.
+//! Authentication with a shared secret or a user name and password needs the `auth` feature.
+//! Connected peers ping each other when the other side is quiet and either side ends the session
+//! with a bye.
+//!
+//! [`NetworkSession`] is a state machine that does no I/O itself. Received packets are passed to
+//! [`NetworkSession::handle`] and the packets to send are taken from
+//! [`NetworkSession::poll_transmit`]. UMPs are sent with [`NetworkSession::send`]. Times are
+//! durations since an arbitrary start. A std UDP driver is available with the `std` feature.
+//!
+//! ```
+//! use core::time::Duration;
+//! use midi_convert::ump::{
+//!     Group, UmpMessage, Utility,
+//!     net::{NetworkSession, SessionEvent, SessionState},
+//! };
+//! use midi_types::MidiMessage;
+//!
+//! let mut client: NetworkSession = NetworkSession::new("client", "0001");
+//! let mut host: NetworkSession = NetworkSession::new("host", "0002");
+//! let mut buf = [0; 512];
+//! let mut ump = [UmpMessage::Utility(Utility::NoOp); 8];
+//! let now = Duration::ZERO;
+//!
+//! client.connect(now);
+//! while let Some(len) = client.poll_transmit(now, &mut buf).unwrap() {
+//!     host.handle(&buf[..len], now, &mut ump).unwrap();
+//!     while let Some(len) = host.poll_transmit(now, &mut buf).unwrap() {
+//!         client.handle(&buf[..len], now, &mut ump).unwrap();
+//!     }
+//! }
+//! assert_eq!(client.state(), SessionState::Connected);
+//! assert_eq!(host.state(), SessionState::Connected);
+//!
+//! let note_on = UmpMessage::Midi1ChannelVoice {
+//!     group: Group::new(0),
+//!     message: MidiMessage::NoteOn(0.into(), 60.into(), 100.into()),
+//! };
+//! let len = client.send(&[note_on], &mut buf).unwrap();
+//! let event = host.handle(&buf[..len], now, &mut ump).unwrap();
+//! assert_eq!(event, Some(SessionEvent::Ump(1)));
+//! assert_eq!(ump[0], note_on);
+//! ```
+
+use {
+    super::{UmpError, UmpMessage, message_words},
+    core::time::Duration,
+};
+
+#[cfg(feature = "std")]
+pub mod udp;
+
+/// The signature every packet starts with
+pub const SIGNATURE: [u8; 4] = *b"MIDI";
+
+/// Invitation capability flag, the client can authenticate with a shared secret
+pub const CAPABILITY_AUTH: u8 = 0x01;
+
+/// Invitation capability flag, the client can authenticate with a user name and password
+pub const CAPABILITY_USER_AUTH: u8 = 0x02;
+
+const INVITATION: u8 = 0x01;
+const INVITATION_WITH_AUTH: u8 = 0x02;
+const INVITATION_WITH_USER_AUTH: u8 = 0x03;
+const INVITATION_ACCEPTED: u8 = 0x10;
+const INVITATION_PENDING: u8 = 0x11;
+const AUTHENTICATION_REQUIRED: u8 = 0x12;
+const USER_AUTHENTICATION_REQUIRED: u8 = 0x13;
+const PING: u8 = 0x20;
+const PING_REPLY: u8 = 0x21;
+const RETRANSMIT_REQUEST: u8 = 0x80;
+const RETRANSMIT_ERROR: u8 = 0x81;
+const SESSION_RESET: u8 = 0x82;
+const SESSION_RESET_REPLY: u8 = 0x83;
+const NAK: u8 = 0x8f;
+const BYE: u8 = 0xf0;
+const BYE_REPLY: u8 = 0xf1;
+const UMP_DATA: u8 = 0xff;
+
+/// The longest command payload, in words
+const MAX_PAYLOAD: usize = 0xff;
+
+/// The most words sent in one UMP Data command
+const DATA_WORDS: usize = 64;
+
+const INVITATION_INTERVAL: Duration = Duration::from_secs(1);
+const INVITATION_ATTEMPTS: u8 = 10;
+
+/// A quiet peer is pinged every second, after three unanswered pings the session ends
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const PING_ATTEMPTS: u8 = 3;
+
+/// Missing UMP Data commands are requested again when they haven't arrived by then
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(50);
+
+#[cfg(feature = "auth")]
+const AUTHENTICATION_ATTEMPTS: u8 = 3;
+
+/// Errors reading or writing Network MIDI 2.0 packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NetError {
+    /// The buffer is shorter than the data it should hold
+    BufferTooShort,
+
+    /// The packet doesn't start with the `MIDI` signature
+    InvalidSignature,
+
+    /// The command code isn't defined
+    UnknownCommand,
+
+    /// The command payload doesn't match the command
+    InvalidCommand,
+
+    /// The payload exceeds the 255 words a command can hold
+    TooLong,
+
+    /// UMPs can only be sent in a connected session
+    NotConnected,
+}
+
+/// The reason given in a bye command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ByeReason {
+    /// No reason given
+    Unknown,
+
+    /// The user ended the session
+    UserTerminated,
+
+    /// The device is powering down
+    PowerDown,
+
+    /// Too many UMP Data commands were lost
+    TooManyLostPackets,
+
+    /// The peer didn't answer pings
+    Timeout,
+
+    /// A command was received outside a session
+    SessionNotEstablished,
+
+    /// An invitation reply was received without an invitation
+    NoPendingSession,
+
+    /// The peer violated the protocol
+    ProtocolError,
+
+    /// The host can't open another session
+    TooManySessions,
+
+    /// An invitation with authentication was received without a prior invitation
+    MissingInvitation,
+
+    /// The user of the host didn't accept the session
+    UserRejected,
+
+    /// The authentication digest was incorrect
+    AuthenticationFailed,
+
+    /// The user name is not known to the host
+    UsernameNotFound,
+
+    /// Client and host share no authentication method
+    NoMatchingAuthMethod,
+
+    /// The client canceled its invitation
+    InvitationCanceled,
+
+    /// A reason not defined above
+    Other(u8),
+}
+
+impl From<u8> for ByeReason {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0x00 => Self::Unknown,
+            0x01 => Self::UserTerminated,
+            0x02 => Self::PowerDown,
+            0x03 => Self::TooManyLostPackets,
+            0x04 => Self::Timeout,
+            0x05 => Self::SessionNotEstablished,
+            0x06 => Self::NoPendingSession,
+            0x07 => Self::ProtocolError,
+            0x40 => Self::TooManySessions,
+            0x41 => Self::MissingInvitation,
+            0x42 => Self::UserRejected,
+            0x43 => Self::AuthenticationFailed,
+            0x44 => Self::UsernameNotFound,
+            0x45 => Self::NoMatchingAuthMethod,
+            0x80 => Self::InvitationCanceled,
+            reason => Self::Other(reason),
+        }
+    }
+}
+
+impl From<ByeReason> for u8 {
+    fn from(reason: ByeReason) -> Self {
+        match reason {
+            ByeReason::Unknown => 0x00,
+            ByeReason::UserTerminated => 0x01,
+            ByeReason::PowerDown => 0x02,
+            ByeReason::TooManyLostPackets => 0x03,
+            ByeReason::Timeout => 0x04,
+            ByeReason::SessionNotEstablished => 0x05,
+            ByeReason::NoPendingSession => 0x06,
+            ByeReason::ProtocolError => 0x07,
+            ByeReason::TooManySessions => 0x40,
+            ByeReason::MissingInvitation => 0x41,
+            ByeReason::UserRejected => 0x42,
+            ByeReason::AuthenticationFailed => 0x43,
+            ByeReason::UsernameNotFound => 0x44,
+            ByeReason::NoMatchingAuthMethod => 0x45,
+            ByeReason::InvitationCanceled => 0x80,
+            ByeReason::Other(reason) => reason,
+        }
+    }
+}
+
+/// The reason given in a NAK command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NakReason {
+    /// The command code isn't supported
+    CommandNotSupported,
+
+    /// The command isn't expected in the current state
+    CommandNotExpected,
+
+    /// The command is malformed
+    CommandMalformed,
+
+    /// The ping reply doesn't answer a ping
+    BadPingReply,
+
+    /// A reason not defined above
+    Other(u8),
+}
+
+impl From<u8> for NakReason {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0x01 => Self::CommandNotSupported,
+            0x02 => Self::CommandNotExpected,
+            0x03 => Self::CommandMalformed,
+            0x20 => Self::BadPingReply,
+            reason => Self::Other(reason),
+        }
+    }
+}
+
+impl From<NakReason> for u8 {
+    fn from(reason: NakReason) -> Self {
+        match reason {
+            NakReason::CommandNotSupported => 0x01,
+            NakReason::CommandNotExpected => 0x02,
+            NakReason::CommandMalformed => 0x03,
+            NakReason::BadPingReply => 0x20,
+            NakReason::Other(reason) => reason,
+        }
+    }
+}
+
+/// The reason given in a retransmit error command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RetransmitErrorReason {
+    /// No reason given
+    Unknown,
+
+    /// The retransmit buffer no longer holds the requested commands
+    NotBuffered,
+
+    /// A reason not defined above
+    Other(u8),
+}
+
+impl From<u8> for RetransmitErrorReason {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0x00 => Self::Unknown,
+            0x01 => Self::NotBuffered,
+            reason => Self::Other(reason),
+        }
+    }
+}
+
+impl From<RetransmitErrorReason> for u8 {
+    fn from(reason: RetransmitErrorReason) -> Self {
+        match reason {
+            RetransmitErrorReason::Unknown => 0x00,
+            RetransmitErrorReason::NotBuffered => 0x01,
+            RetransmitErrorReason::Other(reason) => reason,
+        }
+    }
+}
+
+/// The payload of a UMP Data command, big endian words
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UmpData<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> UmpData<'a> {
+    /// Wrap the bytes of up to 255 big endian words
+    pub fn new(bytes: &'a [u8]) -> Result<Self, NetError> {
+        if bytes.len() % 4 != 0 {
+            return Err(NetError::InvalidCommand);
+        }
+        if bytes.len() > 4 * MAX_PAYLOAD {
+            return Err(NetError::TooLong);
+        }
+        Ok(Self { bytes })
+    }
+
+    /// The raw bytes
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// The number of words
+    pub fn len(&self) -> usize {
+        self.bytes.len() / 4
+    }
+
+    /// Returns true when the payload holds no words
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The words of the payload
+    pub fn words(&self) -> impl Iterator<Item = u32> + use<'a> {
+        self.bytes
+            .chunks_exact(4)
+            .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+    }
+
+    /// The packets in the payload
+    ///
+    /// Errors don't end the iteration, except for a truncated last packet.
+    pub fn messages(&self) -> impl Iterator<Item = Result<UmpMessage, UmpError>> + use<'a> {
+        let mut words = self.words();
+        core::iter::from_fn(move || {
+            let first = words.next()?;
+            let mut packet = [first, 0, 0, 0];
+            let len = message_words(first);
+            for word in &mut packet[1..len] {
+                match words.next() {
+                    Some(next) => *word = next,
+                    None => return Some(Err(UmpError::BufferTooShort)),
+                }
+            }
+            Some(UmpMessage::parse(&packet[..len]))
+        })
+    }
+}
+
+/// Network MIDI 2.0 commands
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command<'a> {
+    /// An invitation from a client, `capabilities` holds the `CAPABILITY_*` flags
+    Invitation {
+        name: &'a str,
+        product_instance_id: &'a str,
+        capabilities: u8,
+    },
+
+    /// An invitation answering an authentication request with the digest of the shared secret
+    InvitationWithAuth { digest: [u8; 32] },
+
+    /// An invitation answering a user authentication request
+    InvitationWithUserAuth { digest: [u8; 32], username: &'a str },
+
+    /// The host accepted the invitation
+    InvitationAccepted {
+        name: &'a str,
+        product_instance_id: &'a str,
+    },
+
+    /// The host waits for its user to accept the invitation
+    InvitationPending {
+        name: &'a str,
+        product_instance_id: &'a str,
+    },
+
+    /// The host asks for the digest of `nonce` and the shared secret, `failed` is set when the
+    /// previous digest was incorrect
+    AuthenticationRequired {
+        nonce: [u8; 16],
+        failed: bool,
+        name: &'a str,
+        product_instance_id: &'a str,
+    },
+
+    /// The host asks for a user name and the digest of `nonce`, the user name and the password
+    UserAuthenticationRequired {
+        nonce: [u8; 16],
+        failed: bool,
+        name: &'a str,
+        product_instance_id: &'a str,
+    },
+
+    /// A ping
+    Ping { id: u32 },
+
+    /// The answer to the ping `id`
+    PingReply { id: u32 },
+
+    /// A request to send `count` UMP Data commands again, starting at `sequence`
+    RetransmitRequest { sequence: u16, count: u16 },
+
+    /// The requested commands can't be sent again, `sequence` is the oldest that can
+    RetransmitError {
+        sequence: u16,
+        reason: RetransmitErrorReason,
+    },
+
+    /// A request to start the sequence numbers of both directions again at 0
+    SessionReset,
+
+    /// The answer to a session reset
+    SessionResetReply,
+
+    /// The command with the header `header` was rejected
+    Nak {
+        reason: NakReason,
+        header: [u8; 4],
+        message: &'a str,
+    },
+
+    /// The end of the session or a rejected invitation
+    Bye { reason: ByeReason, message: &'a str },
+
+    /// The answer to a bye
+    ByeReply,
+
+    /// UMPs
+    UmpData { sequence: u16, data: UmpData<'a> },
+}
+
+impl<'a> Command<'a> {
+    /// Parse the command at the start of `bytes`, returning it and the bytes that follow it
+    pub fn parse(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), NetError> {
+        let (header, payload, rest) = split(bytes)?;
+        Ok((Self::decode(header, payload)?, rest))
+    }
+
+    fn decode(header: Header, payload: &'a [u8]) -> Result<Self, NetError> {
+        let fixed = |words: usize| {
+            if payload.len() == 4 * words {
+                Ok(())
+            } else {
+                Err(NetError::InvalidCommand)
+            }
+        };
+        let half = |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+        let word = || u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+
+        let data = [header[2], header[3]];
+        Ok(match header[0] {
+            INVITATION => {
+                let (name, product_instance_id) = names(payload, data[0])?;
+                Self::Invitation {
+                    name,
+                    product_instance_id,
+                    capabilities: data[1],
+                }
+            }
+            INVITATION_WITH_AUTH => {
+                fixed(8)?;
+                Self::InvitationWithAuth {
+                    digest: digest(payload)?,
+                }
+            }
+            INVITATION_WITH_USER_AUTH => Self::InvitationWithUserAuth {
+                digest: digest(payload)?,
+                username: text(&payload[32..])?,
+            },
+            INVITATION_ACCEPTED | INVITATION_PENDING => {
+                let (name, product_instance_id) = names(payload, data[0])?;
+                if header[0] == INVITATION_ACCEPTED {
+                    Self::InvitationAccepted {
+                        name,
+                        product_instance_id,
+                    }
+                } else {
+                    Self::InvitationPending {
+                        name,
+                        product_instance_id,
+                    }
+                }
+            }
+            AUTHENTICATION_REQUIRED | USER_AUTHENTICATION_REQUIRED => {
+                let nonce = payload.get(..16).ok_or(NetError::InvalidCommand)?;
+                let nonce = core::array::from_fn(|i| nonce[i]);
+                let failed = data[1] != 0;
+                let (name, product_instance_id) = names(&payload[16..], data[0])?;
+                if header[0] == AUTHENTICATION_REQUIRED {
+                    Self::AuthenticationRequired {
+                        nonce,
+                        failed,
+                        name,
+                        product_instance_id,
+                    }
+                } else {
+                    Self::UserAuthenticationRequired {
+                        nonce,
+                        failed,
+                        name,
+                        product_instance_id,
+                    }
+                }
+            }
+            PING | PING_REPLY => {
+                fixed(1)?;
+                if header[0] == PING {
+                    Self::Ping { id: word() }
+                } else {
+                    Self::PingReply { id: word() }
+                }
+            }
+            RETRANSMIT_REQUEST => {
+                fixed(1)?;
+                Self::RetransmitRequest {
+                    sequence: u16::from_be_bytes(data),
+                    count: half(0),
+                }
+            }
+            RETRANSMIT_ERROR => {
+                fixed(1)?;
+                Self::RetransmitError {
+                    sequence: half(0),
+                    reason: data[1].into(),
+                }
+            }
+            SESSION_RESET => {
+                fixed(0)?;
+                Self::SessionReset
+            }
+            SESSION_RESET_REPLY => {
+                fixed(0)?;
+                Self::SessionResetReply
+            }
+            NAK => {
+                let nak = payload.get(..4).ok_or(NetError::InvalidCommand)?;
+                Self::Nak {
+                    reason: data[0].into(),
+                    header: [nak[0], nak[1], nak[2], nak[3]],
+                    message: text(&payload[4..])?,
+                }
+            }
+            BYE => Self::Bye {
+                reason: data[0].into(),
+                message: text(payload)?,
+            },
+            BYE_REPLY => {
+                fixed(0)?;
+                Self::ByeReply
+            }
+            UMP_DATA => Self::UmpData {
+                sequence: u16::from_be_bytes(data),
+                data: UmpData::new(payload)?,
+            },
+            _ => return Err(NetError::UnknownCommand),
+        })
+    }
+
+    /// The length of the command in bytes
+    pub fn size(&self) -> usize {
+        4 + 4 * self.payload_words()
+    }
+
+    fn payload_words(&self) -> usize {
+        match self {
+            Self::Invitation {
+                name,
+                product_instance_id,
+                ..
+            }
+            | Self::InvitationAccepted {
+                name,
+                product_instance_id,
+            }
+            | Self::InvitationPending {
+                name,
+                product_instance_id,
+            } => text_words(name) + text_words(product_instance_id),
+            Self::InvitationWithAuth { .. } => 8,
+            Self::InvitationWithUserAuth { username, .. } => 8 + text_words(username),
+            Self::AuthenticationRequired {
+                name,
+                product_instance_id,
+                ..
+            }
+            | Self::UserAuthenticationRequired {
+                name,
+                product_instance_id,
+                ..
+            } => 4 + text_words(name) + text_words(product_instance_id),
+            Self::Ping { .. }
+            | Self::PingReply { .. }
+            | Self::RetransmitRequest { .. }
+            | Self::RetransmitError { .. } => 1,
+            Self::SessionReset | Self::SessionResetReply | Self::ByeReply => 0,
+            Self::Nak { message, .. } => 1 + text_words(message),
+            Self::Bye { message, .. } => text_words(message),
+            Self::UmpData { data, .. } => data.len(),
+        }
+    }
+
+    /// Write the command to the start of `buf` and return its length
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        let mut writer = Writer { buf, len: 0 };
+        writer.command(self)?;
+        Ok(writer.len)
+    }
+}
+
+/// Write a packet holding `commands` to the start of `buf` and return its length
+pub fn write_packet(commands: &[Command], buf: &mut [u8]) -> Result<usize, NetError> {
+    let mut writer = Writer { buf, len: 0 };
+    writer.push(&SIGNATURE)?;
+    for command in commands {
+        writer.command(command)?;
+    }
+    Ok(writer.len)
+}
+
+/// Iterate over the commands in `packet`
+///
+/// Commands that can't be parsed don't end the iteration, except for a truncated last command.
+pub fn commands(packet: &[u8]) -> Result<Commands<'_>, NetError> {
+    packet
+        .strip_prefix(&SIGNATURE)
+        .map(|bytes| Commands { bytes })
+        .ok_or(NetError::InvalidSignature)
+}
+
+/// An iterator over the commands in a packet, see [`commands`]
+#[derive(Debug, Clone)]
+pub struct Commands<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Commands<'a> {
+    /// The next command together with its header, which NAK commands repeat
+    fn next_with_header(&mut self) -> Option<(Header, Result<Command<'a>, NetError>)> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        match split(self.bytes) {
+            Ok((header, payload, rest)) => {
+                self.bytes = rest;
+                Some((header, Command::decode(header, payload)))
+            }
+            Err(error) => {
+                let mut header = [0; 4];
+                let len = self.bytes.len().min(4);
+                header[..len].copy_from_slice(&self.bytes[..len]);
+                self.bytes = &[];
+                Some((header, Err(error)))
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Commands<'a> {
+    type Item = Result<Command<'a>, NetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_header().map(|(_, command)| command)
+    }
+}
+
+/// A command header, the code, the payload length in words and two command specific bytes
+type Header = [u8; 4];
+
+/// Split the command at the start of `bytes` into its header, its payload and the bytes after it
+fn split(bytes: &[u8]) -> Result<(Header, &[u8], &[u8]), NetError> {
+    let header = bytes.get(..4).ok_or(NetError::BufferTooShort)?;
+    let header = [header[0], header[1], header[2], header[3]];
+    let end = 4 + 4 * usize::from(header[1]);
+    let payload = bytes.get(4..end).ok_or(NetError::BufferTooShort)?;
+    Ok((header, payload, &bytes[end..]))
+}
+
+/// A zero padded text
+fn text(bytes: &[u8]) -> Result<&str, NetError> {
+    let end = bytes
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |i| i + 1);
+    core::str::from_utf8(&bytes[..end]).map_err(|_| NetError::InvalidCommand)
+}
+
+fn text_words(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+/// An endpoint name of `name_words` words followed by a product instance id
+fn names(payload: &[u8], name_words: u8) -> Result<(&str, &str), NetError> {
+    let split = 4 * usize::from(name_words);
+    let name = payload.get(..split).ok_or(NetError::InvalidCommand)?;
+    Ok((text(name)?, text(&payload[split..])?))
+}
+
+fn digest(payload: &[u8]) -> Result<[u8; 32], NetError> {
+    let digest = payload.get(..32).ok_or(NetError::InvalidCommand)?;
+    Ok(core::array::from_fn(|i| digest[i]))
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), NetError> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(NetError::BufferTooShort)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// Returns true when nothing but the signature was written
+    fn is_empty(&self) -> bool {
+        self.len <= SIGNATURE.len()
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), NetError> {
+        self.push(text.as_bytes())?;
+        self.push(&[0; 3][..4 * text_words(text) - text.len()])
+    }
+
+    /// Write `command` when it fits the packet or the packet is empty, returning true when written
+    fn try_command(&mut self, command: &Command) -> Result<bool, NetError> {
+        if !self.is_empty() && command.size() > self.remaining() {
+            return Ok(false);
+        }
+        self.command(command)?;
+        Ok(true)
+    }
+
+    fn command(&mut self, command: &Command) -> Result<(), NetError> {
+        let words = command.payload_words();
+        if words > MAX_PAYLOAD {
+            return Err(NetError::TooLong);
+        }
+        let (code, data) = match command {
+            Command::Invitation {
+                name, capabilities, ..
+            } => (INVITATION, [text_words(name) as u8, *capabilities]),
+            Command::InvitationWithAuth { .. } => (INVITATION_WITH_AUTH, [0; 2]),
+            Command::InvitationWithUserAuth { .. } => (INVITATION_WITH_USER_AUTH, [0; 2]),
+            Command::InvitationAccepted { name, .. } => {
+                (INVITATION_ACCEPTED, [text_words(name) as u8, 0])
+            }
+            Command::InvitationPending { name, .. } => {
+                (INVITATION_PENDING, [text_words(name) as u8, 0])
+            }
+            Command::AuthenticationRequired { name, failed, .. } => (
+                AUTHENTICATION_REQUIRED,
+                [text_words(name) as u8, u8::from(*failed)],
+            ),
+            Command::UserAuthenticationRequired { name, failed, .. } => (
+                USER_AUTHENTICATION_REQUIRED,
+                [text_words(name) as u8, u8::from(*failed)],
+            ),
+            Command::Ping { .. } => (PING, [0; 2]),
+            Command::PingReply { .. } => (PING_REPLY, [0; 2]),
+            Command::RetransmitRequest { sequence, .. } => {
+                (RETRANSMIT_REQUEST, sequence.to_be_bytes())
+            }
+            Command::RetransmitError { reason, .. } => (RETRANSMIT_ERROR, [0, (*reason).into()]),
+            Command::SessionReset => (SESSION_RESET, [0; 2]),
+            Command::SessionResetReply => (SESSION_RESET_REPLY, [0; 2]),
+            Command::Nak { reason, .. } => (NAK, [(*reason).into(), 0]),
+            Command::Bye { reason, .. } => (BYE, [(*reason).into(), 0]),
+            Command::ByeReply => (BYE_REPLY, [0; 2]),
+            Command::UmpData { sequence, .. } => (UMP_DATA, sequence.to_be_bytes()),
+        };
+        self.push(&[code, words as u8, data[0], data[1]])?;
+
+        match command {
+            Command::Invitation {
+                name,
+                product_instance_id,
+                ..
+            }
+            | Command::InvitationAccepted {
+                name,
+                product_instance_id,
+            }
+            | Command::InvitationPending {
+                name,
+                product_instance_id,
+            } => {
+                self.text(name)?;
+                self.text(product_instance_id)
+            }
+            Command::InvitationWithAuth { digest } => self.push(digest),
+            Command::InvitationWithUserAuth { digest, username } => {
+                self.push(digest)?;
+                self.text(username)
+            }
+            Command::AuthenticationRequired {
+                nonce,
+                name,
+                product_instance_id,
+                ..
+            }
+            | Command::UserAuthenticationRequired {
+                nonce,
+                name,
+                product_instance_id,
+                ..
+            } => {
+                self.push(nonce)?;
+                self.text(name)?;
+                self.text(product_instance_id)
+            }
+            Command::Ping { id } | Command::PingReply { id } => self.push(&id.to_be_bytes()),
+            Command::RetransmitRequest { count, .. } => {
+                self.push(&count.to_be_bytes())?;
+                self.push(&[0; 2])
+            }
+            Command::RetransmitError { sequence, .. } => {
+                self.push(&sequence.to_be_bytes())?;
+                self.push(&[0; 2])
+            }
+            Command::SessionReset | Command::SessionResetReply | Command::ByeReply => Ok(()),
+            Command::Nak {
+                header, message, ..
+            } => {
+                self.push(header)?;
+                self.text(message)
+            }
+            Command::Bye { message, .. } => self.text(message),
+            Command::UmpData { data, .. } => self.push(data.bytes()),
+        }
+    }
+
+    /// Write a UMP Data command holding `words`
+    fn data(&mut self, sequence: u16, words: &[u32]) -> Result<(), NetError> {
+        let [high, low] = sequence.to_be_bytes();
+        self.push(&[UMP_DATA, words.len() as u8, high, low])?;
+        for word in words {
+            self.push(&word.to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Credentials for authenticated sessions
+#[cfg(feature = "auth")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Credentials<'a> {
+    /// A secret shared by client and host
+    Secret(&'a str),
+
+    /// A user account of the host
+    User {
+        username: &'a str,
+        password: &'a str,
+    },
+}
+
+#[cfg(feature = "auth")]
+impl Credentials<'_> {
+    /// The SHA-256 digest answering an authentication request with `nonce`
+    pub fn digest(&self, nonce: &[u8; 16]) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+
+        let mut hash = Sha256::new();
+        hash.update(nonce);
+        match self {
+            Self::Secret(secret) => hash.update(secret.as_bytes()),
+            Self::User { username, password } => {
+                hash.update(username.as_bytes());
+                hash.update(password.as_bytes());
+            }
+        }
+        hash.finalize().into()
+    }
+
+    fn capability(&self) -> u8 {
+        match self {
+            Self::Secret(_) => CAPABILITY_AUTH,
+            Self::User { .. } => CAPABILITY_USER_AUTH,
+        }
+    }
+}
+
+/// The state of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionState {
+    /// Not connected, waiting for an invitation
+    Idle,
+
+    /// Inviting the host
+    Inviting,
+
+    /// Asked the client to authenticate
+    Authenticating,
+
+    /// Connected, UMPs can be exchanged
+    Connected,
+}
+
+/// What happened when handling a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionEvent {
+    /// The session is connected
+    Connected,
+
+    /// The host waits for its user to accept the invitation
+    Pending,
+
+    /// The invitation was rejected
+    Rejected(ByeReason),
+
+    /// The peer ended the session
+    Ended(ByeReason),
+
+    /// The peer reset the sequence numbers
+    Reset,
+
+    /// The given number of UMPs were received
+    Ump(usize),
+}
+
+/// The latest UMP Data commands sent, kept for retransmission
+#[derive(Debug, Clone)]
+struct RetransmitBuffer<const N: usize> {
+    slots: [([u32; DATA_WORDS], usize); N],
+    oldest: u16,
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RetransmitBuffer<N> {
+    const fn new() -> Self {
+        const { assert!(N > 0, "The retransmit buffer needs at least one slot") };
+        Self {
+            slots: [([0; DATA_WORDS], 0); N],
+            oldest: 0,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Forget all commands and start the sequence numbers at 0
+    fn clear(&mut self) {
+        self.oldest = 0;
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// The sequence number of the next command
+    fn next(&self) -> u16 {
+        self.oldest.wrapping_add(self.len as u16)
+    }
+
+    /// Render `messages`, at most [`DATA_WORDS`] words, into the next command, dropping the
+    /// oldest one when full
+    fn push(&mut self, messages: &[UmpMessage]) -> (u16, &[u32]) {
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+            self.oldest = self.oldest.wrapping_add(1);
+            self.len -= 1;
+        }
+        let sequence = self.next();
+        let (words, len) = &mut self.slots[(self.head + self.len) % N];
+        self.len += 1;
+        *len = 0;
+        for message in messages {
+            *len += message.render(&mut words[*len..]).unwrap_or(0);
+        }
+        (sequence, &words[..*len])
+    }
+
+    fn get(&self, sequence: u16) -> Option<&[u32]> {
+        let offset = usize::from(sequence.wrapping_sub(self.oldest));
+        (offset < self.len).then(|| {
+            let (words, len) = &self.slots[(self.head + offset) % N];
+            &words[..*len]
+        })
+    }
+}
+
+/// UMP Data commands received ahead of a missing one, indexed by their distance to it
+#[derive(Debug, Clone)]
+struct ReorderBuffer<const N: usize> {
+    slots: [([u32; DATA_WORDS], Option<usize>); N],
+    head: usize,
+}
+
+impl<const N: usize> ReorderBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            slots: [([0; DATA_WORDS], None); N],
+            head: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        for (_, len) in &mut self.slots {
+            *len = None;
+        }
+        self.head = 0;
+    }
+
+    /// Keep `data`, `offset` commands after the expected one, if it fits
+    fn insert(&mut self, offset: usize, data: UmpData) {
+        if offset < N && data.len() <= DATA_WORDS {
+            let (words, len) = &mut self.slots[(self.head + offset) % N];
+            for (word, received) in words.iter_mut().zip(data.words()) {
+                *word = received;
+            }
+            *len = Some(data.len());
+        }
+    }
+
+    fn contains(&self, offset: usize) -> bool {
+        offset < N && self.slots[(self.head + offset) % N].1.is_some()
+    }
+
+    /// The expected command, if it was received ahead
+    fn first(&self) -> Option<&[u32]> {
+        let (words, len) = &self.slots[self.head];
+        len.map(|len| &words[..len])
+    }
+
+    /// Drop the expected command and move on to the next one
+    fn advance(&mut self) {
+        self.slots[self.head].1 = None;
+        self.head = (self.head + 1) % N;
+    }
+}
+
+/// Split `messages` into runs of at most [`DATA_WORDS`] words
+fn chunks(messages: &[UmpMessage]) -> impl Iterator<Item = &[UmpMessage]> {
+    let mut rest = messages;
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut words = 0;
+        let len = rest
+            .iter()
+            .take_while(|message| {
+                words += message.words();
+                words <= DATA_WORDS
+            })
+            .count();
+        let (chunk, tail) = rest.split_at(len);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// A sans-IO Network MIDI 2.0 session with one peer, see the [module documentation](self)
+///
+/// A session either acts as client and invites a host with [`connect`](Self::connect) or acts as
+/// host and accepts the invitations it receives. The last `N` UMP Data commands sent are kept for
+/// retransmission, each holding up to 64 words, and up to `N` commands received after a missing
+/// one are kept until it arrives.
+#[derive(Debug, Clone)]
+pub struct NetworkSession<'a, const N: usize = 16> {
+    name: &'a str,
+    product_instance_id: &'a str,
+    #[cfg(feature = "auth")]
+    credentials: Option<Credentials<'a>>,
+    #[cfg(feature = "auth")]
+    nonce: [u8; 16],
+    #[cfg(feature = "auth")]
+    failures: u8,
+    state: SessionState,
+    client: bool,
+    pending: [Option<Command<'a>>; 4],
+    invitation: Option<Command<'a>>,
+    attempts: u8,
+    next_invitation: Duration,
+    pings: u8,
+    ping_id: u32,
+    ping: Option<(u32, Duration)>,
+    next_ping: Duration,
+    round_trip: Option<Duration>,
+    sent: RetransmitBuffer<N>,
+    fec: usize,
+    retransmit: Option<(u16, u16)>,
+    received: ReorderBuffer<N>,
+    expected: u16,
+    newest: Option<u16>,
+    next_request: Duration,
+    lost: u32,
+}
+
+impl<'a, const N: usize> NetworkSession<'a, N> {
+    /// Create a session announcing the UMP endpoint `name` and `product_instance_id`
+    pub fn new(name: &'a str, product_instance_id: &'a str) -> Self {
+        Self {
+            name,
+            product_instance_id,
+            #[cfg(feature = "auth")]
+            credentials: None,
+            #[cfg(feature = "auth")]
+            nonce: [0; 16],
+            #[cfg(feature = "auth")]
+            failures: 0,
+            state: SessionState::Idle,
+            client: false,
+            pending: [const { None }; 4],
+            invitation: None,
+            attempts: 0,
+            next_invitation: Duration::ZERO,
+            pings: 0,
+            ping_id: 0,
+            ping: None,
+            next_ping: Duration::ZERO,
+            round_trip: None,
+            sent: RetransmitBuffer::new(),
+            fec: 0,
+            retransmit: None,
+            received: ReorderBuffer::new(),
+            expected: 0,
+            newest: None,
+            next_request: Duration::ZERO,
+            lost: 0,
+        }
+    }
+
+    /// Authenticate with `credentials`
+    ///
+    /// A host with credentials asks every client to authenticate, a client offers the method of
+    /// its credentials in its invitations.
+    #[cfg(feature = "auth")]
+    pub fn set_credentials(&mut self, credentials: Option<Credentials<'a>>) {
+        self.credentials = credentials;
+    }
+
+    /// Set the nonce of the next authentication request
+    ///
+    /// Hosts should set a fresh random nonce before every invitation, a nonce that was answered
+    /// with an incorrect digest is replaced by one derived from it.
+    #[cfg(feature = "auth")]
+    pub fn set_nonce(&mut self, nonce: [u8; 16]) {
+        self.nonce = nonce;
+    }
+
+    /// Repeat the latest `count` UMP Data commands in front of the new ones, as far as they fit
+    /// the packet
+    pub fn set_fec(&mut self, count: usize) {
+        self.fec = count;
+    }
+
+    /// The state of the session
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// The time the latest ping took to be answered
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip
+    }
+
+    /// The number of UMP Data commands the peer could no longer retransmit
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+
+    /// Invite a host
+    ///
+    /// The invitation is repeated every second until the host answers, after 10 attempts the
+    /// session returns to [`SessionState::Idle`].
+    pub fn connect(&mut self, now: Duration) {
+        self.reset();
+        self.client = true;
+        self.state = SessionState::Inviting;
+        self.attempts = 0;
+        self.next_invitation = now;
+        self.invitation = Some(Command::Invitation {
+            name: self.name,
+            product_instance_id: self.product_instance_id,
+            capabilities: self.capabilities(),
+        });
+    }
+
+    /// End the session, the bye is sent by the next [`poll_transmit`](Self::poll_transmit)
+    pub fn disconnect(&mut self) {
+        let reason = match self.state {
+            SessionState::Idle => None,
+            SessionState::Inviting => Some(ByeReason::InvitationCanceled),
+            _ => Some(ByeReason::UserTerminated),
+        };
+        if let Some(reason) = reason {
+            self.queue(bye(reason));
+        }
+        self.reset();
+    }
+
+    /// Start the sequence numbers of both directions again at 0
+    ///
+    /// UMP Data commands in flight during the reset may be lost.
+    pub fn reset_session(&mut self) {
+        if self.state == SessionState::Connected {
+            self.queue(Command::SessionReset);
+        }
+    }
+
+    /// Write a packet holding `messages` in UMP Data commands, returning its length
+    ///
+    /// Earlier commands are repeated in front of them as set with [`set_fec`](Self::set_fec).
+    /// Nothing is written when `messages` is empty.
+    pub fn send(&mut self, messages: &[UmpMessage], buf: &mut [u8]) -> Result<usize, NetError> {
+        if self.state != SessionState::Connected {
+            return Err(NetError::NotConnected);
+        }
+        if messages.is_empty() {
+            return Ok(0);
+        }
+        let size = |words: usize| 4 + 4 * words;
+        let new: usize = chunks(messages)
+            .map(|chunk| size(chunk.iter().map(UmpMessage::words).sum()))
+            .sum();
+        let mut space = buf
+            .len()
+            .checked_sub(SIGNATURE.len() + new)
+            .ok_or(NetError::BufferTooShort)?;
+
+        let next = self.sent.next();
+        let mut repeated = 0;
+        while repeated < self.fec.min(self.sent.len) {
+            let sequence = next.wrapping_sub(repeated as u16 + 1);
+            let len = size(self.sent.get(sequence).map_or(0, <[u32]>::len));
+            if len > space {
+                break;
+            }
+            space -= len;
+            repeated += 1;
+        }
+
+        let mut writer = Writer { buf, len: 0 };
+        writer.push(&SIGNATURE)?;
+        for offset in (1..=repeated as u16).rev() {
+            let sequence = next.wrapping_sub(offset);
+            writer.data(sequence, self.sent.get(sequence).unwrap_or(&[]))?;
+        }
+        for chunk in chunks(messages) {
+            let (sequence, words) = self.sent.push(chunk);
+            writer.data(sequence, words)?;
+        }
+        Ok(writer.len)
+    }
+
+    /// Handle a packet received at time `now`
+    ///
+    /// The UMPs of new UMP Data commands are written to `ump` in order and reported with
+    /// [`SessionEvent::Ump`]. Commands that don't fit are dropped and requested again later.
+    pub fn handle(
+        &mut self,
+        packet: &[u8],
+        now: Duration,
+        ump: &mut [UmpMessage],
+    ) -> Result<Option<SessionEvent>, NetError> {
+        let mut commands = commands(packet)?;
+        if self.state == SessionState::Connected {
+            self.pings = 0;
+            self.next_ping = now + PING_INTERVAL;
+        }
+
+        let mut event = None;
+        let mut len = 0;
+        while let Some((header, command)) = commands.next_with_header() {
+            match command {
+                Ok(Command::UmpData { sequence, data }) => {
+                    len += self.receive(sequence, data, now, &mut ump[len..]);
+                }
+                Ok(Command::RetransmitError { sequence, .. }) => {
+                    len += self.skip(sequence, now, &mut ump[len..]);
+                }
+                Ok(command) => {
+                    if let Some(command_event) = self.command(command, now) {
+                        event = Some(command_event);
+                    }
+                }
+                Err(error) => {
+                    let reason = match error {
+                        NetError::UnknownCommand => NakReason::CommandNotSupported,
+                        _ => NakReason::CommandMalformed,
+                    };
+                    self.queue(Command::Nak {
+                        reason,
+                        header,
+                        message: "",
+                    });
+                }
+            }
+        }
+        Ok(event.or((len > 0).then_some(SessionEvent::Ump(len))))
+    }
+
+    /// Write the next packet to send at time `now`, returning its length, or `None` when there is
+    /// nothing to send
+    ///
+    /// Call this until it returns `None` after handling a packet and whenever
+    /// [`next_due`](Self::next_due) has passed.
+    pub fn poll_transmit(
+        &mut self,
+        now: Duration,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, NetError> {
+        let mut writer = Writer { buf, len: 0 };
+        writer.push(&SIGNATURE)?;
+
+        while let Some(command) = self.pending[0].take() {
+            if !writer.try_command(&command)? {
+                self.pending[0] = Some(command);
+                break;
+            }
+            self.pending.rotate_left(1);
+        }
+
+        match self.state {
+            SessionState::Inviting if now >= self.next_invitation => {
+                if self.attempts == INVITATION_ATTEMPTS {
+                    self.reset();
+                } else if let Some(invitation) = &self.invitation {
+                    if writer.try_command(invitation)? {
+                        self.attempts += 1;
+                        self.next_invitation = now + INVITATION_INTERVAL;
+                    }
+                }
+            }
+            SessionState::Connected => {
+                if let Some(newest) = self.newest.filter(|_| now >= self.next_request) {
+                    // Request every run of missing commands up to the newest one seen
+                    let span = usize::from(newest.wrapping_sub(self.expected)).min(N - 1) + 1;
+                    let mut offset = 0;
+                    while offset < span {
+                        let start = offset;
+                        while offset < span && !self.received.contains(offset) {
+                            offset += 1;
+                        }
+                        if offset > start {
+                            let request = Command::RetransmitRequest {
+                                sequence: self.expected.wrapping_add(start as u16),
+                                count: (offset - start) as u16,
+                            };
+                            if !writer.try_command(&request)? {
+                                break;
+                            }
+                            self.next_request = now + RETRANSMIT_INTERVAL;
+                        }
+                        offset += 1;
+                    }
+                }
+
+                while let Some((sequence, count)) = self.retransmit {
+                    let Some(words) = self.sent.get(sequence) else {
+                        self.retransmit = None;
+                        break;
+                    };
+                    if !writer.is_empty() && 4 + 4 * words.len() > writer.remaining() {
+                        break;
+                    }
+                    writer.data(sequence, words)?;
+                    self.retransmit = (count > 1).then(|| (sequence.wrapping_add(1), count - 1));
+                }
+
+                if now >= self.next_ping {
+                    if self.pings == PING_ATTEMPTS {
+                        if writer.try_command(&bye(ByeReason::Timeout))? {
+                            self.reset();
+                        }
+                    } else {
+                        let id = self.ping_id.wrapping_add(1);
+                        if writer.try_command(&Command::Ping { id })? {
+                            self.ping_id = id;
+                            self.ping = Some((id, now));
+                            self.pings += 1;
+                            self.next_ping = now + PING_INTERVAL;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok((!writer.is_empty()).then_some(writer.len))
+    }
+
+    /// The time at which [`poll_transmit`](Self::poll_transmit) has something to send next, or
+    /// `None` when it only sends in response to received packets
+    pub fn next_due(&self) -> Option<Duration> {
+        if self.pending[0].is_some() || self.retransmit.is_some() {
+            return Some(Duration::ZERO);
+        }
+        match self.state {
+            SessionState::Inviting => Some(self.next_invitation),
+            SessionState::Connected => Some(match self.newest {
+                Some(_) => self.next_ping.min(self.next_request),
+                None => self.next_ping,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Handle a UMP Data command, returning the number of UMPs written to `ump`
+    fn receive(
+        &mut self,
+        sequence: u16,
+        data: UmpData,
+        now: Duration,
+        ump: &mut [UmpMessage],
+    ) -> usize {
+        if self.state != SessionState::Connected {
+            self.not_established();
+            return 0;
+        }
+        let ahead = sequence.wrapping_sub(self.expected) as i16;
+        if ahead < 0 {
+            return 0;
+        }
+        if ahead > 0 {
+            // Commands are missing, request them at once when this one opens a new gap
+            let newer = |newest: u16| sequence.wrapping_sub(newest) as i16;
+            if self.newest.is_none_or(|newest| newer(newest) > 1) {
+                self.next_request = now;
+            }
+            if self.newest.is_none_or(|newest| newer(newest) > 0) {
+                self.newest = Some(sequence);
+            }
+            self.received.insert(ahead as usize, data);
+            return 0;
+        }
+        if data.messages().count() > ump.len() {
+            self.newest.get_or_insert(sequence);
+            self.next_request = now;
+            return 0;
+        }
+
+        let mut len = 0;
+        for message in data.messages().flatten() {
+            ump[len] = message;
+            len += 1;
+        }
+        self.advance();
+        len + self.drain(&mut ump[len..])
+    }
+
+    /// Deliver the commands received ahead that are no longer preceded by missing ones
+    fn drain(&mut self, ump: &mut [UmpMessage]) -> usize {
+        let mut len = 0;
+        while let Some(words) = self.received.first() {
+            if super::messages(words).count() > ump.len() - len {
+                // Request it again once there is room
+                self.received.slots[self.received.head].1 = None;
+                self.next_request = Duration::ZERO;
+                break;
+            }
+            for message in super::messages(words).flatten() {
+                ump[len] = message;
+                len += 1;
+            }
+            self.advance();
+        }
+        len
+    }
+
+    /// Move on to the next sequence number
+    fn advance(&mut self) {
+        self.expected = self.expected.wrapping_add(1);
+        self.received.advance();
+        if self
+            .newest
+            .is_some_and(|newest| (newest.wrapping_sub(self.expected) as i16) < 0)
+        {
+            self.newest = None;
+        }
+    }
+
+    /// Give up on the missing commands before `sequence`, delivering those received ahead
+    fn skip(&mut self, sequence: u16, now: Duration, ump: &mut [UmpMessage]) -> usize {
+        let mut len = 0;
+        while self.newest.is_some() && (sequence.wrapping_sub(self.expected) as i16) > 0 {
+            if self.received.first().is_none() {
+                self.lost += 1;
+                self.advance();
+            }
+            len += self.drain(&mut ump[len..]);
+        }
+        self.next_request = now;
+        len
+    }
+
+    fn command(&mut self, command: Command, now: Duration) -> Option<SessionEvent> {
+        match command {
+            Command::Invitation { capabilities, .. } => self.invited(capabilities, now),
+            Command::InvitationWithAuth { digest } => self.authenticate(&digest, None, now),
+            Command::InvitationWithUserAuth { digest, username } => {
+                self.authenticate(&digest, Some(username), now)
+            }
+            Command::InvitationAccepted { .. } => match self.state {
+                SessionState::Inviting => {
+                    self.connected(now);
+                    Some(SessionEvent::Connected)
+                }
+                SessionState::Idle => {
+                    self.queue(bye(ByeReason::NoPendingSession));
+                    None
+                }
+                _ => None,
+            },
+            Command::InvitationPending { .. } => match self.state {
+                SessionState::Inviting => {
+                    self.attempts = 0;
+                    self.next_invitation = now + INVITATION_INTERVAL;
+                    Some(SessionEvent::Pending)
+                }
+                SessionState::Idle => {
+                    self.queue(bye(ByeReason::NoPendingSession));
+                    None
+                }
+                _ => None,
+            },
+            Command::AuthenticationRequired { nonce, failed, .. } => {
+                self.challenged(nonce, failed, false, now)
+            }
+            Command::UserAuthenticationRequired { nonce, failed, .. } => {
+                self.challenged(nonce, failed, true, now)
+            }
+            Command::Ping { id } => {
+                if self.state == SessionState::Connected {
+                    self.queue(Command::PingReply { id });
+                }
+                self.not_established();
+                None
+            }
+            Command::PingReply { id } => {
+                if let Some((_, sent)) = self.ping.filter(|(ping, _)| *ping == id) {
+                    self.round_trip = Some(now.saturating_sub(sent));
+                    self.ping = None;
+                }
+                None
+            }
+            Command::RetransmitRequest { sequence, count } => {
+                if self.state == SessionState::Connected {
+                    if self.sent.get(sequence).is_some() {
+                        // Requests for several runs are merged into one covering them all
+                        let next = self.sent.next();
+                        let end = sequence.wrapping_add(count.min(next.wrapping_sub(sequence)));
+                        let (start, end) = match self.retransmit {
+                            Some((pending, pending_count)) => {
+                                let pending_end = pending.wrapping_add(pending_count);
+                                let before = |a: u16, b: u16| (a.wrapping_sub(b) as i16) < 0;
+                                (
+                                    if before(pending, sequence) {
+                                        pending
+                                    } else {
+                                        sequence
+                                    },
+                                    if before(end, pending_end) {
+                                        pending_end
+                                    } else {
+                                        end
+                                    },
+                                )
+                            }
+                            None => (sequence, end),
+                        };
+                        let count = end.wrapping_sub(start);
+                        self.retransmit = (count > 0).then_some((start, count));
+                    } else {
+                        self.queue(Command::RetransmitError {
+                            sequence: self.sent.oldest,
+                            reason: RetransmitErrorReason::NotBuffered,
+                        });
+                    }
+                }
+                self.not_established();
+                None
+            }
+            Command::SessionReset => {
+                if self.state == SessionState::Connected {
+                    self.restart();
+                    self.queue(Command::SessionResetReply);
+                    return Some(SessionEvent::Reset);
+                }
+                self.not_established();
+                None
+            }
+            Command::SessionResetReply => {
+                if self.state == SessionState::Connected {
+                    self.restart();
+                }
+                None
+            }
+            Command::Bye { reason, .. } => {
+                self.queue(Command::ByeReply);
+                let event = match self.state {
+                    SessionState::Inviting => Some(SessionEvent::Rejected(reason)),
+                    SessionState::Connected => Some(SessionEvent::Ended(reason)),
+                    _ => None,
+                };
+                self.reset();
+                event
+            }
+            Command::Nak { .. }
+            | Command::ByeReply
+            | Command::UmpData { .. }
+            | Command::RetransmitError { .. } => None,
+        }
+    }
+
+    fn invited(&mut self, capabilities: u8, now: Duration) -> Option<SessionEvent> {
+        if self.client && self.state != SessionState::Idle {
+            self.queue(bye(ByeReason::TooManySessions));
+            return None;
+        }
+        self.client = false;
+
+        #[cfg(feature = "auth")]
+        if let Some(credentials) = self.credentials {
+            if capabilities & credentials.capability() == 0 {
+                self.queue(bye(ByeReason::NoMatchingAuthMethod));
+                self.reset();
+            } else {
+                if self.state != SessionState::Authenticating {
+                    self.failures = 0;
+                }
+                self.state = SessionState::Authenticating;
+                self.queue(self.challenge(credentials, false));
+            }
+            return None;
+        }
+        #[cfg(not(feature = "auth"))]
+        let _ = capabilities;
+
+        self.accept(now)
+    }
+
+    fn accept(&mut self, now: Duration) -> Option<SessionEvent> {
+        let event = (self.state != SessionState::Connected).then_some(SessionEvent::Connected);
+        self.connected(now);
+        self.queue(Command::InvitationAccepted {
+            name: self.name,
+            product_instance_id: self.product_instance_id,
+        });
+        event
+    }
+
+    #[cfg(feature = "auth")]
+    fn authenticate(
+        &mut self,
+        digest: &[u8; 32],
+        username: Option<&str>,
+        now: Duration,
+    ) -> Option<SessionEvent> {
+        let credentials = match self.credentials {
+            Some(credentials)
+                if !self.client
+                    && matches!(
+                        self.state,
+                        SessionState::Authenticating | SessionState::Connected
+                    ) =>
+            {
+                credentials
+            }
+            _ => {
+                self.queue(bye(ByeReason::MissingInvitation));
+                return None;
+            }
+        };
+        let reason = match (credentials, username) {
+            (Credentials::Secret(_), None) => None,
+            (Credentials::User { username, .. }, Some(name)) if name == username => None,
+            (Credentials::User { .. }, Some(_)) => Some(ByeReason::UsernameNotFound),
+            _ => Some(ByeReason::NoMatchingAuthMethod),
+        };
+        if let Some(reason) = reason {
+            self.queue(bye(reason));
+            self.reset();
+            return None;
+        }
+
+        // Compare every byte so the time taken doesn't tell how much of the digest was right
+        let expected = credentials.digest(&self.nonce);
+        let difference = digest
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference == 0 {
+            return self.accept(now);
+        }
+        if self.state == SessionState::Connected {
+            return None;
+        }
+
+        self.failures += 1;
+        if self.failures == AUTHENTICATION_ATTEMPTS {
+            self.queue(bye(ByeReason::AuthenticationFailed));
+            self.reset();
+            return None;
+        }
+        let next = Credentials::Secret("").digest(&self.nonce);
+        self.nonce = core::array::from_fn(|i| next[i]);
+        self.queue(self.challenge(credentials, true));
+        None
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn authenticate(
+        &mut self,
+        _digest: &[u8; 32],
+        _username: Option<&str>,
+        _now: Duration,
+    ) -> Option<SessionEvent> {
+        self.queue(bye(ByeReason::MissingInvitation));
+        None
+    }
+
+    #[cfg(feature = "auth")]
+    fn challenge(&self, credentials: Credentials, failed: bool) -> Command<'a> {
+        match credentials {
+            Credentials::Secret(_) => Command::AuthenticationRequired {
+                nonce: self.nonce,
+                failed,
+                name: self.name,
+                product_instance_id: self.product_instance_id,
+            },
+            Credentials::User { .. } => Command::UserAuthenticationRequired {
+                nonce: self.nonce,
+                failed,
+                name: self.name,
+                product_instance_id: self.product_instance_id,
+            },
+        }
+    }
+
+    /// Answer an authentication request from the host
+    fn challenged(
+        &mut self,
+        nonce: [u8; 16],
+        failed: bool,
+        user: bool,
+        now: Duration,
+    ) -> Option<SessionEvent> {
+        match self.state {
+            SessionState::Inviting => {}
+            SessionState::Idle => {
+                self.queue(bye(ByeReason::NoPendingSession));
+                return None;
+            }
+            _ => return None,
+        }
+
+        #[cfg(feature = "auth")]
+        let answer = match self.credentials {
+            _ if failed => None,
+            Some(credentials @ Credentials::Secret(_)) if !user => {
+                Some(Command::InvitationWithAuth {
+                    digest: credentials.digest(&nonce),
+                })
+            }
+            Some(credentials @ Credentials::User { username, .. }) if user => {
+                Some(Command::InvitationWithUserAuth {
+                    digest: credentials.digest(&nonce),
+                    username,
+                })
+            }
+            _ => None,
+        };
+        #[cfg(not(feature = "auth"))]
+        let answer = {
+            let _ = (nonce, user);
+            None
+        };
+
+        if answer.is_some() {
+            self.invitation = answer;
+            self.attempts = 0;
+            self.next_invitation = now;
+            return None;
+        }
+        let reason = if failed {
+            ByeReason::AuthenticationFailed
+        } else {
+            ByeReason::NoMatchingAuthMethod
+        };
+        self.queue(bye(ByeReason::InvitationCanceled));
+        self.reset();
+        Some(SessionEvent::Rejected(reason))
+    }
+
+    fn capabilities(&self) -> u8 {
+        #[cfg(feature = "auth")]
+        if let Some(credentials) = self.credentials {
+            return credentials.capability();
+        }
+        0
+    }
+
+    /// Commands that need a session are answered with a bye outside one
+    fn not_established(&mut self) {
+        if self.state == SessionState::Idle {
+            self.queue(bye(ByeReason::SessionNotEstablished));
+        }
+    }
+
+    fn connected(&mut self, now: Duration) {
+        self.state = SessionState::Connected;
+        self.invitation = None;
+        self.pings = 0;
+        self.ping = None;
+        self.next_ping = now + PING_INTERVAL;
+        self.round_trip = None;
+        self.lost = 0;
+        self.restart();
+    }
+
+    /// Start the sequence numbers of both directions at 0
+    fn restart(&mut self) {
+        self.sent.clear();
+        self.retransmit = None;
+        self.received.clear();
+        self.expected = 0;
+        self.newest = None;
+    }
+
+    fn reset(&mut self) {
+        self.state = SessionState::Idle;
+        self.invitation = None;
+        self.ping = None;
+        self.retransmit = None;
+        self.newest = None;
+    }
+
+    fn queue(&mut self, command: Command<'a>) {
+        // Answers are repeated by the peer, so dropping one when the queue is full is harmless
+        if self.pending.contains(&Some(command.clone())) {
+            return;
+        }
+        if let Some(slot) = self.pending.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(command);
+        }
+    }
+}
+
+fn bye(reason: ByeReason) -> Command<'static> {
+    Command::Bye {
+        reason,
+        message: "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::ump::Group,
+        midi_types::{Channel, MidiMessage, Note, Value7},
+        std::vec::Vec,
+    };
+
+    fn note(i: u8) -> UmpMessage {
+        UmpMessage::Midi1ChannelVoice {
+            group: Group::new(0),
+            message: MidiMessage::NoteOn(Channel::new(0), Note::new(i), Value7::new(100)),
+        }
+    }
+
+    fn codes(packet: &[u8]) -> Vec<u8> {
+        let mut commands = commands(packet).unwrap();
+        core::iter::from_fn(|| commands.next_with_header())
+            .map(|(header, _)| header[0])
+            .collect()
+    }
+
+    /// Deliver packets between two sessions until neither has anything left to send, logging the
+    /// command codes and the UMPs received
+    fn exchange<const A: usize, const B: usize>(
+        a: &mut NetworkSession<'_, A>,
+        b: &mut NetworkSession<'_, B>,
+        now: Duration,
+    ) -> (Vec<(bool, u8)>, Vec<UmpMessage>) {
+        let mut log = Vec::new();
+        let mut received = Vec::new();
+        let mut buf = [0; 512];
+        let mut ump = [note(0); 64];
+        loop {
+            let mut idle = true;
+            for from_a in [true, false] {
+                loop {
+                    let (packet, result) = if from_a {
+                        let len = a.poll_transmit(now, &mut buf).unwrap();
+                        let result = len.map(|len| b.handle(&buf[..len], now, &mut ump));
+                        (len.map(|len| buf[..len].to_vec()), result)
+                    } else {
+                        let len = b.poll_transmit(now, &mut buf).unwrap();
+                        let result = len.map(|len| a.handle(&buf[..len], now, &mut ump));
+                        (len.map(|len| buf[..len].to_vec()), result)
+                    };
+                    let Some(packet) = packet else { break };
+                    idle = false;
+                    log.extend(codes(&packet).into_iter().map(|code| (from_a, code)));
+                    if let Some(Ok(Some(SessionEvent::Ump(len)))) = result {
+                        received.extend_from_slice(&ump[..len]);
+                    }
+                }
+            }
+            if idle {
+                return (log, received);
+            }
+        }
+    }
+
+    fn connected_pair<const A: usize, const B: usize>()
+    -> (NetworkSession<'static, A>, NetworkSession<'static, B>) {
+        let mut client = NetworkSession::new("client", "0001");
+        let mut host = NetworkSession::new("host", "0002");
+        client.connect(Duration::ZERO);
+        exchange(&mut client, &mut host, Duration::ZERO);
+        (client, host)
+    }
+
+    #[test]
+    fn should_write_invitation() {
+        let invitation = Command::Invitation {
+            name: "abcde",
+            product_instance_id: "x",
+            capabilities: CAPABILITY_AUTH,
+        };
+        let packet = [
+            b'M', b'I', b'D', b'I', 0x01, 0x03, 0x02, 0x01, b'a', b'b', b'c', b'd', b'e', 0, 0, 0,
+            b'x', 0, 0, 0,
+        ];
+        let mut buf = [0; 32];
+        assert_eq!(
+            write_packet(core::slice::from_ref(&invitation), &mut buf),
+            Ok(packet.len())
+        );
+        assert_eq!(buf[..packet.len()], packet);
+        assert_eq!(
+            Command::parse(&packet[4..]),
+            Ok((invitation.clone(), &[][..]))
+        );
+        assert_eq!(
+            write_packet(&[invitation], &mut buf[..19]),
+            Err(NetError::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn should_round_trip_commands() {
+        let data = [0x2090_3c64, 0x2080_3c00].map(u32::to_be_bytes).concat();
+        let all = [
+            Command::Invitation {
+                name: "client",
+                product_instance_id: "",
+                capabilities: CAPABILITY_USER_AUTH,
+            },
+            Command::InvitationWithAuth { digest: [7; 32] },
+            Command::InvitationWithUserAuth {
+                digest: [8; 32],
+                username: "user",
+            },
+            Command::InvitationAccepted {
+                name: "host",
+                product_instance_id: "0002",
+            },
+            Command::InvitationPending {
+                name: "",
+                product_instance_id: "pid",
+            },
+            Command::AuthenticationRequired {
+                nonce: [1; 16],
+                failed: false,
+                name: "host",
+                product_instance_id: "0002",
+            },
+            Command::UserAuthenticationRequired {
+                nonce: [2; 16],
+                failed: true,
+                name: "host",
+                product_instance_id: "",
+            },
+            Command::Ping { id: 0x1234_5678 },
+            Command::PingReply { id: 0x1234_5678 },
+            Command::RetransmitRequest {
+                sequence: 0xfffe,
+                count: 3,
+            },
+            Command::RetransmitError {
+                sequence: 5,
+                reason: RetransmitErrorReason::NotBuffered,
+            },
+            Command::SessionReset,
+            Command::SessionResetReply,
+            Command::Nak {
+                reason: NakReason::CommandNotSupported,
+                header: [0x55, 0, 0, 0],
+                message: "what",
+            },
+            Command::Bye {
+                reason: ByeReason::Other(0x99),
+                message: "bye!",
+            },
+            Command::ByeReply,
+            Command::UmpData {
+                sequence: 7,
+                data: UmpData::new(&data).unwrap(),
+            },
+        ];
+        let mut buf = [0; 512];
+        let len = write_packet(&all, &mut buf).unwrap();
+        assert_eq!(len, 4 + all.iter().map(Command::size).sum::<usize>());
+        let parsed: Result<Vec<_>, _> = commands(&buf[..len]).unwrap().collect();
+        assert_eq!(parsed.unwrap(), all);
+
+        let Command::UmpData { data, .. } = &all[all.len() - 1] else {
+            unreachable!()
+        };
+        let messages: Vec<_> = data.messages().collect();
+        assert_eq!(
+            messages,
+            [
+                Ok(note(60)),
+                Ok(UmpMessage::Midi1ChannelVoice {
+                    group: Group::new(0),
+                    message: MidiMessage::NoteOff(Channel::new(0), Note::new(60), Value7::new(0)),
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_packets() {
+        assert_eq!(
+            commands(b"MIDX").map(|_| ()),
+            Err(NetError::InvalidSignature)
+        );
+        let packet = [
+            b'M', b'I', b'D', b'I', 0x55, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0xf0, 0x01,
+            0x00, 0x00, 0xff, 0xfe, 0xff,
+        ];
+        let parsed: Vec<_> = commands(&packet).unwrap().collect();
+        assert_eq!(
+            parsed,
+            [
+                Err(NetError::UnknownCommand),
+                Err(NetError::InvalidCommand),
+                Err(NetError::BufferTooShort),
+            ]
+        );
+        assert_eq!(
+            Command::parse(&[0xf0, 0x01, 0x00, 0x00, 0xc3, 0x28, 0, 0]),
+            Err(NetError::InvalidCommand)
+        );
+        assert_eq!(UmpData::new(&[0; 3]), Err(NetError::InvalidCommand));
+        assert_eq!(UmpData::new(&[0; 1024]), Err(NetError::TooLong));
+        let long = "x".repeat(1021);
+        assert_eq!(
+            Command::Bye {
+                reason: ByeReason::Unknown,
+                message: &long,
+            }
+            .write(&mut [0; 2048]),
+            Err(NetError::TooLong)
+        );
+    }
+
+    #[test]
+    fn should_convert_reasons() {
+        for code in 0..=u8::MAX {
+            assert_eq!(u8::from(ByeReason::from(code)), code);
+            assert_eq!(u8::from(NakReason::from(code)), code);
+            assert_eq!(u8::from(RetransmitErrorReason::from(code)), code);
+        }
+        assert_eq!(ByeReason::from(0x45), ByeReason::NoMatchingAuthMethod);
+        assert_eq!(NakReason::from(0x20), NakReason::BadPingReply);
+    }
+
+    #[test]
+    fn should_connect() {
+        let mut client: NetworkSession = NetworkSession::new("client", "0001");
+        let mut host: NetworkSession = NetworkSession::new("host", "0002");
+        client.connect(Duration::ZERO);
+        let (log, _) = exchange(&mut client, &mut host, Duration::ZERO);
+        assert_eq!(log, [(true, INVITATION), (false, INVITATION_ACCEPTED)]);
+        assert_eq!(client.state(), SessionState::Connected);
+        assert_eq!(host.state(), SessionState::Connected);
+        assert_eq!(client.next_due(), Some(PING_INTERVAL));
+        assert_eq!(host.next_due(), Some(PING_INTERVAL));
+    }
+
+    #[test]
+    fn should_give_up_inviting() {
+        let mut session: NetworkSession = NetworkSession::new("client", "0001");
+        let mut buf = [0; 64];
+        session.connect(Duration::ZERO);
+        for attempt in 0..10 {
+            let now = Duration::from_secs(attempt);
+            assert_eq!(session.next_due(), Some(now));
+            assert!(session.poll_transmit(now, &mut buf).unwrap().is_some());
+            assert_eq!(session.poll_transmit(now, &mut buf), Ok(None));
+        }
+        assert_eq!(session.state(), SessionState::Inviting);
+        assert_eq!(
+            session.poll_transmit(Duration::from_secs(10), &mut buf),
+            Ok(None)
+        );
+        assert_eq!(session.state(), SessionState::Idle);
+    }
+
+    #[test]
+    fn should_wait_for_pending_invitation() {
+        let mut client: NetworkSession = NetworkSession::new("client", "0001");
+        let mut buf = [0; 64];
+        let mut ump = [];
+        client.connect(Duration::ZERO);
+        client.poll_transmit(Duration::ZERO, &mut buf).unwrap();
+        let pending = Command::InvitationPending {
+            name: "host",
+            product_instance_id: "",
+        };
+        let len = write_packet(&[pending], &mut buf).unwrap();
+        let now = Duration::from_millis(900);
+        assert_eq!(
+            client.handle(&buf[..len], now, &mut ump),
+            Ok(Some(SessionEvent::Pending))
+        );
+        assert_eq!(client.next_due(), Some(now + INVITATION_INTERVAL));
+    }
+
+    #[test]
+    fn should_end_session() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 64];
+        let mut ump = [];
+        client.disconnect();
+        assert_eq!(client.state(), SessionState::Idle);
+        let len = client
+            .poll_transmit(Duration::ZERO, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(codes(&buf[..len]), [BYE]);
+        assert_eq!(
+            host.handle(&buf[..len], Duration::ZERO, &mut ump),
+            Ok(Some(SessionEvent::Ended(ByeReason::UserTerminated)))
+        );
+        assert_eq!(host.state(), SessionState::Idle);
+        let len = host
+            .poll_transmit(Duration::ZERO, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(codes(&buf[..len]), [BYE_REPLY]);
+        assert_eq!(
+            client.handle(&buf[..len], Duration::ZERO, &mut ump),
+            Ok(None)
+        );
+        assert_eq!(client.poll_transmit(Duration::ZERO, &mut buf), Ok(None));
+        assert_eq!(
+            client.send(&[note(60)], &mut buf),
+            Err(NetError::NotConnected)
+        );
+    }
+
+    #[test]
+    fn should_answer_commands_outside_session() {
+        let mut host: NetworkSession = NetworkSession::new("host", "0002");
+        let mut buf = [0; 64];
+        let mut ump = [note(0); 4];
+        let mut words = [0; 1];
+        note(60).render(&mut words).unwrap();
+        let bytes = words[0].to_be_bytes();
+        let packet = [
+            Command::Ping { id: 1 },
+            Command::UmpData {
+                sequence: 0,
+                data: UmpData::new(&bytes).unwrap(),
+            },
+            Command::UmpData {
+                sequence: 1,
+                data: UmpData::new(&bytes).unwrap(),
+            },
+        ];
+        let len = write_packet(&packet, &mut buf).unwrap();
+        assert_eq!(host.handle(&buf[..len], Duration::ZERO, &mut ump), Ok(None));
+        let len = host
+            .poll_transmit(Duration::ZERO, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+            [Ok(bye(ByeReason::SessionNotEstablished))]
+        );
+
+        let (_, mut host) = connected_pair::<16, 16>();
+        let packet = [b'M', b'I', b'D', b'I', 0x55, 0x00, 0x12, 0x34];
+        assert_eq!(host.handle(&packet, Duration::ZERO, &mut ump), Ok(None));
+        let len = host
+            .poll_transmit(Duration::ZERO, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+            [Ok(Command::Nak {
+                reason: NakReason::CommandNotSupported,
+                header: [0x55, 0x00, 0x12, 0x34],
+                message: "",
+            })]
+        );
+    }
+
+    #[test]
+    fn should_retransmit_lost_data() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 256];
+        let mut ump = [note(0); 8];
+        let now = Duration::from_millis(10);
+
+        let mut packets = Vec::new();
+        for i in 0..3 {
+            let len = client.send(&[note(60 + i)], &mut buf).unwrap();
+            packets.push(buf[..len].to_vec());
+        }
+        assert_eq!(
+            host.handle(&packets[0], now, &mut ump),
+            Ok(Some(SessionEvent::Ump(1)))
+        );
+        assert_eq!(host.handle(&packets[2], now, &mut ump), Ok(None));
+        assert_eq!(host.next_due(), Some(now));
+
+        let len = host.poll_transmit(now, &mut buf).unwrap().unwrap();
+        assert_eq!(
+            commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+            [Ok(Command::RetransmitRequest {
+                sequence: 1,
+                count: 1
+            })]
+        );
+        assert_eq!(host.next_due(), Some(now + RETRANSMIT_INTERVAL));
+        client.handle(&buf[..len], now, &mut ump).unwrap();
+        let len = client.poll_transmit(now, &mut buf).unwrap().unwrap();
+        assert_eq!(codes(&buf[..len]), [UMP_DATA]);
+        assert_eq!(
+            host.handle(&buf[..len], now, &mut ump),
+            Ok(Some(SessionEvent::Ump(2)))
+        );
+        assert_eq!(ump[..2], [note(61), note(62)]);
+        assert_eq!(host.next_due(), Some(now + PING_INTERVAL));
+    }
+
+    #[test]
+    fn should_request_every_missing_run() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 256];
+        let mut ump = [note(0); 8];
+        let now = Duration::from_millis(10);
+
+        let mut packets = Vec::new();
+        for i in 0..5 {
+            let len = client.send(&[note(60 + i)], &mut buf).unwrap();
+            packets.push(buf[..len].to_vec());
+        }
+        for packet in [&packets[0], &packets[2], &packets[4]] {
+            host.handle(packet, now, &mut ump).unwrap();
+        }
+        let len = host.poll_transmit(now, &mut buf).unwrap().unwrap();
+        assert_eq!(
+            commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+            [
+                Ok(Command::RetransmitRequest {
+                    sequence: 1,
+                    count: 1
+                }),
+                Ok(Command::RetransmitRequest {
+                    sequence: 3,
+                    count: 1
+                })
+            ]
+        );
+
+        client.handle(&buf[..len], now, &mut ump).unwrap();
+        let len = client.poll_transmit(now, &mut buf).unwrap().unwrap();
+        assert_eq!(codes(&buf[..len]), [UMP_DATA; 3]);
+        assert_eq!(
+            host.handle(&buf[..len], now, &mut ump),
+            Ok(Some(SessionEvent::Ump(4)))
+        );
+        assert_eq!(ump[..4], [note(61), note(62), note(63), note(64)]);
+    }
+
+    #[test]
+    fn should_request_again_when_retransmission_is_lost() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 256];
+        let mut ump = [note(0); 8];
+        let now = Duration::from_millis(10);
+        let len = client.send(&[note(60)], &mut buf).unwrap();
+        let _lost = buf[..len].to_vec();
+        let len = client.send(&[note(61)], &mut buf).unwrap();
+        host.handle(&buf[..len], now, &mut ump).unwrap();
+        assert!(host.poll_transmit(now, &mut buf).unwrap().is_some());
+        assert_eq!(host.poll_transmit(now, &mut buf), Ok(None));
+
+        let later = now + RETRANSMIT_INTERVAL;
+        let (log, received) = exchange(&mut host, &mut client, later);
+        assert_eq!(log, [(true, RETRANSMIT_REQUEST), (false, UMP_DATA)]);
+        assert_eq!(received, [note(60), note(61)]);
+        assert_eq!(host.next_due(), Some(later + PING_INTERVAL));
+    }
+
+    #[test]
+    fn should_recover_with_fec() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 256];
+        let mut ump = [note(0); 8];
+        client.set_fec(2);
+
+        let mut packets = Vec::new();
+        for i in 0..4 {
+            let len = client.send(&[note(60 + i)], &mut buf).unwrap();
+            packets.push(buf[..len].to_vec());
+        }
+        assert_eq!(codes(&packets[3]), [UMP_DATA; 3]);
+        assert_eq!(
+            host.handle(&packets[0], Duration::ZERO, &mut ump),
+            Ok(Some(SessionEvent::Ump(1)))
+        );
+        assert_eq!(
+            host.handle(&packets[3], Duration::ZERO, &mut ump),
+            Ok(Some(SessionEvent::Ump(3)))
+        );
+        assert_eq!(ump[..3], [note(61), note(62), note(63)]);
+        assert_eq!(host.poll_transmit(Duration::ZERO, &mut buf), Ok(None));
+
+        // The repeated commands are left out when they don't fit
+        let len = client.send(&[note(64)], &mut buf[..20]).unwrap();
+        assert_eq!(codes(&buf[..len]), [UMP_DATA, UMP_DATA]);
+        assert_eq!(
+            client.send(&[note(65)], &mut buf[..11]),
+            Err(NetError::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn should_split_large_sends() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 1500];
+        let mut ump = [note(0); 128];
+        let messages: Vec<_> = (0..100).map(note).collect();
+        let len = client.send(&messages, &mut buf).unwrap();
+        assert_eq!(codes(&buf[..len]), [UMP_DATA; 2]);
+        assert_eq!(len, 4 + 4 + 4 * 64 + 4 + 4 * 36);
+        assert_eq!(
+            host.handle(&buf[..len], Duration::ZERO, &mut ump),
+            Ok(Some(SessionEvent::Ump(100)))
+        );
+        assert_eq!(ump[..100], messages);
+        assert_eq!(client.send(&[], &mut buf), Ok(0));
+    }
+
+    #[test]
+    fn should_request_data_that_did_not_fit() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 256];
+        let mut ump = [note(0); 2];
+        let len = client
+            .send(&[note(60), note(61), note(62)], &mut buf)
+            .unwrap();
+        assert_eq!(host.handle(&buf[..len], Duration::ZERO, &mut ump), Ok(None));
+        let (log, received) = exchange(&mut host, &mut client, Duration::ZERO);
+        assert_eq!(log, [(true, RETRANSMIT_REQUEST), (false, UMP_DATA)]);
+        assert_eq!(received, [note(60), note(61), note(62)]);
+    }
+
+    #[test]
+    fn should_report_retransmit_error() {
+        let (mut client, mut host) = connected_pair::<2, 16>();
+        let mut buf = [0; 256];
+        let mut ump = [note(0); 8];
+        let mut len = 0;
+        for i in 0..4 {
+            len = client.send(&[note(60 + i)], &mut buf).unwrap();
+        }
+        host.handle(&buf[..len], Duration::ZERO, &mut ump).unwrap();
+        let (log, received) = exchange(&mut host, &mut client, Duration::ZERO);
+        assert_eq!(
+            log,
+            [
+                (true, RETRANSMIT_REQUEST),
+                (false, RETRANSMIT_ERROR),
+                (true, RETRANSMIT_REQUEST),
+                (false, UMP_DATA),
+            ]
+        );
+        assert_eq!(received, [note(62), note(63)]);
+        assert_eq!(host.lost(), 2);
+    }
+
+    #[test]
+    fn should_ping_and_time_out() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 64];
+        let mut ump = [];
+        let now = PING_INTERVAL;
+        let len = client.poll_transmit(now, &mut buf).unwrap().unwrap();
+        assert_eq!(codes(&buf[..len]), [PING]);
+        host.handle(&buf[..len], now, &mut ump).unwrap();
+        let len = host.poll_transmit(now, &mut buf).unwrap().unwrap();
+        assert_eq!(codes(&buf[..len]), [PING_REPLY]);
+        let now = now + Duration::from_millis(10);
+        client.handle(&buf[..len], now, &mut ump).unwrap();
+        assert_eq!(client.round_trip(), Some(Duration::from_millis(10)));
+
+        // The host stays quiet from now on
+        for ping in 1..=3 {
+            let now = now + ping * PING_INTERVAL;
+            assert_eq!(client.next_due(), Some(now));
+            let len = client.poll_transmit(now, &mut buf).unwrap().unwrap();
+            assert_eq!(codes(&buf[..len]), [PING]);
+        }
+        let len = client
+            .poll_transmit(now + 4 * PING_INTERVAL, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+            [Ok(bye(ByeReason::Timeout))]
+        );
+        assert_eq!(client.state(), SessionState::Idle);
+    }
+
+    #[test]
+    fn should_reset_session() {
+        let (mut client, mut host) = connected_pair::<16, 16>();
+        let mut buf = [0; 64];
+        let mut ump = [note(0); 4];
+        for i in 0..2 {
+            let len = client.send(&[note(60 + i)], &mut buf).unwrap();
+            host.handle(&buf[..len], Duration::ZERO, &mut ump).unwrap();
+        }
+
+        client.reset_session();
+        let len = client
+            .poll_transmit(Duration::ZERO, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(codes(&buf[..len]), [SESSION_RESET]);
+        assert_eq!(
+            host.handle(&buf[..len], Duration::ZERO, &mut ump),
+            Ok(Some(SessionEvent::Reset))
+        );
+        let (log, _) = exchange(&mut host, &mut client, Duration::ZERO);
+        assert_eq!(log, [(true, SESSION_RESET_REPLY)]);
+
+        let len = client.send(&[note(62)], &mut buf).unwrap();
+        assert_eq!(buf[6..8], [0, 0]);
+        assert_eq!(
+            host.handle(&buf[..len], Duration::ZERO, &mut ump),
+            Ok(Some(SessionEvent::Ump(1)))
+        );
+    }
+
+    #[cfg(feature = "auth")]
+    mod auth {
+        use super::*;
+
+        fn pair<'a>(
+            client: Option<Credentials<'a>>,
+            host: Option<Credentials<'a>>,
+        ) -> (NetworkSession<'a>, NetworkSession<'a>) {
+            let mut client_session = NetworkSession::new("client", "0001");
+            let mut host_session = NetworkSession::new("host", "0002");
+            client_session.set_credentials(client);
+            host_session.set_credentials(host);
+            host_session.set_nonce([0x5a; 16]);
+            client_session.connect(Duration::ZERO);
+            (client_session, host_session)
+        }
+
+        #[test]
+        fn should_compute_digests() {
+            let digest = Credentials::Secret("secret").digest(&[0; 16]);
+            assert_eq!(digest[..4], [0x36, 0xfc, 0xbd, 0xc4]);
+            assert_eq!(digest[28..], [0x18, 0x37, 0x52, 0xcb]);
+            let user = Credentials::User {
+                username: "user",
+                password: "password",
+            };
+            let digest = user.digest(&core::array::from_fn(|i| i as u8));
+            assert_eq!(digest[..4], [0x62, 0x8e, 0x1f, 0x44]);
+            assert_eq!(digest[28..], [0x85, 0x9f, 0x62, 0x8e]);
+        }
+
+        #[test]
+        fn should_authenticate_with_secret() {
+            let secret = Some(Credentials::Secret("secret"));
+            let (mut client, mut host) = pair(secret, secret);
+            let (log, _) = exchange(&mut client, &mut host, Duration::ZERO);
+            assert_eq!(
+                log,
+                [
+                    (true, INVITATION),
+                    (false, AUTHENTICATION_REQUIRED),
+                    (true, INVITATION_WITH_AUTH),
+                    (false, INVITATION_ACCEPTED),
+                ]
+            );
+            assert_eq!(client.state(), SessionState::Connected);
+            assert_eq!(host.state(), SessionState::Connected);
+        }
+
+        #[test]
+        fn should_authenticate_user() {
+            let user = Some(Credentials::User {
+                username: "user",
+                password: "password",
+            });
+            let (mut client, mut host) = pair(user, user);
+            let (log, _) = exchange(&mut client, &mut host, Duration::ZERO);
+            assert_eq!(
+                log,
+                [
+                    (true, INVITATION),
+                    (false, USER_AUTHENTICATION_REQUIRED),
+                    (true, INVITATION_WITH_USER_AUTH),
+                    (false, INVITATION_ACCEPTED),
+                ]
+            );
+            assert_eq!(host.state(), SessionState::Connected);
+        }
+
+        #[test]
+        fn should_reject_wrong_secret() {
+            let (mut client, mut host) = pair(
+                Some(Credentials::Secret("wrong")),
+                Some(Credentials::Secret("secret")),
+            );
+            let (log, _) = exchange(&mut client, &mut host, Duration::ZERO);
+            assert_eq!(
+                log,
+                [
+                    (true, INVITATION),
+                    (false, AUTHENTICATION_REQUIRED),
+                    (true, INVITATION_WITH_AUTH),
+                    (false, AUTHENTICATION_REQUIRED),
+                    (true, BYE),
+                    (false, BYE_REPLY),
+                ]
+            );
+            assert_eq!(client.state(), SessionState::Idle);
+            assert_eq!(host.state(), SessionState::Idle);
+        }
+
+        #[test]
+        fn should_reject_unknown_user() {
+            let (mut client, mut host) = pair(
+                Some(Credentials::User {
+                    username: "other",
+                    password: "password",
+                }),
+                Some(Credentials::User {
+                    username: "user",
+                    password: "password",
+                }),
+            );
+            let (log, _) = exchange(&mut client, &mut host, Duration::ZERO);
+            assert_eq!(log[3], (false, BYE));
+            assert_eq!(client.state(), SessionState::Idle);
+            assert_eq!(host.state(), SessionState::Idle);
+        }
+
+        #[test]
+        fn should_reject_client_without_credentials() {
+            let (mut client, mut host) = pair(None, Some(Credentials::Secret("secret")));
+            let mut buf = [0; 64];
+            let mut ump = [];
+            let len = client
+                .poll_transmit(Duration::ZERO, &mut buf)
+                .unwrap()
+                .unwrap();
+            host.handle(&buf[..len], Duration::ZERO, &mut ump).unwrap();
+            let len = host
+                .poll_transmit(Duration::ZERO, &mut buf)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                client.handle(&buf[..len], Duration::ZERO, &mut ump),
+                Ok(Some(SessionEvent::Rejected(
+                    ByeReason::NoMatchingAuthMethod
+                )))
+            );
+            assert_eq!(host.state(), SessionState::Idle);
+        }
+
+        #[test]
+        fn should_end_after_repeated_failures() {
+            let mut host: NetworkSession = NetworkSession::new("host", "0002");
+            host.set_credentials(Some(Credentials::Secret("secret")));
+            let mut buf = [0; 128];
+            let mut ump = [];
+            let invitation = Command::Invitation {
+                name: "client",
+                product_instance_id: "",
+                capabilities: CAPABILITY_AUTH,
+            };
+            let len = write_packet(&[invitation], &mut buf).unwrap();
+            host.handle(&buf[..len], Duration::ZERO, &mut ump).unwrap();
+            let mut nonces = Vec::new();
+            for _ in 0..3 {
+                let len = host
+                    .poll_transmit(Duration::ZERO, &mut buf)
+                    .unwrap()
+                    .unwrap();
+                let Some(Ok(Command::AuthenticationRequired { nonce, .. })) =
+                    commands(&buf[..len]).unwrap().next()
+                else {
+                    panic!("expected an authentication request");
+                };
+                nonces.push(nonce);
+                let guess = Command::InvitationWithAuth { digest: [0; 32] };
+                let len = write_packet(&[guess], &mut buf).unwrap();
+                host.handle(&buf[..len], Duration::ZERO, &mut ump).unwrap();
+            }
+            assert_eq!(nonces.len(), 3);
+            assert_ne!(nonces[0], nonces[1]);
+            let len = host
+                .poll_transmit(Duration::ZERO, &mut buf)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+                [Ok(bye(ByeReason::AuthenticationFailed))]
+            );
+            assert_eq!(host.state(), SessionState::Idle);
+        }
+    }
+}
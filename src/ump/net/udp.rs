@@ -0,0 +1,479 @@
+//! Network MIDI 2.0 sessions over a `std::net` UDP socket
+
+#[cfg(feature = "auth")]
+use {super::Credentials, std::hash::BuildHasher};
+use {
+    super::{
+        ByeReason, Command, NetworkSession, SessionEvent, SessionState, commands, write_packet,
+    },
+    crate::ump::UmpMessage,
+    std::{
+        io::{self, ErrorKind},
+        net::{SocketAddr, UdpSocket},
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// The most UMPs sent in one packet, at most 1024 bytes of UMP Data commands
+const PACKET_MESSAGES: usize = 64;
+
+/// A [`NetworkSession`] driven over a non-blocking UDP socket
+///
+/// Received packets are handled and due packets sent while [`poll`](Self::poll) runs, so poll
+/// regularly even when no UMPs are expected. Invitations from other addresses are rejected while
+/// a session is open.
+#[derive(Debug)]
+pub struct UdpSession<'a> {
+    session: NetworkSession<'a>,
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+    start: Instant,
+    buf: [u8; 1500],
+}
+
+impl<'a> UdpSession<'a> {
+    /// Bind a socket to `addr`, when its port is 0 a free port is picked
+    pub fn bind(addr: SocketAddr, name: &'a str, product_instance_id: &'a str) -> io::Result<Self> {
+        Self::from_socket(UdpSocket::bind(addr)?, name, product_instance_id)
+    }
+
+    /// Use an already bound socket
+    pub fn from_socket(
+        socket: UdpSocket,
+        name: &'a str,
+        product_instance_id: &'a str,
+    ) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            session: NetworkSession::new(name, product_instance_id),
+            socket,
+            peer: None,
+            start: Instant::now(),
+            buf: [0; 1500],
+        })
+    }
+
+    /// The address of the socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The session state machine
+    pub fn session(&self) -> &NetworkSession<'a> {
+        &self.session
+    }
+
+    /// Authenticate with `credentials`, see [`NetworkSession::set_credentials`]
+    #[cfg(feature = "auth")]
+    pub fn set_credentials(&mut self, credentials: Option<Credentials<'a>>) {
+        self.session.set_credentials(credentials);
+    }
+
+    /// Repeat the latest `count` UMP Data commands in every packet, see
+    /// [`NetworkSession::set_fec`]
+    pub fn set_fec(&mut self, count: usize) {
+        self.session.set_fec(count);
+    }
+
+    /// Invite the host at `addr`
+    pub fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.peer = Some(addr);
+        self.session.connect(self.now());
+        self.transmit(None)
+    }
+
+    /// End the session
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.session.disconnect();
+        self.transmit(None)
+    }
+
+    /// Send `messages` to the peer
+    pub fn send(&mut self, messages: &[UmpMessage]) -> io::Result<()> {
+        for chunk in messages.chunks(PACKET_MESSAGES) {
+            let len = self
+                .session
+                .send(chunk, &mut self.buf)
+                .map_err(invalid_data)?;
+            if let Some(addr) = self.peer {
+                self.socket.send_to(&self.buf[..len], addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle received packets and send due packets for up to `timeout`, returning at the first
+    /// event
+    ///
+    /// Received UMPs are written to `ump`, see [`NetworkSession::handle`]. Packets that are not
+    /// Network MIDI are ignored.
+    pub fn poll(
+        &mut self,
+        timeout: Duration,
+        ump: &mut [UmpMessage],
+    ) -> io::Result<Option<SessionEvent>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.transmit(None)?;
+            let received = match self.socket.recv_from(&mut self.buf) {
+                Ok(received) => Some(received),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+                Err(e) => return Err(e),
+            };
+
+            if let Some((len, src)) = received {
+                let idle = self.session.state() == SessionState::Idle;
+                if !idle && self.peer != Some(src) {
+                    self.reject(len, src)?;
+                    continue;
+                }
+                #[cfg(feature = "auth")]
+                if idle {
+                    self.session.set_nonce(self.nonce());
+                }
+                let now = self.now();
+                // Anyone can send to the socket, packets that are not Network MIDI are ignored
+                let Ok(event) = self.session.handle(&self.buf[..len], now, ump) else {
+                    continue;
+                };
+                if idle && self.session.state() != SessionState::Idle {
+                    self.peer = Some(src);
+                }
+                self.transmit(idle.then_some(src))?;
+                if event.is_some() {
+                    return Ok(event);
+                }
+                continue;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(1)));
+        }
+    }
+
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Answer invitations from addresses other than the peer with a bye
+    fn reject(&mut self, len: usize, src: SocketAddr) -> io::Result<()> {
+        let Ok(mut received) = commands(&self.buf[..len]) else {
+            return Ok(());
+        };
+        if received.any(|command| matches!(command, Ok(Command::Invitation { .. }))) {
+            let bye = Command::Bye {
+                reason: ByeReason::TooManySessions,
+                message: "",
+            };
+            let len = write_packet(&[bye], &mut self.buf).map_err(invalid_data)?;
+            self.socket.send_to(&self.buf[..len], src)?;
+        }
+        Ok(())
+    }
+
+    /// A nonce for the next authentication request
+    #[cfg(feature = "auth")]
+    fn nonce(&self) -> [u8; 16] {
+        let mut nonce = [0; 16];
+        for half in nonce.chunks_exact_mut(8) {
+            let random = std::hash::RandomState::new().hash_one(self.start.elapsed());
+            half.copy_from_slice(&random.to_be_bytes());
+        }
+        nonce
+    }
+
+    /// Send the packets the session has queued, to `reply` when given and to the peer otherwise
+    fn transmit(&mut self, reply: Option<SocketAddr>) -> io::Result<()> {
+        // A packet that can't be encoded is left to the session to retry or drop
+        while let Ok(Some(len)) = self.session.poll_transmit(self.now(), &mut self.buf) {
+            if let Some(addr) = reply.or(self.peer) {
+                self.socket.send_to(&self.buf[..len], addr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data<E: core::fmt::Debug>(error: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, std::format!("{error:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::ump::Group,
+        midi_types::{Channel, MidiMessage, Note, Value7},
+        std::vec::Vec,
+    };
+
+    fn bind(name: &str) -> UdpSession<'_> {
+        UdpSession::bind("127.0.0.1:0".parse().unwrap(), name, "0001").unwrap()
+    }
+
+    fn note(i: u8) -> UmpMessage {
+        UmpMessage::Midi1ChannelVoice {
+            group: Group::new(i % 16),
+            message: MidiMessage::NoteOn(
+                Channel::new(i % 16),
+                Note::new(i % 128),
+                Value7::new(100),
+            ),
+        }
+    }
+
+    /// Forwards packets between a client and a host, dropping the ones `lose` picks
+    struct Relay {
+        socket: UdpSocket,
+        host: SocketAddr,
+        client: Option<SocketAddr>,
+        data_packets: usize,
+    }
+
+    impl Relay {
+        fn new(host: SocketAddr) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_nonblocking(true).unwrap();
+            Self {
+                socket,
+                host,
+                client: None,
+                data_packets: 0,
+            }
+        }
+
+        fn forward(&mut self, lose: impl Fn(usize) -> bool) {
+            let mut buf = [0; 1500];
+            while let Ok((len, src)) = self.socket.recv_from(&mut buf) {
+                let packet = &buf[..len];
+                if commands(packet)
+                    .unwrap()
+                    .any(|command| matches!(command, Ok(Command::UmpData { .. })))
+                {
+                    self.data_packets += 1;
+                    if lose(self.data_packets) {
+                        continue;
+                    }
+                }
+                let dst = if src == self.host {
+                    self.client
+                } else {
+                    self.client = Some(src);
+                    Some(self.host)
+                };
+                if let Some(dst) = dst {
+                    self.socket.send_to(packet, dst).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Poll both sessions until `done` holds, collecting the UMPs `b` receives, `done` may send
+    fn run(
+        a: &mut UdpSession,
+        b: &mut UdpSession,
+        mut relay: Option<(&mut Relay, &dyn Fn(usize) -> bool)>,
+        mut done: impl FnMut(&mut UdpSession, &UdpSession, &[UmpMessage]) -> bool,
+    ) -> Vec<UmpMessage> {
+        let mut received = Vec::new();
+        let mut ump = [note(0); 128];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(a, b, &received) {
+            assert!(Instant::now() < deadline, "timed out");
+            a.poll(Duration::from_millis(1), &mut ump).unwrap();
+            if let Some((relay, lose)) = relay.as_mut() {
+                relay.forward(*lose);
+            }
+            if let Some(SessionEvent::Ump(len)) =
+                b.poll(Duration::from_millis(1), &mut ump).unwrap()
+            {
+                received.extend_from_slice(&ump[..len]);
+            }
+            if let Some((relay, lose)) = relay.as_mut() {
+                relay.forward(*lose);
+            }
+        }
+        received
+    }
+
+    fn connected(a: &UdpSession, b: &UdpSession) -> bool {
+        a.session().state() == SessionState::Connected
+            && b.session().state() == SessionState::Connected
+    }
+
+    #[test]
+    fn should_exchange_ump_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        client.connect(host.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| connected(a, b));
+
+        let messages: Vec<_> = (0..200).map(note).collect();
+        client.send(&messages).unwrap();
+        let received = run(&mut client, &mut host, None, |_, _, received| {
+            received.len() == messages.len()
+        });
+        assert_eq!(received, messages);
+        run(&mut client, &mut host, None, |a, _, _| {
+            a.session().round_trip().is_some()
+        });
+    }
+
+    #[test]
+    fn should_retransmit_lost_packets_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        let mut relay = Relay::new(host.local_addr().unwrap());
+        client.connect(relay.socket.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| {
+            relay.forward(|_| false);
+            connected(a, b)
+        });
+
+        // Every third packet carrying UMPs is lost, retransmissions included, while at most 8
+        // chunks are in flight
+        let messages: Vec<_> = (0..120).map(note).collect();
+        let mut chunks = messages.chunks(4);
+        let mut sent = 0;
+        let lose = |packet: usize| packet % 3 == 1;
+        let received = run(
+            &mut client,
+            &mut host,
+            Some((&mut relay, &lose)),
+            |a, _, received| {
+                if sent < received.len() + 32 {
+                    if let Some(chunk) = chunks.next() {
+                        a.send(chunk).unwrap();
+                        sent += chunk.len();
+                    }
+                }
+                received.len() == messages.len()
+            },
+        );
+        assert_eq!(received, messages);
+        assert_eq!(host.session().lost(), 0);
+    }
+
+    #[test]
+    fn should_recover_lost_packets_with_fec_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        let mut relay = Relay::new(host.local_addr().unwrap());
+        client.set_fec(2);
+        client.connect(relay.socket.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| {
+            relay.forward(|_| false);
+            connected(a, b)
+        });
+
+        let messages: Vec<_> = (0..120).map(note).collect();
+        let mut chunks = messages.chunks(4);
+        let lose = |packet: usize| packet % 3 == 2;
+        let received = run(
+            &mut client,
+            &mut host,
+            Some((&mut relay, &lose)),
+            |a, _, received| {
+                if let Some(chunk) = chunks.next() {
+                    a.send(chunk).unwrap();
+                }
+                received.len() == messages.len()
+            },
+        );
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn should_reject_second_client_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        client.connect(host.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| connected(a, b));
+
+        let mut other = bind("other");
+        other.connect(host.local_addr().unwrap()).unwrap();
+        run(&mut other, &mut host, None, |a, _, _| {
+            a.session().state() == SessionState::Idle
+        });
+        assert_eq!(host.session().state(), SessionState::Connected);
+    }
+
+    #[test]
+    fn should_ignore_invalid_packets_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        client.connect(host.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| connected(a, b));
+
+        let host_addr = host.local_addr().unwrap();
+        client.socket.send_to(b"garbage", host_addr).unwrap();
+        client.socket.send_to(b"MIDI\xff\x09", host_addr).unwrap();
+        let mut ump = [note(0); 8];
+        assert!(host.poll(Duration::from_millis(10), &mut ump).is_ok());
+        assert_eq!(host.session().state(), SessionState::Connected);
+
+        client.send(&[note(1)]).unwrap();
+        let received = run(&mut client, &mut host, None, |_, _, received| {
+            !received.is_empty()
+        });
+        assert_eq!(received, [note(1)]);
+    }
+
+    #[test]
+    fn should_answer_packets_outside_session_over_localhost() {
+        let mut host = bind("host");
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0; 64];
+        let len = write_packet(&[Command::Ping { id: 1 }], &mut buf).unwrap();
+        other
+            .send_to(&buf[..len], host.local_addr().unwrap())
+            .unwrap();
+
+        let mut ump = [note(0); 8];
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < deadline {
+            host.poll(Duration::from_millis(1), &mut ump).unwrap();
+        }
+        let len = other.recv(&mut buf).unwrap();
+        assert_eq!(
+            commands(&buf[..len]).unwrap().collect::<Vec<_>>(),
+            [Ok(Command::Bye {
+                reason: ByeReason::SessionNotEstablished,
+                message: ""
+            })]
+        );
+        assert_eq!(host.session().state(), SessionState::Idle);
+    }
+
+    #[test]
+    fn should_end_session_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        client.connect(host.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| connected(a, b));
+
+        host.disconnect().unwrap();
+        run(&mut client, &mut host, None, |a, _, _| {
+            a.session().state() == SessionState::Idle
+        });
+        assert!(client.send(&[note(0)]).is_err());
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn should_authenticate_over_localhost() {
+        let mut client = bind("client");
+        let mut host = bind("host");
+        host.set_credentials(Some(Credentials::Secret("secret")));
+        client.set_credentials(Some(Credentials::Secret("secret")));
+        client.connect(host.local_addr().unwrap()).unwrap();
+        run(&mut client, &mut host, None, |a, b, _| connected(a, b));
+    }
+}
@@ -0,0 +1,230 @@
+//! Splitting data over the packets of a multi-packet message and reassembling it
+//!
+//! SysEx, flex data text and stream text messages all send their data in a complete packet or a
+//! start packet followed by continue packets and an end packet.
+
+use super::Form;
+
+/// The most data bytes a single packet carries
+const MAX_PACKET_LEN: usize = 14;
+
+/// Iterator over the chunks of data with the form of the packet carrying them
+#[derive(Debug, Clone)]
+pub(super) struct Segments<'a> {
+    data: &'a [u8],
+    max_len: usize,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> Segments<'a> {
+    /// Split `data` into chunks of up to `max_len` bytes
+    ///
+    /// Empty data gives a single complete packet when `empty_packet` is set and none otherwise.
+    pub(super) fn new(data: &'a [u8], max_len: usize, empty_packet: bool) -> Self {
+        Self {
+            data,
+            max_len,
+            started: false,
+            done: data.is_empty() && !empty_packet,
+        }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = (Form, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let (chunk, rest) = self.data.split_at(self.data.len().min(self.max_len));
+        self.data = rest;
+        self.done = rest.is_empty();
+        let form = match (self.started, self.done) {
+            (false, true) => Form::Complete,
+            (false, false) => Form::Start,
+            (true, false) => Form::Continue,
+            (true, true) => Form::End,
+        };
+        self.started = true;
+        Some((form, chunk))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = if self.done {
+            0
+        } else {
+            self.data.len().div_ceil(self.max_len).max(1)
+        };
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for Segments<'_> {}
+
+/// Errors reassembling a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ReassemblyError {
+    /// The message did not fit in the buffer and was dropped
+    TooLong,
+    /// A start packet arrived before the previous message ended
+    Interrupted,
+    /// A continue or end packet arrived without a start packet
+    Unexpected,
+    /// Every buffer holds an unfinished message
+    NoFreeBuffer,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Buffer<K, const N: usize> {
+    key: Option<K>,
+    data: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<K, const N: usize> Buffer<K, N> {
+    fn append(&mut self, data: &[u8]) {
+        if self.len + data.len() <= N {
+            self.data[self.len..self.len + data.len()].copy_from_slice(data);
+            self.len += data.len();
+        } else {
+            self.overflow = true;
+        }
+    }
+}
+
+/// Reassembles messages of up to `N` bytes, for up to `S` keys at the same time
+#[derive(Debug, Clone)]
+pub(super) struct Reassembler<K, const N: usize, const S: usize> {
+    buffers: [Buffer<K, N>; S],
+    single: [u8; MAX_PACKET_LEN],
+    single_len: usize,
+}
+
+impl<K: Copy + PartialEq, const N: usize, const S: usize> Reassembler<K, N, S> {
+    pub(super) fn new() -> Self {
+        Self {
+            buffers: [Buffer {
+                key: None,
+                data: [0; N],
+                len: 0,
+                overflow: false,
+            }; S],
+            single: [0; MAX_PACKET_LEN],
+            single_len: 0,
+        }
+    }
+
+    /// Drop all unfinished messages
+    pub(super) fn reset(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.key = None;
+        }
+    }
+
+    /// Add the data of a packet for `key`, returning the message it completes
+    ///
+    /// Complete packets are returned straight away and leave the unfinished message of their key
+    /// alone. An interrupted message is dropped and the new one started.
+    pub(super) fn feed(
+        &mut self,
+        key: K,
+        form: Form,
+        data: &[u8],
+    ) -> Result<Option<&[u8]>, ReassemblyError> {
+        let found = self.buffers.iter().position(|b| b.key == Some(key));
+        match form {
+            Form::Complete => {
+                let len = data.len().min(MAX_PACKET_LEN);
+                self.single[..len].copy_from_slice(&data[..len]);
+                self.single_len = len;
+                Ok(Some(&self.single[..len]))
+            }
+            Form::Start => {
+                let index = found
+                    .or_else(|| self.buffers.iter().position(|b| b.key.is_none()))
+                    .ok_or(ReassemblyError::NoFreeBuffer)?;
+                let buffer = &mut self.buffers[index];
+                buffer.key = Some(key);
+                buffer.len = 0;
+                buffer.overflow = false;
+                buffer.append(data);
+                match found {
+                    Some(_) => Err(ReassemblyError::Interrupted),
+                    None => Ok(None),
+                }
+            }
+            Form::Continue | Form::End => {
+                let index = found.ok_or(ReassemblyError::Unexpected)?;
+                let buffer = &mut self.buffers[index];
+                buffer.append(data);
+                if form == Form::Continue {
+                    return Ok(None);
+                }
+                buffer.key = None;
+                if buffer.overflow {
+                    return Err(ReassemblyError::TooLong);
+                }
+                Ok(Some(&buffer.data[..buffer.len]))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, std::vec::Vec};
+
+    #[test]
+    fn should_split_into_forms() {
+        let forms = |data: &[u8], empty_packet| {
+            Segments::new(data, 4, empty_packet)
+                .map(|(form, chunk)| (form, chunk.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(forms(&[], false), []);
+        assert_eq!(forms(&[], true), [(Form::Complete, 0)]);
+        assert_eq!(forms(&[1; 4], false), [(Form::Complete, 4)]);
+        assert_eq!(
+            forms(&[1; 9], false),
+            [(Form::Start, 4), (Form::Continue, 4), (Form::End, 1)]
+        );
+        assert_eq!(Segments::new(&[1; 9], 4, false).len(), 3);
+    }
+
+    #[test]
+    fn should_reassemble_per_key() {
+        let mut reassembler = Reassembler::<u8, 8, 2>::new();
+        assert_eq!(reassembler.feed(0, Form::Start, &[1, 2]), Ok(None));
+        assert_eq!(reassembler.feed(1, Form::Start, &[5]), Ok(None));
+        assert_eq!(
+            reassembler.feed(0, Form::Complete, &[9]),
+            Ok(Some(&[9][..]))
+        );
+        assert_eq!(
+            reassembler.feed(2, Form::Start, &[]),
+            Err(ReassemblyError::NoFreeBuffer)
+        );
+        assert_eq!(
+            reassembler.feed(0, Form::End, &[3]),
+            Ok(Some(&[1, 2, 3][..]))
+        );
+        assert_eq!(
+            reassembler.feed(0, Form::End, &[3]),
+            Err(ReassemblyError::Unexpected)
+        );
+        assert_eq!(
+            reassembler.feed(1, Form::Start, &[6]),
+            Err(ReassemblyError::Interrupted)
+        );
+        assert_eq!(reassembler.feed(1, Form::Continue, &[0; 8]), Ok(None));
+        assert_eq!(
+            reassembler.feed(1, Form::End, &[]),
+            Err(ReassemblyError::TooLong)
+        );
+    }
+}
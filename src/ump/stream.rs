@@ -0,0 +1,1101 @@
+//! UMP stream messages and an endpoint discovery responder
+//!
+//! [`Stream`] is the typed form of a [`StreamMessage`]. [`EndpointResponder`] answers the
+//! discovery and protocol negotiation messages a host sends to a device.
+//!
+//! ```
+//! use midi_convert::ump::{
+//!     UmpMessage,
+//!     stream::{DISCOVER_ENDPOINT_INFO, EndpointConfig, EndpointResponder, Stream, UmpVersion},
+//! };
+//!
+//! let config = EndpointConfig {
+//!     name: "Synth",
+//!     ..EndpointConfig::default()
+//! };
+//! let mut responder = EndpointResponder::new(config);
+//! let discovery = Stream::EndpointDiscovery {
+//!     version: UmpVersion::V1_1,
+//!     filter: DISCOVER_ENDPOINT_INFO,
+//! };
+//! let mut responses = responder.respond(&UmpMessage::from(discovery));
+//! let Some(UmpMessage::Stream(reply)) = responses.next() else {
+//!     panic!()
+//! };
+//! assert!(matches!(Stream::try_from(reply), Ok(Stream::EndpointInfo(_))));
+//! assert_eq!(responses.next(), None);
+//! ```
+
+use super::{Form, Group, StreamMessage, UmpError, UmpMessage, segment::Segments};
+
+const ENDPOINT_DISCOVERY: u16 = 0x00;
+const ENDPOINT_INFO: u16 = 0x01;
+const DEVICE_IDENTITY: u16 = 0x02;
+const ENDPOINT_NAME: u16 = 0x03;
+const PRODUCT_INSTANCE_ID: u16 = 0x04;
+const STREAM_CONFIGURATION_REQUEST: u16 = 0x05;
+const STREAM_CONFIGURATION_NOTIFICATION: u16 = 0x06;
+const FUNCTION_BLOCK_DISCOVERY: u16 = 0x10;
+const FUNCTION_BLOCK_INFO: u16 = 0x11;
+const FUNCTION_BLOCK_NAME: u16 = 0x12;
+const START_OF_CLIP: u16 = 0x20;
+const END_OF_CLIP: u16 = 0x21;
+
+/// Endpoint discovery filter bit asking for the endpoint info
+pub const DISCOVER_ENDPOINT_INFO: u8 = 1 << 0;
+/// Endpoint discovery filter bit asking for the device identity
+pub const DISCOVER_DEVICE_IDENTITY: u8 = 1 << 1;
+/// Endpoint discovery filter bit asking for the endpoint name
+pub const DISCOVER_ENDPOINT_NAME: u8 = 1 << 2;
+/// Endpoint discovery filter bit asking for the product instance id
+pub const DISCOVER_PRODUCT_INSTANCE_ID: u8 = 1 << 3;
+/// Endpoint discovery filter bit asking for the stream configuration
+pub const DISCOVER_STREAM_CONFIGURATION: u8 = 1 << 4;
+
+/// Function block discovery filter bit asking for the block info
+pub const DISCOVER_BLOCK_INFO: u8 = 1 << 0;
+/// Function block discovery filter bit asking for the block name
+pub const DISCOVER_BLOCK_NAME: u8 = 1 << 1;
+
+/// Function block discovery number asking for every block
+pub const ALL_BLOCKS: u8 = 0xff;
+
+/// A UMP format and protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UmpVersion {
+    /// The major version
+    pub major: u8,
+    /// The minor version
+    pub minor: u8,
+}
+
+impl UmpVersion {
+    /// Version 1.1, the first with stream messages
+    pub const V1_1: Self = Self { major: 1, minor: 1 };
+}
+
+/// The protocol of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// MIDI 1.0 protocol
+    Midi1,
+    /// MIDI 2.0 protocol
+    Midi2,
+}
+
+impl Protocol {
+    fn from_code(code: u8) -> Result<Self, UmpError> {
+        match code {
+            0x01 => Ok(Self::Midi1),
+            0x02 => Ok(Self::Midi2),
+            _ => Err(UmpError::InvalidData),
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Self::Midi1 => 0x01,
+            Self::Midi2 => 0x02,
+        }
+    }
+}
+
+/// The endpoint info notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EndpointInfo {
+    /// The UMP version the endpoint supports
+    pub version: UmpVersion,
+    /// Whether the function blocks never change
+    pub static_blocks: bool,
+    /// The number of function blocks, 7 bits
+    pub blocks: u8,
+    /// Whether the endpoint supports the MIDI 2.0 protocol
+    pub midi2: bool,
+    /// Whether the endpoint supports the MIDI 1.0 protocol
+    pub midi1: bool,
+    /// Whether the endpoint can receive jitter reduction timestamps
+    pub rx_jr: bool,
+    /// Whether the endpoint can send jitter reduction timestamps
+    pub tx_jr: bool,
+}
+
+/// The device identity notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceIdentity {
+    /// The SysEx manufacturer id, 7 bits each
+    pub manufacturer: [u8; 3],
+    /// The device family, 14 bits
+    pub family: u16,
+    /// The device family model, 14 bits
+    pub model: u16,
+    /// The software revision, 7 bits each
+    pub revision: [u8; 4],
+}
+
+/// A stream configuration request or notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamConfiguration {
+    /// The protocol
+    pub protocol: Protocol,
+    /// Whether jitter reduction timestamps are received
+    pub rx_jr: bool,
+    /// Whether jitter reduction timestamps are sent
+    pub tx_jr: bool,
+}
+
+/// The function block info notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FunctionBlockInfo {
+    /// The block number, 7 bits
+    pub block: u8,
+    /// Whether the block is active
+    pub active: bool,
+    /// The UI hint, 2 bits: 1 receiver, 2 sender, 3 both, 0 unknown
+    pub ui_hint: u8,
+    /// MIDI 1.0 use, 2 bits: 0 not MIDI 1.0, 1 MIDI 1.0, 2 MIDI 1.0 at 31.25 kb/s
+    pub midi1: u8,
+    /// The direction, 2 bits: 1 input, 2 output, 3 bidirectional
+    pub direction: u8,
+    /// The first group of the block
+    pub first_group: Group,
+    /// The number of groups the block spans
+    pub groups: u8,
+    /// The MIDI-CI message version, 0 if the block does not support MIDI-CI
+    pub ci_version: u8,
+    /// The most SysEx8 streams the block supports at once
+    pub sysex8_streams: u8,
+}
+
+/// A packet of text, holding up to `N` bytes of UTF-8 text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Text<const N: usize> {
+    form: Form,
+    len: u8,
+    data: [u8; N],
+}
+
+impl<const N: usize> Text<N> {
+    /// A packet holding up to `N` bytes
+    ///
+    /// A text ends at the first zero byte, so `data` must not contain any.
+    pub fn new(form: Form, data: &[u8]) -> Result<Self, UmpError> {
+        if data.len() > N || data.contains(&0) {
+            return Err(UmpError::InvalidData);
+        }
+        let mut text = Self {
+            form,
+            len: data.len() as u8,
+            data: [0; N],
+        };
+        text.data[..data.len()].copy_from_slice(data);
+        Ok(text)
+    }
+
+    /// Where the packet sits in the text
+    pub fn form(&self) -> Form {
+        self.form
+    }
+
+    /// The text bytes
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    pub(super) fn decode(form: Form, bytes: &[u8]) -> Self {
+        let len = bytes.iter().take(N).position(|b| *b == 0);
+        let len = len.unwrap_or(bytes.len().min(N));
+        let mut data = [0; N];
+        data[..len].copy_from_slice(&bytes[..len]);
+        Self {
+            form,
+            len: len as u8,
+            data,
+        }
+    }
+}
+
+/// A typed stream message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Stream {
+    /// A host asking an endpoint about itself
+    EndpointDiscovery {
+        /// The highest UMP version the host supports
+        version: UmpVersion,
+        /// The `DISCOVER_*` bits of the notifications to send back
+        filter: u8,
+    },
+    /// The endpoint info
+    EndpointInfo(EndpointInfo),
+    /// The device identity
+    DeviceIdentity(DeviceIdentity),
+    /// A packet of the endpoint name
+    EndpointName(Text<14>),
+    /// A packet of the product instance id
+    ProductInstanceId(Text<14>),
+    /// A host asking for a stream configuration
+    StreamConfigurationRequest(StreamConfiguration),
+    /// The stream configuration in use
+    StreamConfigurationNotification(StreamConfiguration),
+    /// A host asking about function blocks
+    FunctionBlockDiscovery {
+        /// The block number, or [`ALL_BLOCKS`]
+        block: u8,
+        /// The `DISCOVER_BLOCK_*` bits of the notifications to send back
+        filter: u8,
+    },
+    /// The function block info
+    FunctionBlockInfo(FunctionBlockInfo),
+    /// A packet of a function block name
+    FunctionBlockName {
+        /// The block number
+        block: u8,
+        /// The text
+        text: Text<13>,
+    },
+    /// The start of a clip
+    StartOfClip,
+    /// The end of a clip
+    EndOfClip,
+}
+
+impl TryFrom<StreamMessage> for Stream {
+    type Error = UmpError;
+
+    fn try_from(message: StreamMessage) -> Result<Self, UmpError> {
+        let d = &message.data;
+        let bit = |byte: u8, bit: u8| byte >> bit & 1 != 0;
+        let version = UmpVersion {
+            major: d[0],
+            minor: d[1],
+        };
+        match message.status {
+            ENDPOINT_DISCOVERY => Ok(Self::EndpointDiscovery {
+                version,
+                filter: d[5],
+            }),
+            ENDPOINT_INFO => Ok(Self::EndpointInfo(EndpointInfo {
+                version,
+                static_blocks: bit(d[2], 7),
+                blocks: d[2] & 0x7f,
+                midi2: bit(d[4], 1),
+                midi1: bit(d[4], 0),
+                rx_jr: bit(d[5], 1),
+                tx_jr: bit(d[5], 0),
+            })),
+            DEVICE_IDENTITY => Ok(Self::DeviceIdentity(DeviceIdentity {
+                manufacturer: [d[3] & 0x7f, d[4] & 0x7f, d[5] & 0x7f],
+                family: u16::from(d[7] & 0x7f) << 7 | u16::from(d[6] & 0x7f),
+                model: u16::from(d[9] & 0x7f) << 7 | u16::from(d[8] & 0x7f),
+                revision: [d[10] & 0x7f, d[11] & 0x7f, d[12] & 0x7f, d[13] & 0x7f],
+            })),
+            ENDPOINT_NAME => Ok(Self::EndpointName(Text::decode(message.form, d))),
+            PRODUCT_INSTANCE_ID => Ok(Self::ProductInstanceId(Text::decode(message.form, d))),
+            STREAM_CONFIGURATION_REQUEST | STREAM_CONFIGURATION_NOTIFICATION => {
+                let configuration = StreamConfiguration {
+                    protocol: Protocol::from_code(d[0])?,
+                    rx_jr: bit(d[1], 1),
+                    tx_jr: bit(d[1], 0),
+                };
+                Ok(if message.status == STREAM_CONFIGURATION_REQUEST {
+                    Self::StreamConfigurationRequest(configuration)
+                } else {
+                    Self::StreamConfigurationNotification(configuration)
+                })
+            }
+            FUNCTION_BLOCK_DISCOVERY => Ok(Self::FunctionBlockDiscovery {
+                block: d[0],
+                filter: d[1],
+            }),
+            FUNCTION_BLOCK_INFO => Ok(Self::FunctionBlockInfo(FunctionBlockInfo {
+                block: d[0] & 0x7f,
+                active: bit(d[0], 7),
+                ui_hint: d[1] >> 4 & 0x3,
+                midi1: d[1] >> 2 & 0x3,
+                direction: d[1] & 0x3,
+                first_group: Group::new(d[2] & 0xf),
+                groups: d[3],
+                ci_version: d[4],
+                sysex8_streams: d[5],
+            })),
+            FUNCTION_BLOCK_NAME => Ok(Self::FunctionBlockName {
+                block: d[0],
+                text: Text::decode(message.form, &d[1..]),
+            }),
+            START_OF_CLIP => Ok(Self::StartOfClip),
+            END_OF_CLIP => Ok(Self::EndOfClip),
+            _ => Err(UmpError::InvalidStatus),
+        }
+    }
+}
+
+impl From<Stream> for StreamMessage {
+    fn from(stream: Stream) -> Self {
+        let mut message = StreamMessage {
+            form: Form::Complete,
+            status: 0,
+            data: [0; 14],
+        };
+        let d = &mut message.data;
+        let bits = |high: bool, low: bool| u8::from(high) << 1 | u8::from(low);
+        message.status = match stream {
+            Stream::EndpointDiscovery { version, filter } => {
+                d[0] = version.major;
+                d[1] = version.minor;
+                d[5] = filter;
+                ENDPOINT_DISCOVERY
+            }
+            Stream::EndpointInfo(info) => {
+                d[0] = info.version.major;
+                d[1] = info.version.minor;
+                d[2] = u8::from(info.static_blocks) << 7 | info.blocks & 0x7f;
+                d[4] = bits(info.midi2, info.midi1);
+                d[5] = bits(info.rx_jr, info.tx_jr);
+                ENDPOINT_INFO
+            }
+            Stream::DeviceIdentity(identity) => {
+                for (byte, id) in d[3..6].iter_mut().zip(identity.manufacturer) {
+                    *byte = id & 0x7f;
+                }
+                d[6] = identity.family as u8 & 0x7f;
+                d[7] = (identity.family >> 7) as u8 & 0x7f;
+                d[8] = identity.model as u8 & 0x7f;
+                d[9] = (identity.model >> 7) as u8 & 0x7f;
+                for (byte, revision) in d[10..].iter_mut().zip(identity.revision) {
+                    *byte = revision & 0x7f;
+                }
+                DEVICE_IDENTITY
+            }
+            Stream::EndpointName(text) | Stream::ProductInstanceId(text) => {
+                message.form = text.form;
+                d.copy_from_slice(&text.data);
+                if matches!(stream, Stream::EndpointName(_)) {
+                    ENDPOINT_NAME
+                } else {
+                    PRODUCT_INSTANCE_ID
+                }
+            }
+            Stream::StreamConfigurationRequest(configuration)
+            | Stream::StreamConfigurationNotification(configuration) => {
+                d[0] = configuration.protocol.code();
+                d[1] = bits(configuration.rx_jr, configuration.tx_jr);
+                if matches!(stream, Stream::StreamConfigurationRequest(_)) {
+                    STREAM_CONFIGURATION_REQUEST
+                } else {
+                    STREAM_CONFIGURATION_NOTIFICATION
+                }
+            }
+            Stream::FunctionBlockDiscovery { block, filter } => {
+                d[0] = block;
+                d[1] = filter;
+                FUNCTION_BLOCK_DISCOVERY
+            }
+            Stream::FunctionBlockInfo(info) => {
+                d[0] = u8::from(info.active) << 7 | info.block & 0x7f;
+                d[1] = (info.ui_hint & 0x3) << 4 | (info.midi1 & 0x3) << 2 | info.direction & 0x3;
+                d[2] = info.first_group.into();
+                d[3] = info.groups;
+                d[4] = info.ci_version;
+                d[5] = info.sysex8_streams;
+                FUNCTION_BLOCK_INFO
+            }
+            Stream::FunctionBlockName { block, text } => {
+                message.form = text.form;
+                d[0] = block;
+                d[1..].copy_from_slice(&text.data);
+                FUNCTION_BLOCK_NAME
+            }
+            Stream::StartOfClip => START_OF_CLIP,
+            Stream::EndOfClip => END_OF_CLIP,
+        };
+        message
+    }
+}
+
+impl From<Stream> for UmpMessage {
+    fn from(stream: Stream) -> Self {
+        Self::Stream(stream.into())
+    }
+}
+
+/// Iterator over the packets of a text, see [`endpoint_name_packets`]
+#[derive(Debug, Clone)]
+pub struct TextPackets<'a> {
+    status: u16,
+    block: u8,
+    segments: Segments<'a>,
+}
+
+impl Iterator for TextPackets<'_> {
+    type Item = UmpMessage;
+
+    fn next(&mut self) -> Option<UmpMessage> {
+        let (form, chunk) = self.segments.next()?;
+        let mut data = [0; 14];
+        let offset = usize::from(self.status == FUNCTION_BLOCK_NAME);
+        data[..offset].fill(self.block);
+        data[offset..offset + chunk.len()].copy_from_slice(chunk);
+        Some(UmpMessage::Stream(StreamMessage {
+            form,
+            status: self.status,
+            data,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.segments.size_hint()
+    }
+}
+
+impl ExactSizeIterator for TextPackets<'_> {}
+
+fn text_packets(status: u16, block: u8, text: &str) -> TextPackets<'_> {
+    // Text ends at the first zero byte, function block names start with the block number
+    let data = text.as_bytes();
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    let max_len = if status == FUNCTION_BLOCK_NAME {
+        13
+    } else {
+        14
+    };
+    TextPackets {
+        status,
+        block,
+        segments: Segments::new(&data[..len], max_len, false),
+    }
+}
+
+/// Split an endpoint name into packets, an empty name has none
+pub fn endpoint_name_packets(name: &str) -> TextPackets<'_> {
+    text_packets(ENDPOINT_NAME, 0, name)
+}
+
+/// Split a product instance id into packets, an empty id has none
+pub fn product_instance_id_packets(id: &str) -> TextPackets<'_> {
+    text_packets(PRODUCT_INSTANCE_ID, 0, id)
+}
+
+/// Split the name of function block `block` into packets, an empty name has none
+pub fn function_block_name_packets(block: u8, name: &str) -> TextPackets<'_> {
+    text_packets(FUNCTION_BLOCK_NAME, block, name)
+}
+
+/// A function block of an endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FunctionBlock<'a> {
+    /// The block info, the block number is its index in [`EndpointConfig::blocks`]
+    pub info: FunctionBlockInfo,
+    /// The block name
+    pub name: &'a str,
+}
+
+/// What an [`EndpointResponder`] tells a host about the endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EndpointConfig<'a> {
+    /// The endpoint name
+    pub name: &'a str,
+    /// The product instance id, such as a serial number
+    pub product_instance_id: &'a str,
+    /// The device identity
+    pub identity: DeviceIdentity,
+    /// The function blocks, at most 32
+    pub blocks: &'a [FunctionBlock<'a>],
+    /// Whether the function blocks never change
+    pub static_blocks: bool,
+    /// Whether the endpoint supports the MIDI 2.0 protocol
+    pub midi2: bool,
+    /// Whether the endpoint supports the MIDI 1.0 protocol
+    pub midi1: bool,
+    /// Whether the endpoint can receive jitter reduction timestamps
+    pub rx_jr: bool,
+    /// Whether the endpoint can send jitter reduction timestamps
+    pub tx_jr: bool,
+}
+
+impl Default for EndpointConfig<'_> {
+    fn default() -> Self {
+        Self {
+            name: "",
+            product_instance_id: "",
+            identity: DeviceIdentity::default(),
+            blocks: &[],
+            static_blocks: true,
+            midi2: true,
+            midi1: true,
+            rx_jr: false,
+            tx_jr: false,
+        }
+    }
+}
+
+impl EndpointConfig<'_> {
+    fn info(&self) -> EndpointInfo {
+        EndpointInfo {
+            version: UmpVersion::V1_1,
+            static_blocks: self.static_blocks,
+            blocks: self.blocks.len() as u8,
+            midi2: self.midi2,
+            midi1: self.midi1,
+            rx_jr: self.rx_jr,
+            tx_jr: self.tx_jr,
+        }
+    }
+
+    fn supports(&self, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Midi1 => self.midi1,
+            Protocol::Midi2 => self.midi2,
+        }
+    }
+}
+
+/// Answers endpoint and function block discovery and stream configuration requests
+#[derive(Debug, Clone)]
+pub struct EndpointResponder<'a> {
+    config: EndpointConfig<'a>,
+    configuration: StreamConfiguration,
+}
+
+impl<'a> EndpointResponder<'a> {
+    /// Create a new responder, using MIDI 2.0 if the endpoint supports it and without jitter
+    /// reduction timestamps
+    pub fn new(config: EndpointConfig<'a>) -> Self {
+        let protocol = if config.midi2 {
+            Protocol::Midi2
+        } else {
+            Protocol::Midi1
+        };
+        Self {
+            config,
+            configuration: StreamConfiguration {
+                protocol,
+                rx_jr: false,
+                tx_jr: false,
+            },
+        }
+    }
+
+    /// The endpoint config
+    pub fn config(&self) -> &EndpointConfig<'a> {
+        &self.config
+    }
+
+    /// The stream configuration in use
+    pub fn configuration(&self) -> StreamConfiguration {
+        self.configuration
+    }
+
+    /// Handle a message from the host, returning the messages to send back
+    ///
+    /// A stream configuration request for an unsupported protocol leaves the configuration as it
+    /// is, jitter reduction timestamps are only turned on where the endpoint supports them. The
+    /// reply is the configuration in use either way. Other messages get no reply.
+    pub fn respond(&mut self, message: &UmpMessage) -> Responses<'a> {
+        let mut responses = Responses {
+            config: self.config,
+            configuration: self.configuration,
+            endpoint: 0,
+            blocks: 0..0,
+            block_filter: 0,
+            block_info: false,
+            text: None,
+        };
+        let UmpMessage::Stream(message) = message else {
+            return responses;
+        };
+        match Stream::try_from(*message) {
+            Ok(Stream::EndpointDiscovery { filter, .. }) => responses.endpoint = filter,
+            Ok(Stream::StreamConfigurationRequest(request)) => {
+                if self.config.supports(request.protocol) {
+                    self.configuration = StreamConfiguration {
+                        protocol: request.protocol,
+                        rx_jr: request.rx_jr && self.config.rx_jr,
+                        tx_jr: request.tx_jr && self.config.tx_jr,
+                    };
+                }
+                responses.configuration = self.configuration;
+                responses.endpoint = DISCOVER_STREAM_CONFIGURATION;
+            }
+            Ok(Stream::FunctionBlockDiscovery { block, filter }) => {
+                let len = self.config.blocks.len();
+                responses.blocks = match usize::from(block) {
+                    _ if block == ALL_BLOCKS => 0..len,
+                    index if index < len => index..index + 1,
+                    _ => 0..0,
+                };
+                responses.block_filter = filter;
+                responses.block_info = filter & DISCOVER_BLOCK_INFO != 0;
+            }
+            _ => {}
+        }
+        responses
+    }
+}
+
+/// Iterator over the replies to a message, see [`EndpointResponder::respond`]
+#[derive(Debug, Clone)]
+pub struct Responses<'a> {
+    config: EndpointConfig<'a>,
+    configuration: StreamConfiguration,
+    endpoint: u8,
+    blocks: core::ops::Range<usize>,
+    block_filter: u8,
+    block_info: bool,
+    text: Option<TextPackets<'a>>,
+}
+
+impl<'a> Responses<'a> {
+    fn endpoint_reply(&mut self, bit: u8) -> Option<UmpMessage> {
+        let config = self.config;
+        let stream = match bit {
+            DISCOVER_ENDPOINT_INFO => Stream::EndpointInfo(config.info()),
+            DISCOVER_DEVICE_IDENTITY => Stream::DeviceIdentity(config.identity),
+            DISCOVER_ENDPOINT_NAME => {
+                self.text = Some(endpoint_name_packets(config.name));
+                return None;
+            }
+            DISCOVER_PRODUCT_INSTANCE_ID => {
+                self.text = Some(product_instance_id_packets(config.product_instance_id));
+                return None;
+            }
+            DISCOVER_STREAM_CONFIGURATION => {
+                Stream::StreamConfigurationNotification(self.configuration)
+            }
+            _ => return None,
+        };
+        Some(stream.into())
+    }
+}
+
+impl Iterator for Responses<'_> {
+    type Item = UmpMessage;
+
+    fn next(&mut self) -> Option<UmpMessage> {
+        loop {
+            if let Some(packet) = self.text.as_mut().and_then(Iterator::next) {
+                return Some(packet);
+            }
+            self.text = None;
+
+            if self.endpoint != 0 {
+                let bit = self.endpoint & self.endpoint.wrapping_neg();
+                self.endpoint &= !bit;
+                if let Some(reply) = self.endpoint_reply(bit) {
+                    return Some(reply);
+                }
+                continue;
+            }
+
+            let index = self.blocks.start;
+            let block = self
+                .config
+                .blocks
+                .get(index)
+                .filter(|_| index < self.blocks.end)?;
+            if self.block_info {
+                self.block_info = false;
+                let info = FunctionBlockInfo {
+                    block: index as u8,
+                    ..block.info
+                };
+                return Some(Stream::FunctionBlockInfo(info).into());
+            }
+            self.blocks.start += 1;
+            self.block_info = self.block_filter & DISCOVER_BLOCK_INFO != 0;
+            if self.block_filter & DISCOVER_BLOCK_NAME != 0 {
+                self.text = Some(function_block_name_packets(index as u8, block.name));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        std::{string::String, vec::Vec},
+    };
+
+    const BLOCKS: [FunctionBlock; 2] = [
+        FunctionBlock {
+            info: FunctionBlockInfo {
+                block: 0,
+                active: true,
+                ui_hint: 3,
+                midi1: 0,
+                direction: 3,
+                first_group: Group::new(0),
+                groups: 1,
+                ci_version: 2,
+                sysex8_streams: 0,
+            },
+            name: "Main",
+        },
+        FunctionBlock {
+            info: FunctionBlockInfo {
+                block: 0,
+                active: true,
+                ui_hint: 1,
+                midi1: 2,
+                direction: 1,
+                first_group: Group::new(1),
+                groups: 1,
+                ci_version: 0,
+                sysex8_streams: 0,
+            },
+            name: "DIN input for the sequencer",
+        },
+    ];
+
+    fn config() -> EndpointConfig<'static> {
+        EndpointConfig {
+            name: "Example synthesizer",
+            product_instance_id: "SN0001",
+            identity: DeviceIdentity {
+                manufacturer: [0x00, 0x21, 0x09],
+                family: 0x0102,
+                model: 0x3fff,
+                revision: [1, 2, 3, 4],
+            },
+            blocks: &BLOCKS,
+            static_blocks: true,
+            midi2: true,
+            midi1: true,
+            rx_jr: true,
+            tx_jr: false,
+        }
+    }
+
+    /// A host sending `request` over the wire and decoding the replies
+    fn host(responder: &mut EndpointResponder, request: Stream) -> Vec<Stream> {
+        let mut words = [0; 4];
+        UmpMessage::from(request).render(&mut words).unwrap();
+        let request = UmpMessage::parse(&words).unwrap();
+        responder
+            .respond(&request)
+            .map(|reply| {
+                reply.render(&mut words).unwrap();
+                match UmpMessage::parse(&words).unwrap() {
+                    UmpMessage::Stream(message) => Stream::try_from(message).unwrap(),
+                    other => panic!("{other:?}"),
+                }
+            })
+            .collect()
+    }
+
+    fn text<const N: usize>(packets: impl IntoIterator<Item = Text<N>>) -> String {
+        let mut forms = Vec::new();
+        let mut bytes = Vec::new();
+        for packet in packets {
+            forms.push(packet.form());
+            bytes.extend_from_slice(packet.data());
+        }
+        assert!(matches!(
+            forms[..],
+            [Form::Complete] | [Form::Start, .., Form::End]
+        ));
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn should_encode_stream_messages() {
+        let cases = [
+            (
+                Stream::EndpointDiscovery {
+                    version: UmpVersion::V1_1,
+                    filter: 0x1f,
+                },
+                [0xf000_0101, 0x0000_001f, 0, 0],
+            ),
+            (
+                Stream::EndpointInfo(EndpointInfo {
+                    version: UmpVersion::V1_1,
+                    static_blocks: true,
+                    blocks: 2,
+                    midi2: true,
+                    midi1: false,
+                    rx_jr: true,
+                    tx_jr: true,
+                }),
+                [0xf001_0101, 0x8200_0203, 0, 0],
+            ),
+            (
+                Stream::DeviceIdentity(DeviceIdentity {
+                    manufacturer: [0x00, 0x21, 0x09],
+                    family: 0x0102,
+                    model: 0x3fff,
+                    revision: [1, 2, 3, 4],
+                }),
+                [0xf002_0000, 0x0000_2109, 0x0202_7f7f, 0x0102_0304],
+            ),
+            (
+                Stream::StreamConfigurationRequest(StreamConfiguration {
+                    protocol: Protocol::Midi1,
+                    rx_jr: false,
+                    tx_jr: true,
+                }),
+                [0xf005_0101, 0, 0, 0],
+            ),
+            (
+                Stream::StreamConfigurationNotification(StreamConfiguration {
+                    protocol: Protocol::Midi2,
+                    rx_jr: true,
+                    tx_jr: false,
+                }),
+                [0xf006_0202, 0, 0, 0],
+            ),
+            (
+                Stream::FunctionBlockDiscovery {
+                    block: ALL_BLOCKS,
+                    filter: 0x03,
+                },
+                [0xf010_ff03, 0, 0, 0],
+            ),
+            (
+                Stream::FunctionBlockInfo(FunctionBlockInfo {
+                    block: 1,
+                    ..BLOCKS[1].info
+                }),
+                [0xf011_8119, 0x0101_0000, 0, 0],
+            ),
+            (
+                Stream::FunctionBlockName {
+                    block: 5,
+                    text: Text::new(Form::Complete, b"Drums").unwrap(),
+                },
+                [0xf012_0544, 0x7275_6d73, 0, 0],
+            ),
+            (
+                Stream::EndpointName(Text::new(Form::End, b"Synth").unwrap()),
+                [0xfc03_5379, 0x6e74_6800, 0, 0],
+            ),
+            (Stream::StartOfClip, [0xf020_0000, 0, 0, 0]),
+            (Stream::EndOfClip, [0xf021_0000, 0, 0, 0]),
+        ];
+        for (stream, words) in cases {
+            let mut buf = [0; 4];
+            assert_eq!(UmpMessage::from(stream).render(&mut buf), Ok(4));
+            assert_eq!(buf, words, "{stream:?}");
+            let Ok(UmpMessage::Stream(message)) = UmpMessage::parse(&words) else {
+                panic!("{words:x?}");
+            };
+            assert_eq!(Stream::try_from(message), Ok(stream));
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_stream_messages() {
+        let message = |status, data| StreamMessage {
+            form: Form::Complete,
+            status,
+            data,
+        };
+        assert_eq!(
+            Stream::try_from(message(0x07, [0; 14])),
+            Err(UmpError::InvalidStatus)
+        );
+        assert_eq!(
+            Stream::try_from(message(STREAM_CONFIGURATION_REQUEST, [3; 14])),
+            Err(UmpError::InvalidData)
+        );
+        assert_eq!(
+            Text::<13>::new(Form::Complete, &[b'a'; 14]),
+            Err(UmpError::InvalidData)
+        );
+        assert_eq!(
+            Text::<14>::new(Form::Complete, b"a\0b"),
+            Err(UmpError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn should_split_text() {
+        assert_eq!(endpoint_name_packets("").len(), 0);
+        assert_eq!(endpoint_name_packets("a").len(), 1);
+        assert_eq!(endpoint_name_packets("Fourteen bytes").len(), 1);
+        assert_eq!(endpoint_name_packets("Fifteen bytes!!").len(), 2);
+        assert_eq!(function_block_name_packets(0, "Fourteen bytes").len(), 2);
+        assert_eq!(function_block_name_packets(0, "a\0b").len(), 1);
+    }
+
+    #[test]
+    fn should_answer_endpoint_discovery() {
+        let mut responder = EndpointResponder::new(config());
+        let replies = host(
+            &mut responder,
+            Stream::EndpointDiscovery {
+                version: UmpVersion::V1_1,
+                filter: 0x1f,
+            },
+        );
+
+        assert_eq!(
+            replies[0],
+            Stream::EndpointInfo(EndpointInfo {
+                version: UmpVersion::V1_1,
+                static_blocks: true,
+                blocks: 2,
+                midi2: true,
+                midi1: true,
+                rx_jr: true,
+                tx_jr: false,
+            })
+        );
+        assert_eq!(replies[1], Stream::DeviceIdentity(config().identity));
+        let name = replies.iter().filter_map(|reply| match reply {
+            Stream::EndpointName(text) => Some(*text),
+            _ => None,
+        });
+        assert_eq!(text(name), "Example synthesizer");
+        let id = replies.iter().filter_map(|reply| match reply {
+            Stream::ProductInstanceId(text) => Some(*text),
+            _ => None,
+        });
+        assert_eq!(text(id), "SN0001");
+        assert_eq!(
+            replies.last(),
+            Some(&Stream::StreamConfigurationNotification(
+                StreamConfiguration {
+                    protocol: Protocol::Midi2,
+                    rx_jr: false,
+                    tx_jr: false,
+                }
+            ))
+        );
+        assert_eq!(replies.len(), 6);
+
+        let replies = host(
+            &mut responder,
+            Stream::EndpointDiscovery {
+                version: UmpVersion::V1_1,
+                filter: DISCOVER_DEVICE_IDENTITY,
+            },
+        );
+        assert_eq!(replies, [Stream::DeviceIdentity(config().identity)]);
+    }
+
+    #[test]
+    fn should_answer_function_block_discovery() {
+        let mut responder = EndpointResponder::new(config());
+        let replies = host(
+            &mut responder,
+            Stream::FunctionBlockDiscovery {
+                block: ALL_BLOCKS,
+                filter: DISCOVER_BLOCK_INFO | DISCOVER_BLOCK_NAME,
+            },
+        );
+        let mut names: [Vec<Text<13>>; 2] = Default::default();
+        let mut infos = Vec::new();
+        for reply in replies {
+            match reply {
+                Stream::FunctionBlockInfo(info) => {
+                    // Each block's info comes before its name
+                    assert!(names[usize::from(info.block)].is_empty());
+                    infos.push(info);
+                }
+                Stream::FunctionBlockName { block, text } => names[usize::from(block)].push(text),
+                other => panic!("{other:?}"),
+            }
+        }
+        assert_eq!(
+            infos,
+            [
+                BLOCKS[0].info,
+                FunctionBlockInfo {
+                    block: 1,
+                    ..BLOCKS[1].info
+                }
+            ]
+        );
+        let [main, din] = names;
+        assert_eq!(text(main), "Main");
+        assert_eq!(text(din), "DIN input for the sequencer");
+
+        let replies = host(
+            &mut responder,
+            Stream::FunctionBlockDiscovery {
+                block: 1,
+                filter: DISCOVER_BLOCK_INFO,
+            },
+        );
+        assert_eq!(
+            replies,
+            [Stream::FunctionBlockInfo(FunctionBlockInfo {
+                block: 1,
+                ..BLOCKS[1].info
+            })]
+        );
+        let replies = host(
+            &mut responder,
+            Stream::FunctionBlockDiscovery {
+                block: 2,
+                filter: DISCOVER_BLOCK_INFO,
+            },
+        );
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn should_negotiate_protocol() {
+        let mut responder = EndpointResponder::new(EndpointConfig {
+            midi1: false,
+            ..config()
+        });
+        let request = |protocol, rx_jr, tx_jr| {
+            Stream::StreamConfigurationRequest(StreamConfiguration {
+                protocol,
+                rx_jr,
+                tx_jr,
+            })
+        };
+        let midi2_rx_jr = StreamConfiguration {
+            protocol: Protocol::Midi2,
+            rx_jr: true,
+            tx_jr: false,
+        };
+
+        let replies = host(&mut responder, request(Protocol::Midi2, true, true));
+        assert_eq!(
+            replies,
+            [Stream::StreamConfigurationNotification(midi2_rx_jr)]
+        );
+        assert_eq!(responder.configuration(), midi2_rx_jr);
+
+        let replies = host(&mut responder, request(Protocol::Midi1, false, false));
+        assert_eq!(
+            replies,
+            [Stream::StreamConfigurationNotification(midi2_rx_jr)]
+        );
+
+        let mut responder = EndpointResponder::new(config());
+        host(&mut responder, request(Protocol::Midi1, false, false));
+        assert_eq!(responder.configuration().protocol, Protocol::Midi1);
+    }
+
+    #[test]
+    fn should_ignore_other_messages() {
+        let mut responder = EndpointResponder::new(config());
+        assert_eq!(
+            responder
+                .respond(&UmpMessage::from(Stream::StartOfClip))
+                .count(),
+            0
+        );
+        assert_eq!(
+            responder
+                .respond(&UmpMessage::Utility(crate::ump::Utility::NoOp))
+                .count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Splitting SysEx messages into UMPs and reassembling them
+//!
+//! ```
+//! use midi_convert::ump::{
+//!     Group,
+//!     sysex::{SysExReassembler, sysex7_packets},
+//! };
+//!
+//! let message = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0x02, 0x03, 0x04, 0xF7];
+//! let mut reassembler = SysExReassembler::<64, 4>::new();
+//! let mut completed = None;
+//! for packet in sysex7_packets(Group::new(0), &message).unwrap() {
+//!     if let Some(sysex) = reassembler.push(&packet).unwrap() {
+//!         completed = Some(sysex.data.to_vec());
+//!     }
+//! }
+//! assert_eq!(completed.as_deref(), Some(&message[1..8]));
+//! ```
+
+use {
+    super::{
+        Group, SysEx7, SysEx8, UmpError, UmpMessage,
+        segment::{Reassembler, ReassemblyError, Segments},
+    },
+    midi_types::status::{SYSEX_END, SYSEX_START},
+};
+
+/// Iterator over the packets of a SysEx message, see [`sysex7_packets`] and [`sysex8_packets`]
+#[derive(Debug, Clone)]
+pub struct SysExPackets<'a> {
+    group: Group,
+    stream: Option<u8>,
+    segments: Segments<'a>,
+}
+
+impl Iterator for SysExPackets<'_> {
+    type Item = UmpMessage;
+
+    fn next(&mut self) -> Option<UmpMessage> {
+        let (form, chunk) = self.segments.next()?;
+        let len = chunk.len() as u8;
+        Some(match self.stream {
+            Some(stream) => {
+                let mut data = [0; SysEx8::MAX_LEN];
+                data[..chunk.len()].copy_from_slice(chunk);
+                UmpMessage::SysEx8(SysEx8 {
+                    group: self.group,
+                    form,
+                    stream,
+                    len,
+                    data,
+                })
+            }
+            None => {
+                let mut data = [0; SysEx7::MAX_LEN];
+                data[..chunk.len()].copy_from_slice(chunk);
+                UmpMessage::SysEx7(SysEx7 {
+                    group: self.group,
+                    form,
+                    len,
+                    data,
+                })
+            }
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.segments.size_hint()
+    }
+}
+
+impl ExactSizeIterator for SysExPackets<'_> {}
+
+/// Split a SysEx message into SysEx7 packets for `group`
+///
+/// A leading `0xF0` and trailing `0xF7` are stripped, the other bytes must be data bytes.
+pub fn sysex7_packets(group: Group, data: &[u8]) -> Result<SysExPackets<'_>, UmpError> {
+    let data = data.strip_prefix(&[SYSEX_START]).unwrap_or(data);
+    let data = data.strip_suffix(&[SYSEX_END]).unwrap_or(data);
+    if data.iter().any(|b| b & 0x80 != 0) {
+        return Err(UmpError::InvalidData);
+    }
+    Ok(SysExPackets {
+        group,
+        stream: None,
+        segments: Segments::new(data, SysEx7::MAX_LEN, true),
+    })
+}
+
+/// Split 8-bit SysEx data into SysEx8 packets for `stream` of `group`
+pub fn sysex8_packets(group: Group, stream: u8, data: &[u8]) -> SysExPackets<'_> {
+    SysExPackets {
+        group,
+        stream: Some(stream),
+        segments: Segments::new(data, SysEx8::MAX_LEN, true),
+    }
+}
+
+/// A reassembled SysEx message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SysExMessage<'a> {
+    /// The group
+    pub group: Group,
+    /// The stream id for SysEx8, `None` for SysEx7
+    pub stream: Option<u8>,
+    /// The data, without `0xF0` and `0xF7`
+    pub data: &'a [u8],
+}
+
+/// Errors reassembling SysEx messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SysExError {
+    /// The message did not fit in the buffer and was dropped
+    TooLong {
+        /// The group
+        group: Group,
+        /// The stream id for SysEx8
+        stream: Option<u8>,
+    },
+
+    /// A start packet arrived before the previous message ended, the unfinished message was dropped
+    Interrupted {
+        /// The group
+        group: Group,
+        /// The stream id for SysEx8
+        stream: Option<u8>,
+    },
+
+    /// A continue or end packet arrived without a start packet
+    Unexpected {
+        /// The group
+        group: Group,
+        /// The stream id for SysEx8
+        stream: Option<u8>,
+    },
+
+    /// Every buffer holds an unfinished message, the message was dropped
+    NoFreeBuffer {
+        /// The group
+        group: Group,
+        /// The stream id for SysEx8
+        stream: Option<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    group: Group,
+    stream: Option<u8>,
+}
+
+impl SysExError {
+    fn new(error: ReassemblyError, Key { group, stream }: Key) -> Self {
+        match error {
+            ReassemblyError::TooLong => Self::TooLong { group, stream },
+            ReassemblyError::Interrupted => Self::Interrupted { group, stream },
+            ReassemblyError::Unexpected => Self::Unexpected { group, stream },
+            ReassemblyError::NoFreeBuffer => Self::NoFreeBuffer { group, stream },
+        }
+    }
+}
+
+/// Reassembles SysEx7 and SysEx8 messages of up to `N` bytes, for up to `S` groups and streams at
+/// the same time
+#[derive(Debug, Clone)]
+pub struct SysExReassembler<const N: usize, const S: usize> {
+    reassembler: Reassembler<Key, N, S>,
+}
+
+impl<const N: usize, const S: usize> SysExReassembler<N, S> {
+    /// Create a new reassembler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a packet, returning the message it completes
+    ///
+    /// Packets other than SysEx7 and SysEx8 are ignored. Complete packets are returned straight
+    /// away and leave unfinished messages of their group and stream alone.
+    pub fn push(&mut self, packet: &UmpMessage) -> Result<Option<SysExMessage<'_>>, SysExError> {
+        let (key, form, data) = match packet {
+            UmpMessage::SysEx7(packet) => (
+                Key {
+                    group: packet.group,
+                    stream: None,
+                },
+                packet.form,
+                packet.data(),
+            ),
+            UmpMessage::SysEx8(packet) => (
+                Key {
+                    group: packet.group,
+                    stream: Some(packet.stream),
+                },
+                packet.form,
+                packet.data(),
+            ),
+            _ => return Ok(None),
+        };
+        let data = self
+            .reassembler
+            .feed(key, form, data)
+            .map_err(|error| SysExError::new(error, key))?;
+        Ok(data.map(|data| SysExMessage {
+            group: key.group,
+            stream: key.stream,
+            data,
+        }))
+    }
+
+    /// Drop all unfinished messages
+    pub fn reset(&mut self) {
+        self.reassembler.reset();
+    }
+}
+
+impl<const N: usize, const S: usize> Default for SysExReassembler<N, S> {
+    fn default() -> Self {
+        Self {
+            reassembler: Reassembler::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, crate::ump::Form, std::vec::Vec};
+
+    type SysExResult = Result<Option<(Group, Option<u8>, Vec<u8>)>, SysExError>;
+
+    fn push_all<const N: usize, const S: usize>(
+        reassembler: &mut SysExReassembler<N, S>,
+        packets: impl IntoIterator<Item = UmpMessage>,
+    ) -> Vec<SysExResult> {
+        packets
+            .into_iter()
+            .map(|packet| {
+                reassembler
+                    .push(&packet)
+                    .map(|sysex| sysex.map(|s| (s.group, s.stream, s.data.to_vec())))
+            })
+            .collect()
+    }
+
+    fn forms(packets: SysExPackets) -> Vec<(Form, usize)> {
+        packets
+            .map(|packet| match packet {
+                UmpMessage::SysEx7(p) => (p.form(), p.data().len()),
+                UmpMessage::SysEx8(p) => (p.form(), p.data().len()),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_split_sysex7() {
+        let data: Vec<u8> = (1..=14).collect();
+        let mut framed = [SYSEX_START].to_vec();
+        framed.extend_from_slice(&data);
+        framed.push(SYSEX_END);
+        let packets = sysex7_packets(Group::new(2), &framed).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(
+            forms(packets.clone()),
+            [(Form::Start, 6), (Form::Continue, 6), (Form::End, 2)]
+        );
+        let mut words = Vec::new();
+        for packet in packets {
+            let mut buf = [0; 2];
+            packet.render(&mut buf).unwrap();
+            words.extend_from_slice(&buf);
+        }
+        assert_eq!(
+            words,
+            [
+                0x3216_0102,
+                0x0304_0506,
+                0x3226_0708,
+                0x090a_0b0c,
+                0x3232_0d0e,
+                0x0000_0000
+            ]
+        );
+        assert_eq!(
+            forms(sysex7_packets(Group::new(2), &data).unwrap()),
+            [(Form::Start, 6), (Form::Continue, 6), (Form::End, 2)]
+        );
+    }
+
+    #[test]
+    fn should_split_short_sysex7() {
+        let packets = sysex7_packets(Group::new(0), &[0xf0, 0xf7]).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(forms(packets), [(Form::Complete, 0)]);
+        assert_eq!(
+            forms(sysex7_packets(Group::new(0), &[1, 2, 3, 4, 5, 6]).unwrap()),
+            [(Form::Complete, 6)]
+        );
+        assert_eq!(
+            forms(sysex7_packets(Group::new(0), &[0; 12]).unwrap()),
+            [(Form::Start, 6), (Form::End, 6)]
+        );
+        assert_eq!(
+            sysex7_packets(Group::new(0), &[0xf0, 0x01, 0x80, 0xf7]).err(),
+            Some(UmpError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn should_split_sysex8() {
+        let data: Vec<u8> = (0..30).map(|i| 0xf0 ^ i).collect();
+        let packets = sysex8_packets(Group::new(1), 7, &data);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(
+            forms(packets.clone()),
+            [(Form::Start, 13), (Form::Continue, 13), (Form::End, 4)]
+        );
+        let mut reassembler = SysExReassembler::<32, 1>::new();
+        assert_eq!(
+            push_all(&mut reassembler, packets).pop(),
+            Some(Ok(Some((Group::new(1), Some(7), data))))
+        );
+    }
+
+    #[test]
+    fn should_reassemble_interleaved_groups_and_streams() {
+        let a: Vec<u8> = (0..20).collect();
+        let b: Vec<u8> = (20..40).collect();
+        let c: Vec<u8> = (0..40).map(|i| 0x80 | i).collect();
+        let d: Vec<u8> = (40..60).collect();
+        let streams = [
+            sysex7_packets(Group::new(0), &a).unwrap(),
+            sysex7_packets(Group::new(1), &b).unwrap(),
+            sysex8_packets(Group::new(0), 1, &c),
+            sysex8_packets(Group::new(0), 2, &d),
+        ];
+        let mut iters: Vec<_> = streams.into_iter().collect();
+        let mut interleaved = Vec::new();
+        while iters.iter().any(|i| i.len() > 0) {
+            for iter in iters.iter_mut() {
+                interleaved.extend(iter.next());
+            }
+        }
+
+        let mut reassembler = SysExReassembler::<64, 4>::new();
+        let completed: Vec<_> = push_all(&mut reassembler, interleaved)
+            .into_iter()
+            .filter_map(|result| result.unwrap())
+            .collect();
+        assert_eq!(completed.len(), 4);
+        assert!(completed.contains(&(Group::new(0), None, a)));
+        assert!(completed.contains(&(Group::new(1), None, b)));
+        assert!(completed.contains(&(Group::new(0), Some(1), c)));
+        assert!(completed.contains(&(Group::new(0), Some(2), d)));
+    }
+
+    #[test]
+    fn should_report_too_long_messages() {
+        let mut reassembler = SysExReassembler::<8, 1>::new();
+        let results = push_all(
+            &mut reassembler,
+            sysex7_packets(Group::new(3), &[0; 14]).unwrap(),
+        );
+        assert_eq!(
+            results,
+            [
+                Ok(None),
+                Ok(None),
+                Err(SysExError::TooLong {
+                    group: Group::new(3),
+                    stream: None
+                })
+            ]
+        );
+        let results = push_all(
+            &mut reassembler,
+            sysex7_packets(Group::new(3), &[1; 8]).unwrap(),
+        );
+        assert_eq!(results[1], Ok(Some((Group::new(3), None, [1; 8].to_vec()))));
+    }
+
+    #[test]
+    fn should_report_interrupted_and_unexpected_packets() {
+        let group = Group::new(0);
+        let packet =
+            |form, data: &[u8]| UmpMessage::SysEx7(SysEx7::new(group, form, data).unwrap());
+        let mut reassembler = SysExReassembler::<16, 2>::new();
+        assert_eq!(
+            push_all(
+                &mut reassembler,
+                [
+                    packet(Form::Continue, &[1]),
+                    packet(Form::Start, &[1]),
+                    packet(Form::Complete, &[9]),
+                    packet(Form::Start, &[2]),
+                    packet(Form::End, &[3]),
+                    packet(Form::End, &[4]),
+                ]
+            ),
+            [
+                Err(SysExError::Unexpected {
+                    group,
+                    stream: None
+                }),
+                Ok(None),
+                Ok(Some((group, None, [9].to_vec()))),
+                Err(SysExError::Interrupted {
+                    group,
+                    stream: None
+                }),
+                Ok(Some((group, None, [2, 3].to_vec()))),
+                Err(SysExError::Unexpected {
+                    group,
+                    stream: None
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_missing_buffers() {
+        let mut reassembler = SysExReassembler::<16, 1>::new();
+        let mut first = sysex7_packets(Group::new(0), &[1; 8]).unwrap();
+        let mut second = sysex7_packets(Group::new(1), &[2; 8]).unwrap();
+        assert_eq!(push_all(&mut reassembler, first.next()), [Ok(None)]);
+        assert_eq!(
+            push_all(&mut reassembler, second.next()),
+            [Err(SysExError::NoFreeBuffer {
+                group: Group::new(1),
+                stream: None
+            })]
+        );
+        assert_eq!(
+            push_all(&mut reassembler, first),
+            [Ok(Some((Group::new(0), None, [1; 8].to_vec())))]
+        );
+
+        // Resetting drops the unfinished message and frees its buffer
+        let mut second = sysex7_packets(Group::new(1), &[2; 8]).unwrap();
+        assert_eq!(push_all(&mut reassembler, second.next()), [Ok(None)]);
+        reassembler.reset();
+        assert_eq!(
+            push_all(&mut reassembler, second),
+            [Err(SysExError::Unexpected {
+                group: Group::new(1),
+                stream: None
+            })]
+        );
+        assert_eq!(
+            push_all(
+                &mut reassembler,
+                sysex7_packets(Group::new(2), &[3; 8]).unwrap()
+            ),
+            [Ok(None), Ok(Some((Group::new(2), None, [3; 8].to_vec())))]
+        );
+    }
+
+    #[test]
+    fn should_ignore_other_packets() {
+        let mut reassembler = SysExReassembler::<16, 1>::new();
+        assert_eq!(
+            reassembler.push(&UmpMessage::Utility(crate::ump::Utility::NoOp)),
+            Ok(None)
+        );
+    }
+}
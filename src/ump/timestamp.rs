@@ -0,0 +1,601 @@
+//! Jitter reduction timestamps and delta clockstamps
+//!
+//! Jitter reduction (JR) clocks and timestamps count 1/31250 s ticks, 32 µs, in 16 bits, so they
+//! wrap about every two seconds. [`JrStamper`] adds them to an outgoing stream and [`JrReceiver`]
+//! takes them off an incoming one, estimating the sender clock with a [`JrClockEstimator`].
+//!
+//! Delta clockstamps count ticks of a musical clock between events, as in clip files.
+//! [`DeltaClockWriter`] and [`DeltaClockReader`] convert between them and absolute ticks.
+//!
+//! ```
+//! use {
+//!     midi_convert::ump::{
+//!         Group, UmpMessage,
+//!         timestamp::{DeltaClockReader, DeltaClockWriter},
+//!     },
+//!     midi_types::MidiMessage,
+//! };
+//!
+//! let message = UmpMessage::System {
+//!     group: Group::new(0),
+//!     message: MidiMessage::TuneRequest,
+//! };
+//! let mut writer = DeltaClockWriter::new();
+//! let mut reader = DeltaClockReader::new();
+//! let mut events = writer
+//!     .stamp(960, message)
+//!     .filter_map(|packet| reader.push(&packet));
+//! assert_eq!(events.next(), Some((960, message)));
+//! ```
+
+use {
+    super::{UmpMessage, Utility},
+    core::time::Duration,
+};
+
+/// JR clock ticks per second
+pub const JR_TICKS_PER_SECOND: u32 = 31_250;
+
+const JR_TICK_MICROS: u64 = 1_000_000 / JR_TICKS_PER_SECOND as u64;
+const JR_WRAP: u64 = 1 << 16;
+
+/// The largest delta clockstamp
+pub const MAX_DELTA_CLOCKSTAMP: u32 = 0xf_ffff;
+
+/// The JR clock at host time `now`
+pub fn jr_time(now: Duration) -> u16 {
+    (now.as_micros() as u64 / JR_TICK_MICROS) as u16
+}
+
+/// The host time of JR time `jr`, taking the one closest to `reference`
+pub fn jr_to_host(jr: u16, reference: Duration) -> Duration {
+    let reference = reference.as_micros() as u64 / JR_TICK_MICROS;
+    let delta = jr.wrapping_sub(reference as u16) as i16;
+    let ticks = reference.saturating_add_signed(delta.into());
+    Duration::from_micros(ticks * JR_TICK_MICROS)
+}
+
+/// Adds JR clocks and timestamps to outgoing messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JrStamper {
+    interval: Duration,
+    next_clock: Option<Duration>,
+}
+
+impl JrStamper {
+    /// The JR clock interval the specification asks for
+    pub const INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Create a new stamper, sending a JR clock every [`JrStamper::INTERVAL`]
+    pub fn new() -> Self {
+        Self::with_interval(Self::INTERVAL)
+    }
+
+    /// Create a new stamper, sending a JR clock every `interval`
+    pub fn with_interval(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_clock: None,
+        }
+    }
+
+    /// A JR clock, if one is due at `now`
+    ///
+    /// Call this when idle, to keep sending clocks without messages to stamp.
+    pub fn poll(&mut self, now: Duration) -> Option<UmpMessage> {
+        if self.next_clock.is_some_and(|next| now < next) {
+            return None;
+        }
+        self.next_clock = Some(now + self.interval);
+        Some(UmpMessage::Utility(Utility::JrClock(jr_time(now))))
+    }
+
+    /// `message` sent at `now`, after a JR clock if one is due and a JR timestamp
+    pub fn stamp(
+        &mut self,
+        now: Duration,
+        message: UmpMessage,
+    ) -> impl Iterator<Item = UmpMessage> + use<> {
+        let timestamp = UmpMessage::Utility(Utility::JrTimestamp(jr_time(now)));
+        [self.poll(now), Some(timestamp), Some(message)]
+            .into_iter()
+            .flatten()
+    }
+}
+
+impl Default for JrStamper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Estimates the offset and drift of a sender clock from its JR clocks
+///
+/// The estimate is a least squares line through the last `N` clocks against the host time they
+/// arrived at, so it evens out transport jitter.
+#[derive(Debug, Clone)]
+pub struct JrClockEstimator<const N: usize> {
+    /// Unwrapped sender ticks and host microseconds
+    samples: [(u64, u64); N],
+    len: usize,
+    next: usize,
+    last: Option<(u64, u64)>,
+}
+
+impl<const N: usize> JrClockEstimator<N> {
+    /// Create a new estimator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a JR clock `clock` received at host time `now`
+    ///
+    /// The time since the last clock tells how often the clock wrapped in between, so clocks
+    /// don't need to arrive every two seconds.
+    pub fn update(&mut self, clock: u16, now: Duration) {
+        let now = now.as_micros() as u64;
+        let ticks = match self.last {
+            Some((ticks, time)) => {
+                let expected = now.saturating_sub(time) / JR_TICK_MICROS;
+                let delta = u64::from(clock.wrapping_sub(ticks as u16));
+                let wraps = (expected.saturating_sub(delta) + JR_WRAP / 2) / JR_WRAP;
+                ticks + delta + wraps * JR_WRAP
+            }
+            None => u64::from(clock),
+        };
+        self.last = Some((ticks, now));
+        self.samples[self.next] = (ticks, now);
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// Forget all clocks, as when the sender restarts
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+        self.last = None;
+    }
+
+    /// The host microseconds per sender tick and how much later than its arrival the line puts
+    /// the last clock, needs two clocks
+    fn line(&self) -> Option<(f64, f64)> {
+        let samples = &self.samples[..self.len];
+        let (origin_ticks, origin_time) = self.last?;
+        let n = samples.len() as f64;
+        let centered = |&(ticks, time): &(u64, u64)| {
+            (
+                ticks as f64 - origin_ticks as f64,
+                time as f64 - origin_time as f64,
+            )
+        };
+        let (sum_ticks, sum_time) = samples
+            .iter()
+            .map(centered)
+            .fold((0.0, 0.0), |(a, b), (x, y)| (a + x, b + y));
+        let (mean_ticks, mean_time) = (sum_ticks / n, sum_time / n);
+        let (covariance, variance) =
+            samples
+                .iter()
+                .map(centered)
+                .fold((0.0, 0.0), |(c, v), (x, y)| {
+                    let x = x - mean_ticks;
+                    (c + x * (y - mean_time), v + x * x)
+                });
+        if variance == 0.0 {
+            return None;
+        }
+        let slope = covariance / variance;
+        Some((slope, mean_time - slope * mean_ticks))
+    }
+
+    /// The sender clock minus the host clock at the last JR clock, in µs, known after the first
+    /// clock
+    ///
+    /// The sender clock starts at its first JR clock value, so this is only useful relative to
+    /// earlier offsets or to convert timestamps.
+    pub fn offset(&self) -> Option<i64> {
+        let (ticks, time) = self.last?;
+        let time = match self.line() {
+            Some((_, intercept)) => time as f64 + intercept,
+            None => time as f64,
+        };
+        Some((ticks * JR_TICK_MICROS) as i64 - time as i64)
+    }
+
+    /// How much faster the sender clock runs than the host clock, in parts per million, known
+    /// after the second clock
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let (slope, _) = self.line()?;
+        Some((JR_TICK_MICROS as f64 / slope - 1.0) * 1e6)
+    }
+
+    /// The host time the sender meant by JR timestamp `timestamp`, known after the first clock
+    ///
+    /// Timestamps are taken to be within about a second of the last clock.
+    pub fn to_host(&self, timestamp: u16) -> Option<Duration> {
+        let (ticks, time) = self.last?;
+        let delta = f64::from(timestamp.wrapping_sub(ticks as u16) as i16);
+        let (slope, intercept) = self.line().unwrap_or((JR_TICK_MICROS as f64, 0.0));
+        let host = time as f64 + intercept + slope * delta;
+        Some(Duration::from_micros(if host > 0.0 {
+            host as u64
+        } else {
+            0
+        }))
+    }
+}
+
+impl<const N: usize> Default for JrClockEstimator<N> {
+    fn default() -> Self {
+        Self {
+            samples: [(0, 0); N],
+            len: 0,
+            next: 0,
+            last: None,
+        }
+    }
+}
+
+/// A message with the JR timestamp sent before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimedMessage {
+    /// The message
+    pub message: UmpMessage,
+    /// The JR timestamp, if the sender sent one
+    pub timestamp: Option<u16>,
+    /// The host time of the timestamp, once the sender clock is known
+    pub time: Option<Duration>,
+}
+
+/// Takes JR clocks and timestamps off incoming messages
+#[derive(Debug, Clone, Default)]
+pub struct JrReceiver<const N: usize> {
+    estimator: JrClockEstimator<N>,
+    timestamp: Option<u16>,
+}
+
+impl<const N: usize> JrReceiver<N> {
+    /// Create a new receiver
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The sender clock estimate
+    pub fn estimator(&self) -> &JrClockEstimator<N> {
+        &self.estimator
+    }
+
+    /// Add a message received at host time `now`, returning it unless it is a JR clock,
+    /// timestamp or no-op
+    ///
+    /// A JR timestamp applies to the next message.
+    pub fn push(&mut self, message: &UmpMessage, now: Duration) -> Option<TimedMessage> {
+        match *message {
+            UmpMessage::Utility(Utility::JrClock(clock)) => {
+                self.estimator.update(clock, now);
+                None
+            }
+            UmpMessage::Utility(Utility::JrTimestamp(timestamp)) => {
+                self.timestamp = Some(timestamp);
+                None
+            }
+            UmpMessage::Utility(Utility::NoOp) => None,
+            message => {
+                let timestamp = self.timestamp.take();
+                Some(TimedMessage {
+                    message,
+                    timestamp,
+                    time: timestamp.and_then(|timestamp| self.estimator.to_host(timestamp)),
+                })
+            }
+        }
+    }
+}
+
+/// Turns delta clockstamps into absolute ticks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeltaClockReader {
+    ticks_per_quarter_note: Option<u16>,
+    ticks: u64,
+}
+
+impl DeltaClockReader {
+    /// Create a new reader, starting at tick 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ticks per quarter note, once a DCTPQ message arrived
+    pub fn ticks_per_quarter_note(&self) -> Option<u16> {
+        self.ticks_per_quarter_note
+    }
+
+    /// The current tick
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Add a message, returning it with its tick unless it is a delta clockstamp, DCTPQ or no-op
+    pub fn push(&mut self, message: &UmpMessage) -> Option<(u64, UmpMessage)> {
+        match *message {
+            UmpMessage::Utility(Utility::DeltaClockstamp(ticks)) => {
+                self.ticks += u64::from(ticks);
+                None
+            }
+            UmpMessage::Utility(Utility::TicksPerQuarterNote(ticks)) => {
+                self.ticks_per_quarter_note = Some(ticks);
+                None
+            }
+            UmpMessage::Utility(Utility::NoOp) => None,
+            message => Some((self.ticks, message)),
+        }
+    }
+}
+
+/// Turns absolute ticks into delta clockstamps
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeltaClockWriter {
+    ticks: u64,
+}
+
+impl DeltaClockWriter {
+    /// Create a new writer, starting at tick 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current tick
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// `message` at tick `ticks`, after the delta clockstamps to get there
+    ///
+    /// Ticks before the current tick are taken as the current tick.
+    pub fn stamp(&mut self, ticks: u64, message: UmpMessage) -> DeltaClockstamps {
+        let delta = ticks.saturating_sub(self.ticks);
+        self.ticks = self.ticks.max(ticks);
+        DeltaClockstamps {
+            delta,
+            message: Some(message),
+        }
+    }
+}
+
+/// Iterator over a message and the delta clockstamps before it, see [`DeltaClockWriter::stamp`]
+#[derive(Debug, Clone)]
+pub struct DeltaClockstamps {
+    delta: u64,
+    message: Option<UmpMessage>,
+}
+
+impl Iterator for DeltaClockstamps {
+    type Item = UmpMessage;
+
+    fn next(&mut self) -> Option<UmpMessage> {
+        if self.delta == 0 {
+            return self.message.take();
+        }
+        let ticks = self.delta.min(MAX_DELTA_CLOCKSTAMP.into());
+        self.delta -= ticks;
+        Some(UmpMessage::Utility(Utility::DeltaClockstamp(ticks as u32)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.delta.div_ceil(MAX_DELTA_CLOCKSTAMP.into()) as usize
+            + usize::from(self.message.is_some());
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for DeltaClockstamps {}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::ump::Group,
+        midi_types::{Channel, MidiMessage, Note, Value7},
+        std::vec::Vec,
+    };
+
+    fn note(note: u8) -> UmpMessage {
+        UmpMessage::Midi1ChannelVoice {
+            group: Group::new(0),
+            message: MidiMessage::NoteOn(Channel::new(0), Note::new(note), Value7::new(100)),
+        }
+    }
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn should_convert_jr_time() {
+        assert_eq!(jr_time(Duration::ZERO), 0);
+        assert_eq!(jr_time(Duration::from_micros(32)), 1);
+        assert_eq!(jr_time(Duration::from_secs(2)), 62_500);
+        assert_eq!(jr_time(Duration::from_micros(65_536 * 32)), 0);
+
+        let reference = Duration::from_secs(100);
+        for offset in [-1_000_000i64, -32, 0, 32, 999_968] {
+            let host = Duration::from_micros(100_000_000u64.saturating_add_signed(offset));
+            assert_eq!(jr_to_host(jr_time(host), reference), host);
+        }
+        assert_eq!(jr_to_host(0xffff, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn should_stamp_messages() {
+        let mut stamper = JrStamper::new();
+        let stamped: Vec<_> = stamper.stamp(millis(1), note(60)).collect();
+        assert_eq!(
+            stamped,
+            [
+                UmpMessage::Utility(Utility::JrClock(31)),
+                UmpMessage::Utility(Utility::JrTimestamp(31)),
+                note(60)
+            ]
+        );
+        assert_eq!(stamper.stamp(millis(100), note(61)).count(), 2);
+        assert_eq!(stamper.poll(millis(250)), None);
+        assert_eq!(
+            stamper.poll(millis(251)),
+            Some(UmpMessage::Utility(Utility::JrClock(7843)))
+        );
+        assert_eq!(stamper.stamp(millis(501), note(62)).count(), 3);
+    }
+
+    #[test]
+    fn should_receive_timestamps() {
+        let mut receiver = JrReceiver::<8>::new();
+        assert_eq!(
+            receiver.push(&note(60), millis(0)),
+            Some(TimedMessage {
+                message: note(60),
+                timestamp: None,
+                time: None
+            })
+        );
+        receiver.push(&UmpMessage::Utility(Utility::JrTimestamp(100)), millis(0));
+        assert_eq!(
+            receiver.push(&note(61), millis(0)).map(|timed| timed.time),
+            Some(None)
+        );
+
+        // The sender clock is 1 s ahead
+        let mut stamper = JrStamper::new();
+        let mut received = Vec::new();
+        for ms in (0..2_000).step_by(50) {
+            for message in stamper.stamp(millis(ms + 1_000), note(60)) {
+                received.extend(receiver.push(&message, millis(ms + 3)));
+            }
+        }
+        let last = received.last().unwrap();
+        assert_eq!(last.timestamp, Some(jr_time(millis(2_950))));
+        let time = last.time.unwrap().as_micros() as i64;
+        assert!((time - 1_953_000).abs() <= 64, "{time}");
+    }
+
+    #[test]
+    fn should_estimate_offset_and_drift() {
+        // The sender runs 100 ppm fast and starts 5 s ahead
+        let sender = |host: u64| 5_000_000 + host + host / 10_000;
+        let mut estimator = JrClockEstimator::<64>::new();
+        assert_eq!(estimator.offset(), None);
+        for ms in (0..10_000).step_by(250) {
+            let host = ms * 1_000;
+            // Up to 200 µs of transport jitter
+            let jitter = (ms * 7919) % 200;
+            estimator.update(
+                jr_time(Duration::from_micros(sender(host))),
+                Duration::from_micros(host + jitter),
+            );
+        }
+        let drift = estimator.drift_ppm().unwrap();
+        assert!((drift - 100.0).abs() < 20.0, "{drift}");
+
+        let host = 9_800_000;
+        let time = estimator
+            .to_host(jr_time(Duration::from_micros(sender(host))))
+            .unwrap();
+        let error = time.as_micros() as i64 - host as i64;
+        assert!((-50..250).contains(&error), "{error}");
+
+        let offset = estimator.offset().unwrap();
+        estimator.update(
+            jr_time(Duration::from_micros(sender(10_000_000))),
+            Duration::from_micros(10_000_100),
+        );
+        assert!((estimator.offset().unwrap() - offset).abs() < 200);
+
+        estimator.reset();
+        assert_eq!(estimator.drift_ppm(), None);
+    }
+
+    #[test]
+    fn should_unwrap_clocks_across_gaps() {
+        let mut estimator = JrClockEstimator::<4>::new();
+        for seconds in [0, 5, 11, 12] {
+            let host = Duration::from_secs(seconds);
+            estimator.update(jr_time(host), host);
+        }
+        let drift = estimator.drift_ppm().unwrap();
+        assert!(drift.abs() < 1.0, "{drift}");
+        assert!(estimator.offset().unwrap().abs() <= 1);
+    }
+
+    #[test]
+    fn should_read_delta_clockstamps() {
+        let mut reader = DeltaClockReader::new();
+        let messages = [
+            UmpMessage::Utility(Utility::TicksPerQuarterNote(480)),
+            note(60),
+            UmpMessage::Utility(Utility::DeltaClockstamp(480)),
+            note(62),
+            UmpMessage::Utility(Utility::NoOp),
+            UmpMessage::Utility(Utility::DeltaClockstamp(240)),
+            UmpMessage::Utility(Utility::DeltaClockstamp(240)),
+            note(64),
+            note(65),
+        ];
+        let events: Vec<_> = messages.iter().filter_map(|m| reader.push(m)).collect();
+        assert_eq!(
+            events,
+            [
+                (0, note(60)),
+                (480, note(62)),
+                (960, note(64)),
+                (960, note(65))
+            ]
+        );
+        assert_eq!(reader.ticks_per_quarter_note(), Some(480));
+        assert_eq!(reader.ticks(), 960);
+    }
+
+    #[test]
+    fn should_write_delta_clockstamps() {
+        let mut writer = DeltaClockWriter::new();
+        let events = [
+            (0, note(60)),
+            (480, note(62)),
+            (400, note(63)),
+            (0x20_01e1, note(64)),
+        ];
+        let mut packets = Vec::new();
+        for (ticks, message) in events {
+            let stamps = writer.stamp(ticks, message);
+            let len = stamps.len();
+            let stamps: Vec<_> = stamps.collect();
+            assert_eq!(stamps.len(), len);
+            packets.extend(stamps);
+        }
+        assert_eq!(
+            packets,
+            [
+                note(60),
+                UmpMessage::Utility(Utility::DeltaClockstamp(480)),
+                note(62),
+                note(63),
+                UmpMessage::Utility(Utility::DeltaClockstamp(0xf_ffff)),
+                UmpMessage::Utility(Utility::DeltaClockstamp(0xf_ffff)),
+                UmpMessage::Utility(Utility::DeltaClockstamp(3)),
+                note(64),
+            ]
+        );
+        assert_eq!(writer.ticks(), 0x20_01e1);
+
+        let mut reader = DeltaClockReader::new();
+        let ticks: Vec<_> = packets
+            .iter()
+            .filter_map(|p| reader.push(p))
+            .map(|(t, _)| t)
+            .collect();
+        assert_eq!(ticks, [0, 480, 480, 0x20_01e1]);
+    }
+}
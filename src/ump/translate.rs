@@ -0,0 +1,515 @@
+//! Translation between MIDI 2.0 and MIDI 1.0 channel voice messages
+//!
+//! Follows the translation rules of the UMP and MIDI 2.0 protocol specification. Values are
+//! scaled with min-center-max scaling, registered and assignable controllers map to RPN and NRPN
+//! sequences, per-note and relative messages have no MIDI 1.0 equivalent and are dropped.
+//! Controller 127 and program 127 can't be represented by `midi-types` and are dropped as well.
+//!
+//! ```
+//! use midi_convert::ump::{Midi2ChannelVoice, translate::Midi1ToMidi2};
+//! use midi_types::MidiMessage;
+//!
+//! let mut translator = Midi1ToMidi2::new();
+//! let note_on = MidiMessage::NoteOn(0.into(), 60.into(), 0x7f.into());
+//! assert_eq!(
+//!     translator.translate(&note_on),
+//!     Some((
+//!         0.into(),
+//!         Midi2ChannelVoice::NoteOn {
+//!             note: 60.into(),
+//!             velocity: 0xffff,
+//!             attribute_type: 0,
+//!             attribute: 0,
+//!         }
+//!     ))
+//! );
+//! ```
+
+use {
+    super::Midi2ChannelVoice,
+    crate::scale::{
+        u16_to_value7, u32_to_value7, u32_to_value14, value7_to_u16, value7_to_u32, value14_to_u32,
+    },
+    midi_types::{Channel, Control, MidiMessage, Program, Value7, Value14},
+};
+
+const BANK_SELECT: u8 = 0;
+const BANK_SELECT_LSB: u8 = 32;
+const DATA_ENTRY: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+/// The controller and program number `midi-types` can't represent
+const MAX_NUMBER: u8 = 127;
+
+/// A selected registered (RPN) or assignable (NRPN) controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Parameter {
+    Registered { bank: u8, index: u8 },
+    Assignable { bank: u8, index: u8 },
+}
+
+/// Translates MIDI 2.0 channel voice messages to MIDI 1.0 messages
+///
+/// The RPN or NRPN selected on each channel is remembered so repeated controller messages only
+/// send the data entry controllers.
+#[derive(Debug, Clone, Default)]
+pub struct Midi2ToMidi1 {
+    selected: [Option<Parameter>; 16],
+}
+
+impl Midi2ToMidi1 {
+    /// Create a new translator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Translate a message on `channel`, returning the MIDI 1.0 messages to send
+    ///
+    /// Control changes of controller 127 and program changes to program 127 send nothing.
+    pub fn translate(
+        &mut self,
+        channel: Channel,
+        message: &Midi2ChannelVoice,
+    ) -> impl Iterator<Item = MidiMessage> + use<> {
+        let cc = |control: u8, value: u8| {
+            MidiMessage::ControlChange(channel, Control::new(control), Value7::new(value))
+        };
+        let mut out = [None; 4];
+        match *message {
+            Midi2ChannelVoice::NoteOff { note, velocity, .. } => {
+                out[0] = Some(MidiMessage::NoteOff(channel, note, u16_to_value7(velocity)));
+            }
+            Midi2ChannelVoice::NoteOn { note, velocity, .. } => {
+                // A MIDI 1.0 velocity of 0 would be a note off
+                let velocity = u8::from(u16_to_value7(velocity)).max(1);
+                out[0] = Some(MidiMessage::NoteOn(channel, note, Value7::new(velocity)));
+            }
+            Midi2ChannelVoice::KeyPressure { note, data } => {
+                out[0] = Some(MidiMessage::KeyPressure(channel, note, u32_to_value7(data)));
+            }
+            Midi2ChannelVoice::ControlChange { index, data } => {
+                if (NRPN_LSB..=RPN_MSB).contains(&index) {
+                    self.selected[usize::from(u8::from(channel))] = None;
+                }
+                if index < MAX_NUMBER {
+                    out[0] = Some(cc(index, u32_to_value7(data).into()));
+                }
+            }
+            Midi2ChannelVoice::ProgramChange { program, .. } if program >= MAX_NUMBER => {}
+            Midi2ChannelVoice::ProgramChange { program, bank } => {
+                if let Some(bank) = bank {
+                    let (msb, lsb): (u8, u8) = bank.into();
+                    out[0] = Some(cc(BANK_SELECT, msb));
+                    out[1] = Some(cc(BANK_SELECT_LSB, lsb));
+                }
+                out[2] = Some(MidiMessage::ProgramChange(channel, Program::new(program)));
+            }
+            Midi2ChannelVoice::ChannelPressure(data) => {
+                out[0] = Some(MidiMessage::ChannelPressure(channel, u32_to_value7(data)));
+            }
+            Midi2ChannelVoice::PitchBendChange(data) => {
+                out[0] = Some(MidiMessage::PitchBendChange(channel, u32_to_value14(data)));
+            }
+            Midi2ChannelVoice::RegisteredController { bank, index, data }
+            | Midi2ChannelVoice::AssignableController { bank, index, data } => {
+                let (parameter, msb, lsb) = match message {
+                    Midi2ChannelVoice::RegisteredController { .. } => {
+                        (Parameter::Registered { bank, index }, RPN_MSB, RPN_LSB)
+                    }
+                    _ => (Parameter::Assignable { bank, index }, NRPN_MSB, NRPN_LSB),
+                };
+                let selected = &mut self.selected[usize::from(u8::from(channel))];
+                if *selected != Some(parameter) {
+                    *selected = Some(parameter);
+                    out[0] = Some(cc(msb, bank));
+                    out[1] = Some(cc(lsb, index));
+                }
+                let (msb, lsb): (u8, u8) = u32_to_value14(data).into();
+                out[2] = Some(cc(DATA_ENTRY, msb));
+                out[3] = Some(cc(DATA_ENTRY_LSB, lsb));
+            }
+            // No MIDI 1.0 equivalent
+            Midi2ChannelVoice::RegisteredPerNoteController { .. }
+            | Midi2ChannelVoice::AssignablePerNoteController { .. }
+            | Midi2ChannelVoice::RelativeRegisteredController { .. }
+            | Midi2ChannelVoice::RelativeAssignableController { .. }
+            | Midi2ChannelVoice::PerNotePitchBend { .. }
+            | Midi2ChannelVoice::PerNoteManagement { .. } => {}
+        }
+        out.into_iter().flatten()
+    }
+}
+
+/// The bank and controller selections of a MIDI 1.0 channel
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+    bank: Option<(u8, u8)>,
+    registered: Option<bool>,
+    parameter: (u8, u8),
+    data: (u8, u8),
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            bank: None,
+            registered: None,
+            parameter: (0x7f, 0x7f),
+            data: (0, 0),
+        }
+    }
+}
+
+/// Translates MIDI 1.0 channel voice messages to MIDI 2.0 messages
+///
+/// Bank select controllers are held until the next program change, RPN and NRPN sequences become
+/// registered and assignable controller messages on each data entry controller.
+#[derive(Debug, Clone, Default)]
+pub struct Midi1ToMidi2 {
+    channels: [ChannelState; 16],
+}
+
+impl Midi1ToMidi2 {
+    /// Create a new translator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Translate a message, returning the MIDI 2.0 message and its channel
+    ///
+    /// System messages and the controllers that only change the translator state give `None`.
+    pub fn translate(&mut self, message: &MidiMessage) -> Option<(Channel, Midi2ChannelVoice)> {
+        let translated = match *message {
+            MidiMessage::NoteOff(channel, note, velocity) => (
+                channel,
+                Midi2ChannelVoice::NoteOff {
+                    note,
+                    velocity: value7_to_u16(velocity),
+                    attribute_type: 0,
+                    attribute: 0,
+                },
+            ),
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) == 0 => (
+                channel,
+                Midi2ChannelVoice::NoteOff {
+                    note,
+                    velocity: 0x8000,
+                    attribute_type: 0,
+                    attribute: 0,
+                },
+            ),
+            MidiMessage::NoteOn(channel, note, velocity) => (
+                channel,
+                Midi2ChannelVoice::NoteOn {
+                    note,
+                    velocity: value7_to_u16(velocity),
+                    attribute_type: 0,
+                    attribute: 0,
+                },
+            ),
+            MidiMessage::KeyPressure(channel, note, value) => (
+                channel,
+                Midi2ChannelVoice::KeyPressure {
+                    note,
+                    data: value7_to_u32(value),
+                },
+            ),
+            MidiMessage::ControlChange(channel, control, value) => {
+                return self.control_change(channel, control.into(), value.into());
+            }
+            MidiMessage::ProgramChange(channel, program) => (
+                channel,
+                Midi2ChannelVoice::ProgramChange {
+                    program: program.into(),
+                    bank: self.channels[usize::from(u8::from(channel))]
+                        .bank
+                        .map(|(msb, lsb)| Value14::new(msb, lsb)),
+                },
+            ),
+            MidiMessage::ChannelPressure(channel, value) => (
+                channel,
+                Midi2ChannelVoice::ChannelPressure(value7_to_u32(value)),
+            ),
+            MidiMessage::PitchBendChange(channel, value) => (
+                channel,
+                Midi2ChannelVoice::PitchBendChange(value14_to_u32(value)),
+            ),
+            _ => return None,
+        };
+        Some(translated)
+    }
+
+    fn control_change(
+        &mut self,
+        channel: Channel,
+        control: u8,
+        value: u8,
+    ) -> Option<(Channel, Midi2ChannelVoice)> {
+        let state = &mut self.channels[usize::from(u8::from(channel))];
+        match control {
+            BANK_SELECT => state.bank = Some((value, state.bank.map_or(0, |(_, lsb)| lsb))),
+            BANK_SELECT_LSB => state.bank = Some((state.bank.map_or(0, |(msb, _)| msb), value)),
+            RPN_MSB | NRPN_MSB => {
+                state.registered = Some(control == RPN_MSB);
+                state.parameter.0 = value;
+            }
+            RPN_LSB | NRPN_LSB => {
+                state.registered = Some(control == RPN_LSB);
+                state.parameter.1 = value;
+            }
+            DATA_ENTRY | DATA_ENTRY_LSB => {
+                if control == DATA_ENTRY {
+                    state.data = (value, 0);
+                } else {
+                    state.data.1 = value;
+                }
+                let (bank, index) = state.parameter;
+                let data = value14_to_u32(Value14::new(state.data.0, state.data.1));
+                return match state.registered {
+                    // The null RPN, 127/127, deselects
+                    _ if (bank, index) == (0x7f, 0x7f) => None,
+                    Some(true) => {
+                        Some(Midi2ChannelVoice::RegisteredController { bank, index, data })
+                    }
+                    Some(false) => {
+                        Some(Midi2ChannelVoice::AssignableController { bank, index, data })
+                    }
+                    None => None,
+                }
+                .map(|message| (channel, message));
+            }
+            _ => {
+                return Some((
+                    channel,
+                    Midi2ChannelVoice::ControlChange {
+                        index: control,
+                        data: value7_to_u32(Value7::new(value)),
+                    },
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::{
+            parse::MidiTryParseSlice,
+            render_slice::MidiRenderSlice,
+            ump::{Group, UmpMessage},
+        },
+        std::vec::Vec,
+    };
+
+    fn midi2(words: [u32; 2]) -> (Channel, Midi2ChannelVoice) {
+        match UmpMessage::parse(&words) {
+            Ok(UmpMessage::Midi2ChannelVoice {
+                channel, message, ..
+            }) => (channel, message),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    fn words((channel, message): (Channel, Midi2ChannelVoice)) -> [u32; 2] {
+        let mut words = [0; 2];
+        UmpMessage::Midi2ChannelVoice {
+            group: Group::new(0),
+            channel,
+            message,
+        }
+        .render(&mut words)
+        .unwrap();
+        words
+    }
+
+    /// Translate MIDI 1.0 bytes to MIDI 2.0 packets
+    fn up(translator: &mut Midi1ToMidi2, bytes: &[u8]) -> Vec<[u32; 2]> {
+        let mut packets = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let message = MidiMessage::try_parse_slice(rest).unwrap();
+            rest = &rest[message.len()..];
+            packets.extend(translator.translate(&message).map(words));
+        }
+        packets
+    }
+
+    /// Translate a MIDI 2.0 packet to MIDI 1.0 bytes
+    fn down(translator: &mut Midi2ToMidi1, packet: [u32; 2]) -> Vec<u8> {
+        let (channel, message) = midi2(packet);
+        let mut bytes = Vec::new();
+        for message in translator.translate(channel, &message) {
+            let mut buf = [0; 3];
+            let len = message.render_slice(&mut buf);
+            bytes.extend_from_slice(&buf[..len]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn should_translate_midi1_to_midi2() {
+        let mut translator = Midi1ToMidi2::new();
+        let vectors: [(&[u8], &[[u32; 2]]); 10] = [
+            (&[0x93, 0x3c, 0x7f], &[[0x4093_3c00, 0xffff_0000]]),
+            (&[0x93, 0x3c, 0x00], &[[0x4083_3c00, 0x8000_0000]]),
+            (&[0x83, 0x3c, 0x40], &[[0x4083_3c00, 0x8000_0000]]),
+            (&[0xa3, 0x3c, 0x41], &[[0x40a3_3c00, 0x8208_2082]]),
+            (&[0xb3, 0x07, 0x40], &[[0x40b3_0700, 0x8000_0000]]),
+            (&[0xd3, 0x01], &[[0x40d3_0000, 0x0200_0000]]),
+            (&[0xe3, 0x00, 0x40], &[[0x40e3_0000, 0x8000_0000]]),
+            (&[0xe3, 0x7f, 0x7f], &[[0x40e3_0000, 0xffff_ffff]]),
+            (&[0xc3, 0x05], &[[0x40c3_0000, 0x0500_0000]]),
+            (
+                &[0xb3, 0x00, 0x01, 0xb3, 0x20, 0x02, 0xc3, 0x05, 0xc3, 0x06],
+                &[[0x40c3_0001, 0x0500_0102], [0x40c3_0001, 0x0600_0102]],
+            ),
+        ];
+        for (bytes, packets) in vectors {
+            assert_eq!(up(&mut translator, bytes), packets, "{bytes:x?}");
+        }
+        // Other channels keep their own bank
+        assert_eq!(
+            up(&mut translator, &[0xc4, 0x05]),
+            [[0x40c4_0000, 0x0500_0000]]
+        );
+    }
+
+    #[test]
+    fn should_translate_rpn_and_nrpn_to_midi2() {
+        let mut translator = Midi1ToMidi2::new();
+        // Data entry without a selection is dropped
+        assert!(up(&mut translator, &[0xb0, 0x06, 0x02]).is_empty());
+        assert_eq!(
+            up(
+                &mut translator,
+                &[0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00, 0xb0, 0x06, 0x02]
+            ),
+            [[0x4020_0000, 0x0400_0000]]
+        );
+        assert_eq!(
+            up(&mut translator, &[0xb0, 0x26, 0x40]),
+            [[0x4020_0000, 0x0500_0000]]
+        );
+        assert_eq!(
+            up(
+                &mut translator,
+                &[
+                    0xb0, 0x63, 0x12, 0xb0, 0x62, 0x34, 0xb0, 0x06, 0x7f, 0xb0, 0x26, 0x7f
+                ]
+            ),
+            [[0x4030_1234, 0xfe03_f01f], [0x4030_1234, 0xffff_ffff]]
+        );
+        // The null RPN deselects
+        assert!(
+            up(
+                &mut translator,
+                &[0xb0, 0x65, 0x7f, 0xb0, 0x64, 0x7f, 0xb0, 0x06, 0x02]
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn should_translate_midi2_to_midi1() {
+        let mut translator = Midi2ToMidi1::new();
+        let vectors: [([u32; 2], &[u8]); 10] = [
+            ([0x4093_3c00, 0xffff_0000], &[0x93, 0x3c, 0x7f]),
+            ([0x4093_3c00, 0x0000_0000], &[0x93, 0x3c, 0x01]),
+            ([0x4093_3c00, 0x01ff_0000], &[0x93, 0x3c, 0x01]),
+            ([0x4083_3c03, 0x8000_1234], &[0x83, 0x3c, 0x40]),
+            ([0x40a3_3c00, 0x8208_2082], &[0xa3, 0x3c, 0x41]),
+            ([0x40b3_0700, 0x81ff_ffff], &[0xb3, 0x07, 0x40]),
+            ([0x40d3_0000, 0x0200_0000], &[0xd3, 0x01]),
+            ([0x40e3_0000, 0x8000_0000], &[0xe3, 0x00, 0x40]),
+            ([0x40c3_0000, 0x0500_0000], &[0xc3, 0x05]),
+            (
+                [0x40c3_0001, 0x0500_0102],
+                &[0xb3, 0x00, 0x01, 0xb3, 0x20, 0x02, 0xc3, 0x05],
+            ),
+        ];
+        for (packet, bytes) in vectors {
+            assert_eq!(down(&mut translator, packet), bytes, "{packet:x?}");
+        }
+    }
+
+    #[test]
+    fn should_translate_controllers_to_rpn_and_nrpn() {
+        let mut translator = Midi2ToMidi1::new();
+        assert_eq!(
+            down(&mut translator, [0x4020_0000, 0x0400_0000]),
+            [
+                0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00, 0xb0, 0x06, 0x02, 0xb0, 0x26, 0x00
+            ]
+        );
+        // The selection is only sent when it changes
+        assert_eq!(
+            down(&mut translator, [0x4020_0000, 0x8000_0000]),
+            [0xb0, 0x06, 0x40, 0xb0, 0x26, 0x00]
+        );
+        assert_eq!(
+            down(&mut translator, [0x4030_1234, 0xffff_ffff]),
+            [
+                0xb0, 0x63, 0x12, 0xb0, 0x62, 0x34, 0xb0, 0x06, 0x7f, 0xb0, 0x26, 0x7f
+            ]
+        );
+        assert_eq!(
+            down(&mut translator, [0x4021_0000, 0x0400_0000]),
+            [
+                0xb1, 0x65, 0x00, 0xb1, 0x64, 0x00, 0xb1, 0x06, 0x02, 0xb1, 0x26, 0x00
+            ]
+        );
+        // A raw selection controller invalidates the remembered one
+        assert_eq!(down(&mut translator, [0x40b1_6500, 0]), [0xb1, 0x65, 0x00]);
+        assert_eq!(
+            down(&mut translator, [0x4021_0000, 0x0400_0000]),
+            [
+                0xb1, 0x65, 0x00, 0xb1, 0x64, 0x00, 0xb1, 0x06, 0x02, 0xb1, 0x26, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn should_drop_messages_without_midi1_equivalent() {
+        let mut translator = Midi2ToMidi1::new();
+        for packet in [
+            [0x4000_3c01, 0x1234_5678],
+            [0x4010_3c01, 0x1234_5678],
+            [0x4040_0102, 0x0000_0001],
+            [0x4050_0102, 0xffff_ffff],
+            [0x4060_3c00, 0x8000_0000],
+            [0x40f0_3c03, 0],
+            [0x40b0_7f00, 0xffff_ffff],
+            [0x40c0_0000, 0x7f00_0000],
+            [0x40c0_0001, 0x7f00_0102],
+        ] {
+            assert!(down(&mut translator, packet).is_empty(), "{packet:x?}");
+        }
+    }
+
+    #[test]
+    fn should_round_trip_midi1_messages() {
+        let mut up_translator = Midi1ToMidi2::new();
+        let mut down_translator = Midi2ToMidi1::new();
+        let bytes = [
+            0x90, 0x3c, 0x64, 0x80, 0x3c, 0x00, 0xa5, 0x40, 0x7f, 0xb5, 0x07, 0x7e, 0xc5, 0x7e,
+            0xd5, 0x33, 0xe5, 0x12, 0x34, 0xb2, 0x65, 0x00, 0xb2, 0x64, 0x01, 0xb2, 0x06, 0x40,
+            0xb2, 0x26, 0x01,
+        ];
+        let packets = up(&mut up_translator, &bytes);
+        let translated: Vec<u8> = packets
+            .into_iter()
+            .flat_map(|packet| down(&mut down_translator, packet))
+            .collect();
+        // CC 6 is sent again with the LSB of 0 it implies
+        let mut expected = bytes.to_vec();
+        expected.splice(28..28, [0xb2, 0x26, 0x00, 0xb2, 0x06, 0x40]);
+        assert_eq!(translated, expected);
+    }
+}
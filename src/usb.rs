@@ -0,0 +1,529 @@
+//! USB-MIDI 1.0 event packets
+//!
+//! USB-MIDI 1.0 transfers midi as 4 byte event packets. The first byte holds the virtual cable
+//! number in the high nibble and the Code Index Number (CIN), which tells how many of the following
+//! three bytes are used, in the low nibble.
+//!
+//! ```
+//! use midi_convert::usb::{UsbMidiDecoder, UsbMidiEvent, UsbMidiPacket};
+//! use midi_types::MidiMessage;
+//!
+//! let message = MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into());
+//! let packet = UsbMidiPacket::from_message(1, &message);
+//! assert_eq!(packet.to_bytes(), [0x19, 0x92, 0x76, 0x34]);
+//!
+//! let mut decoder: UsbMidiDecoder<64> = UsbMidiDecoder::new();
+//! assert_eq!(
+//!     decoder.decode(packet),
+//!     Ok(Some(UsbMidiEvent::Message { cable: 1, message }))
+//! );
+//! ```
+
+use {
+    crate::{parse::MidiParser, render::encode},
+    midi_types::{MidiMessage, status::*},
+};
+
+#[cfg(feature = "usb-device")]
+pub mod class;
+
+/// Code Index Numbers for SysEx packets
+const CIN_SYSEX: u8 = 0x4;
+const CIN_SYSEX_END_1: u8 = 0x5;
+const CIN_SYSEX_END_2: u8 = 0x6;
+const CIN_SYSEX_END_3: u8 = 0x7;
+
+/// A single USB-MIDI 1.0 event packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UsbMidiPacket([u8; 4]);
+
+impl UsbMidiPacket {
+    /// Create a packet from its raw bytes
+    pub const fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self(bytes)
+    }
+
+    /// The raw bytes of the packet
+    pub const fn to_bytes(self) -> [u8; 4] {
+        self.0
+    }
+
+    /// Create a packet from a cable number, a Code Index Number and up to 3 midi bytes
+    fn new(cable: u8, cin: u8, bytes: &[u8]) -> Self {
+        debug_assert!(cable <= 15, "Cable number exceeds valid range");
+        let mut packet = [(cable & 0x0f) << 4 | cin, 0, 0, 0];
+        packet[1..=bytes.len()].copy_from_slice(bytes);
+        Self(packet)
+    }
+
+    /// Encode a message for the virtual cable `cable` (0..15)
+    pub fn from_message(cable: u8, message: &MidiMessage) -> Self {
+        let mut buf = [0; 3];
+        let bytes = encode::<false>(message, &mut None, &mut buf);
+        let status = bytes[0];
+        let cin = match status {
+            0x80..=0xEF => status >> 4,
+            TUNE_REQUEST => 0x5,
+            0xF0..=0xF7 => bytes.len() as u8,
+            _ => 0xF,
+        };
+        Self::new(cable, cin, bytes)
+    }
+
+    /// The virtual cable number
+    pub const fn cable(&self) -> u8 {
+        self.0[0] >> 4
+    }
+
+    /// The Code Index Number
+    pub const fn code_index(&self) -> u8 {
+        self.0[0] & 0x0f
+    }
+
+    /// The midi bytes carried by this packet, this is empty for reserved Code Index Numbers
+    pub fn payload(&self) -> &[u8] {
+        let len = match self.code_index() {
+            0x5 | 0xF => 1,
+            0x2 | 0x6 | 0xC | 0xD => 2,
+            0x3 | 0x4 | 0x7..=0xB | 0xE => 3,
+            _ => 0,
+        };
+        &self.0[1..=len]
+    }
+}
+
+impl From<[u8; 4]> for UsbMidiPacket {
+    fn from(bytes: [u8; 4]) -> Self {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl From<UsbMidiPacket> for [u8; 4] {
+    fn from(packet: UsbMidiPacket) -> Self {
+        packet.to_bytes()
+    }
+}
+
+/// Split a buffer received from a USB endpoint into packets, trailing bytes that do not form a whole
+/// packet are ignored
+pub fn packets(buf: &[u8]) -> impl Iterator<Item = UsbMidiPacket> + '_ {
+    buf.chunks_exact(4)
+        .map(|chunk| UsbMidiPacket([chunk[0], chunk[1], chunk[2], chunk[3]]))
+}
+
+/// Iterator over the packets of a SysEx message, see [`sysex_packets`]
+#[derive(Debug, Clone)]
+pub struct SysExPackets<'a> {
+    cable: u8,
+    data: &'a [u8],
+}
+
+impl Iterator for SysExPackets<'_> {
+    type Item = UsbMidiPacket;
+
+    fn next(&mut self) -> Option<UsbMidiPacket> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let len = self.data.len().min(3);
+        let (chunk, rest) = self.data.split_at(len);
+        self.data = rest;
+        let cin = if rest.is_empty() && chunk[len - 1] == SYSEX_END {
+            match len {
+                1 => CIN_SYSEX_END_1,
+                2 => CIN_SYSEX_END_2,
+                _ => CIN_SYSEX_END_3,
+            }
+        } else {
+            CIN_SYSEX
+        };
+        Some(UsbMidiPacket::new(self.cable, cin, chunk))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.data.len().div_ceil(3);
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for SysExPackets<'_> {}
+
+/// Split a complete SysEx message, including the leading 0xF0 and trailing 0xF7, into packets for
+/// the virtual cable `cable`.
+///
+/// ```
+/// use midi_convert::usb::sysex_packets;
+///
+/// let packets: Vec<[u8; 4]> = sysex_packets(0, &[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7])
+///     .map(Into::into)
+///     .collect();
+/// assert_eq!(packets, [[0x04, 0xF0, 0x7E, 0x7F], [0x07, 0x06, 0x01, 0xF7]]);
+/// ```
+pub fn sysex_packets(cable: u8, data: &[u8]) -> SysExPackets<'_> {
+    SysExPackets { cable, data }
+}
+
+/// Decoded USB-MIDI events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UsbMidiEvent<'a> {
+    /// A midi message
+    Message { cable: u8, message: MidiMessage },
+
+    /// A complete SysEx message, including the leading 0xF0 and trailing 0xF7
+    SysEx { cable: u8, data: &'a [u8] },
+}
+
+/// Errors decoding USB-MIDI packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UsbMidiError {
+    /// The packet does not contain a valid midi message for its Code Index Number
+    InvalidPacket,
+
+    /// A SysEx message did not fit in the reassembly buffer and was dropped
+    SysExTooLong { cable: u8 },
+
+    /// SysEx data was received for a cable without a SysEx start
+    UnexpectedSysEx { cable: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SysExBuffer<const N: usize> {
+    data: [u8; N],
+    len: usize,
+    active: bool,
+    overflow: bool,
+}
+
+/// Decodes USB-MIDI packets into midi messages, reassembling SysEx messages of up to N bytes per
+/// virtual cable
+#[derive(Debug, Clone)]
+pub struct UsbMidiDecoder<const N: usize> {
+    sysex: [SysExBuffer<N>; 16],
+}
+
+impl<const N: usize> UsbMidiDecoder<N> {
+    /// Create a new decoder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode a packet, returning an event when a message or SysEx message is completed
+    pub fn decode(
+        &mut self,
+        packet: UsbMidiPacket,
+    ) -> Result<Option<UsbMidiEvent<'_>>, UsbMidiError> {
+        Ok(self.feed(packet)?.map(|completed| self.event(completed)))
+    }
+
+    /// Decode a packet without borrowing the completed SysEx data, see [`event`](Self::event)
+    pub(crate) fn feed(
+        &mut self,
+        packet: UsbMidiPacket,
+    ) -> Result<Option<Completed>, UsbMidiError> {
+        let cable = packet.cable();
+        let payload = packet.payload();
+        match packet.code_index() {
+            // Reserved for future extensions, also used for padding
+            0x0 | 0x1 => Ok(None),
+            CIN_SYSEX => self.sysex_data(cable, payload, false),
+            CIN_SYSEX_END_1 if payload[0] != SYSEX_END => parse_message(cable, payload),
+            CIN_SYSEX_END_1..=CIN_SYSEX_END_3 => self.sysex_data(cable, payload, true),
+            _ => parse_message(cable, payload),
+        }
+    }
+
+    /// The event for a completed message returned by [`feed`](Self::feed)
+    pub(crate) fn event(&self, completed: Completed) -> UsbMidiEvent<'_> {
+        match completed {
+            Completed::Message(cable, message) => UsbMidiEvent::Message { cable, message },
+            Completed::SysEx(cable) => {
+                let buffer = &self.sysex[usize::from(cable)];
+                UsbMidiEvent::SysEx {
+                    cable,
+                    data: &buffer.data[..buffer.len],
+                }
+            }
+        }
+    }
+
+    /// Drop all partially received SysEx messages
+    pub fn reset(&mut self) {
+        for buffer in self.sysex.iter_mut() {
+            buffer.active = false;
+            buffer.overflow = false;
+            buffer.len = 0;
+        }
+    }
+
+    fn sysex_data(
+        &mut self,
+        cable: u8,
+        payload: &[u8],
+        end: bool,
+    ) -> Result<Option<Completed>, UsbMidiError> {
+        let buffer = &mut self.sysex[usize::from(cable)];
+        if payload[0] == SYSEX_START {
+            // A new SysEx start aborts any unfinished SysEx
+            buffer.active = true;
+            buffer.overflow = false;
+            buffer.len = 0;
+        } else if !buffer.active {
+            return Err(UsbMidiError::UnexpectedSysEx { cable });
+        }
+
+        if buffer.len + payload.len() <= N {
+            buffer.data[buffer.len..buffer.len + payload.len()].copy_from_slice(payload);
+            buffer.len += payload.len();
+        } else {
+            buffer.overflow = true;
+        }
+
+        if !end {
+            return Ok(None);
+        }
+
+        buffer.active = false;
+        if buffer.overflow {
+            buffer.overflow = false;
+            return Err(UsbMidiError::SysExTooLong { cable });
+        }
+        Ok(Some(Completed::SysEx(cable)))
+    }
+}
+
+/// A message completed by [`UsbMidiDecoder::feed`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Completed {
+    Message(u8, MidiMessage),
+    SysEx(u8),
+}
+
+impl<const N: usize> Default for UsbMidiDecoder<N> {
+    fn default() -> Self {
+        Self {
+            sysex: [SysExBuffer {
+                data: [0; N],
+                len: 0,
+                active: false,
+                overflow: false,
+            }; 16],
+        }
+    }
+}
+
+/// Parse the payload of a non SysEx packet, every packet must hold exactly one message
+fn parse_message(cable: u8, payload: &[u8]) -> Result<Option<Completed>, UsbMidiError> {
+    let mut parser = MidiParser::new();
+    let mut result = None;
+    for (i, byte) in payload.iter().enumerate() {
+        if let Some(message) = parser.parse(*byte) {
+            if i + 1 != payload.len() || message.len() != payload.len() {
+                break;
+            }
+            result = Some(message);
+        }
+    }
+
+    match result {
+        Some(message) => Ok(Some(Completed::Message(cable, message))),
+        None => Err(UsbMidiError::InvalidPacket),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{TEST_1BYTE, TEST_2BYTE, TEST_3BYTE};
+
+    extern crate std;
+    use std::vec::Vec;
+
+    type SysExResult = Result<Option<(u8, Vec<u8>)>, UsbMidiError>;
+
+    fn decode_all<const N: usize>(
+        decoder: &mut UsbMidiDecoder<N>,
+        packets: impl Iterator<Item = UsbMidiPacket>,
+    ) -> Vec<SysExResult> {
+        packets
+            .map(|packet| {
+                decoder.decode(packet).map(|event| match event {
+                    Some(UsbMidiEvent::SysEx { cable, data }) => Some((cable, data.to_vec())),
+                    Some(UsbMidiEvent::Message { .. }) => panic!("unexpected message"),
+                    None => None,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_encode_code_index_numbers() {
+        let expected: [(MidiMessage, [u8; 4]); 9] = [
+            (
+                MidiMessage::NoteOff(2.into(), 0x3c.into(), 0x40.into()),
+                [0x08, 0x82, 0x3c, 0x40],
+            ),
+            (
+                MidiMessage::ControlChange(0.into(), 7.into(), 0x40.into()),
+                [0x0B, 0xB0, 0x07, 0x40],
+            ),
+            (
+                MidiMessage::ProgramChange(15.into(), 3.into()),
+                [0x0C, 0xCF, 0x03, 0x00],
+            ),
+            (
+                MidiMessage::ChannelPressure(1.into(), 3.into()),
+                [0x0D, 0xD1, 0x03, 0x00],
+            ),
+            (
+                MidiMessage::PitchBendChange(8.into(), (0x56, 0x14).into()),
+                [0x0E, 0xE8, 0x14, 0x56],
+            ),
+            (
+                MidiMessage::QuarterFrame(0x23.into()),
+                [0x02, 0xF1, 0x23, 0x00],
+            ),
+            (
+                MidiMessage::SongPositionPointer((0x68, 0x7f).into()),
+                [0x03, 0xF2, 0x7f, 0x68],
+            ),
+            (MidiMessage::TuneRequest, [0x05, 0xF6, 0x00, 0x00]),
+            (MidiMessage::TimingClock, [0x0F, 0xF8, 0x00, 0x00]),
+        ];
+        for (message, bytes) in expected {
+            assert_eq!(UsbMidiPacket::from_message(0, &message).to_bytes(), bytes);
+        }
+        assert_eq!(
+            UsbMidiPacket::from_message(15, &MidiMessage::Start).to_bytes(),
+            [0xFF, 0xFA, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn should_round_trip_messages() {
+        let mut decoder: UsbMidiDecoder<0> = UsbMidiDecoder::new();
+        for (cable, message) in TEST_1BYTE
+            .iter()
+            .chain(TEST_2BYTE.iter())
+            .chain(TEST_3BYTE.iter())
+            .enumerate()
+        {
+            let cable = cable as u8 % 16;
+            let packet = UsbMidiPacket::from_message(cable, message);
+            assert_eq!(packet.cable(), cable);
+            assert_eq!(packet.payload().len(), message.len());
+            assert_eq!(
+                decoder.decode(packet),
+                Ok(Some(UsbMidiEvent::Message {
+                    cable,
+                    message: *message
+                }))
+            );
+        }
+    }
+
+    #[test]
+    fn should_split_sysex() {
+        let cases: [(&[u8], &[[u8; 4]]); 4] = [
+            (&[0xF0, 0xF7], &[[0x36, 0xF0, 0xF7, 0x00]]),
+            (&[0xF0, 0x01, 0xF7], &[[0x37, 0xF0, 0x01, 0xF7]]),
+            (
+                &[0xF0, 0x01, 0x02, 0xF7],
+                &[[0x34, 0xF0, 0x01, 0x02], [0x35, 0xF7, 0x00, 0x00]],
+            ),
+            (
+                &[0xF0, 0x01, 0x02, 0x03, 0xF7],
+                &[[0x34, 0xF0, 0x01, 0x02], [0x36, 0x03, 0xF7, 0x00]],
+            ),
+        ];
+        for (data, expected) in cases {
+            let packets: Vec<[u8; 4]> = sysex_packets(3, data).map(Into::into).collect();
+            assert_eq!(packets, expected);
+            assert_eq!(sysex_packets(3, data).len(), expected.len());
+        }
+    }
+
+    #[test]
+    fn should_reassemble_sysex_per_cable() {
+        let a = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+        let b = [0xF0, 0x43, 0x10, 0x4C, 0x00, 0x00, 0x7E, 0x00, 0xF7];
+        let mut interleaved = Vec::new();
+        let mut packets_a = sysex_packets(0, &a);
+        let mut packets_b = sysex_packets(1, &b);
+        interleaved.extend(packets_b.next());
+        interleaved.extend(packets_a.next());
+        interleaved.push(UsbMidiPacket::from_message(1, &MidiMessage::TimingClock));
+        interleaved.extend(packets_b);
+        interleaved.extend(packets_a);
+
+        let mut decoder: UsbMidiDecoder<16> = UsbMidiDecoder::new();
+        let mut events = Vec::new();
+        for packet in interleaved {
+            match decoder.decode(packet).unwrap() {
+                Some(UsbMidiEvent::SysEx { cable, data }) => events.push((cable, data.to_vec())),
+                Some(UsbMidiEvent::Message { cable, message }) => {
+                    assert_eq!((cable, message), (1, MidiMessage::TimingClock))
+                }
+                None => {}
+            }
+        }
+        assert_eq!(events, [(1, b.to_vec()), (0, a.to_vec())]);
+    }
+
+    #[test]
+    fn should_report_sysex_overflow() {
+        let mut decoder: UsbMidiDecoder<4> = UsbMidiDecoder::new();
+        let data = [0xF0, 0x01, 0x02, 0x03, 0x04, 0xF7];
+        assert_eq!(
+            decode_all(&mut decoder, sysex_packets(2, &data)),
+            [Ok(None), Err(UsbMidiError::SysExTooLong { cable: 2 })]
+        );
+
+        // The decoder recovers for the next message
+        let data = [0xF0, 0x01, 0xF7];
+        assert_eq!(
+            decode_all(&mut decoder, sysex_packets(2, &data)),
+            [Ok(Some((2, data.to_vec())))]
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_packets() {
+        let mut decoder: UsbMidiDecoder<4> = UsbMidiDecoder::new();
+        assert_eq!(
+            decoder.decode([0x07, 0x01, 0x02, 0xF7].into()),
+            Err(UsbMidiError::UnexpectedSysEx { cable: 0 })
+        );
+        assert_eq!(
+            decoder
+                .decode([0x09, 0x80, 0x3c, 0x40].into())
+                .map(|e| e.is_some()),
+            Ok(true)
+        );
+        assert_eq!(
+            decoder.decode([0x09, 0x3c, 0x40, 0x00].into()),
+            Err(UsbMidiError::InvalidPacket)
+        );
+        assert_eq!(
+            decoder.decode([0x0C, 0x90, 0x3c, 0x40].into()),
+            Err(UsbMidiError::InvalidPacket)
+        );
+        assert_eq!(decoder.decode([0x00, 0x00, 0x00, 0x00].into()), Ok(None));
+    }
+
+    #[test]
+    fn should_split_endpoint_buffers() {
+        let buf = [0x09, 0x90, 0x3c, 0x40, 0x0F, 0xF8, 0x00, 0x00, 0x0B];
+        let packets: Vec<UsbMidiPacket> = packets(&buf).collect();
+        assert_eq!(
+            packets,
+            [
+                UsbMidiPacket::from_bytes([0x09, 0x90, 0x3c, 0x40]),
+                UsbMidiPacket::from_bytes([0x0F, 0xF8, 0x00, 0x00]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,695 @@
+//! A USB-MIDI 1.0 device class for `usb-device`
+//!
+//! The class describes a MIDIStreaming interface with a configurable number of virtual cables in
+//! each direction. Every cable is an embedded jack connected to an external jack, packets received
+//! on the bulk OUT endpoint are decoded with a [`UsbMidiDecoder`] and messages written to the class
+//! are sent as packets on the bulk IN endpoint.
+//!
+//! ```no_run
+//! # fn example<B: usb_device::class_prelude::UsbBus>(alloc: usb_device::class_prelude::UsbBusAllocator<B>) {
+//! use midi_convert::render::MidiRenderer;
+//! use midi_convert::usb::{UsbMidiEvent, class::UsbMidiClass};
+//! use usb_device::prelude::*;
+//!
+//! let mut midi: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 1);
+//! let mut device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x5e4)).build();
+//!
+//! loop {
+//!     device.poll(&mut [&mut midi]);
+//!     while let Ok(Some(event)) = midi.read() {
+//!         if let UsbMidiEvent::Message { message, .. } = event {
+//!             // Echo every message back to the host
+//!             MidiRenderer::<_>::new(&mut midi).render(&message).ok();
+//!             break;
+//!         }
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! The configuration descriptor takes 101 bytes with one cable in each direction and grows by 16
+//! bytes for every further cable. `usb-device` uses a 128 byte control buffer by default, which
+//! fits three cables in total, enable its `control-buffer-256` feature for more.
+
+use {
+    super::{UsbMidiDecoder, UsbMidiError, UsbMidiEvent, UsbMidiPacket, sysex_packets},
+    crate::{parse::MidiParser, render::MidiTransport},
+    core::fmt,
+    midi_types::MidiMessage,
+    usb_device::class_prelude::*,
+};
+
+/// Max packet size of the bulk endpoints
+const MAX_PACKET_SIZE: usize = 64;
+
+const AUDIO_CLASS: u8 = 0x01;
+const AUDIOCONTROL_SUBCLASS: u8 = 0x01;
+const MIDISTREAMING_SUBCLASS: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+const HEADER_SUBTYPE: u8 = 0x01;
+const MIDI_IN_JACK_SUBTYPE: u8 = 0x02;
+const MIDI_OUT_JACK_SUBTYPE: u8 = 0x03;
+const MS_GENERAL_SUBTYPE: u8 = 0x01;
+
+const EMBEDDED: u8 = 0x01;
+const EXTERNAL: u8 = 0x02;
+
+/// Errors reading from or writing to a [`UsbMidiClass`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbMidiClassError {
+    /// The USB stack reported an error, `WouldBlock` means the IN endpoint is still busy
+    Usb(UsbError),
+
+    /// A received packet could not be decoded
+    Midi(UsbMidiError),
+}
+
+impl From<UsbError> for UsbMidiClassError {
+    fn from(error: UsbError) -> Self {
+        Self::Usb(error)
+    }
+}
+
+impl From<UsbMidiError> for UsbMidiClassError {
+    fn from(error: UsbMidiError) -> Self {
+        Self::Midi(error)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for UsbMidiClassError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            // usb-device implements a different major version of defmt
+            Self::Usb(error) => defmt::write!(f, "Usb({})", defmt::Debug2Format(error)),
+            Self::Midi(error) => defmt::write!(f, "Midi({})", error),
+        }
+    }
+}
+
+/// USB-MIDI 1.0 class with `rx_cables` virtual cables from the host and `tx_cables` virtual cables
+/// to the host, reassembling received SysEx messages of up to N bytes per cable.
+///
+/// Received packets are read with [`read`](Self::read). Written messages are collected into a
+/// packet buffer that is sent when it is full, when [`flush`](Self::flush) is called or when the
+/// device is polled. The class also implements [`MidiTransport`] so a
+/// [`MidiRenderer`](crate::render::MidiRenderer) can write to it, those messages are sent on the
+/// cable selected with [`set_cable`](Self::set_cable).
+pub struct UsbMidiClass<'a, B: UsbBus, const N: usize = 64> {
+    audio_if: InterfaceNumber,
+    midi_if: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+    rx_cables: u8,
+    tx_cables: u8,
+    decoder: UsbMidiDecoder<N>,
+    rx_buf: [u8; MAX_PACKET_SIZE],
+    rx_pos: usize,
+    rx_len: usize,
+    tx_buf: [u8; MAX_PACKET_SIZE],
+    tx_len: usize,
+    parser: MidiParser,
+    cable: u8,
+}
+
+impl<'a, B: UsbBus, const N: usize> UsbMidiClass<'a, B, N> {
+    /// Allocate the interfaces and endpoints for a class with `rx_cables` and `tx_cables` virtual
+    /// cables, both must be between 1 and 16
+    pub fn new(alloc: &'a UsbBusAllocator<B>, rx_cables: u8, tx_cables: u8) -> Self {
+        assert!((1..=16).contains(&rx_cables), "Invalid number of rx cables");
+        assert!((1..=16).contains(&tx_cables), "Invalid number of tx cables");
+        Self {
+            audio_if: alloc.interface(),
+            midi_if: alloc.interface(),
+            ep_out: alloc.bulk(MAX_PACKET_SIZE as u16),
+            ep_in: alloc.bulk(MAX_PACKET_SIZE as u16),
+            rx_cables,
+            tx_cables,
+            decoder: UsbMidiDecoder::new(),
+            rx_buf: [0; MAX_PACKET_SIZE],
+            rx_pos: 0,
+            rx_len: 0,
+            tx_buf: [0; MAX_PACKET_SIZE],
+            tx_len: 0,
+            parser: MidiParser::new(),
+            cable: 0,
+        }
+    }
+
+    /// The number of virtual cables from the host
+    pub fn rx_cables(&self) -> u8 {
+        self.rx_cables
+    }
+
+    /// The number of virtual cables to the host
+    pub fn tx_cables(&self) -> u8 {
+        self.tx_cables
+    }
+
+    /// Select the cable used for messages written through [`MidiTransport`]
+    pub fn set_cable(&mut self, cable: u8) {
+        assert!(cable < self.tx_cables, "Cable number exceeds tx cables");
+        self.cable = cable;
+    }
+
+    /// Read the next event received from the host, returns `None` when no more packets are
+    /// available
+    pub fn read(&mut self) -> Result<Option<UsbMidiEvent<'_>>, UsbMidiClassError> {
+        loop {
+            if self.rx_pos + 4 > self.rx_len {
+                match self.ep_out.read(&mut self.rx_buf) {
+                    Ok(len) => {
+                        self.rx_pos = 0;
+                        self.rx_len = len;
+                    }
+                    Err(UsbError::WouldBlock) => return Ok(None),
+                    Err(error) => return Err(error.into()),
+                }
+                continue;
+            }
+
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&self.rx_buf[self.rx_pos..self.rx_pos + 4]);
+            self.rx_pos += 4;
+            if let Some(completed) = self.decoder.feed(UsbMidiPacket::from_bytes(bytes))? {
+                return Ok(Some(self.decoder.event(completed)));
+            }
+        }
+    }
+
+    /// Queue a message for the virtual cable `cable`
+    ///
+    /// Fails with `UsbError::WouldBlock` when the packet buffer is full and the IN endpoint is
+    /// still busy, the message is not queued in that case.
+    pub fn write_message(
+        &mut self,
+        cable: u8,
+        message: &MidiMessage,
+    ) -> Result<(), UsbMidiClassError> {
+        self.write_packet(UsbMidiPacket::from_message(cable, message))
+    }
+
+    /// Queue a SysEx message, including the leading 0xF0 and trailing 0xF7, for the virtual cable
+    /// `cable`
+    ///
+    /// Returns the number of bytes queued. This is less than the length of `data` when the IN
+    /// endpoint is busy, the remaining bytes should be written again later.
+    pub fn write_sysex(&mut self, cable: u8, data: &[u8]) -> Result<usize, UsbMidiClassError> {
+        let mut written = 0;
+        for packet in sysex_packets(cable, data) {
+            match self.write_packet(packet) {
+                Ok(()) => written += packet.payload().len(),
+                Err(UsbMidiClassError::Usb(UsbError::WouldBlock)) => break,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Send the queued packets to the host
+    pub fn flush(&mut self) -> Result<(), UsbMidiClassError> {
+        if self.tx_len > 0 {
+            self.ep_in.write(&self.tx_buf[..self.tx_len])?;
+            self.tx_len = 0;
+        }
+        Ok(())
+    }
+
+    fn write_packet(&mut self, packet: UsbMidiPacket) -> Result<(), UsbMidiClassError> {
+        debug_assert!(
+            packet.cable() < self.tx_cables,
+            "Cable number exceeds tx cables"
+        );
+        if self.tx_len == MAX_PACKET_SIZE {
+            self.flush()?;
+        }
+        self.tx_buf[self.tx_len..self.tx_len + 4].copy_from_slice(&packet.to_bytes());
+        self.tx_len += 4;
+        Ok(())
+    }
+
+    /// The jack id of the embedded IN jack for rx cable `cable`
+    fn rx_jack(&self, cable: u8) -> u8 {
+        1 + 2 * cable
+    }
+
+    /// The jack id of the embedded OUT jack for tx cable `cable`
+    fn tx_jack(&self, cable: u8) -> u8 {
+        2 * self.rx_cables + 2 + 2 * cable
+    }
+}
+
+impl<B: UsbBus, const N: usize> UsbClass<B> for UsbMidiClass<'_, B, N> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.audio_if, AUDIO_CLASS, AUDIOCONTROL_SUBCLASS, 0)?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                HEADER_SUBTYPE,
+                0x00, // bcdADC 1.00
+                0x01,
+                0x09, // wTotalLength
+                0x00,
+                0x01, // bInCollection
+                self.midi_if.into(),
+            ],
+        )?;
+
+        writer.interface(self.midi_if, AUDIO_CLASS, MIDISTREAMING_SUBCLASS, 0)?;
+        let cables = u16::from(self.rx_cables + self.tx_cables);
+        let total = 7 + cables * (6 + 9) + 2 * (9 + 4) + cables;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                HEADER_SUBTYPE,
+                0x00, // bcdMSC 1.00
+                0x01,
+                total as u8,
+                (total >> 8) as u8,
+            ],
+        )?;
+
+        for cable in 0..self.rx_cables {
+            let jack = self.rx_jack(cable);
+            writer.write(CS_INTERFACE, &[MIDI_IN_JACK_SUBTYPE, EMBEDDED, jack, 0x00])?;
+            writer.write(
+                CS_INTERFACE,
+                &[
+                    MIDI_OUT_JACK_SUBTYPE,
+                    EXTERNAL,
+                    jack + 1,
+                    0x01,
+                    jack,
+                    0x01,
+                    0x00,
+                ],
+            )?;
+        }
+        for cable in 0..self.tx_cables {
+            let jack = self.tx_jack(cable);
+            writer.write(
+                CS_INTERFACE,
+                &[MIDI_IN_JACK_SUBTYPE, EXTERNAL, jack - 1, 0x00],
+            )?;
+            writer.write(
+                CS_INTERFACE,
+                &[
+                    MIDI_OUT_JACK_SUBTYPE,
+                    EMBEDDED,
+                    jack,
+                    0x01,
+                    jack - 1,
+                    0x01,
+                    0x00,
+                ],
+            )?;
+        }
+
+        // Audio class endpoints have 2 extra bytes, bRefresh and bSynchAddress
+        let audio_endpoint = |buf: &mut [u8]| {
+            buf[..2].fill(0);
+            Ok(2)
+        };
+
+        let mut jacks = [0; 17];
+        jacks[0] = MS_GENERAL_SUBTYPE;
+
+        writer.endpoint_ex(&self.ep_out, audio_endpoint)?;
+        let len = usize::from(self.rx_cables);
+        jacks[1] = self.rx_cables;
+        for cable in 0..self.rx_cables {
+            jacks[2 + usize::from(cable)] = self.rx_jack(cable);
+        }
+        writer.write(CS_ENDPOINT, &jacks[..2 + len])?;
+
+        writer.endpoint_ex(&self.ep_in, audio_endpoint)?;
+        let len = usize::from(self.tx_cables);
+        jacks[1] = self.tx_cables;
+        for cable in 0..self.tx_cables {
+            jacks[2 + usize::from(cable)] = self.tx_jack(cable);
+        }
+        writer.write(CS_ENDPOINT, &jacks[..2 + len])
+    }
+
+    fn reset(&mut self) {
+        self.decoder.reset();
+        self.rx_pos = 0;
+        self.rx_len = 0;
+        self.tx_len = 0;
+        self.parser = MidiParser::new();
+    }
+
+    fn poll(&mut self) {
+        // A busy endpoint keeps the packets queued for the next poll
+        self.flush().ok();
+    }
+}
+
+impl<B: UsbBus, const N: usize> MidiTransport for UsbMidiClass<'_, B, N> {
+    type Error = UsbMidiClassError;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), UsbMidiClassError> {
+        for byte in bytes {
+            if let Some(message) = self.parser.parse(*byte) {
+                self.write_message(self.cable, &message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: UsbBus, const N: usize> MidiTransport for &mut UsbMidiClass<'_, B, N> {
+    type Error = UsbMidiClassError;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), UsbMidiClassError> {
+        (**self).write(bytes)
+    }
+}
+
+impl<B: UsbBus, const N: usize> fmt::Debug for UsbMidiClass<'_, B, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UsbMidiClass")
+            .field("rx_cables", &self.rx_cables)
+            .field("tx_cables", &self.tx_cables)
+            .field("decoder", &self.decoder)
+            .field("tx_len", &self.tx_len)
+            .field("cable", &self.cable)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::render::MidiRenderer;
+    use midi_types::{Channel, Note, Value7};
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        vec::Vec,
+    };
+    use usb_device::{
+        UsbDirection,
+        bus::PollResult,
+        endpoint::EndpointType,
+        prelude::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+    };
+
+    #[derive(Debug, Default)]
+    struct Host {
+        next_out: usize,
+        next_in: usize,
+        setup: Option<Vec<u8>>,
+        out: [VecDeque<Vec<u8>>; 16],
+        written: [Vec<Vec<u8>>; 16],
+        in_complete: u16,
+        busy: bool,
+    }
+
+    /// A simulated bus, the test plays the host through the shared state
+    struct SimBus(Arc<Mutex<Host>>);
+
+    impl UsbBus for SimBus {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            ep_addr: Option<EndpointAddress>,
+            _ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> usb_device::Result<EndpointAddress> {
+            if let Some(addr) = ep_addr {
+                return Ok(addr);
+            }
+            let mut host = self.0.lock().unwrap();
+            let next = match ep_dir {
+                UsbDirection::Out => &mut host.next_out,
+                UsbDirection::In => &mut host.next_in,
+            };
+            *next += 1;
+            Ok(EndpointAddress::from_parts(*next, ep_dir))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _addr: u8) {}
+
+        fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+            let mut host = self.0.lock().unwrap();
+            if host.busy && ep_addr.index() != 0 {
+                return Err(UsbError::WouldBlock);
+            }
+            host.written[ep_addr.index()].push(buf.to_vec());
+            host.in_complete |= 1 << ep_addr.index();
+            Ok(buf.len())
+        }
+
+        fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+            let mut host = self.0.lock().unwrap();
+            let packet = match ep_addr.index() {
+                0 if host.setup.is_some() => host.setup.take(),
+                index => host.out[index].pop_front(),
+            };
+            let packet = packet.ok_or(UsbError::WouldBlock)?;
+            buf.get_mut(..packet.len())
+                .ok_or(UsbError::BufferOverflow)?
+                .copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            let mut host = self.0.lock().unwrap();
+            let ep_setup = u16::from(host.setup.is_some());
+            let ep_out = (0..16)
+                .filter(|i| !host.out[*i].is_empty())
+                .fold(0, |bits, i| bits | 1 << i);
+            let ep_in_complete = core::mem::take(&mut host.in_complete);
+            if ep_setup | ep_out | ep_in_complete == 0 {
+                PollResult::None
+            } else {
+                PollResult::Data {
+                    ep_out,
+                    ep_in_complete,
+                    ep_setup,
+                }
+            }
+        }
+    }
+
+    fn sim_bus() -> (Arc<Mutex<Host>>, UsbBusAllocator<SimBus>) {
+        let host = Arc::new(Mutex::new(Host::default()));
+        let alloc = UsbBusAllocator::new(SimBus(host.clone()));
+        (host, alloc)
+    }
+
+    /// Build the device, the bus can not be used before it is frozen by the device
+    fn device(alloc: &UsbBusAllocator<SimBus>) -> UsbDevice<'_, SimBus> {
+        UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x5e4))
+            .max_packet_size_0(64)
+            .unwrap()
+            .build()
+    }
+
+    #[test]
+    fn should_describe_midistreaming_interface() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 1);
+        let mut device = device(&alloc);
+
+        // GET_DESCRIPTOR(CONFIGURATION)
+        host.lock().unwrap().setup =
+            Some([0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00].to_vec());
+        for _ in 0..8 {
+            device.poll(&mut [&mut class]);
+        }
+        let descriptor: Vec<u8> = host.lock().unwrap().written[0].concat();
+
+        assert_eq!(&descriptor[..4], &[0x09, 0x02, 101, 0]);
+        assert_eq!(
+            &descriptor[9..],
+            &[
+                // AudioControl interface and header
+                0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, //
+                0x09, 0x24, 0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01, //
+                // MIDIStreaming interface and header
+                0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00, //
+                0x07, 0x24, 0x01, 0x00, 0x01, 0x41, 0x00, //
+                // Embedded IN jack 1 to external OUT jack 2
+                0x06, 0x24, 0x02, 0x01, 0x01, 0x00, //
+                0x09, 0x24, 0x03, 0x02, 0x02, 0x01, 0x01, 0x01, 0x00, //
+                // External IN jack 3 to embedded OUT jack 4
+                0x06, 0x24, 0x02, 0x02, 0x03, 0x00, //
+                0x09, 0x24, 0x03, 0x01, 0x04, 0x01, 0x03, 0x01, 0x00, //
+                // Bulk OUT endpoint feeding jack 1
+                0x09, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, //
+                0x05, 0x25, 0x01, 0x01, 0x01, //
+                // Bulk IN endpoint fed by jack 4
+                0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, //
+                0x05, 0x25, 0x01, 0x01, 0x04,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn should_read_packets_from_out_endpoint() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_, 8> = UsbMidiClass::new(&alloc, 2, 1);
+        let _device = device(&alloc);
+        host.lock().unwrap().out[1].push_back(
+            [
+                0x19, 0x92, 0x76, 0x34, // note on, cable 1
+                0x04, 0xf0, 0x7e, 0x7f, // sysex start, cable 0
+                0x0f, 0xf8, 0x00, 0x00, // timing clock, cable 0
+            ]
+            .to_vec(),
+        );
+        host.lock().unwrap().out[1].push_back([0x06, 0x01, 0xf7, 0x00].to_vec());
+
+        assert_eq!(
+            class.read(),
+            Ok(Some(UsbMidiEvent::Message {
+                cable: 1,
+                message: MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into())
+            }))
+        );
+        assert_eq!(
+            class.read(),
+            Ok(Some(UsbMidiEvent::Message {
+                cable: 0,
+                message: MidiMessage::TimingClock
+            }))
+        );
+        assert_eq!(
+            class.read(),
+            Ok(Some(UsbMidiEvent::SysEx {
+                cable: 0,
+                data: &[0xf0, 0x7e, 0x7f, 0x01, 0xf7]
+            }))
+        );
+        assert_eq!(class.read(), Ok(None));
+    }
+
+    #[test]
+    fn should_report_invalid_packets() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 1);
+        let _device = device(&alloc);
+        host.lock().unwrap().out[1].push_back([0x09, 0x3c, 0x40, 0x00].to_vec());
+
+        assert_eq!(
+            class.read(),
+            Err(UsbMidiClassError::Midi(UsbMidiError::InvalidPacket))
+        );
+        assert_eq!(class.read(), Ok(None));
+    }
+
+    #[test]
+    fn should_write_packets_on_flush() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 2);
+        let _device = device(&alloc);
+        class
+            .write_message(1, &MidiMessage::NoteOn(2.into(), 0x76.into(), 0x34.into()))
+            .unwrap();
+        assert_eq!(class.write_sysex(0, &[0xf0, 0x01, 0xf7]), Ok(3));
+        assert!(host.lock().unwrap().written[1].is_empty());
+
+        class.flush().unwrap();
+        assert_eq!(
+            host.lock().unwrap().written[1],
+            [[0x19, 0x92, 0x76, 0x34, 0x07, 0xf0, 0x01, 0xf7].to_vec()]
+        );
+    }
+
+    #[test]
+    fn should_flush_when_polled() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 1);
+        let mut device = device(&alloc);
+        class.write_message(0, &MidiMessage::Start).unwrap();
+
+        // The class is polled when the bus reports any data
+        host.lock().unwrap().out[1].push_back([0x0f, 0xfc, 0x00, 0x00].to_vec());
+        device.poll(&mut [&mut class]);
+        assert_eq!(
+            host.lock().unwrap().written[1],
+            [[0x0f, 0xfa, 0x00, 0x00].to_vec()]
+        );
+        assert_eq!(
+            class.read(),
+            Ok(Some(UsbMidiEvent::Message {
+                cable: 0,
+                message: MidiMessage::Stop
+            }))
+        );
+    }
+
+    #[test]
+    fn should_keep_packets_while_endpoint_busy() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 1);
+        let _device = device(&alloc);
+        host.lock().unwrap().busy = true;
+
+        for _ in 0..16 {
+            class.write_message(0, &MidiMessage::TimingClock).unwrap();
+        }
+        assert_eq!(
+            class.write_message(0, &MidiMessage::TimingClock),
+            Err(UsbMidiClassError::Usb(UsbError::WouldBlock))
+        );
+        assert_eq!(class.write_sysex(0, &[0xf0, 0x01, 0x02, 0x03, 0xf7]), Ok(0));
+
+        host.lock().unwrap().busy = false;
+        assert_eq!(class.write_sysex(0, &[0xf0, 0x01, 0x02, 0x03, 0xf7]), Ok(5));
+        class.flush().unwrap();
+
+        let written = &host.lock().unwrap().written[1];
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0].len(), 64);
+        assert_eq!(written[1], [0x04, 0xf0, 0x01, 0x02, 0x06, 0x03, 0xf7, 0x00]);
+    }
+
+    #[test]
+    fn should_render_through_transport() {
+        let (host, alloc) = sim_bus();
+        let mut class: UsbMidiClass<_> = UsbMidiClass::new(&alloc, 1, 2);
+        let _device = device(&alloc);
+        class.set_cable(1);
+
+        let mut renderer = MidiRenderer::<_, true>::new(&mut class);
+        let channel = Channel::new(0);
+        renderer
+            .render(&MidiMessage::NoteOn(channel, Note::C3, Value7::new(0x40)))
+            .unwrap();
+        // Sent with running status
+        renderer
+            .render(&MidiMessage::NoteOn(channel, Note::D4, Value7::new(0x41)))
+            .unwrap();
+        class.flush().unwrap();
+
+        assert_eq!(
+            host.lock().unwrap().written[1],
+            [[0x19, 0x90, 0x3c, 0x40, 0x19, 0x90, 0x4a, 0x41].to_vec()]
+        );
+    }
+}